@@ -0,0 +1,675 @@
+use std::collections::HashMap;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use eng_core::editor::core_event::Kind as CoreEventKind;
+use eng_core::editor::handshake_request::ClientKind;
+use eng_core::editor::{BufferInfo, CoreEvent};
+use eng_core::desktop::{self, project_root};
+use eng_core::remote::{self, CoreCommand};
+use eng_core::transport::{self, Address};
+use futures_util::future::join_all;
+use tokio::sync::broadcast;
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::launcher::{Launcher, ProcessHandle};
+use crate::proxy::CoreLink;
+
+/// 起動時に立ち上げ、プロジェクトを指定しないリクエストを受け持つコアの名前
+pub const DEFAULT_CORE: &str = "default";
+/// ヘルスチェックの間隔と、応答を待つ時間
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+/// 続けてこの回数ヘルスチェックに失敗したら、固まったとみなして止める
+const HEALTH_CHECK_FAILURES: u32 = 3;
+/// 落ちたコアを起動し直すまでの待ち時間。続けて落ちるたびに延ばし、使い切ったら諦める。
+const RESTART_DELAYS: [Duration; 5] = [
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+    Duration::from_secs(30),
+];
+/// これより長く動いていたコアが落ちたら、続けて落ちたとはみなさない
+const STABLE_UPTIME: Duration = Duration::from_secs(60);
+/// WatchCores で読み遅れた購読者のために溜めておく知らせの数
+const EVENT_CAPACITY: usize = 64;
+
+/// コアのセッション名 (ENG_DESKTOP_SESSION)。プロジェクトのコアはそのプロジェクトのセッションを使い、
+/// 名前で起動したコアは名前のセッションを使う。同じディレクトリで動くコア同士でセッションファイルを分け合わない。
+fn session_name(name: &str, project_root: Option<&Path>) -> String {
+    match project_root {
+        Some(root) => desktop::project_session_name(root),
+        None => name.replace('/', "!"),
+    }
+}
+
+/// コアの起動方法。Agent の起動時の引数で決まり、全てのコアで同じ。
+#[derive(Debug, Clone)]
+pub enum CoreKind {
+    /// 実行時ディレクトリの Unix ソケット、または [::1] の TCP で待ち受けさせる
+    Socket { tcp: bool },
+    /// 標準入出力の上で話す
+    Stdio,
+    /// 任意のコマンドで起動し、その標準入出力の上で話す (リモート開発)
+    Command(CoreCommand),
+    /// テスト用: プロセスの中で動かすコア。起動に delay かかる。desktop があれば、そこに保存したセッションを起動時に復元する
+    #[cfg(test)]
+    Stub { delay: Duration, desktop: Option<PathBuf> },
+}
+
+/// 起動中のコア
+#[derive(Debug)]
+pub struct Core {
+    pub name: String,
+    pub project_root: Option<PathBuf>,
+    pub link: CoreLink,
+    pub started: SystemTime,
+    handle: ProcessHandle,
+    /// 続けて落ちた回数 (起動し直したコアのみ 0 以外)
+    crashes: u32,
+    /// 続けて失敗したヘルスチェックの数
+    failures: u32,
+}
+
+impl Core {
+    pub fn address(&self) -> Option<&Address> {
+        self.handle.address.as_ref()
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        self.handle.child.id()
+    }
+}
+
+/// 起動しているところのコアの枠。起動を待つ全員が同じ結果を受け取る。
+/// 落ちたコアの枠は起動し直すまで残し、その間に来たリクエストも起動し直すのを待つ。
+#[derive(Debug)]
+struct Slot {
+    project_root: Option<PathBuf>,
+    /// 落ちたコアを起動し直すなら、続けて落ちた回数。0 なら初めて起動する
+    crashes: u32,
+    cell: tokio::sync::OnceCell<Result<CoreLink, String>>,
+}
+
+type Starting = Arc<Slot>;
+
+/// Agent を通して開いたバッファ
+#[derive(Debug, Clone)]
+struct BufferEntry {
+    /// バッファを持つコアの名前
+    core: String,
+    /// 訪問しているファイル。ファイルのバッファでなければ空
+    path: String,
+    /// 今動いているコアでのバッファID。コアが落ちてから、起動し直したコアで同じファイルが見つかるまでは None
+    current: Option<String>,
+}
+
+/// プロジェクトごと (または名前ごと) のコア。必要になったときに起動し、落ちたら起動し直す。
+#[derive(Debug)]
+pub struct CorePool {
+    kind: CoreKind,
+    cores: tokio::sync::Mutex<HashMap<String, Core>>,
+    /// 起動しているところのコア。同じコアを2つ起動しないよう、起動する前に名前の枠を取る。
+    /// 起動を待つ間は cores のロックを持たないので、他のコアへのリクエストを止めない。
+    starting: Mutex<HashMap<String, Starting>>,
+    /// UI が持っているバッファIDごとの、それを持つコア。バッファを指定するリクエストはそのコアへ送る。
+    /// コアを起動し直すとバッファIDが変わるので、起動し直したコアでのIDに置き換えて送る。
+    buffers: Mutex<HashMap<String, BufferEntry>>,
+    /// ソケットの名前を重ねないための通し番号
+    next_id: AtomicU32,
+    /// コアの起動・終了・再起動の知らせ (WatchCores)
+    events: broadcast::Sender<CoreEvent>,
+}
+
+impl CorePool {
+    pub fn new(kind: CoreKind) -> Self {
+        Self {
+            kind,
+            cores: tokio::sync::Mutex::new(HashMap::new()),
+            starting: Mutex::new(HashMap::new()),
+            buffers: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(0),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    /// コアをリモートで起動するか (CoreKind::Command)
+    pub fn is_remote(&self) -> bool {
+        matches!(self.kind, CoreKind::Command(_))
+    }
+
+    /// コアの起動・終了・再起動の知らせを受け取る
+    pub fn subscribe(&self) -> broadcast::Receiver<CoreEvent> {
+        self.events.subscribe()
+    }
+
+    fn notify(&self, kind: CoreEventKind, name: &str, message: String, buffers: u32) {
+        // 購読者がいなければ捨てる
+        let _ = self.events.send(CoreEvent { kind: kind.into(), name: name.to_string(), message, buffers });
+    }
+
+    fn buffers(&self) -> std::sync::MutexGuard<'_, HashMap<String, BufferEntry>> {
+        self.buffers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn starting(&self) -> std::sync::MutexGuard<'_, HashMap<String, Starting>> {
+        self.starting.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// name のコアを起動する枠。既に枠があれば (起動しているところ、または起動し直すのを待っているところなら)、
+    /// その枠と false
+    fn reserve(&self, name: &str, project_root: Option<&Path>, crashes: u32) -> (Starting, bool) {
+        let mut starting = self.starting();
+        if let Some(slot) = starting.get(name) {
+            return (slot.clone(), false);
+        }
+        let slot = Arc::new(Slot {
+            project_root: project_root.map(Path::to_path_buf),
+            crashes,
+            cell: tokio::sync::OnceCell::new(),
+        });
+        starting.insert(name.to_string(), slot.clone());
+        (slot, true)
+    }
+
+    /// 枠を取ったコアを起動して一覧に加える。同じ枠で待っていれば、先に待ち始めた方の起動を待つ。
+    /// 落ちたコアの枠なら待ち時間をおいて起動し直し (relaunch)、起動し直したことを知らせる。
+    async fn fill(&self, slot: &Starting, name: &str) -> Result<CoreLink, String> {
+        slot.cell
+            .get_or_init(|| async {
+                let project_root = slot.project_root.as_deref();
+                let launched = match slot.crashes {
+                    0 => self.launch(name, project_root).await.map(|core| (core, None)).map_err(|e| e.to_string()),
+                    crashes => self.relaunch(name, project_root, crashes).await.map(|(core, delay)| (core, Some(delay))),
+                };
+                // リクエストを受ける前に、落ちる前のバッファIDを起動し直したコアのものに置き換える
+                if let Ok((core, _)) = &launched {
+                    self.remap_buffers(name, &core.link).await;
+                }
+                let mut cores = self.cores.lock().await;
+                self.starting().remove(name);
+                let (core, delay) = launched?;
+                let link = core.link.clone();
+                cores.insert(name.to_string(), core);
+                drop(cores);
+                match delay {
+                    None => self.notify(CoreEventKind::Started, name, String::new(), 0),
+                    Some(delay) => {
+                        let buffers = link.recovered_buffers().await;
+                        eprintln!("Agent: Core {:?} restarted ({} buffers recovered).", name, buffers);
+                        self.notify(CoreEventKind::Restarted, name, format!("Restarted after {:?}", delay), buffers);
+                    }
+                }
+                Ok(link)
+            })
+            .await
+            .clone()
+    }
+
+    /// name のコアへの接続。起動していなければ project_root で起動する。
+    /// 落ちたコアなら、見守りと同じく待ち時間をおいて起動し直すのを待つ。
+    pub async fn get_or_start(self: &Arc<Self>, name: &str, project_root: Option<&Path>) -> Result<CoreLink, Status> {
+        let slot = {
+            let mut cores = self.cores.lock().await;
+            if let Some(core) = cores.get_mut(name) {
+                if matches!(core.handle.child.try_wait(), Ok(None)) {
+                    return Ok(core.link.clone());
+                }
+                // 見守りより先にリクエストが来た。このリクエストが取り消されても起動し直すよう、見守りと同じく別のタスクで起動し直す
+                if let Some(slot) = self.take_down(&mut cores, name, "has exited".to_string()) {
+                    tokio::spawn(self.clone().restart(name.to_string(), slot));
+                }
+            }
+            self.reserve(name, project_root, 0).0
+        };
+        self.fill(&slot, name)
+            .await
+            .map_err(|e| Status::unavailable(format!("Failed to start core {:?}: {}", name, e)))
+    }
+
+    /// path を含むプロジェクトのコアへの接続。コアの名前はプロジェクトのルート。
+    pub async fn for_project(self: &Arc<Self>, path: &Path) -> Result<(String, CoreLink), Status> {
+        let root = project_root(path);
+        let name = root.display().to_string();
+        let link = self.get_or_start(&name, Some(&root)).await?;
+        Ok((name, link))
+    }
+
+    async fn launch(&self, name: &str, project_root: Option<&Path>) -> Result<Core, Box<dyn std::error::Error>> {
+        let session = session_name(name, project_root);
+        let (handle, link) = match &self.kind {
+            CoreKind::Socket { tcp } => {
+                let listen = if *tcp {
+                    Address::Tcp(SocketAddr::from((Ipv6Addr::LOCALHOST, 0)))
+                } else {
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                    Address::Unix(transport::socket_path(&format!("core-{}-{}", std::process::id(), id))?)
+                };
+                let token = Uuid::new_v4().to_string();
+                let handle = Launcher::launch_core(&token, &listen, project_root, &session).await?;
+                let address = handle.address.clone().ok_or("Core address is missing")?;
+                eprintln!("Agent: Core {:?} launched on {}", name, address);
+                let channel = transport::connect(&address).await?;
+                (handle, CoreLink::new(channel, Some(&token))?)
+            }
+            CoreKind::Stdio | CoreKind::Command(_) => {
+                let handle = match &self.kind {
+                    CoreKind::Command(command) => Launcher::launch_core_command(command, project_root, &session).await?,
+                    _ => Launcher::launch_core_stdio(project_root, &session).await?,
+                };
+                let channel = handle.channel.clone().ok_or("Core stdio channel is missing")?;
+                let client_name = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+                let server_name = remote::handshake(channel.clone(), ClientKind::Agent, &client_name).await?;
+                eprintln!("Agent: Core {:?} launched on stdio ({})", name, server_name);
+                // パイプを持っているのは Agent だけなので、トークンは付けない
+                (handle, CoreLink::new(channel, None)?)
+            }
+            #[cfg(test)]
+            CoreKind::Stub { delay, desktop } => {
+                tokio::time::sleep(*delay).await;
+                let desktop = desktop.clone().map(|dir| desktop::DesktopConfig { dir, session: session.clone() });
+                let handle = tests::launch_stub(desktop).await?;
+                let channel = handle.channel.clone().ok_or("Core stdio channel is missing")?;
+                (handle, CoreLink::new(channel, None)?)
+            }
+        };
+        Ok(Core {
+            name: name.to_string(),
+            project_root: project_root.map(Path::to_path_buf),
+            link,
+            started: SystemTime::now(),
+            handle,
+            crashes: 0,
+            failures: 0,
+        })
+    }
+
+    /// 起動中のコアへの接続。落ちていても起動し直さない。
+    pub async fn link(&self, name: &str) -> Option<CoreLink> {
+        self.cores.lock().await.get(name).map(|core| core.link.clone())
+    }
+
+    /// Agent を通して開いたバッファの数
+    pub fn buffer_count(&self, core: &str) -> usize {
+        self.buffers().values().filter(|entry| entry.core == core && entry.current.is_some()).count()
+    }
+
+    /// バッファを持つコアを記録する
+    pub fn remember_buffer(&self, info: &BufferInfo, core: &str) {
+        let entry = BufferEntry { core: core.to_string(), path: info.path.clone(), current: Some(info.buffer_id.clone()) };
+        self.buffers().insert(info.buffer_id.clone(), entry);
+    }
+
+    /// バッファを持つコアの名前と、今そのコアでのバッファID。Agent を通して開いたバッファでなければ None。
+    /// コアが落ちて、起動し直したコアにそのバッファが無ければIDも None
+    pub fn core_for_buffer(&self, buffer_id: &str) -> Option<(String, Option<String>)> {
+        self.buffers().get(buffer_id).map(|entry| (entry.core.clone(), entry.current.clone()))
+    }
+
+    /// 起動し直したコアが desktop から開き直したファイルを探し、落ちる前のバッファIDを新しいIDに置き換える
+    async fn remap_buffers(&self, name: &str, link: &CoreLink) {
+        let lost = self.buffers().values().any(|entry| entry.core == name && entry.current.is_none());
+        if !lost {
+            return;
+        }
+        let reopened = link.buffer_list().await;
+        for entry in self.buffers().values_mut().filter(|entry| entry.core == name && entry.current.is_none()) {
+            if entry.path.is_empty() {
+                continue;
+            }
+            if let Some(info) = reopened.iter().find(|info| info.path == entry.path) {
+                entry.current = Some(info.buffer_id.clone());
+            }
+        }
+    }
+
+    /// 名前の順に、起動中のコアについて f の結果を集める
+    pub async fn list<T>(&self, f: impl Fn(&Core) -> T) -> Vec<T> {
+        let cores = self.cores.lock().await;
+        let mut names: Vec<_> = cores.keys().collect();
+        names.sort();
+        names.into_iter().map(|name| f(&cores[name])).collect()
+    }
+
+    /// コアを終了させる。起動していなければ false
+    pub async fn stop(&self, name: &str) -> bool {
+        let Some(mut core) = self.cores.lock().await.remove(name) else {
+            return false;
+        };
+        self.buffers().retain(|_, entry| entry.core != name);
+        Launcher::terminate(&mut core.handle.child).await;
+        eprintln!("Agent: Core {:?} stopped.", name);
+        self.notify(CoreEventKind::Stopped, name, String::new(), 0);
+        true
+    }
+
+    /// 全てのコアの、保存していない変更のあるファイル
+    pub async fn unsaved_files(&self) -> Vec<String> {
+        let links: Vec<CoreLink> = self.cores.lock().await.values().map(|core| core.link.clone()).collect();
+        let mut files = Vec::new();
+        for link in links {
+            files.extend(link.unsaved_files().await);
+        }
+        files
+    }
+
+    /// 全てのコアを終了させる (Agent の終了時)
+    pub async fn stop_all(&self) {
+        let cores: Vec<Core> = self.cores.lock().await.drain().map(|(_, core)| core).collect();
+        self.buffers().clear();
+        for mut core in cores {
+            Launcher::terminate(&mut core.handle.child).await;
+        }
+    }
+
+    /// コアを見守り続ける。落ちたコアと、ヘルスチェックに続けて応えないコアを起動し直す。
+    pub async fn supervise(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+            let names: Vec<String> = self.cores.lock().await.keys().cloned().collect();
+            let checks = join_all(names.iter().map(|name| self.check(name))).await;
+            for (name, slot) in names.into_iter().zip(checks) {
+                if let Some(slot) = slot {
+                    tokio::spawn(self.clone().restart(name, slot));
+                }
+            }
+        }
+    }
+
+    /// コアが動いていて、ヘルスチェックに応えるかを確かめる。止めたら、起動し直す枠
+    async fn check(&self, name: &str) -> Option<Starting> {
+        let (link, pid) = {
+            let mut cores = self.cores.lock().await;
+            let core = cores.get_mut(name)?;
+            match core.handle.child.try_wait() {
+                Ok(None) => (core.link.clone(), core.pid()),
+                Ok(Some(status)) => return self.take_down(&mut cores, name, format!("exited ({})", status)),
+                Err(e) => return self.take_down(&mut cores, name, format!("is lost ({})", e)),
+            }
+        };
+        let healthy = link.healthy(HEALTH_CHECK_TIMEOUT).await;
+        let mut cores = self.cores.lock().await;
+        // 確かめている間に止めたか、起動し直していれば何もしない
+        let core = cores.get_mut(name).filter(|core| core.pid() == pid)?;
+        if healthy {
+            core.failures = 0;
+            return None;
+        }
+        core.failures += 1;
+        if core.failures < HEALTH_CHECK_FAILURES {
+            return None;
+        }
+        let _ = core.handle.child.start_kill();
+        let reason = format!("did not respond to {} health checks", HEALTH_CHECK_FAILURES);
+        self.take_down(&mut cores, name, reason)
+    }
+
+    /// 落ちたコアを一覧から外し、起動し直す枠を取る。持っていたバッファは、起動し直したときに置き換えるまで使えない
+    fn take_down(&self, cores: &mut HashMap<String, Core>, name: &str, reason: String) -> Option<Starting> {
+        let core = cores.remove(name)?;
+        for entry in self.buffers().values_mut().filter(|entry| entry.core == name) {
+            entry.current = None;
+        }
+        if let Some(Address::Unix(path)) = core.address() {
+            let _ = std::fs::remove_file(path);
+        }
+        eprintln!("Agent: Core {:?} {}.", name, reason);
+        self.notify(CoreEventKind::Exited, name, reason, 0);
+        // 起動してすぐに落ちたなら、続けて落ちたとみなして待ち時間を延ばす
+        let uptime = core.started.elapsed().unwrap_or_default();
+        let crashes = if uptime < STABLE_UPTIME { core.crashes + 1 } else { 1 };
+        // 起動し直すまでの間に来たリクエストは、新しく起動せずに起動し直すのを待つ
+        Some(self.reserve(name, core.project_root.as_deref(), crashes).0)
+    }
+
+    /// 落ちたコアを枠で起動し直す。コアは自動保存した desktop からバッファを復元する。
+    async fn restart(self: Arc<Self>, name: String, slot: Starting) {
+        // 失敗は relaunch が知らせる
+        let _ = self.fill(&slot, &name).await;
+    }
+
+    /// 待ち時間をおいてコアを起動する。続けて失敗するたびに待ち時間を延ばし、使い切ったら諦める。
+    /// 起動したコアと、最後に待った時間を返す。
+    async fn relaunch(&self, name: &str, project_root: Option<&Path>, mut crashes: u32) -> Result<(Core, Duration), String> {
+        while let Some(delay) = RESTART_DELAYS.get(crashes as usize - 1) {
+            tokio::time::sleep(*delay).await;
+            match self.launch(name, project_root).await {
+                Ok(mut core) => {
+                    core.crashes = crashes;
+                    return Ok((core, *delay));
+                }
+                Err(e) => {
+                    eprintln!("Agent: Failed to restart core {:?}: {}", name, e);
+                    crashes += 1;
+                }
+            }
+        }
+        eprintln!("Agent: Core {:?} keeps crashing. Giving up.", name);
+        let message = format!("Crashed {} times in a row", RESTART_DELAYS.len());
+        self.notify(CoreEventKind::GaveUp, name, message.clone(), 0);
+        Err(message)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use eng_core::editor::buffer_service_client::BufferServiceClient;
+    use eng_core::editor::buffer_service_server::BufferServiceServer;
+    use eng_core::editor::editor_service_server::EditorServiceServer;
+    use eng_core::editor::editor_service_client::EditorServiceClient;
+    use eng_core::editor::{ApplyEditsRequest, CreateBufferRequest, ExecuteCommandRequest, TextEdit};
+    use eng_core::sched;
+    use eng_core::state::EditorState;
+    use eng_core::{MyBufferService, MyEditorService};
+    use std::time::Instant;
+    use tokio_stream::StreamExt;
+
+    /// プロセスの中でコアを動かし、パイプでつなぐ。子プロセスは代わりの sleep で、止めるとコアが落ちたことになる。
+    pub async fn launch_stub(desktop: Option<desktop::DesktopConfig>) -> Result<ProcessHandle, Box<dyn std::error::Error>> {
+        let state = Arc::new(EditorState::new());
+        if let Some(config) = desktop {
+            desktop::enable(&state, config).await?;
+        }
+        let (client_end, server_end) = tokio::io::duplex(64 * 1024);
+        let (server_reader, server_writer) = tokio::io::split(server_end);
+        let (stream, closed) = transport::PipeStream::new(server_reader, server_writer);
+        let (_, health_service) = tonic_health::server::health_reporter();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(EditorServiceServer::new(MyEditorService::new(state.clone())))
+                .add_service(BufferServiceServer::new(MyBufferService::new(state.clone())))
+                .add_service(health_service)
+                .serve_with_incoming_shutdown(
+                    tokio_stream::once(Ok::<_, std::io::Error>(stream)).chain(tokio_stream::pending()),
+                    async {
+                        let _ = closed.await;
+                    },
+                ),
+        );
+        tokio::spawn(sched::run(state));
+        let (client_reader, client_writer) = tokio::io::split(client_end);
+        let channel = transport::connect_pipe(client_reader, client_writer).await?;
+        let child = tokio::process::Command::new("sleep").arg("1000").kill_on_drop(true).spawn()?;
+        Ok(ProcessHandle { child, address: None, channel: Some(channel) })
+    }
+
+    /// コアを落とし、見守りが気付いたことにする
+    async fn crash(pool: &CorePool, name: &str) -> Starting {
+        pool.cores.lock().await.get_mut(name).unwrap().handle.child.kill().await.unwrap();
+        pool.check(name).await.unwrap()
+    }
+
+    /// 他のモジュールのテスト用: コアを落とし、リクエストが来るまで起動し直さずにおく
+    pub async fn kill(pool: &CorePool, name: &str) {
+        crash(pool, name).await;
+    }
+
+    #[test]
+    fn test_session_name() {
+        let dir = std::env::temp_dir().join(format!("eng-agent-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("Cargo.toml"), "").unwrap();
+        // コアを分けるプロジェクトのルートと、コアのセッションは同じ定義
+        let root = project_root(&dir.join("src/main.rs"));
+        assert_eq!(root, dir);
+        assert_eq!(session_name(&root.display().to_string(), Some(&root)), desktop::project_session_name(&dir.join("src")));
+        // 名前で起動したコアは、同じディレクトリで動いていてもセッションを分ける
+        assert_eq!(session_name(DEFAULT_CORE, None), "default");
+        assert_ne!(session_name(DEFAULT_CORE, None), session_name(&root.display().to_string(), Some(&root)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_start_does_not_block_other_cores() {
+        let pool = Arc::new(CorePool::new(CoreKind::Stub { delay: Duration::from_millis(300), desktop: None }));
+        let mut events = pool.subscribe();
+
+        // 同じコアを同時に求めても1つだけ起動する
+        let (a, b) = tokio::join!(pool.get_or_start("a", None), pool.get_or_start("a", None));
+        assert!(a.is_ok() && b.is_ok());
+        assert_eq!(pool.list(|core| core.name.clone()).await, ["a"]);
+        assert_eq!(events.try_recv().unwrap().kind, CoreEventKind::Started as i32);
+        assert!(events.try_recv().is_err());
+
+        // 他のコアが起動しているところでも、起動済みのコアへのリクエストは待たされない
+        let starting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.get_or_start("b", None).await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let begin = Instant::now();
+        pool.get_or_start("a", None).await.unwrap();
+        assert_eq!(pool.list(|core| core.name.clone()).await, ["a"]);
+        assert!(begin.elapsed() < Duration::from_millis(200));
+        starting.await.unwrap().unwrap();
+        assert_eq!(pool.list(|core| core.name.clone()).await, ["a", "b"]);
+        pool.stop_all().await;
+    }
+
+    #[tokio::test]
+    async fn test_restart_reports_recovered_buffers() {
+        let dir = std::env::temp_dir().join(format!("eng-agent-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pool = Arc::new(CorePool::new(CoreKind::Stub { delay: Duration::ZERO, desktop: Some(dir.join("desktop")) }));
+        let link = pool.get_or_start("a", None).await.unwrap();
+        let mut events = pool.subscribe();
+
+        // 未保存の内容のあるファイル2つと、ファイルの無いバッファを自動保存する
+        let mut buffers = BufferServiceClient::new(link.channel());
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            let path = dir.join(name);
+            std::fs::write(&path, "a\n").unwrap();
+            let info = link.open_file(&path.display().to_string()).await.unwrap();
+            if name == "c.txt" {
+                continue;
+            }
+            let edit = TextEdit { start: 0, end: 0, text: "x".into() };
+            let request = ApplyEditsRequest { buffer_id: info.buffer_id, edits: vec![edit], base_version: None };
+            buffers.apply_edits(request).await.unwrap();
+        }
+        buffers.create_buffer(CreateBufferRequest { name: "notes".into(), text: "draft".into() }).await.unwrap();
+        let request = ExecuteCommandRequest { command: "desktop-save".into(), args: vec![String::new()], ..Default::default() };
+        EditorServiceClient::new(link.channel()).execute_command(request).await.unwrap();
+        assert_eq!(link.recovered_buffers().await, 0);
+
+        // 自動保存の後にファイルが変えられていたら、未保存の内容は別のバッファに戻す
+        let file = std::fs::File::options().write(true).open(dir.join("b.txt")).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+
+        // 起動し直したコアが復元したときに知らせた数を使う
+        let slot = crash(&pool, "a").await;
+        assert_eq!(events.recv().await.unwrap().kind, CoreEventKind::Exited as i32);
+        pool.clone().restart("a".into(), slot).await;
+        let event = events.recv().await.unwrap();
+        assert_eq!((event.kind, event.buffers), (CoreEventKind::Restarted as i32, 2));
+        let link = pool.link("a").await.unwrap();
+        let names: Vec<_> = link.buffer_list().await.into_iter().map(|info| info.name).collect();
+        assert!(names.contains(&"b.txt (auto-save)".to_string()));
+        assert_eq!(link.unsaved_files().await.len(), 1);
+        pool.stop_all().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restart_backoff() {
+        let pool = Arc::new(CorePool::new(CoreKind::Stub { delay: Duration::ZERO, desktop: None }));
+        pool.get_or_start("a", None).await.unwrap();
+        let mut events = pool.subscribe();
+
+        // 起動し直したコアがすぐに落ちたら、続けて落ちたとみなして次の待ち時間を延ばす
+        let slot = crash(&pool, "a").await;
+        assert_eq!(slot.crashes, 1);
+        let begin = Instant::now();
+        pool.clone().restart("a".into(), slot).await;
+        assert!(begin.elapsed() >= RESTART_DELAYS[0]);
+        assert_eq!(crash(&pool, "a").await.crashes, 2);
+
+        // 見守りより先にリクエストが来ても、落ちた回数を数えたまま待ち時間をおいて起動し直す
+        let begin = Instant::now();
+        pool.get_or_start("a", None).await.unwrap();
+        assert!(begin.elapsed() >= RESTART_DELAYS[1]);
+        assert_eq!(pool.cores.lock().await["a"].crashes, 2);
+
+        // 長く動いていたコアが落ちたのなら数え直す
+        if let Some(core) = pool.cores.lock().await.get_mut("a") {
+            core.crashes = 3;
+            core.started = SystemTime::now() - STABLE_UPTIME * 2;
+        }
+        assert_eq!(crash(&pool, "a").await.crashes, 1);
+
+        // 待ち時間を使い切ったら諦める
+        pool.starting().remove("a");
+        let (slot, _) = pool.reserve("a", None, RESTART_DELAYS.len() as u32 + 1);
+        pool.clone().restart("a".into(), slot).await;
+        let kinds: Vec<_> = std::iter::from_fn(|| events.try_recv().ok()).map(|event| event.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                CoreEventKind::Exited as i32,
+                CoreEventKind::Restarted as i32,
+                CoreEventKind::Exited as i32,
+                CoreEventKind::Restarted as i32,
+                CoreEventKind::Exited as i32,
+                CoreEventKind::GaveUp as i32,
+            ]
+        );
+        assert!(pool.link("a").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_buffers_remapped_after_restart() {
+        let pool = Arc::new(CorePool::new(CoreKind::Stub { delay: Duration::ZERO, desktop: None }));
+        let link = pool.get_or_start("a", None).await.unwrap();
+        let dir = std::env::temp_dir().join(format!("eng-agent-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.txt").display().to_string();
+        std::fs::write(&path, "a\n").unwrap();
+        let file = link.open_file(&path).await.unwrap();
+        pool.remember_buffer(&file, "a");
+        let request = CreateBufferRequest { name: "notes".into(), text: String::new() };
+        let notes = BufferServiceClient::new(link.channel()).create_buffer(request).await.unwrap().into_inner();
+        pool.remember_buffer(&notes, "a");
+        assert_eq!(pool.core_for_buffer(&file.buffer_id), Some(("a".into(), Some(file.buffer_id.clone()))));
+
+        // 落ちている間は、どのコアのどのバッファにも送らない
+        crash(&pool, "a").await;
+        assert_eq!(pool.core_for_buffer(&file.buffer_id), Some(("a".into(), None)));
+
+        // 起動し直したコアが開き直したファイルは、落ちる前のIDで使える。開き直さなかったバッファは使えないまま
+        let link = pool.get_or_start("a", None).await.unwrap();
+        let reopened = link.open_file(&path).await.unwrap();
+        assert_ne!(reopened.buffer_id, file.buffer_id);
+        pool.remap_buffers("a", &link).await;
+        assert_eq!(pool.core_for_buffer(&file.buffer_id), Some(("a".into(), Some(reopened.buffer_id))));
+        assert_eq!(pool.core_for_buffer(&notes.buffer_id), Some(("a".into(), None)));
+        assert_eq!(pool.buffer_count("a"), 1);
+        pool.stop_all().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,12 +1,23 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::process::{Child, Command};
 
+use eng_core::remote::{self, CoreCommand};
+use eng_core::transport::Address;
+use tonic::transport::Channel;
+
+/// terminate で終了を待つ時間。過ぎたら強制終了する。
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug)]
 pub struct ProcessHandle {
     pub child: Child,
-    pub port: Option<u16>,
+    /// gRPC の待ち受け先 (コアのみ)
+    pub address: Option<Address>,
+    /// 標準入出力の上の gRPC 接続 (--stdio で起動したコアのみ)
+    pub channel: Option<Channel>,
 }
 
 pub struct Launcher;
@@ -23,38 +34,46 @@ impl Launcher {
         }
 
         // 2. 現在の実行ファイルの隣を探す
-        if let Ok(current_exe) = std::env::current_exe() {
-            if let Some(parent) = current_exe.parent() {
-                let bin_name = if cfg!(windows) {
-                    format!("{}.exe", name)
-                } else {
-                    name.to_string()
-                };
-                
-                let candidate = parent.join(&bin_name);
+        if let Ok(current_exe) = std::env::current_exe()
+            && let Some(parent) = current_exe.parent()
+        {
+            let bin_name = if cfg!(windows) {
+                format!("{}.exe", name)
+            } else {
+                name.to_string()
+            };
+
+            let candidate = parent.join(&bin_name);
+            if candidate.exists() {
+                return Ok(candidate);
+            }
+
+            // cargo run 時の target/debug/deps フォールバック
+            if let Some(grandparent) = parent.parent() {
+                let candidate = grandparent.join(&bin_name);
                 if candidate.exists() {
                     return Ok(candidate);
                 }
-                
-                // cargo run 時の target/debug/deps フォールバック
-                if let Some(grandparent) = parent.parent() {
-                    let candidate = grandparent.join(&bin_name);
-                    if candidate.exists() {
-                        return Ok(candidate);
-                    }
-                }
             }
         }
 
         Err(format!("Binary '{}' not found. Please set {} or place it in the same directory.", name, env_var))
     }
 
-    /// eng-core を起動する
-    pub async fn launch_core(token: &str) -> Result<ProcessHandle, Box<dyn std::error::Error>> {
+    /// eng-core を起動し、listen で待ち受けさせる。project_root を指定するとそこを作業ディレクトリにする。
+    /// session はコアが desktop を保存するセッションの名前。
+    pub async fn launch_core(token: &str, listen: &Address, project_root: Option<&Path>, session: &str) -> Result<ProcessHandle, Box<dyn std::error::Error>> {
         let binary_path = Self::resolve_binary_path("eng-core", "ENG_CORE_PATH")?;
         eprintln!("Agent: Launching core from {:?}", binary_path);
 
-        let mut child = Command::new(binary_path)
+        let mut command = Command::new(binary_path);
+        command.env("ENG_DESKTOP_SESSION", session);
+        if let Some(root) = project_root {
+            command.current_dir(root);
+        }
+        let mut child = command
+            .arg("--listen")
+            .arg(listen.to_string())
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::inherit())
@@ -68,35 +87,68 @@ impl Launcher {
             stdin.flush().await?;
         }
 
-        // ポート受信
-        let mut port = 0;
+        // 待ち受け先の受信 (TCP のポート 0 は実際のポートに置き換わって返る)
+        let mut address = None;
         if let Some(stdout) = child.stdout.take() {
             let mut reader = BufReader::new(stdout);
             let mut line = String::new();
             if reader.read_line(&mut line).await? > 0 {
-                port = line.trim().parse().unwrap_or(0);
+                address = line.trim().parse::<Address>().ok();
             }
         }
 
-        if port == 0 {
+        if address.is_none() {
             let _ = child.kill().await;
-            return Err("Failed to retrieve port from core".into());
+            return Err("Failed to retrieve listen address from core".into());
         }
 
-        Ok(ProcessHandle { child, port: Some(port) })
+        Ok(ProcessHandle { child, address, channel: None })
     }
 
-    /// eng-ui を起動する
-    pub async fn launch_ui(agent_port: u16, agent_token: &str, test_mode: bool) -> Result<ProcessHandle, Box<dyn std::error::Error>> {
+    /// eng-core を --stdio で起動し、標準入出力の上で gRPC を話す。待ち受けるポートもソケットも作らない。
+    pub async fn launch_core_stdio(project_root: Option<&Path>, session: &str) -> Result<ProcessHandle, Box<dyn std::error::Error>> {
+        let binary_path = Self::resolve_binary_path("eng-core", "ENG_CORE_PATH")?;
+        eprintln!("Agent: Launching core from {:?} on stdio", binary_path);
+
+        let mut command = Command::new(binary_path);
+        command.env("ENG_DESKTOP_SESSION", session);
+        if let Some(root) = project_root {
+            command.current_dir(root);
+        }
+        let (child, channel) = remote::spawn_stdio(command.arg("--stdio")).await?;
+        Ok(ProcessHandle { child, address: None, channel: Some(channel) })
+    }
+
+    /// 任意のコマンド (ssh host eng-core --stdio など) でコアを起動し、その標準入出力の上で gRPC を話す。
+    /// リモートの作業ディレクトリは変えられないので、プロジェクトのルートは ENG_PROJECT_ROOT で渡す
+    /// (例: "ssh host 'cd $ENG_PROJECT_ROOT && eng-core --stdio'")。セッションの名前は ENG_DESKTOP_SESSION で渡す。
+    pub async fn launch_core_command(command: &CoreCommand, project_root: Option<&Path>, session: &str) -> Result<ProcessHandle, Box<dyn std::error::Error>> {
+        eprintln!("Agent: Launching core with {:?}", command.command);
+
+        let mut shell = Command::new("sh");
+        shell.env("ENG_DESKTOP_SESSION", session);
+        if let Some(root) = project_root {
+            shell.env("ENG_PROJECT_ROOT", root);
+        }
+        let (child, channel) = remote::spawn_stdio(shell.arg("-c").arg(&command.command)).await?;
+        Ok(ProcessHandle { child, address: None, channel: Some(channel) })
+    }
+
+    /// eng-ui を起動する。ui_id は UI が Agent に名乗る ID
+    pub async fn launch_ui(ui_id: &str, agent_address: &Address, agent_token: &str, test_mode: bool) -> Result<ProcessHandle, Box<dyn std::error::Error>> {
         let binary_path = Self::resolve_binary_path("ui", "ENG_UI_PATH")?;
         eprintln!("Agent: Launching UI from {:?}", binary_path);
 
         // UIには引数で接続情報を渡す
         let mut command = Command::new(binary_path);
-        command.arg("--agent-port")
-            .arg(agent_port.to_string())
-            .arg("--agent-token")
+        match agent_address {
+            Address::Unix(path) => command.arg("--agent-socket").arg(path),
+            Address::Tcp(addr) => command.arg("--agent-port").arg(addr.port().to_string()),
+        };
+        command.arg("--agent-token")
             .arg(agent_token)
+            .arg("--ui-id")
+            .arg(ui_id)
             .kill_on_drop(true);
 
         if test_mode {
@@ -105,6 +157,19 @@ impl Launcher {
 
         let child = command.spawn()?;
 
-        Ok(ProcessHandle { child, port: None })
+        Ok(ProcessHandle { child, address: None, channel: None })
+    }
+
+    /// SIGTERM で終了させ (コアは desktop を保存してから終わる)、STOP_TIMEOUT を過ぎたら強制終了する
+    pub async fn terminate(child: &mut Child) {
+        if let Some(pid) = child.id() {
+            // SAFETY: 自分で起動して、まだ回収していない子プロセスにシグナルを送るだけ
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGTERM);
+            }
+        }
+        if tokio::time::timeout(STOP_TIMEOUT, child.wait()).await.is_err() {
+            let _ = child.kill().await;
+        }
     }
 }
@@ -1,28 +1,77 @@
+mod cores;
 mod launcher;
+mod proxy;
+mod remote;
 mod runtime;
-use launcher::Launcher;
+mod uis;
+use cores::{CoreKind, CorePool, DEFAULT_CORE};
+use proxy::{BufferProxy, EditorProxy};
+use remote::RemoteFiles;
+use uis::Uis;
 use runtime::{save_runtime_info, load_runtime_info, cleanup_runtime_info};
 use uuid::Uuid;
 use tokio::net::TcpListener;
-use tokio_stream::wrappers::TcpListenerStream;
-use tonic::transport::{Server, Channel};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream, UnixListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use futures_util::future::join_all;
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Channel, Server};
 use eng_core::auth::AuthInterceptor;
+use eng_core::desktop;
+use eng_core::remote::CoreCommand;
+use eng_core::tls;
+use eng_core::MyPairingService;
+use eng_core::transport::{self, Address};
 use tonic::{Request, Response, Status};
 use std::pin::Pin;
-use std::net::SocketAddr;
+use std::future::Future;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::path::Path;
 use std::sync::Arc;
-use tokio_stream::{Stream, StreamExt};
+use std::time::UNIX_EPOCH;
 use clap::Parser;
 
 // eng-core で生成されたコードを使用
-use eng_core::editor::editor_service_server::{EditorService, EditorServiceServer};
+use eng_core::editor::buffer_service_server::BufferServiceServer;
+use eng_core::editor::editor_service_server::EditorServiceServer;
 use eng_core::editor::agent_service_server::{AgentService, AgentServiceServer};
 use eng_core::editor::agent_service_client::AgentServiceClient;
-use eng_core::editor::{HandshakeRequest, HandshakeResponse, SpawnUiRequest, SpawnUiResponse};
+use eng_core::editor::editor_service_client::EditorServiceClient;
+use eng_core::editor::execute_command_response::Status as CommandStatus;
+use eng_core::editor::ui_command::Kind as UiCommandKind;
+use eng_core::editor::pairing_service_server::PairingServiceServer;
+use eng_core::editor::{
+    CloseUiRequest, CloseUiResponse, CoreEvent, CoreInfo, ExecuteCommandRequest, FileLocation, FocusUiRequest,
+    FocusUiResponse, ListCoresRequest, ListCoresResponse, ListUisRequest, ListUisResponse, OpenFilesRequest,
+    OpenFilesResponse, OpenedFile, SpawnUiRequest, SpawnUiResponse, StopCoreRequest, StopCoreResponse,
+    SubscribeUiRequest, UiCommand, UiInfo, WatchCoresRequest,
+};
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about)]
 struct Args {
+    /// Files to open in the running editor. "+LINE" or "+LINE:COLUMN" before a file jumps to that position.
+    /// With --eval, these are passed to the command as its arguments instead
+    #[arg(value_name = "FILE")]
+    files: Vec<String>,
+
+    /// Files after "--" are opened as they are, even if they start with "+"
+    #[arg(last = true, value_name = "FILE")]
+    literal_files: Vec<String>,
+
+    /// Do not wait for the opened files to be closed
+    #[arg(short = 'n', long)]
+    no_wait: bool,
+
+    /// Open the files in a new window
+    #[arg(short = 'c', long)]
+    create_window: bool,
+
+    /// Run an editor command in the running editor (e.g. "save-some-buffers") and print its message
+    #[arg(short = 'e', long, value_name = "COMMAND")]
+    eval: Option<String>,
+
     /// Run in test mode (propagate to UI)
     #[arg(long)]
     test_mode: bool,
@@ -30,80 +79,317 @@ struct Args {
     /// Run as daemon (do not exit when UI closes)
     #[arg(long)]
     daemon: bool,
+
+    /// Listen on TCP [::1] instead of a Unix socket in the runtime directory (for remote setups)
+    #[arg(long)]
+    tcp: bool,
+
+    /// Talk to the core over its stdin/stdout instead of a socket (the core listens on nothing)
+    #[arg(long)]
+    core_stdio: bool,
+
+    /// Launch the core through this shell command and talk to it over the command's stdin/stdout
+    /// (e.g. "ssh host eng-core --stdio"). Defaults to $ENG_CORE_COMMAND
+    #[arg(long, value_name = "COMMAND")]
+    core_command: Option<String>,
+
+    /// Also accept remote UIs on this TCP address over TLS. Only clients paired with the printed
+    /// one-time code are accepted (e.g. "tcp:0.0.0.0:7000")
+    #[arg(long, value_name = "ADDR")]
+    listen_tls: Option<Address>,
+}
+
+impl Args {
+    /// FILE 引数。"--" の後に書いたファイルは "--" を挟んで続ける (parse_file_args)
+    fn file_args(&self) -> Vec<String> {
+        let mut files = self.files.clone();
+        if !self.literal_files.is_empty() {
+            files.push("--".into());
+            files.extend(self.literal_files.iter().cloned());
+        }
+        files
+    }
+}
+
+enum Listener {
+    Unix(tokio::net::UnixListener),
+    Tcp(TcpListener),
 }
 
 #[derive(Debug)]
 struct GlobalState {
-    agent_port: u16,
+    agent_address: Address,
     agent_token: String,
     test_mode: bool,
 }
 
-#[derive(Debug, Default)]
-struct MyEditorServiceImpl;
+#[derive(Debug, Clone)]
+struct MyAgentServiceImpl {
+    state: Arc<GlobalState>,
+    cores: Arc<CorePool>,
+    uis: Arc<Uis>,
+    /// コアをリモートで起動するときのみ。BufferProxy と同じものを使う
+    remote: Option<Arc<RemoteFiles>>,
+}
 
 #[tonic::async_trait]
-impl EditorService for MyEditorServiceImpl {
-    type HandshakeStream = Pin<Box<dyn Stream<Item = Result<HandshakeResponse, Status>> + Send + Sync + 'static>>;
+impl AgentService for MyAgentServiceImpl {
+    async fn spawn_ui(&self, _request: Request<SpawnUiRequest>) -> Result<Response<SpawnUiResponse>, Status> {
+        eprintln!("Agent: Received SpawnUi request.");
+        match self.uis.spawn(&self.state.agent_address, &self.state.agent_token, self.state.test_mode).await {
+            Ok(_) => Ok(Response::new(SpawnUiResponse { success: true })),
+            Err(e) => Err(Status::internal(format!("Failed to launch UI: {}", e))),
+        }
+    }
+
+    async fn list_cores(&self, _request: Request<ListCoresRequest>) -> Result<Response<ListCoresResponse>, Status> {
+        let cores = self
+            .cores
+            .list(|core| CoreInfo {
+                name: core.name.clone(),
+                project_root: core.project_root.as_ref().map(|root| root.display().to_string()).unwrap_or_default(),
+                address: core.address().map(|address| address.to_string()).unwrap_or_default(),
+                pid: core.pid().unwrap_or_default(),
+                started_at: core.started.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+                buffers: 0,
+            })
+            .await
+            .into_iter()
+            .map(|info| CoreInfo { buffers: self.cores.buffer_count(&info.name) as u32, ..info })
+            .collect();
+        Ok(Response::new(ListCoresResponse { cores }))
+    }
+
+    async fn stop_core(&self, request: Request<StopCoreRequest>) -> Result<Response<StopCoreResponse>, Status> {
+        let name = request.into_inner().name;
+        if name.is_empty() {
+            return Err(Status::invalid_argument("name is required"));
+        }
+        Ok(Response::new(StopCoreResponse { stopped: self.cores.stop(&name).await }))
+    }
 
-    async fn handshake(
-        &self,
-        request: Request<tonic::Streaming<HandshakeRequest>>,
-    ) -> Result<Response<Self::HandshakeStream>, Status> {
-        let mut in_stream = request.into_inner();
-        let (tx, rx) = tokio::sync::mpsc::channel(128);
+    type WatchCoresStream = Pin<Box<dyn Stream<Item = Result<CoreEvent, Status>> + Send + Sync + 'static>>;
 
+    async fn watch_cores(&self, _request: Request<WatchCoresRequest>) -> Result<Response<Self::WatchCoresStream>, Status> {
+        let mut events = self.cores.subscribe();
+        let (tx, rx) = mpsc::channel(16);
         tokio::spawn(async move {
-            while let Some(result) = in_stream.next().await {
-                match result {
-                    Ok(req) => {
-                        let response = HandshakeResponse {
-                            server_message: format!("Agent Proxy: {}", req.client_message),
-                        };
-                        if tx.send(Ok(response)).await.is_err() {
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => event,
+                    _ = tx.closed() => break,
+                };
+                match event {
+                    Ok(event) => {
+                        if tx.send(Ok(event)).await.is_err() {
                             break;
                         }
                     }
-                    Err(e) => {
-                        eprintln!("Agent: Handshake error: {:?}", e);
-                        break;
-                    }
+                    // 読み遅れた分は捨てて続ける
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
                 }
             }
         });
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
 
-        let out_stream = tokio_stream::wrappers::ReceiverStream::new(rx);
-        Ok(Response::new(Box::pin(out_stream)))
+    async fn open_files(&self, request: Request<OpenFilesRequest>) -> Result<Response<OpenFilesResponse>, Status> {
+        let request = request.into_inner();
+        let (files, ui_id) = self.open(request.files, request.new_window).await?;
+        if request.wait {
+            // 開いたバッファが全て閉じられるか、表示した UI が閉じるまで待つ (server-edit)
+            let killed = join_all(files.iter().map(|file| async {
+                let buffer_id = file.buffer.as_ref().map(|buffer| buffer.buffer_id.as_str()).unwrap_or_default();
+                if let Some(remote) = &self.remote {
+                    remote.wait_killed(buffer_id).await;
+                } else if let Some(link) = self.cores.link(&file.core).await {
+                    link.wait_killed(buffer_id).await;
+                }
+            }));
+            tokio::select! {
+                _ = killed => {}
+                _ = self.uis.wait_gone(&ui_id) => {}
+            }
+        }
+        Ok(Response::new(OpenFilesResponse { files, ui_id }))
+    }
+
+    async fn list_uis(&self, _request: Request<ListUisRequest>) -> Result<Response<ListUisResponse>, Status> {
+        let uis = self
+            .uis
+            .list()
+            .into_iter()
+            .map(|ui| {
+                let (client_name, client_kind) = match ui.client {
+                    Some((name, kind)) => (name, kind.name().to_string()),
+                    None => Default::default(),
+                };
+                UiInfo {
+                    id: ui.id,
+                    pid: ui.pid.unwrap_or_default(),
+                    client_name,
+                    client_kind,
+                    sessions: ui.sessions as u32,
+                    subscribed: ui.subscribed,
+                    started_at: ui.started.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+                }
+            })
+            .collect();
+        Ok(Response::new(ListUisResponse { uis }))
+    }
+
+    async fn close_ui(&self, request: Request<CloseUiRequest>) -> Result<Response<CloseUiResponse>, Status> {
+        Ok(Response::new(CloseUiResponse { closed: self.uis.close(&request.into_inner().id) }))
+    }
+
+    async fn focus_ui(&self, request: Request<FocusUiRequest>) -> Result<Response<FocusUiResponse>, Status> {
+        self.uis.focus(&request.into_inner().id).map_err(Status::failed_precondition)?;
+        Ok(Response::new(FocusUiResponse {}))
+    }
+
+    type SubscribeUiStream = Pin<Box<dyn Stream<Item = Result<UiCommand, Status>> + Send + Sync + 'static>>;
+
+    async fn subscribe_ui(&self, request: Request<SubscribeUiRequest>) -> Result<Response<Self::SubscribeUiStream>, Status> {
+        let id = request.into_inner().id;
+        let id = if id.is_empty() { self.uis.new_id() } else { id };
+        let commands = self.uis.subscribe(&id);
+        Ok(Response::new(Box::pin(ReceiverStream::new(commands).map(Ok))))
     }
 }
 
-#[derive(Debug)]
-struct MyAgentServiceImpl {
-    state: Arc<GlobalState>,
+impl MyAgentServiceImpl {
+    /// ファイルをそれぞれのプロジェクトのコアで開き、UI に表示させる。リモートのコアで開くファイルは Agent が手元に持つ。
+    /// new_window でなければ最後に開いた UI に表示する。表示した UI の ID も返す。
+    async fn open(&self, locations: Vec<FileLocation>, new_window: bool) -> Result<(Vec<OpenedFile>, String), Status> {
+        if locations.is_empty() {
+            return Err(Status::invalid_argument("No files to open"));
+        }
+        let mut files = Vec::with_capacity(locations.len());
+        for location in locations {
+            let (core, buffer) = match &self.remote {
+                Some(remote) => {
+                    let root = desktop::project_root(Path::new(&location.path));
+                    let core = root.display().to_string();
+                    let buffer = remote.open(&core, Some(root), &location.path).await?;
+                    (core, buffer)
+                }
+                None => {
+                    let (core, link) = self.cores.for_project(Path::new(&location.path)).await?;
+                    let buffer = link.open_file(&location.path).await?;
+                    self.cores.remember_buffer(&buffer, &core);
+                    (core, buffer)
+                }
+            };
+            files.push(OpenedFile { location: Some(location), core, buffer: Some(buffer) });
+        }
+        let ui_id = match self.uis.latest().filter(|_| !new_window) {
+            Some(id) => id,
+            None => {
+                let state = &self.state;
+                match self.uis.spawn(&state.agent_address, &state.agent_token, state.test_mode).await {
+                    Ok(id) => id,
+                    Err(e) => return Err(Status::internal(format!("Failed to launch UI: {}", e))),
+                }
+            }
+        };
+        let command = UiCommand { kind: UiCommandKind::Open.into(), files: files.clone() };
+        self.uis.send(&ui_id, command).map_err(Status::failed_precondition)?;
+        Ok((files, ui_id))
+    }
 }
 
-#[tonic::async_trait]
-impl AgentService for MyAgentServiceImpl {
-    async fn spawn_ui(&self, _request: Request<SpawnUiRequest>) -> Result<Response<SpawnUiResponse>, Status> {
-        eprintln!("Agent: Received SpawnUi request.");
-        match Launcher::launch_ui(self.state.agent_port, &self.state.agent_token, self.state.test_mode).await {
-            Ok(_) => Ok(Response::new(SpawnUiResponse { success: true })),
-            Err(e) => Err(Status::internal(format!("Failed to launch UI: {}", e))),
+/// "+LINE[:COLUMN] FILE" の並び (emacsclient と同じ書き方) を FileLocation にする。相対パスは作業ディレクトリで解決する。
+/// "--" より後は、+ で始まっていてもファイル名とみなす。
+fn parse_file_args(args: &[String]) -> Result<Vec<FileLocation>, String> {
+    let cwd = std::env::current_dir().map_err(|e| format!("Failed to get the current directory: {}", e))?;
+    let invalid = |arg: &str| format!("Invalid position: {} (expected +LINE or +LINE:COLUMN)", arg);
+    let mut files = Vec::new();
+    let mut position = None;
+    let mut literal = false;
+    for arg in args {
+        if !literal && arg == "--" {
+            literal = true;
+            continue;
         }
+        if !literal && let Some(spec) = arg.strip_prefix('+') {
+            let (line, column) = match spec.split_once(':') {
+                Some((line, column)) => (line, column.parse().map_err(|_| invalid(arg))?),
+                None => (spec, 0),
+            };
+            position = Some((line.parse().map_err(|_| invalid(arg))?, column));
+            continue;
+        }
+        let (line, column) = position.take().unwrap_or_default();
+        files.push(FileLocation { path: cwd.join(arg).display().to_string(), line, column });
+    }
+    if position.is_some() {
+        return Err("+LINE must be followed by a file".into());
     }
+    Ok(files)
 }
 
-async fn try_delegate_to_existing_agent(port: u16, token: String) -> Result<(), Box<dyn std::error::Error>> {
-    let uri = format!("http://[::1]:{}", port);
-    let channel = Channel::from_shared(uri)?.connect().await?;
-    let token_metadata: tonic::metadata::MetadataValue<_> = token.parse()?;
+/// UI が1つも残っていない状態になるまで待つ。保存していないファイルがあれば終了せず、次の UI を待って続ける。
+async fn wait_for_last_ui(uis: &Uis, cores: &CorePool) {
+    loop {
+        uis.wait_until_empty().await;
+        let unsaved = cores.unsaved_files().await;
+        if unsaved.is_empty() {
+            return;
+        }
+        eprintln!("Agent: All UIs closed, but {} files have unsaved changes:", unsaved.len());
+        for file in &unsaved {
+            eprintln!("Agent:   {}", file);
+        }
+        eprintln!("Agent: Keeping the session. Run eng-agent again to reopen a window, or press Ctrl+C to quit.");
+        uis.wait_until_any().await;
+    }
+}
 
-    let mut client = AgentServiceClient::with_interceptor(channel, move |mut req: Request<()>| {
+/// 起動中の Agent に依頼する。-e ならコマンドを実行し、ファイルを指定していれば開かせ、
+/// どちらでもなければ新しい UI を開かせる。
+#[allow(clippy::result_large_err)]
+async fn delegate(channel: Channel, token: &str, args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let token_metadata: MetadataValue<_> = token.parse()?;
+    let authorize = move |mut req: Request<()>| {
         req.metadata_mut().insert("authorization", token_metadata.clone());
         Ok(req)
-    });
+    };
 
-    client.spawn_ui(Request::new(SpawnUiRequest {})).await?;
+    if let Some(command) = &args.eval {
+        // ファイルを開いたときと同じく、作業ディレクトリのプロジェクトのコアで実行する
+        let cwd = std::env::current_dir()?;
+        let project: MetadataValue<_> = cwd.to_string_lossy().parse()?;
+        let mut client = EditorServiceClient::with_interceptor(channel, authorize);
+        let mut request =
+            Request::new(ExecuteCommandRequest { command: command.clone(), args: [args.files.clone(), args.literal_files.clone()].concat(), ..Default::default() });
+        request.metadata_mut().insert(proxy::PROJECT_METADATA, project);
+        let response = client.execute_command(request).await?.into_inner();
+        return match response.status() {
+            CommandStatus::Ok => {
+                if !response.message.is_empty() {
+                    println!("{}", response.message);
+                }
+                Ok(())
+            }
+            CommandStatus::MissingArgument => Err(format!("{} needs an argument: {}", command, response.prompt).into()),
+            CommandStatus::Error | CommandStatus::Quit => Err(response.message.into()),
+        };
+    }
+
+    let mut client = AgentServiceClient::with_interceptor(channel, authorize);
+    let files = parse_file_args(&args.file_args())?;
+    if files.is_empty() {
+        client.spawn_ui(Request::new(SpawnUiRequest {})).await?;
+        return Ok(());
+    }
+    let wait = !args.no_wait;
+    if wait {
+        eprintln!("Agent: Waiting for the editor to close the files...");
+    }
+    let request = OpenFilesRequest { files, new_window: args.create_window, wait };
+    let response = client.open_files(request).await?.into_inner();
+    eprintln!("Agent: Opened {} files in {}.", response.files.len(), response.ui_id);
     Ok(())
 }
 
@@ -115,9 +401,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     if let Some(info) = load_runtime_info() {
         // ポートファイルがある場合、接続を試みる
         // 接続できれば委譲して終了。できなければ（ゾンビファイルなら）クリーンアップして続行。
-        eprintln!("Agent: Found port file (port: {}). Connecting...", info.port);
-        match try_delegate_to_existing_agent(info.port, info.token).await {
-            Ok(_) => {
+        eprintln!("Agent: Found runtime file (address: {}). Connecting...", info.address);
+        match transport::connect(&info.address).await {
+            Ok(channel) => {
+                delegate(channel, &info.token, &args).await?;
                 eprintln!("Agent: Delegated to existing agent.");
                 return Ok(());
             }
@@ -127,47 +414,110 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
     }
+    if args.eval.is_some() {
+        return Err("No running editor to run the command in".into());
+    }
+    let files = parse_file_args(&args.file_args())?;
 
     // 2. 新規起動 (Server Mode)
     eprintln!("Agent: Starting new session... (Test Mode: {}, Daemon: {})", args.test_mode, args.daemon);
 
-    // Core起動
-    let core_token = Uuid::new_v4().to_string();
-    let core_handle = Launcher::launch_core(&core_token).await?;
-    eprintln!("Agent: Core launched on port {}", core_handle.port.unwrap());
+    // Core起動。既定のコアだけを先に起動し、プロジェクトごとのコアは必要になったときに起動する。
+    let core_command = args.core_command.clone().map(CoreCommand::new).or_else(CoreCommand::from_env);
+    let kind = match core_command {
+        Some(command) => CoreKind::Command(command),
+        None if args.core_stdio => CoreKind::Stdio,
+        None => CoreKind::Socket { tcp: args.tcp },
+    };
+    let pool = Arc::new(CorePool::new(kind));
+    pool.get_or_start(DEFAULT_CORE, None).await?;
+    // 落ちたコアや固まったコアを起動し直す
+    let supervisor = tokio::spawn(pool.clone().supervise());
 
-    // Agentサーバーポート確保
-    let addr: SocketAddr = "[::1]:0".parse()?;
-    let listener = TcpListener::bind(addr).await?;
-    let agent_addr = listener.local_addr()?;
-    let agent_port = agent_addr.port();
+    // Agentサーバーの待ち受け先の確保。既定は所有者だけが入れる実行時ディレクトリのUnixソケット。
+    let (listener, agent_address) = if args.tcp {
+        let listener = TcpListener::bind(SocketAddr::from((Ipv6Addr::LOCALHOST, 0))).await?;
+        let address = Address::Tcp(listener.local_addr()?);
+        (Listener::Tcp(listener), address)
+    } else {
+        let path = transport::socket_path("agent")?;
+        (Listener::Unix(transport::bind_unix(&path)?), Address::Unix(path))
+    };
     let agent_token = Uuid::new_v4().to_string();
     
-    eprintln!("Agent: Listening on {}", agent_addr);
-    save_runtime_info(agent_port, &agent_token)?;
+    eprintln!("Agent: Listening on {}", agent_address);
+    save_runtime_info(&agent_address, &agent_token)?;
 
     // UI起動 (初期ウィンドウ)
-    let mut ui_handle = Launcher::launch_ui(agent_port, &agent_token, args.test_mode).await?;
-    eprintln!("Agent: UI process spawned.");
+    let uis = Arc::new(Uis::default());
+    let ui_id = uis.spawn(&agent_address, &agent_token, args.test_mode).await?;
+    eprintln!("Agent: UI process spawned ({}).", ui_id);
 
     // gRPCサーバー構成
     let interceptor = AuthInterceptor::new(agent_token.clone())?;
     let global_state = Arc::new(GlobalState {
-        agent_port,
+        agent_address: agent_address.clone(),
         agent_token,
         test_mode: args.test_mode,
     });
 
-    let editor_service = MyEditorServiceImpl::default();
-    let agent_service = MyAgentServiceImpl { state: global_state };
-    let incoming = TcpListenerStream::new(listener);
+    // UI からの EditorService と BufferService は起動したコアへ中継する
+    // リモートのコアで開くファイルは、UI から開いても OpenFiles で開いても Agent が手元に持つ
+    let remote = pool.is_remote().then(|| Arc::new(RemoteFiles::new(pool.clone())));
+    let editor_service = EditorProxy::new(pool.clone(), uis.clone());
+    let buffer_service = BufferProxy::new(pool.clone(), remote.clone());
+    let agent_service = MyAgentServiceImpl { state: global_state, cores: pool.clone(), uis: uis.clone(), remote };
+    // コマンドラインで指定したファイルは最初の UI に表示する
+    if !files.is_empty() {
+        agent_service.open(files, false).await?;
+    }
 
-    let server_future = Server::builder()
+    // リモートのUI向けの TLS の待ち受け。ペアリングしたクライアント証明書だけを受け入れる。
+    let tls_server = match &args.listen_tls {
+        Some(Address::Tcp(addr)) => {
+            let dir = tls::tls_dir()?;
+            let identity = tls::Identity::load_or_create(&dir, "agent")?;
+            let pins = Arc::new(tls::Pins::load(&dir.join("agent.clients"))?);
+            let pairing = Arc::new(tls::Pairing::default());
+            let listener = TcpListener::bind(addr).await?;
+            let ticket = tls::PairingTicket { code: pairing.issue(), fingerprint: identity.fingerprint() };
+            eprintln!("Agent: Listening on {} (TLS)", Address::Tcp(listener.local_addr()?));
+            eprintln!(
+                "Agent: Pair a new UI within {} minutes with: {}",
+                tls::PAIRING_CODE_TTL.as_secs() / 60,
+                ticket
+            );
+            let server_name = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            let interceptor = AuthInterceptor::pinned(pins.clone());
+            let router = Server::builder()
+                .http2_keepalive_interval(Some(std::time::Duration::from_secs(10)))
+                .http2_keepalive_timeout(Some(std::time::Duration::from_secs(5)))
+                .add_service(EditorServiceServer::with_interceptor(editor_service.clone(), interceptor.clone()))
+                .add_service(BufferServiceServer::with_interceptor(buffer_service.clone(), interceptor.clone()))
+                .add_service(AgentServiceServer::with_interceptor(agent_service.clone(), interceptor))
+                .add_service(PairingServiceServer::new(MyPairingService::new(pins, pairing, server_name)));
+            Some(router.serve_with_incoming(tls::incoming(listener, tls::server_config(&identity)?)))
+        }
+        Some(Address::Unix(_)) => return Err("--listen-tls requires a tcp: address".into()),
+        None => None,
+    };
+
+    let router = Server::builder()
         .http2_keepalive_interval(Some(std::time::Duration::from_secs(10)))
         .http2_keepalive_timeout(Some(std::time::Duration::from_secs(5)))
         .add_service(EditorServiceServer::with_interceptor(editor_service, interceptor.clone()))
-        .add_service(AgentServiceServer::with_interceptor(agent_service, interceptor))
-        .serve_with_incoming(incoming);
+        .add_service(BufferServiceServer::with_interceptor(buffer_service, interceptor.clone()))
+        .add_service(AgentServiceServer::with_interceptor(agent_service, interceptor));
+    let local_server: Pin<Box<dyn Future<Output = Result<(), tonic::transport::Error>> + Send>> = match listener {
+        Listener::Unix(listener) => Box::pin(router.serve_with_incoming(UnixListenerStream::new(listener))),
+        Listener::Tcp(listener) => Box::pin(router.serve_with_incoming(TcpListenerStream::new(listener))),
+    };
+    let server_future = async move {
+        match tls_server {
+            Some(tls_server) => tokio::try_join!(local_server, tls_server).map(|_| ()),
+            None => local_server.await,
+        }
+    };
 
     eprintln!("Agent: Session active.");
 
@@ -179,17 +529,148 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             _ = tokio::signal::ctrl_c() => eprintln!("Agent: Received Ctrl+C."),
         }
     } else {
-        // 通常モード: UI終了またはCtrl+Cで終了
-        // ※ 複数UIに対応するなら、UIプロセスリストを管理して「0になったら終了」にする必要があるが、
-        // 今回は「最初のUI」が閉じたら終了という簡易仕様で進める。
+        // 通常モード: 起動したUIと接続中のUIが全て閉じるか、Ctrl+Cで終了
         tokio::select! {
             res = server_future => eprintln!("Agent: Server error: {:?}", res),
             _ = tokio::signal::ctrl_c() => eprintln!("Agent: Received Ctrl+C."),
-            status = ui_handle.child.wait() => eprintln!("Agent: Primary UI exited: {:?}", status),
+            _ = wait_for_last_ui(&uis, &pool) => eprintln!("Agent: Last UI closed."),
         }
     }
 
     eprintln!("Agent: Shutting down...");
     cleanup_runtime_info();
+    supervisor.abort();
+    pool.stop_all().await;
+    if let Address::Unix(path) = &agent_address {
+        let _ = std::fs::remove_file(path);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eng_core::editor::buffer_service_client::BufferServiceClient;
+    use eng_core::editor::{ApplyEditsRequest, SaveBufferRequest, TextEdit};
+    use eng_core::handshake::{ClientKind, Hello};
+    use std::time::Duration;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn locations(files: &[FileLocation]) -> Vec<(String, u32, u32)> {
+        files.iter().map(|file| (file.path.clone(), file.line, file.column)).collect()
+    }
+
+    #[test]
+    fn test_parse_file_args() {
+        let cwd = std::env::current_dir().unwrap();
+        let path = |name: &str| cwd.join(name).display().to_string();
+        let files = parse_file_args(&args(&["+3", "a.rs", "+4:7", "b.rs", "c.rs"])).unwrap();
+        assert_eq!(locations(&files), vec![(path("a.rs"), 3, 0), (path("b.rs"), 4, 7), (path("c.rs"), 0, 0)]);
+
+        // "--" の後は + で始まってもファイル名
+        let files = parse_file_args(&args(&["+2", "--", "+foo", "+3"])).unwrap();
+        assert_eq!(locations(&files), vec![(path("+foo"), 2, 0), (path("+3"), 0, 0)]);
+
+        assert!(parse_file_args(&args(&["a.rs", "+5"])).unwrap_err().contains("must be followed by a file"));
+        assert!(parse_file_args(&args(&["+x", "a.rs"])).unwrap_err().contains("Invalid position: +x"));
+        assert!(parse_file_args(&args(&["+1:y", "a.rs"])).unwrap_err().contains("Invalid position: +1:y"));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_last_ui_keeps_unsaved_files() {
+        let pool = Arc::new(CorePool::new(CoreKind::Stub { delay: std::time::Duration::ZERO, desktop: None }));
+        let link = pool.get_or_start(DEFAULT_CORE, None).await.unwrap();
+        let dir = std::env::temp_dir().join(format!("eng-agent-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.txt").display().to_string();
+        std::fs::write(&path, "a\n").unwrap();
+        let file = link.open_file(&path).await.unwrap();
+        let mut buffers = BufferServiceClient::new(link.channel());
+        let edit = TextEdit { start: 0, end: 0, text: "x".into() };
+        let request = ApplyEditsRequest { buffer_id: file.buffer_id.clone(), edits: vec![edit], base_version: None };
+        buffers.apply_edits(request).await.unwrap();
+
+        let uis = Arc::new(Uis::default());
+        let hello = Hello {
+            min_protocol_version: 1,
+            protocol_version: 1,
+            kind: ClientKind::Gui,
+            name: "gui".into(),
+            capabilities: Vec::new(),
+            encodings: Vec::new(),
+            session_id: None,
+        };
+        let session = uis.connect(None, &hello);
+        let waiting = wait_for_last_ui(&uis, &pool);
+        tokio::pin!(waiting);
+
+        // 保存していないファイルがあれば、最後の UI が閉じても終わらない
+        drop(session);
+        let timeout = std::time::Duration::from_millis(300);
+        assert!(tokio::time::timeout(timeout, &mut waiting).await.is_err());
+
+        // 次の UI で保存してから閉じれば終わる
+        let session = uis.connect(None, &hello);
+        assert!(tokio::time::timeout(timeout, &mut waiting).await.is_err());
+        buffers.save_buffer(SaveBufferRequest { buffer_id: file.buffer_id, path: String::new() }).await.unwrap();
+        drop(session);
+        tokio::time::timeout(std::time::Duration::from_secs(5), waiting).await.unwrap();
+        pool.stop_all().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_open_files_on_remote_core() {
+        let pool = Arc::new(CorePool::new(CoreKind::Stub { delay: Duration::ZERO, desktop: None }));
+        let remote = Arc::new(RemoteFiles::new(pool.clone()));
+        let uis = Arc::new(Uis::default());
+        let mut commands = uis.subscribe("ui-1");
+        let address = Address::Unix(std::env::temp_dir().join("eng-agent-test.sock"));
+        let state = Arc::new(GlobalState { agent_address: address, agent_token: String::new(), test_mode: true });
+        let service = MyAgentServiceImpl { state, cores: pool.clone(), uis, remote: Some(remote.clone()) };
+        let dir = std::env::temp_dir().join(format!("eng-agent-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(".eng-project"), "").unwrap();
+        let path = dir.join("a.txt").display().to_string();
+        std::fs::write(&path, "a\n").unwrap();
+
+        // リモートのコアで開くファイルは、UI から開いたときと同じく Agent が手元に持つ
+        let request = OpenFilesRequest {
+            files: vec![FileLocation { path: path.clone(), line: 1, column: 0 }],
+            new_window: false,
+            wait: true,
+        };
+        let task = tokio::spawn({
+            let service = service.clone();
+            async move { service.open_files(Request::new(request)).await }
+        });
+        let command = commands.recv().await.unwrap();
+        let opened = &command.files[0];
+        let buffer_id = opened.buffer.as_ref().unwrap().buffer_id.clone();
+        assert_eq!(opened.core, dir.display().to_string());
+        assert!(remote.get(&buffer_id).is_some());
+        assert!(pool.core_for_buffer(&buffer_id).is_none());
+
+        // 手元のバッファがコアで閉じられるまで待つ
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!task.is_finished());
+        let link = pool.link(&opened.core).await.unwrap();
+        let request = ExecuteCommandRequest { command: "kill-buffer".into(), args: args(&["a.txt"]), ..Default::default() };
+        EditorServiceClient::new(link.channel()).execute_command(request).await.unwrap();
+        let response = tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap().unwrap();
+        assert_eq!(response.into_inner().files[0].buffer.as_ref().unwrap().buffer_id, buffer_id);
+        assert!(remote.get(&buffer_id).is_none());
+        pool.stop_all().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_args_after_double_dash() {
+        let parsed = Args::try_parse_from(["eng-agent", "+2", "a.rs", "--", "+foo", "-n"]).unwrap();
+        assert_eq!(parsed.file_args(), args(&["+2", "a.rs", "--", "+foo", "-n"]));
+        assert!(!parsed.no_wait);
+    }
+}
@@ -0,0 +1,512 @@
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use eng_core::editor::buffer_service_client::BufferServiceClient;
+use eng_core::editor::buffer_service_server::BufferService;
+use eng_core::editor::editor_service_client::EditorServiceClient;
+use eng_core::editor::editor_service_server::EditorService;
+use eng_core::editor::{
+    ApplyEditsRequest, ApplyEditsResponse, BufferInfo, BufferUpdate, CreateBufferRequest, CreateWindowRequest,
+    CreateWindowResponse, DescribeKeyRequest, DescribeKeyResponse, ExecuteCommandRequest, ExecuteCommandResponse,
+    GetDesktopStatusRequest, GetDesktopStatusResponse, GetLatencyMetricsRequest, GetLatencyMetricsResponse,
+    GetLinesRequest, GetLinesResponse, GetTextRequest, GetTextResponse, HandshakeRequest, HandshakeResponse,
+    KeyEventRequest, KeyEventResponse, LayoutUpdate, ListBuffersRequest, ListBuffersResponse, ListCommandsRequest,
+    ListCommandsResponse, MinibufferUpdate, NotifyActivityRequest, NotifyActivityResponse, OpenFileRequest,
+    QuitRequest, QuitResponse, SaveBufferRequest, SaveBufferResponse, SetWindowSizeRequest, SetWindowSizeResponse,
+    SubscribeBufferRequest, SubscribeLayoutRequest, SubscribeMinibufferRequest, Viewport,
+};
+use eng_core::desktop;
+use eng_core::handshake::Hello;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::codegen::InterceptedService;
+use tonic::metadata::{Ascii, MetadataMap, MetadataValue};
+use tonic::service::Interceptor;
+use tonic::transport::Channel;
+use tonic::{Request, Response, Status, Streaming};
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::HealthCheckRequest;
+
+use crate::cores::{CorePool, DEFAULT_CORE};
+use crate::remote::{RemoteFile, RemoteFiles};
+use crate::uis::{UiSession, Uis};
+
+/// コアの名前を指定するメタデータ
+pub const CORE_METADATA: &str = "eng-core";
+/// プロジェクトのパスを指定するメタデータ。そのプロジェクトのコアが無ければ起動する。
+pub const PROJECT_METADATA: &str = "eng-project";
+/// Agent が起動した UI が、ハンドシェイクで --ui-id の ID を名乗るメタデータ
+pub const UI_METADATA: &str = "eng-ui";
+
+pub type ProxyStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send + Sync + 'static>>;
+
+/// コアへのリクエストにコアのトークンを付ける。標準入出力でつないだコアには付けない。
+#[derive(Debug, Clone)]
+pub struct CoreToken(Option<MetadataValue<Ascii>>);
+
+impl Interceptor for CoreToken {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(token) = &self.0 {
+            request.metadata_mut().insert("authorization", token.clone());
+        }
+        Ok(request)
+    }
+}
+
+/// Agent が起動したコアへの接続
+#[derive(Debug, Clone)]
+pub struct CoreLink {
+    channel: Channel,
+    token: CoreToken,
+}
+
+impl CoreLink {
+    pub fn new(channel: Channel, token: Option<&str>) -> Result<Self, Box<dyn std::error::Error>> {
+        let token = token.map(|t| t.parse()).transpose()?;
+        Ok(Self { channel, token: CoreToken(token) })
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel.clone()
+    }
+
+    fn editor(&self) -> EditorServiceClient<InterceptedService<Channel, CoreToken>> {
+        EditorServiceClient::with_interceptor(self.channel.clone(), self.token.clone())
+    }
+
+    fn buffers(&self) -> BufferServiceClient<InterceptedService<Channel, CoreToken>> {
+        BufferServiceClient::with_interceptor(self.channel.clone(), self.token.clone())
+    }
+
+    /// timeout 以内にヘルスチェック (grpc.health.v1) に SERVING と応えたか
+    pub async fn healthy(&self, timeout: Duration) -> bool {
+        let mut health = HealthClient::with_interceptor(self.channel.clone(), self.token.clone());
+        let check = health.check(HealthCheckRequest { service: String::new() });
+        match tokio::time::timeout(timeout, check).await {
+            Ok(Ok(response)) => response.into_inner().status == ServingStatus::Serving as i32,
+            _ => false,
+        }
+    }
+
+    /// 起動し直したコアが自動保存から未保存の内容を戻したバッファの数。ファイルが変えられていて
+    /// "<名前> (auto-save)" の別のバッファに戻したものも数える。コアに尋ねられなければ 0
+    pub async fn recovered_buffers(&self) -> u32 {
+        match self.editor().get_desktop_status(GetDesktopStatusRequest {}).await {
+            Ok(response) => {
+                let status = response.into_inner();
+                status.recovered + status.conflicts.len() as u32
+            }
+            Err(_) => 0,
+        }
+    }
+
+    pub async fn open_file(&self, path: &str) -> Result<BufferInfo, Status> {
+        let request = OpenFileRequest { path: path.to_string() };
+        Ok(self.buffers().open_file(request).await?.into_inner())
+    }
+
+    /// バッファが閉じられる (kill-buffer) まで待つ。コアとの接続が切れても返る。
+    pub async fn wait_killed(&self, buffer_id: &str) {
+        // 行を送らせない範囲を購読し、ストリームが終わるのを待つ
+        let request = SubscribeBufferRequest {
+            buffer_id: buffer_id.to_string(),
+            resume_from_version: None,
+            viewport: Some(Viewport { start_line: 0, count: 0 }),
+        };
+        if let Ok(response) = self.buffers().subscribe_buffer(request).await {
+            let mut updates = response.into_inner();
+            while let Ok(Some(_)) = updates.message().await {}
+        }
+    }
+
+    /// コアが開いているバッファ。コアに尋ねられなければ空
+    pub async fn buffer_list(&self) -> Vec<BufferInfo> {
+        match self.buffers().list_buffers(ListBuffersRequest {}).await {
+            Ok(response) => response.into_inner().buffers,
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// 保存していない変更のあるファイルのパス。コアに尋ねられなければ空
+    pub async fn unsaved_files(&self) -> Vec<String> {
+        self.buffer_list()
+            .await
+            .into_iter()
+            .filter(|buffer| buffer.modified && !buffer.path.is_empty())
+            .map(|buffer| buffer.path)
+            .collect()
+    }
+}
+
+/// UI から届いたリクエストの中身だけをコアへ送る。UI のメタデータ (Agent のトークン) は送らない。
+fn forward<T>(request: Request<T>) -> Request<T> {
+    Request::new(request.into_inner())
+}
+
+/// コアから届くストリームを UI へ中継する。UI が切断したらコアのストリームも閉じる。
+fn relay<T: Send + 'static>(mut stream: Streaming<T>) -> ProxyStream<T> {
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            let item = tokio::select! {
+                item = stream.next() => item,
+                _ = tx.closed() => break,
+            };
+            let Some(item) = item else { break };
+            let failed = item.is_err();
+            if tx.send(item).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    Box::pin(ReceiverStream::new(rx))
+}
+
+/// リクエストを送るコア。UI はメタデータの eng-core でコアの名前を、eng-project でプロジェクトの
+/// パスを指定できる。どちらも無ければ既定のコアへ送る。
+async fn route(pool: &Arc<CorePool>, metadata: &MetadataMap) -> Result<(String, CoreLink), Status> {
+    if let Some(name) = metadata.get(CORE_METADATA) {
+        let name = name.to_str().map_err(|_| Status::invalid_argument("Invalid core name"))?;
+        return Ok((name.to_string(), pool.get_or_start(name, None).await?));
+    }
+    if let Some(path) = metadata.get(PROJECT_METADATA) {
+        let path = path.to_str().map_err(|_| Status::invalid_argument("Invalid project path"))?;
+        return pool.for_project(Path::new(path)).await;
+    }
+    Ok((DEFAULT_CORE.to_string(), pool.get_or_start(DEFAULT_CORE, None).await?))
+}
+
+/// ハンドシェイクを済ませる前は閉じない
+async fn closed(session: &mut Option<UiSession>) {
+    match session {
+        Some(session) => session.closed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// EditorService をコアへ中継する。ハンドシェイクを済ませた UI は Agent でも記録する。
+#[derive(Debug, Clone)]
+pub struct EditorProxy {
+    pool: Arc<CorePool>,
+    uis: Arc<Uis>,
+}
+
+impl EditorProxy {
+    pub fn new(pool: Arc<CorePool>, uis: Arc<Uis>) -> Self {
+        Self { pool, uis }
+    }
+
+    async fn core<T>(&self, request: &Request<T>) -> Result<CoreLink, Status> {
+        Ok(route(&self.pool, request.metadata()).await?.1)
+    }
+}
+
+#[tonic::async_trait]
+impl EditorService for EditorProxy {
+    type HandshakeStream = ProxyStream<HandshakeResponse>;
+
+    async fn handshake(
+        &self,
+        request: Request<Streaming<HandshakeRequest>>,
+    ) -> Result<Response<Self::HandshakeStream>, Status> {
+        let (metadata, _, mut inbound) = request.into_parts();
+        let core = route(&self.pool, &metadata).await?.1;
+        let ui_id = match metadata.get(UI_METADATA) {
+            Some(id) => Some(id.to_str().map_err(|_| Status::invalid_argument("Invalid UI id"))?.to_string()),
+            None => None,
+        };
+        let Some(first) = inbound.message().await? else {
+            return Err(Status::failed_precondition("Handshake closed before the client sent its hello"));
+        };
+        // 取り決めはコアが行う。UI からのメッセージは閉じるまでそのまま流す。
+        let hello = Hello::try_from(first.clone()).ok();
+        let (req_tx, req_rx) = mpsc::channel(4);
+        let _ = req_tx.send(first).await;
+        tokio::spawn(async move {
+            while let Ok(Some(message)) = inbound.message().await {
+                if req_tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let mut responses = core.editor().handshake(Request::new(ReceiverStream::new(req_rx))).await?.into_inner();
+
+        let uis = self.uis.clone();
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            // ストリームが続く間 (UI が接続している間) だけ保持する。CloseUi で閉じるよう指示されたら閉じる。
+            let mut session: Option<UiSession> = None;
+            loop {
+                let item = tokio::select! {
+                    item = responses.next() => item,
+                    _ = tx.closed() => break,
+                    _ = closed(&mut session) => break,
+                };
+                let Some(item) = item else { break };
+                if let (Ok(_), Some(hello), None) = (&item, &hello, &session) {
+                    session = Some(uis.connect(ui_id.as_deref(), hello));
+                }
+                let failed = item.is_err();
+                if tx.send(item).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn create_window(&self, request: Request<CreateWindowRequest>) -> Result<Response<CreateWindowResponse>, Status> {
+        self.core(&request).await?.editor().create_window(forward(request)).await
+    }
+
+    async fn set_window_size(&self, request: Request<SetWindowSizeRequest>) -> Result<Response<SetWindowSizeResponse>, Status> {
+        self.core(&request).await?.editor().set_window_size(forward(request)).await
+    }
+
+    type SubscribeLayoutStream = ProxyStream<LayoutUpdate>;
+
+    async fn subscribe_layout(&self, request: Request<SubscribeLayoutRequest>) -> Result<Response<Self::SubscribeLayoutStream>, Status> {
+        let stream = self.core(&request).await?.editor().subscribe_layout(forward(request)).await?.into_inner();
+        Ok(Response::new(relay(stream)))
+    }
+
+    async fn execute_command(&self, request: Request<ExecuteCommandRequest>) -> Result<Response<ExecuteCommandResponse>, Status> {
+        self.core(&request).await?.editor().execute_command(forward(request)).await
+    }
+
+    async fn list_commands(&self, request: Request<ListCommandsRequest>) -> Result<Response<ListCommandsResponse>, Status> {
+        self.core(&request).await?.editor().list_commands(forward(request)).await
+    }
+
+    async fn send_key(&self, request: Request<KeyEventRequest>) -> Result<Response<KeyEventResponse>, Status> {
+        self.core(&request).await?.editor().send_key(forward(request)).await
+    }
+
+    async fn describe_key(&self, request: Request<DescribeKeyRequest>) -> Result<Response<DescribeKeyResponse>, Status> {
+        self.core(&request).await?.editor().describe_key(forward(request)).await
+    }
+
+    type SubscribeMinibufferStream = ProxyStream<MinibufferUpdate>;
+
+    async fn subscribe_minibuffer(&self, request: Request<SubscribeMinibufferRequest>) -> Result<Response<Self::SubscribeMinibufferStream>, Status> {
+        let stream = self.core(&request).await?.editor().subscribe_minibuffer(forward(request)).await?.into_inner();
+        Ok(Response::new(relay(stream)))
+    }
+
+    async fn quit(&self, request: Request<QuitRequest>) -> Result<Response<QuitResponse>, Status> {
+        self.core(&request).await?.editor().quit(forward(request)).await
+    }
+
+    async fn get_latency_metrics(&self, request: Request<GetLatencyMetricsRequest>) -> Result<Response<GetLatencyMetricsResponse>, Status> {
+        self.core(&request).await?.editor().get_latency_metrics(forward(request)).await
+    }
+
+    async fn get_desktop_status(&self, request: Request<GetDesktopStatusRequest>) -> Result<Response<GetDesktopStatusResponse>, Status> {
+        self.core(&request).await?.editor().get_desktop_status(forward(request)).await
+    }
+
+    async fn notify_activity(&self, request: Request<NotifyActivityRequest>) -> Result<Response<NotifyActivityResponse>, Status> {
+        self.core(&request).await?.editor().notify_activity(forward(request)).await
+    }
+}
+
+/// BufferService をコアへ中継する。ファイルはそのファイルを含むプロジェクトのコアで開き、
+/// バッファを指定するリクエストはそのバッファを持つコアへ送る。
+/// リモートのコアで開くファイルは Agent が手元に持ち、編集にすぐ応える (RemoteFiles)。
+#[derive(Debug, Clone)]
+pub struct BufferProxy {
+    pool: Arc<CorePool>,
+    /// コアをリモートで起動するときのみ
+    remote: Option<Arc<RemoteFiles>>,
+}
+
+impl BufferProxy {
+    pub fn new(pool: Arc<CorePool>, remote: Option<Arc<RemoteFiles>>) -> Self {
+        Self { pool, remote }
+    }
+
+    /// buffer_id を持つコアと、そのコアでのバッファID。Agent を通さずに作られたバッファなら、メタデータで指定したコア。
+    /// コアを起動し直していれば、起動し直したコアでのIDに置き換える。
+    async fn core(&self, metadata: &MetadataMap, buffer_id: &str) -> Result<(CoreLink, String), Status> {
+        let Some((name, current)) = self.pool.core_for_buffer(buffer_id) else {
+            return Ok((route(&self.pool, metadata).await?.1, buffer_id.to_string()));
+        };
+        let link = self
+            .pool
+            .link(&name)
+            .await
+            .ok_or_else(|| Status::not_found(format!("Core {:?} holding buffer {} has stopped", name, buffer_id)))?;
+        let current = current.ok_or_else(|| {
+            Status::not_found(format!("Buffer {} was not restored when core {:?} restarted; open it again", buffer_id, name))
+        })?;
+        Ok((link, current))
+    }
+
+    /// Agent が手元に持っているリモートのファイル
+    fn remote_file(&self, buffer_id: &str) -> Option<Arc<RemoteFile>> {
+        self.remote.as_ref()?.get(buffer_id)
+    }
+}
+
+#[tonic::async_trait]
+impl BufferService for BufferProxy {
+    async fn create_buffer(&self, request: Request<CreateBufferRequest>) -> Result<Response<BufferInfo>, Status> {
+        let (name, core) = route(&self.pool, request.metadata()).await?;
+        let response = core.buffers().create_buffer(forward(request)).await?;
+        self.pool.remember_buffer(response.get_ref(), &name);
+        Ok(response)
+    }
+
+    async fn open_file(&self, request: Request<OpenFileRequest>) -> Result<Response<BufferInfo>, Status> {
+        let by_name = request.metadata().contains_key(CORE_METADATA) || request.get_ref().path.is_empty();
+        if let Some(remote) = &self.remote
+            && !request.get_ref().path.is_empty()
+        {
+            let path = &request.get_ref().path;
+            let (name, root) = if by_name {
+                (route(&self.pool, request.metadata()).await?.0, None)
+            } else {
+                let root = desktop::project_root(Path::new(path));
+                (root.display().to_string(), Some(root))
+            };
+            return Ok(Response::new(remote.open(&name, root, path).await?));
+        }
+        let (name, core) = if by_name {
+            route(&self.pool, request.metadata()).await?
+        } else {
+            self.pool.for_project(Path::new(&request.get_ref().path)).await?
+        };
+        let response = core.buffers().open_file(forward(request)).await?;
+        self.pool.remember_buffer(response.get_ref(), &name);
+        Ok(response)
+    }
+
+    async fn get_text(&self, mut request: Request<GetTextRequest>) -> Result<Response<GetTextResponse>, Status> {
+        if let Some(file) = self.remote_file(&request.get_ref().buffer_id) {
+            return file.get_text(request.into_inner()).map(Response::new);
+        }
+        let (core, buffer_id) = self.core(request.metadata(), &request.get_ref().buffer_id).await?;
+        request.get_mut().buffer_id = buffer_id;
+        core.buffers().get_text(forward(request)).await
+    }
+
+    async fn get_lines(&self, mut request: Request<GetLinesRequest>) -> Result<Response<GetLinesResponse>, Status> {
+        if let Some(file) = self.remote_file(&request.get_ref().buffer_id) {
+            return Ok(Response::new(file.get_lines(request.into_inner())));
+        }
+        let (core, buffer_id) = self.core(request.metadata(), &request.get_ref().buffer_id).await?;
+        request.get_mut().buffer_id = buffer_id;
+        core.buffers().get_lines(forward(request)).await
+    }
+
+    async fn apply_edits(&self, mut request: Request<ApplyEditsRequest>) -> Result<Response<ApplyEditsResponse>, Status> {
+        if let Some(file) = self.remote_file(&request.get_ref().buffer_id) {
+            return file.apply_edits(request.into_inner()).map(Response::new);
+        }
+        let (core, buffer_id) = self.core(request.metadata(), &request.get_ref().buffer_id).await?;
+        request.get_mut().buffer_id = buffer_id;
+        core.buffers().apply_edits(forward(request)).await
+    }
+
+    async fn save_buffer(&self, mut request: Request<SaveBufferRequest>) -> Result<Response<SaveBufferResponse>, Status> {
+        if let Some(file) = self.remote_file(&request.get_ref().buffer_id) {
+            if !request.get_ref().path.is_empty() {
+                return Err(Status::invalid_argument("Saving a remote file under another name is not supported"));
+            }
+            return file.save().await.map(Response::new);
+        }
+        let (core, buffer_id) = self.core(request.metadata(), &request.get_ref().buffer_id).await?;
+        request.get_mut().buffer_id = buffer_id;
+        core.buffers().save_buffer(forward(request)).await
+    }
+
+    async fn list_buffers(&self, request: Request<ListBuffersRequest>) -> Result<Response<ListBuffersResponse>, Status> {
+        let (name, core) = route(&self.pool, request.metadata()).await?;
+        let mut response = core.buffers().list_buffers(forward(request)).await?;
+        // Agent が手元に持っているファイルは、Agent のバッファIDと手元のテキストで返す
+        if let Some(remote) = &self.remote {
+            for buffer in &mut response.get_mut().buffers {
+                if let Some(file) = remote.find(&name, Path::new(&buffer.path)) {
+                    *buffer = file.info();
+                }
+            }
+        }
+        Ok(response)
+    }
+
+    type SubscribeBufferStream = ProxyStream<BufferUpdate>;
+
+    async fn subscribe_buffer(&self, mut request: Request<SubscribeBufferRequest>) -> Result<Response<Self::SubscribeBufferStream>, Status> {
+        if let Some(file) = self.remote_file(&request.get_ref().buffer_id) {
+            return Ok(Response::new(file.subscribe(request.into_inner())));
+        }
+        let (core, buffer_id) = self.core(request.metadata(), &request.get_ref().buffer_id).await?;
+        request.get_mut().buffer_id = buffer_id;
+        let stream = core.buffers().subscribe_buffer(forward(request)).await?.into_inner();
+        Ok(Response::new(relay(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cores::{tests::kill, CoreKind};
+    use tonic::Code;
+    use uuid::Uuid;
+
+    fn get_text(buffer_id: &str) -> Request<GetTextRequest> {
+        Request::new(GetTextRequest { buffer_id: buffer_id.to_string(), start: 0, end: None })
+    }
+
+    #[tokio::test]
+    async fn test_route_by_project_and_buffer() {
+        let pool = Arc::new(CorePool::new(CoreKind::Stub { delay: Duration::ZERO, desktop: None }));
+        let proxy = BufferProxy::new(pool.clone(), None);
+        let dir = std::env::temp_dir().join(format!("eng-agent-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("Cargo.toml"), "").unwrap();
+        std::fs::write(dir.join("src/main.rs"), "fn main() {}\n").unwrap();
+        let root = dir.display().to_string();
+
+        // ファイルはプロジェクトのコアで開く
+        let path = dir.join("src/main.rs").display().to_string();
+        let file = proxy.open_file(Request::new(OpenFileRequest { path })).await.unwrap().into_inner();
+        assert_eq!(pool.list(|core| core.name.clone()).await, vec![root.clone()]);
+
+        // バッファを指定するリクエストは、メタデータが無くてもそのバッファを持つコアへ送る
+        let text = proxy.get_text(get_text(&file.buffer_id)).await.unwrap().into_inner();
+        assert_eq!(text.text, "fn main() {}\n");
+        assert_eq!(pool.list(|core| core.name.clone()).await, vec![root.clone()]);
+
+        // コアの名前を指定すれば、そのコアへ送る
+        let mut request = Request::new(CreateBufferRequest { name: "notes".into(), text: String::new() });
+        request.metadata_mut().insert(CORE_METADATA, MetadataValue::from_static("scratch"));
+        let notes = proxy.create_buffer(request).await.unwrap().into_inner();
+        assert_eq!(pool.core_for_buffer(&notes.buffer_id).map(|(core, _)| core).as_deref(), Some("scratch"));
+
+        // プロジェクトを指定すれば、そのプロジェクトのコアのバッファを返す
+        let mut request = Request::new(ListBuffersRequest {});
+        request.metadata_mut().insert(PROJECT_METADATA, root.parse().unwrap());
+        let buffers = proxy.list_buffers(request).await.unwrap().into_inner().buffers;
+        assert!(buffers.iter().any(|buffer| buffer.buffer_id == file.buffer_id));
+        assert!(!buffers.iter().any(|buffer| buffer.buffer_id == notes.buffer_id));
+
+        // コアが落ちている間と、起動し直したコアが開き直さなかったバッファは、他のコアへ送らずにエラーにする
+        kill(&pool, &root).await;
+        let error = proxy.get_text(get_text(&file.buffer_id)).await.unwrap_err();
+        assert_eq!(error.code(), Code::NotFound);
+        assert!(error.message().contains("has stopped"));
+        pool.get_or_start(&root, Some(&dir)).await.unwrap();
+        let error = proxy.get_text(get_text(&file.buffer_id)).await.unwrap_err();
+        assert_eq!(error.code(), Code::NotFound);
+        assert!(error.message().contains("was not restored"));
+        assert_eq!(pool.list(|core| core.name.clone()).await, vec![root.clone(), "scratch".to_string()]);
+
+        pool.stop_all().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
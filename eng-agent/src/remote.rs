@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use eng_core::editor::buffer_service_client::BufferServiceClient;
+use eng_core::editor::{
+    self, ApplyEditsRequest, ApplyEditsResponse, BufferInfo, BufferUpdate, GetLinesRequest, GetLinesResponse,
+    GetTextRequest, GetTextResponse, SaveBufferResponse, SubscribeBufferRequest,
+};
+use eng_core::remote::{self, Connection, RemoteBuffer, RemoteSession};
+use eng_core::subscription::{Cursor, Viewport};
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Code, Status};
+use uuid::Uuid;
+
+use crate::cores::CorePool;
+use crate::proxy::ProxyStream;
+
+type SharedSession = Arc<tokio::sync::Mutex<RemoteSession>>;
+
+/// リモートのコア (CoreKind::Command) で開いたファイル。Agent が手元にテキストを持って編集にすぐ応え、
+/// 裏でコアへ送る。コアが落ちたらプールが起動し直し、開き直して送っていない編集を載せ直す。
+/// バッファIDは Agent が振るので、コアを起動し直しても変わらない。
+#[derive(Debug)]
+pub struct RemoteFiles {
+    pool: Arc<CorePool>,
+    /// コアの名前ごとの接続
+    sessions: Mutex<HashMap<String, SharedSession>>,
+    /// Agent が振ったバッファIDごとのファイル
+    files: Mutex<HashMap<String, Arc<RemoteFile>>>,
+}
+
+impl RemoteFiles {
+    pub fn new(pool: Arc<CorePool>) -> Self {
+        Self { pool, sessions: Mutex::new(HashMap::new()), files: Mutex::new(HashMap::new()) }
+    }
+
+    fn files(&self) -> MutexGuard<'_, HashMap<String, Arc<RemoteFile>>> {
+        self.files.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// core への接続。切れていたら、落ちたコアをプールに起動し直させて繋ぎ直す。
+    fn session(&self, core: &str, project_root: Option<PathBuf>) -> SharedSession {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        let session = sessions.entry(core.to_string()).or_insert_with(|| {
+            let (pool, name) = (self.pool.clone(), core.to_string());
+            let session = RemoteSession::new(move || {
+                let (pool, name, root) = (pool.clone(), name.clone(), project_root.clone());
+                async move {
+                    let link = pool.get_or_start(&name, root.as_deref()).await.map_err(|e| e.message().to_string())?;
+                    Ok(Connection::new(link.channel(), name, None))
+                }
+            });
+            Arc::new(tokio::sync::Mutex::new(session))
+        });
+        session.clone()
+    }
+
+    /// バッファIDのファイル。Agent を通してリモートのコアで開いたものでなければ None
+    pub fn get(&self, buffer_id: &str) -> Option<Arc<RemoteFile>> {
+        self.files().get(buffer_id).cloned()
+    }
+
+    /// core で path を開く。既に開いていればそのバッファを返す。
+    pub async fn open(&self, core: &str, project_root: Option<PathBuf>, path: &str) -> Result<BufferInfo, Status> {
+        let path = std::path::absolute(path).unwrap_or_else(|_| PathBuf::from(path));
+        if let Some(file) = self.find(core, &path) {
+            return Ok(file.info());
+        }
+        let session = self.session(core, project_root);
+        let buffer = RemoteBuffer::open(&mut *session.lock().await, &path).await?;
+        let mut files = self.files();
+        // 開いている間に他のリクエストが同じファイルを開いていれば、そちらを使う
+        if let Some(file) = files.values().find(|file| file.core == core && file.path == path) {
+            return Ok(file.info());
+        }
+        let file = Arc::new(RemoteFile {
+            id: Uuid::new_v4().to_string(),
+            core: core.to_string(),
+            path,
+            session,
+            changed: watch::channel(buffer.revision()).0,
+            buffer: Mutex::new(buffer),
+        });
+        files.insert(file.id.clone(), file.clone());
+        Ok(file.info())
+    }
+
+    /// core で開いている path のファイル
+    pub fn find(&self, core: &str, path: &Path) -> Option<Arc<RemoteFile>> {
+        self.files().values().find(|file| file.core == core && file.path == path).cloned()
+    }
+
+    /// Agent のバッファIDのファイルがコアで閉じられる (kill-buffer) まで待ち、閉じられたら手放す。
+    /// コアが落ちたら、起動し直したコアで開き直したバッファを待ち続ける。コアに繋がらなくなっても返る。
+    pub async fn wait_killed(&self, buffer_id: &str) {
+        let Some(file) = self.get(buffer_id) else {
+            return;
+        };
+        if file.wait_killed().await {
+            self.files().remove(buffer_id);
+        }
+    }
+}
+
+/// リモートのコアで開いたファイル1つ
+#[derive(Debug)]
+pub struct RemoteFile {
+    id: String,
+    core: String,
+    path: PathBuf,
+    session: SharedSession,
+    buffer: Mutex<RemoteBuffer>,
+    /// 手元のテキストの版が変わったら知らせる (SubscribeBuffer)
+    changed: watch::Sender<u64>,
+}
+
+impl RemoteFile {
+    fn buffer(&self) -> MutexGuard<'_, RemoteBuffer> {
+        self.buffer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// バッファの情報。バッファIDは Agent が振ったもの
+    pub fn info(&self) -> BufferInfo {
+        BufferInfo { buffer_id: self.id.clone(), ..self.buffer().info() }
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn get_text(&self, request: GetTextRequest) -> Result<GetTextResponse, Status> {
+        let snapshot = self.buffer().snapshot();
+        let end = request.end.map_or(snapshot.len_chars(), |end| end as usize);
+        let text = snapshot.text_range(request.start as usize..end).map_err(Status::out_of_range)?;
+        Ok(GetTextResponse { text, version: snapshot.version })
+    }
+
+    pub fn get_lines(&self, request: GetLinesRequest) -> GetLinesResponse {
+        let snapshot = self.buffer().snapshot();
+        let start = request.start_line as usize;
+        let lines = (start..start.saturating_add(request.count as usize)).map_while(|line| snapshot.line(line)).collect();
+        GetLinesResponse { lines, total_lines: snapshot.len_lines() as u64, version: snapshot.version }
+    }
+
+    /// 手元に反映してすぐに応え、コアへは裏で送る
+    #[allow(clippy::result_large_err)]
+    pub fn apply_edits(self: &Arc<Self>, request: ApplyEditsRequest) -> Result<ApplyEditsResponse, Status> {
+        let version = self.buffer().apply(&request.edits, request.base_version)?;
+        if request.edits.is_empty() {
+            return Ok(ApplyEditsResponse { version });
+        }
+        self.changed.send_replace(version);
+        let file = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = file.flush().await {
+                eprintln!("Agent: Failed to send edits to {}: {}", file.path.display(), e.message());
+            }
+        });
+        Ok(ApplyEditsResponse { version })
+    }
+
+    /// 溜めている編集をコアへ送る。送っている間も手元の編集は受け付ける。
+    async fn flush(&self) -> Result<(), Status> {
+        let mut session = self.session.lock().await;
+        let before = self.buffer().clone();
+        let mut after = before.clone();
+        let flushed = after.flush(&mut session).await;
+        self.absorb(&before, after)?;
+        flushed.map(|_| ())
+    }
+
+    /// 送っていない編集を送ってから保存する
+    pub async fn save(&self) -> Result<SaveBufferResponse, Status> {
+        let mut session = self.session.lock().await;
+        let before = self.buffer().clone();
+        let mut after = before.clone();
+        let saved = after.save(&mut session).await;
+        self.absorb(&before, after)?;
+        saved?;
+        Ok(SaveBufferResponse { path: self.path.display().to_string(), version: self.buffer().revision() })
+    }
+
+    /// コアで開いているバッファが閉じられるまで待つ。閉じられたら true、コアに繋がらなくなったら false
+    async fn wait_killed(&self) -> bool {
+        loop {
+            // 繋ぎ直していれば開き直す
+            if self.flush().await.is_err() {
+                return false;
+            }
+            let Ok(channel) = self.session.lock().await.channel().await else {
+                return false;
+            };
+            let Some(buffer_id) = self.buffer().buffer_id().map(str::to_string) else {
+                continue;
+            };
+            // 行を送らせない範囲を購読し、ストリームが終わるのを待つ
+            let mut client = BufferServiceClient::new(channel);
+            let request = SubscribeBufferRequest {
+                buffer_id: buffer_id.clone(),
+                resume_from_version: None,
+                viewport: Some(editor::Viewport { start_line: 0, count: 0 }),
+            };
+            if let Ok(response) = client.subscribe_buffer(request).await {
+                let mut updates = response.into_inner();
+                while let Ok(Some(_)) = updates.message().await {}
+            }
+            // 閉じられたのか、接続が切れたのか
+            match client.get_text(GetTextRequest { buffer_id, start: 0, end: Some(0) }).await {
+                Err(status) if status.code() == Code::NotFound => return true,
+                Err(status) if remote::is_disconnected(&status) => self.session.lock().await.disconnect(),
+                Err(_) => return false,
+                Ok(_) => {}
+            }
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn absorb(&self, before: &RemoteBuffer, after: RemoteBuffer) -> Result<(), Status> {
+        let mut buffer = self.buffer();
+        let revision = buffer.revision();
+        let absorbed = buffer.absorb(before, after);
+        // 他のクライアントの編集を取り込んだ
+        if buffer.revision() != revision {
+            self.changed.send_replace(buffer.revision());
+        }
+        absorbed
+    }
+
+    /// 手元のテキストの変化を購読する。コアのバッファの購読 (SubscribeBuffer) と同じく、最初に全体か表示範囲を送り、
+    /// 以降は手元の版ごとの差分を送る。resume_from_version の版からの差分が残っていなければ全体から送り直す。
+    pub fn subscribe(self: &Arc<Self>, request: SubscribeBufferRequest) -> ProxyStream<BufferUpdate> {
+        let file = self.clone();
+        let mut changed = self.changed.subscribe();
+        let viewport = request.viewport.map(|v| Viewport { start_line: v.start_line as usize, count: v.count as usize });
+        let mut cursor = Cursor::new(request.resume_from_version, viewport);
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                // 版を進めてから知らせるので、既読にしてから読めば以降の変更を取りこぼさない
+                changed.borrow_and_update();
+                let update = cursor.update(&*file.buffer());
+                if let Some(update) = update
+                    && tx.send(Ok(update.into())).await.is_err()
+                {
+                    break;
+                }
+                tokio::select! {
+                    result = changed.changed() => if result.is_err() { break },
+                    _ = tx.closed() => break,
+                }
+            }
+        });
+        Box::pin(ReceiverStream::new(rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cores::CoreKind;
+    use eng_core::editor::buffer_update::Update;
+    use eng_core::editor::{BufferDelta, TextEdit};
+    use std::time::Duration;
+    use tokio_stream::StreamExt;
+
+    async fn next(updates: &mut ProxyStream<BufferUpdate>) -> Update {
+        updates.next().await.unwrap().unwrap().update.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_sends_deltas() {
+        let pool = Arc::new(CorePool::new(CoreKind::Stub { delay: Duration::ZERO, desktop: None }));
+        let files = RemoteFiles::new(pool.clone());
+        let dir = std::env::temp_dir().join(format!("eng-agent-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.txt").display().to_string();
+        std::fs::write(&path, "a\nb\n").unwrap();
+        let info = files.open("a", None, &path).await.unwrap();
+        let file = files.get(&info.buffer_id).unwrap();
+        let subscribe = |resume_from_version, viewport| {
+            file.subscribe(SubscribeBufferRequest { buffer_id: info.buffer_id.clone(), resume_from_version, viewport })
+        };
+
+        // 最初は全体を送り、以降は手元の版ごとの差分を送る
+        let mut updates = subscribe(None, None);
+        let Update::Snapshot(text) = next(&mut updates).await else { panic!("expected a snapshot") };
+        assert_eq!((text.text.as_str(), text.version), ("a\nb\n", 0));
+        let edit = TextEdit { start: 0, end: 0, text: "x".into() };
+        let request = ApplyEditsRequest { buffer_id: info.buffer_id.clone(), edits: vec![edit], base_version: None };
+        file.apply_edits(request).unwrap();
+        let delta = BufferDelta { start: 0, end: 0, text: "x".into(), version: 1 };
+        let Update::Deltas(deltas) = next(&mut updates).await else { panic!("expected deltas") };
+        assert_eq!(deltas.deltas, std::slice::from_ref(&delta));
+
+        // 再接続: 残っている差分から再開し、差分が無ければ全体から送り直す
+        let Update::Deltas(deltas) = next(&mut subscribe(Some(0), None)).await else { panic!("expected deltas") };
+        assert_eq!(deltas.deltas, [delta]);
+        let Update::Deltas(deltas) = next(&mut subscribe(Some(1), None)).await else { panic!("expected deltas") };
+        assert!(deltas.deltas.is_empty());
+        let Update::Snapshot(text) = next(&mut subscribe(Some(9), None)).await else { panic!("expected a snapshot") };
+        assert_eq!((text.text.as_str(), text.version), ("xa\nb\n", 1));
+
+        // 表示範囲を指定すれば、その行だけを送る
+        let viewport = Some(eng_core::editor::Viewport { start_line: 1, count: 1 });
+        let Update::Lines(lines) = next(&mut subscribe(None, viewport)).await else { panic!("expected lines") };
+        assert_eq!((lines.lines, lines.total_lines, lines.version), (vec!["b".to_string()], 3, 1));
+        pool.stop_all().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
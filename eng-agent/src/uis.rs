@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use eng_core::editor::ui_command::Kind as UiCommandKind;
+use eng_core::editor::UiCommand;
+use eng_core::handshake::{ClientKind, Hello};
+use eng_core::transport::Address;
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::launcher::Launcher;
+
+/// CloseUi で UI が自分で閉じるのを待つ時間。過ぎたら終了させる。
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Agent が起動した UI、または Agent に接続している UI
+#[derive(Debug)]
+struct Ui {
+    /// Agent が起動したプロセス。終了したら None
+    pid: Option<u32>,
+    /// プロセスを閉じさせる
+    close: Option<oneshot::Sender<()>>,
+    /// 最後にハンドシェイクしたクライアントの名前と種類
+    client: Option<(String, ClientKind)>,
+    /// 開いているハンドシェイクの数
+    sessions: usize,
+    /// SubscribeUi で購読している UI へ送る指示
+    commands: Option<mpsc::Sender<UiCommand>>,
+    /// 起動した UI が購読を始める前に届いた指示。購読したら送る。
+    pending: Vec<UiCommand>,
+    /// CloseUi で true にし、開いているハンドシェイクを閉じる
+    closing: watch::Sender<bool>,
+    started: SystemTime,
+}
+
+impl Ui {
+    fn new(pid: Option<u32>) -> Self {
+        Self {
+            pid,
+            close: None,
+            client: None,
+            sessions: 0,
+            commands: None,
+            pending: Vec::new(),
+            closing: watch::channel(false).0,
+            started: SystemTime::now(),
+        }
+    }
+
+    /// プロセスが動いているか、どれかの接続が開いている
+    fn alive(&self) -> bool {
+        self.pid.is_some() || self.sessions > 0 || self.commands.is_some()
+    }
+
+    /// 指示を受け取れる (購読している、または起動したのでこれから購読する)
+    fn accepts_commands(&self) -> bool {
+        self.commands.is_some() || self.pid.is_some()
+    }
+}
+
+/// ListUis で返す UI の状態
+#[derive(Debug, Clone)]
+pub struct UiStatus {
+    pub id: String,
+    pub pid: Option<u32>,
+    pub client: Option<(String, ClientKind)>,
+    pub sessions: usize,
+    pub subscribed: bool,
+    pub started: SystemTime,
+}
+
+/// UI の一覧。非デーモンモードの Agent は、最後の UI が閉じたら終了する。
+#[derive(Debug)]
+pub struct Uis {
+    uis: Mutex<HashMap<String, Ui>>,
+    next_id: AtomicU32,
+    /// 残っている UI の数
+    count: watch::Sender<usize>,
+}
+
+impl Default for Uis {
+    fn default() -> Self {
+        Self { uis: Mutex::new(HashMap::new()), next_id: AtomicU32::new(1), count: watch::channel(0).0 }
+    }
+}
+
+impl Uis {
+    fn uis(&self) -> std::sync::MutexGuard<'_, HashMap<String, Ui>> {
+        self.uis.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn new_id(&self) -> String {
+        format!("ui-{}", self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// 閉じた UI を一覧から外し、残りの数を知らせる
+    fn update(&self, uis: &mut HashMap<String, Ui>) {
+        uis.retain(|_, ui| ui.alive());
+        self.count.send_replace(uis.len());
+    }
+
+    /// UI を起動して一覧に加える。プロセスが終了したら一覧から外れる。
+    pub async fn spawn(
+        self: &Arc<Self>,
+        agent_address: &Address,
+        agent_token: &str,
+        test_mode: bool,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let id = self.new_id();
+        let mut handle = Launcher::launch_ui(&id, agent_address, agent_token, test_mode).await?;
+        let (close_tx, close_rx) = oneshot::channel();
+        {
+            let mut ui = Ui::new(handle.child.id());
+            ui.close = Some(close_tx);
+            let mut uis = self.uis();
+            uis.insert(id.clone(), ui);
+            self.update(&mut uis);
+        }
+        let uis = self.clone();
+        let ui_id = id.clone();
+        tokio::spawn(async move {
+            let child = &mut handle.child;
+            tokio::select! {
+                status = child.wait() => eprintln!("Agent: UI {} exited: {:?}", ui_id, status),
+                Ok(()) = close_rx => {
+                    // 自分で閉じるのを待ち、閉じなければ終了させる
+                    if tokio::time::timeout(CLOSE_TIMEOUT, child.wait()).await.is_err() {
+                        Launcher::terminate(child).await;
+                    }
+                    eprintln!("Agent: UI {} closed.", ui_id);
+                }
+            }
+            let mut all = uis.uis();
+            if let Some(ui) = all.get_mut(&ui_id) {
+                ui.pid = None;
+                ui.close = None;
+            }
+            uis.update(&mut all);
+        });
+        Ok(id)
+    }
+
+    /// ハンドシェイクを記録する。id は UI がメタデータで名乗ったもの (Agent が起動した UI のみ)。
+    /// 返した UiSession を drop すると (切断すると) 記録が外れる。
+    pub fn connect(self: &Arc<Self>, id: Option<&str>, hello: &Hello) -> UiSession {
+        let id = id.map(str::to_string).unwrap_or_else(|| self.new_id());
+        let mut uis = self.uis();
+        let ui = uis.entry(id.clone()).or_insert_with(|| Ui::new(None));
+        ui.client = Some((hello.name.clone(), hello.kind));
+        ui.sessions += 1;
+        let closing = ui.closing.subscribe();
+        self.update(&mut uis);
+        UiSession { uis: self.clone(), id, closing }
+    }
+
+    fn disconnect(&self, id: &str) {
+        let mut uis = self.uis();
+        if let Some(ui) = uis.get_mut(id) {
+            ui.sessions = ui.sessions.saturating_sub(1);
+        }
+        self.update(&mut uis);
+    }
+
+    /// UI への指示の購読を始める。ストリームを閉じるまで UI は残っているとみなす。
+    pub fn subscribe(self: &Arc<Self>, id: &str) -> mpsc::Receiver<UiCommand> {
+        let (tx, rx) = mpsc::channel(8);
+        {
+            let mut uis = self.uis();
+            let ui = uis.entry(id.to_string()).or_insert_with(|| Ui::new(None));
+            for command in ui.pending.drain(..) {
+                let _ = tx.try_send(command);
+            }
+            ui.commands = Some(tx.clone());
+            self.update(&mut uis);
+        }
+        let uis = self.clone();
+        let id = id.to_string();
+        tokio::spawn(async move {
+            tx.closed().await;
+            let mut all = uis.uis();
+            if let Some(ui) = all.get_mut(&id)
+                && ui.commands.as_ref().is_some_and(|commands| commands.same_channel(&tx))
+            {
+                ui.commands = None;
+            }
+            uis.update(&mut all);
+        });
+        rx
+    }
+
+    /// ID の順
+    pub fn list(&self) -> Vec<UiStatus> {
+        let mut list: Vec<_> = self
+            .uis()
+            .iter()
+            .map(|(id, ui)| UiStatus {
+                id: id.clone(),
+                pid: ui.pid,
+                client: ui.client.clone(),
+                sessions: ui.sessions,
+                subscribed: ui.commands.is_some(),
+                started: ui.started,
+            })
+            .collect();
+        list.sort_by_key(|ui| ui.id.trim_start_matches("ui-").parse::<u32>().unwrap_or(u32::MAX));
+        list
+    }
+
+    /// UI を閉じる。閉じるように指示し、ハンドシェイクを閉じ、起動したプロセスが残っていれば終了させる。
+    /// その UI が無ければ false
+    pub fn close(&self, id: &str) -> bool {
+        let mut uis = self.uis();
+        let Some(ui) = uis.get_mut(id) else {
+            return false;
+        };
+        if let Some(commands) = &ui.commands {
+            let _ = commands.try_send(UiCommand { kind: UiCommandKind::Close.into(), files: Vec::new() });
+        }
+        ui.closing.send_replace(true);
+        if let Some(close) = ui.close.take() {
+            let _ = close.send(());
+        }
+        true
+    }
+
+    /// UI のウィンドウを前面に出させる
+    pub fn focus(&self, id: &str) -> Result<(), String> {
+        self.send(id, UiCommand { kind: UiCommandKind::Focus.into(), files: Vec::new() })
+    }
+
+    /// UI へ指示を送る。起動してまだ購読していない UI には、購読したときに送る。
+    pub fn send(&self, id: &str, command: UiCommand) -> Result<(), String> {
+        let mut uis = self.uis();
+        let ui = uis.get_mut(id).ok_or_else(|| format!("No such UI: {}", id))?;
+        match &ui.commands {
+            Some(commands) => commands.try_send(command).map_err(|e| format!("Failed to send to UI {}: {}", id, e)),
+            None if ui.pid.is_some() => {
+                ui.pending.push(command);
+                Ok(())
+            }
+            None => Err(format!("UI {} does not accept commands", id)),
+        }
+    }
+
+    /// 最後に開いた、指示を受け取れる UI
+    pub fn latest(&self) -> Option<String> {
+        self.list()
+            .into_iter()
+            .rev()
+            .find(|status| self.uis().get(&status.id).is_some_and(Ui::accepts_commands))
+            .map(|status| status.id)
+    }
+
+    /// UI が閉じて一覧から外れるまで待つ
+    pub async fn wait_gone(&self, id: &str) {
+        let mut count = self.count.subscribe();
+        while self.uis().contains_key(id) {
+            if count.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// UI が1つも残っていない状態になるまで待つ
+    pub async fn wait_until_empty(&self) {
+        let _ = self.count.subscribe().wait_for(|count| *count == 0).await;
+    }
+
+    /// UI が1つ以上になるまで待つ
+    pub async fn wait_until_any(&self) {
+        let _ = self.count.subscribe().wait_for(|count| *count > 0).await;
+    }
+}
+
+/// 開いているハンドシェイク。CloseUi で閉じるように指示されたら closed が返る。
+#[derive(Debug)]
+pub struct UiSession {
+    uis: Arc<Uis>,
+    pub id: String,
+    closing: watch::Receiver<bool>,
+}
+
+impl UiSession {
+    pub async fn closed(&mut self) {
+        let _ = self.closing.wait_for(|closing| *closing).await;
+    }
+}
+
+impl Drop for UiSession {
+    fn drop(&mut self) {
+        self.uis.disconnect(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eng_core::editor::{FileLocation, OpenedFile};
+
+    fn hello(name: &str) -> Hello {
+        Hello {
+            min_protocol_version: 1,
+            protocol_version: 1,
+            kind: ClientKind::Gui,
+            name: name.into(),
+            capabilities: Vec::new(),
+            encodings: Vec::new(),
+            session_id: None,
+        }
+    }
+
+    fn open(paths: &[&str]) -> UiCommand {
+        let files = paths
+            .iter()
+            .map(|path| OpenedFile {
+                location: Some(FileLocation { path: path.to_string(), line: 0, column: 0 }),
+                ..Default::default()
+            })
+            .collect();
+        UiCommand { kind: UiCommandKind::Open.into(), files }
+    }
+
+    #[tokio::test]
+    async fn test_sessions_keep_ui_alive() {
+        let uis = Arc::new(Uis::default());
+        let first = uis.connect(None, &hello("a"));
+        let second = uis.connect(Some(&first.id), &hello("b"));
+        let list = uis.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].sessions, 2);
+        assert_eq!(list[0].client.as_ref().map(|(name, _)| name.as_str()), Some("b"));
+
+        drop(first);
+        assert_eq!(uis.list().len(), 1);
+        let waiting = tokio::spawn({
+            let uis = uis.clone();
+            async move { uis.wait_until_empty().await }
+        });
+        drop(second);
+        tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        assert!(uis.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_commands_before_subscribe_are_delivered() {
+        let uis = Arc::new(Uis::default());
+        // 起動したが、まだ購読していない UI
+        uis.uis().insert("ui-1".into(), Ui::new(Some(1)));
+        uis.send("ui-1", open(&["/tmp/a"])).unwrap();
+        assert_eq!(uis.latest().as_deref(), Some("ui-1"));
+
+        let mut commands = uis.subscribe("ui-1");
+        assert_eq!(commands.recv().await.unwrap().files[0].location.as_ref().unwrap().path, "/tmp/a");
+        uis.focus("ui-1").unwrap();
+        assert_eq!(commands.recv().await.unwrap().kind, i32::from(UiCommandKind::Focus));
+    }
+
+    #[tokio::test]
+    async fn test_send_requires_subscriber() {
+        let uis = Arc::new(Uis::default());
+        let session = uis.connect(None, &hello("cli"));
+        assert!(uis.send("ui-9", open(&[])).unwrap_err().contains("No such UI"));
+        assert!(uis.send(&session.id, open(&[])).unwrap_err().contains("does not accept commands"));
+        assert_eq!(uis.latest(), None);
+    }
+
+    #[tokio::test]
+    async fn test_close_ui() {
+        let uis = Arc::new(Uis::default());
+        let mut session = uis.connect(None, &hello("gui"));
+        let id = session.id.clone();
+        let mut commands = uis.subscribe(&id);
+        assert!(uis.close(&id));
+        assert_eq!(commands.recv().await.unwrap().kind, i32::from(UiCommandKind::Close));
+        tokio::time::timeout(Duration::from_secs(5), session.closed()).await.unwrap();
+
+        // 購読を止め、ハンドシェイクを閉じたら一覧から外れる
+        let gone = tokio::spawn({
+            let (uis, id) = (uis.clone(), id.clone());
+            async move { uis.wait_gone(&id).await }
+        });
+        drop(commands);
+        drop(session);
+        tokio::time::timeout(Duration::from_secs(5), gone).await.unwrap().unwrap();
+        assert!(!uis.close(&id));
+    }
+
+    #[test]
+    fn test_list_in_id_order() {
+        let uis = Uis::default();
+        for id in ["ui-10", "ui-2", "ui-1"] {
+            uis.uis().insert(id.into(), Ui::new(Some(1)));
+        }
+        let ids: Vec<_> = uis.list().into_iter().map(|ui| ui.id).collect();
+        assert_eq!(ids, vec!["ui-1", "ui-2", "ui-10"]);
+        assert_eq!(uis.latest().as_deref(), Some("ui-10"));
+    }
+}
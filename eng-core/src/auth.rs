@@ -1,6 +1,9 @@
 use tonic::{Request, Status};
 use tonic::metadata::MetadataValue;
 use tonic::service::Interceptor;
+use std::sync::Arc;
+
+use crate::tls::{PeerCertificate, Pins};
 
 pub fn validate_auth_token(token: &str) -> bool {
     !token.is_empty()
@@ -8,22 +11,45 @@ pub fn validate_auth_token(token: &str) -> bool {
 
 #[derive(Debug, Clone)]
 pub struct AuthInterceptor {
-    expected_token: MetadataValue<tonic::metadata::Ascii>,
+    /// None なら検査しない
+    expected_token: Option<MetadataValue<tonic::metadata::Ascii>>,
+    /// TLS で待ち受けているとき、ペアリングしたクライアント証明書
+    pins: Option<Arc<Pins>>,
 }
 
 impl AuthInterceptor {
+    #[allow(clippy::result_large_err)]
     pub fn new(token_str: String) -> Result<Self, Status> {
         let expected_token = token_str
             .parse()
             .map_err(|_| Status::internal("Invalid auth token format"))?;
-        Ok(Self { expected_token })
+        Ok(Self { expected_token: Some(expected_token), pins: None })
+    }
+
+    /// トークンを検査しない。標準入出力のパイプのように、接続できること自体が認証になっている場合に使う。
+    pub fn trusted() -> Self {
+        Self { expected_token: None, pins: None }
+    }
+
+    /// トークンの代わりに、クライアント証明書がペアリング済みかを検査する (TLS で待ち受けるとき)
+    pub fn pinned(pins: Arc<Pins>) -> Self {
+        Self { expected_token: None, pins: Some(pins) }
     }
 }
 
 impl Interceptor for AuthInterceptor {
     fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(pins) = &self.pins {
+            match request.extensions().get::<PeerCertificate>() {
+                Some(peer) if pins.contains(&peer.fingerprint) => {}
+                _ => return Err(Status::unauthenticated("Client certificate is not paired")),
+            }
+        }
+        let Some(expected_token) = &self.expected_token else {
+            return Ok(request);
+        };
         match request.metadata().get("authorization") {
-            Some(t) if t == self.expected_token => Ok(request),
+            Some(t) if t == expected_token => Ok(request),
             _ => Err(Status::unauthenticated("No valid auth token")),
         }
     }
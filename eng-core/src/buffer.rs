@@ -1,10 +1,12 @@
 use ropey::Rope;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::ops::Range;
-use std::collections::HashMap;
-use std::sync::Arc;
 use std::path::PathBuf;
-use tokio::sync::RwLock;
-use uuid::Uuid;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::watch;
+
+use crate::mode::{Value, FUNDAMENTAL_MODE};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Encoding {
@@ -17,6 +19,197 @@ pub enum LineEnding {
     CrLf,
 }
 
+/// バッファ一覧 (list-buffers など) に表示する情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BufferInfo {
+    pub id: String,
+    pub name: String,
+    pub path: Option<PathBuf>,
+    pub modified: bool,
+    pub read_only: bool,
+    pub major_mode: String,
+    /// 文字数
+    pub size: usize,
+    pub version: u64,
+}
+
+/// 1回の挿入・削除 (after-change-functions の引数)。start..end が変更後のテキスト。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    pub start: usize,
+    pub end: usize,
+    /// 削除したテキストの長さ
+    pub old_len: usize,
+}
+
+/// 版ごとの変更 (バッファの購読者に送る差分)。直前の版のテキストの start..end を text で置き換えると
+/// version の版になる。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delta {
+    pub version: u64,
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+/// ある版のバッファの内容。Rope の複製は O(1) なので、検索や構文解析などの長い読み取りは
+/// バッファのロックを外してスナップショットに対して行い、入力による編集を待たせない。
+#[derive(Debug, Clone)]
+pub struct BufferSnapshot {
+    text: Rope,
+    pub version: u64,
+    pub name: String,
+    pub path: Option<PathBuf>,
+    pub major_mode: String,
+}
+
+impl BufferSnapshot {
+    /// バッファの外で持っているテキスト (RemoteBuffer の手元のテキスト) のスナップショット
+    pub(crate) fn from_rope(text: Rope, version: u64, name: String, path: Option<PathBuf>, major_mode: String) -> Self {
+        Self { text, version, name, path, major_mode }
+    }
+
+    pub fn rope(&self) -> &Rope {
+        &self.text
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.text.len_chars()
+    }
+
+    pub fn len_lines(&self) -> usize {
+        self.text.len_lines()
+    }
+
+    pub fn text_range(&self, range: Range<usize>) -> Result<String, String> {
+        let char_len = self.text.len_chars();
+        if range.end > char_len || range.start > range.end {
+            return Err(format!("Invalid range: {:?} (len: {})", range, char_len));
+        }
+        Ok(self.text.slice(range).to_string())
+    }
+
+    /// 行の内容 (改行文字を含まない)
+    pub fn line(&self, line_idx: usize) -> Option<String> {
+        if line_idx >= self.text.len_lines() {
+            return None;
+        }
+        let line = self.text.line(line_idx).to_string();
+        let end = line.strip_suffix("\r\n").or_else(|| line.strip_suffix('\n')).map_or(line.len(), str::len);
+        Some(line[..end].to_string())
+    }
+}
+
+impl std::fmt::Display for BufferSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for chunk in self.text.chunks() {
+            f.write_str(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// 取り消しの記録 (buffer-undo-list)。owner は編集したコマンドの実行 (quit::EditLog の ID)。
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum UndoEntry {
+    /// start..end に挿入した
+    Insert { start: usize, end: usize, owner: Option<u64> },
+    /// start から text を削除した
+    Delete { start: usize, text: String, owner: Option<u64> },
+    /// コマンドの区切り。区切りの時点で変更済みだったか。
+    Boundary { modified: bool },
+}
+
+impl UndoEntry {
+    fn owner(&self) -> Option<u64> {
+        match self {
+            UndoEntry::Insert { owner, .. } | UndoEntry::Delete { owner, .. } => *owner,
+            UndoEntry::Boundary { .. } => None,
+        }
+    }
+
+    fn op(&self) -> Option<Op> {
+        match self {
+            UndoEntry::Insert { start, end, .. } => Some(Op::Insert { at: *start, len: end - start }),
+            UndoEntry::Delete { start, text, .. } => Some(Op::Delete { at: *start, len: text.chars().count() }),
+            UndoEntry::Boundary { .. } => None,
+        }
+    }
+
+    /// 前の編集を取り消して位置がずれたときに、記録の位置を更新する
+    fn move_to(&mut self, op: Op) {
+        match (self, op) {
+            (UndoEntry::Insert { start, end, .. }, Op::Insert { at, len }) => (*start, *end) = (at, at + len),
+            (UndoEntry::Delete { start, .. }, Op::Delete { at, .. }) => *start = at,
+            _ => {}
+        }
+    }
+}
+
+/// 位置と長さだけを見た編集。取り消す編集を後の編集の位置に合わせるのに使う。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Insert { at: usize, len: usize },
+    Delete { at: usize, len: usize },
+}
+
+impl Op {
+    fn inverse(self) -> Op {
+        match self {
+            Op::Insert { at, len } => Op::Delete { at, len },
+            Op::Delete { at, len } => Op::Insert { at, len },
+        }
+    }
+}
+
+/// 同じテキストに対する編集 a と b から、b の後に適用する a' と a の後に適用する b' を求める。
+/// 削除した範囲にもう一方の編集が重なっていれば None を返す。
+fn transform(a: Op, b: Op) -> Option<(Op, Op)> {
+    match (a, b) {
+        (Op::Insert { at: p, len: n }, Op::Insert { at: q, len: m }) => {
+            // 同じ位置なら a を先に置く (取り消して戻すテキストを後から挿入された文字の前に戻す)
+            if q < p {
+                Some((Op::Insert { at: p + m, len: n }, b))
+            } else {
+                Some((a, Op::Insert { at: q + n, len: m }))
+            }
+        }
+        (Op::Insert { at: p, len: n }, Op::Delete { at: q, len: l }) => {
+            if p <= q {
+                Some((a, Op::Delete { at: q + n, len: l }))
+            } else if p >= q + l {
+                Some((Op::Insert { at: p - l, len: n }, b))
+            } else {
+                None
+            }
+        }
+        (Op::Delete { at: s, len: l }, Op::Insert { at: q, len: m }) => {
+            if q <= s {
+                Some((Op::Delete { at: s + m, len: l }, b))
+            } else if q >= s + l {
+                Some((a, Op::Insert { at: q - l, len: m }))
+            } else {
+                None
+            }
+        }
+        (Op::Delete { at: s, len: l }, Op::Delete { at: q, len: k }) => {
+            if q + k <= s {
+                Some((Op::Delete { at: s - k, len: l }, b))
+            } else if s + l <= q {
+                Some((a, Op::Delete { at: q - l, len: k }))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// 保持する取り消しの記録の数 (undo-limit)。超えたら古いものから捨てる。
+const UNDO_LIMIT: usize = 10_000;
+
+/// 再接続した購読者に送り直すために保持する差分の数。これより遅れた購読者にはスナップショットを送る。
+pub(crate) const DELTA_LOG_LIMIT: usize = 1_000;
+
 #[derive(Debug, Clone)]
 pub struct Buffer {
     text: Rope,
@@ -27,6 +220,21 @@ pub struct Buffer {
     pub read_only: bool,
     pub encoding: Encoding,
     pub line_ending: LineEnding,
+    pub major_mode: String,
+    /// 有効なマイナーモード。メジャーモードを切り替えても維持する。
+    pub minor_modes: BTreeSet<String>,
+    /// 訪問したファイルを最後に読み書きしたときの更新時刻 (visited-file-modtime)。読み書きしていなければ None
+    pub file_mtime: Option<SystemTime>,
+    /// バッファローカル変数。値が無ければ既定値 (Modes::default_value) を使う。
+    locals: BTreeMap<String, Value>,
+    /// after-change-functions にまだ渡していない変更
+    changes: Vec<Change>,
+    /// 古い順
+    undo_list: Vec<UndoEntry>,
+    /// 直近の差分 (古い順)
+    deltas: VecDeque<Delta>,
+    /// 版が変わるたびに購読者へ知らせる
+    version_tx: Arc<watch::Sender<u64>>,
 }
 
 impl Buffer {
@@ -40,10 +248,22 @@ impl Buffer {
             read_only: false,
             encoding: Encoding::Utf8,
             line_ending: LineEnding::Lf,
+            major_mode: FUNDAMENTAL_MODE.to_string(),
+            minor_modes: BTreeSet::new(),
+            file_mtime: None,
+            locals: BTreeMap::new(),
+            changes: Vec::new(),
+            undo_list: Vec::new(),
+            deltas: VecDeque::new(),
+            version_tx: Arc::new(watch::Sender::new(0)),
         }
     }
 
     pub fn insert(&mut self, char_idx: usize, text: &str) -> Result<(), String> {
+        self.insert_by(char_idx, text, None)
+    }
+
+    fn insert_by(&mut self, char_idx: usize, text: &str, owner: Option<u64>) -> Result<(), String> {
         if self.read_only {
             return Err("Buffer is read-only".into());
         }
@@ -52,12 +272,19 @@ impl Buffer {
              return Err(format!("Index out of bounds: {} > {}", char_idx, char_len));
         }
         self.text.insert(char_idx, text);
-        self.version += 1;
+        self.record_delta(char_idx, char_idx, text.to_string());
         self.modified = true;
+        let end = char_idx + text.chars().count();
+        self.changes.push(Change { start: char_idx, end, old_len: 0 });
+        self.record_undo(UndoEntry::Insert { start: char_idx, end, owner });
         Ok(())
     }
 
     pub fn delete(&mut self, range: Range<usize>) -> Result<(), String> {
+        self.delete_by(range, None)
+    }
+
+    fn delete_by(&mut self, range: Range<usize>, owner: Option<u64>) -> Result<(), String> {
          if self.read_only {
              return Err("Buffer is read-only".into());
          }
@@ -65,26 +292,265 @@ impl Buffer {
          if range.end > char_len || range.start > range.end {
              return Err(format!("Invalid range: {:?} (len: {})", range, char_len));
          }
-         self.text.remove(range);
-         self.version += 1;
+         self.changes.push(Change { start: range.start, end: range.start, old_len: range.len() });
+         let text = self.text.slice(range.clone()).to_string();
+         self.record_undo(UndoEntry::Delete { start: range.start, text, owner });
+         self.text.remove(range.clone());
+         self.record_delta(range.start, range.end, String::new());
          self.modified = true;
          Ok(())
     }
 
+    /// range を text で置き換える
+    pub fn replace(&mut self, range: Range<usize>, text: &str) -> Result<(), String> {
+        self.replace_by(range, text, None)
+    }
+
+    /// owner (実行中のコマンド) の編集として range を text で置き換える。revert_owned で巻き戻せる。
+    pub fn replace_by(&mut self, range: Range<usize>, text: &str, owner: Option<u64>) -> Result<(), String> {
+        let start = range.start;
+        if !range.is_empty() {
+            self.delete_by(range, owner)?;
+        }
+        if !text.is_empty() {
+            self.insert_by(start, text, owner)?;
+        }
+        Ok(())
+    }
+
+    /// 編集のたびに増える版番号
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn snapshot(&self) -> BufferSnapshot {
+        BufferSnapshot {
+            text: self.text.clone(),
+            version: self.version,
+            name: self.name.clone(),
+            path: self.path.clone(),
+            major_mode: self.major_mode.clone(),
+        }
+    }
+
     pub fn set_path(&mut self, path: PathBuf) {
         // パスが設定されたら、名前もファイル名に更新するのが一般的
-        if let Some(file_name) = path.file_name() {
-            if let Some(name_str) = file_name.to_str() {
-                self.name = name_str.to_string();
-            }
+        if let Some(file_name) = path.file_name()
+            && let Some(name_str) = file_name.to_str()
+        {
+            self.name = name_str.to_string();
         }
         self.path = Some(path);
     }
 
+    pub fn info(&self, id: &str) -> BufferInfo {
+        BufferInfo {
+            id: id.to_string(),
+            name: self.name.clone(),
+            path: self.path.clone(),
+            modified: self.modified,
+            read_only: self.read_only,
+            major_mode: self.major_mode.clone(),
+            size: self.len_chars(),
+            version: self.version,
+        }
+    }
+
+    fn record_undo(&mut self, entry: UndoEntry) {
+        if self.undo_list.len() >= UNDO_LIMIT {
+            // コマンドの途中で切らないよう、区切りの位置から後を残す
+            let cut = self.undo_list[UNDO_LIMIT / 10..]
+                .iter()
+                .position(|entry| matches!(entry, UndoEntry::Boundary { .. }))
+                .map_or(UNDO_LIMIT / 10, |i| UNDO_LIMIT / 10 + i);
+            self.undo_list.drain(..cut);
+        }
+        self.undo_list.push(entry);
+    }
+
+    /// コマンドの区切りを記録する (undo-boundary)。直前も区切りなら新しく記録しない。
+    pub fn undo_boundary(&mut self) {
+        let modified = self.modified;
+        match self.undo_list.last_mut() {
+            Some(UndoEntry::Boundary { modified: last }) => *last = modified,
+            _ => self.undo_list.push(UndoEntry::Boundary { modified }),
+        }
+    }
+
+    /// 直前の区切りより後の変更を元に戻す。中断したコマンドの途中までの編集を取り消すのに使う。
+    /// 戻した変更があれば true を返す。
+    pub fn revert_to_boundary(&mut self) -> bool {
+        let mut reverted = false;
+        while let Some(entry) = self.undo_list.pop() {
+            match entry {
+                UndoEntry::Insert { start, end, .. } => {
+                    self.text.remove(start..end);
+                    self.record_delta(start, end, String::new());
+                    self.changes.push(Change { start, end: start, old_len: end - start });
+                }
+                UndoEntry::Delete { start, text, .. } => {
+                    self.text.insert(start, &text);
+                    let end = start + text.chars().count();
+                    self.record_delta(start, start, text);
+                    self.changes.push(Change { start, end, old_len: 0 });
+                }
+                UndoEntry::Boundary { modified } => {
+                    if reverted {
+                        self.modified = modified;
+                    }
+                    // 区切りは次の取り消しのために残す
+                    self.undo_list.push(UndoEntry::Boundary { modified });
+                    return reverted;
+                }
+            }
+            reverted = true;
+        }
+        reverted
+    }
+
+    /// owner の編集だけを新しいものから元に戻す。中断したコマンドの途中までの編集を取り消すのに使う。
+    /// 後から他のコマンドやクライアントが行った編集は残し、その位置に合わせて戻す。他の編集が重なっていて
+    /// 戻せない編集があればそこで止めてエラーを返す。他の編集が無ければ modified (編集を始める前の状態) に戻す。
+    pub fn revert_owned(&mut self, owner: u64, modified: bool) -> Result<bool, String> {
+        let Some(first) = self.undo_list.iter().position(|entry| entry.owner() == Some(owner)) else {
+            return Ok(false);
+        };
+        // 戻した編集より後の他の編集 (古い順)。位置は戻した後のテキストに合わせて更新していく。
+        let mut later: Vec<(usize, Op)> = Vec::new();
+        let mut reverted = Vec::new();
+        let mut result = Ok(());
+        for i in (first..self.undo_list.len()).rev() {
+            let Some(op) = self.undo_list[i].op() else {
+                continue;
+            };
+            if self.undo_list[i].owner() != Some(owner) {
+                later.insert(0, (i, op));
+                continue;
+            }
+            let mut undo = op.inverse();
+            let mut moved = Vec::with_capacity(later.len());
+            for &(_, other) in &later {
+                match transform(undo, other) {
+                    Some((a, b)) => {
+                        undo = a;
+                        moved.push(b);
+                    }
+                    None => break,
+                }
+            }
+            if moved.len() < later.len() {
+                result = Err(format!("Cannot revert {}: a later edit overlaps it", self.name));
+                break;
+            }
+            for (entry, op) in later.iter_mut().zip(moved) {
+                entry.1 = op;
+            }
+            match (undo, &self.undo_list[i]) {
+                (Op::Delete { at, len }, _) => {
+                    self.text.remove(at..at + len);
+                    self.record_delta(at, at + len, String::new());
+                    self.changes.push(Change { start: at, end: at, old_len: len });
+                }
+                (Op::Insert { at, len }, UndoEntry::Delete { text, .. }) => {
+                    let text = text.clone();
+                    self.text.insert(at, &text);
+                    self.record_delta(at, at, text);
+                    self.changes.push(Change { start: at, end: at + len, old_len: 0 });
+                }
+                _ => unreachable!("the inverse of an insertion is a deletion"),
+            }
+            reverted.push(i);
+        }
+        for &(i, op) in &later {
+            self.undo_list[i].move_to(op);
+        }
+        // reverted は新しい順なので、後ろから外せば残りの位置は変わらない
+        for &i in &reverted {
+            self.undo_list.remove(i);
+        }
+        if result.is_ok() && later.is_empty() && !reverted.is_empty() {
+            self.modified = modified;
+        }
+        result.map(|()| !reverted.is_empty())
+    }
+
+    /// テキストを変更した後に呼び、版を進めて差分を記録する
+    fn record_delta(&mut self, start: usize, end: usize, text: String) {
+        self.version += 1;
+        if self.deltas.len() >= DELTA_LOG_LIMIT {
+            self.deltas.pop_front();
+        }
+        self.deltas.push_back(Delta { version: self.version, start, end, text });
+        self.version_tx.send_replace(self.version);
+    }
+
+    /// version より後の差分 (古い順)。保持している差分で足りなければ None を返す。
+    pub fn deltas_since(&self, version: u64) -> Option<Vec<Delta>> {
+        if version > self.version {
+            return None;
+        }
+        if version == self.version {
+            return Some(Vec::new());
+        }
+        let oldest = self.deltas.front()?.version;
+        if oldest > version + 1 {
+            return None;
+        }
+        Some(self.deltas.iter().filter(|d| d.version > version).cloned().collect())
+    }
+
+    /// 版の変化を待つための受信側。バッファが捨てられると閉じる。
+    pub fn watch_version(&self) -> watch::Receiver<u64> {
+        self.version_tx.subscribe()
+    }
+
+    /// 前回から記録した変更を取り出す
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        std::mem::take(&mut self.changes)
+    }
+
+    pub fn local(&self, name: &str) -> Option<&Value> {
+        self.locals.get(name)
+    }
+
+    pub fn locals(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.locals.iter().map(|(name, value)| (name.as_str(), value))
+    }
+
+    pub fn set_local(&mut self, name: &str, value: Value) {
+        self.locals.insert(name.to_string(), value);
+    }
+
+    pub fn kill_local(&mut self, name: &str) -> Option<Value> {
+        self.locals.remove(name)
+    }
+
+    /// メジャーモードの切り替え時にローカル変数を全て既定値へ戻す
+    pub fn kill_all_local_variables(&mut self) {
+        self.locals.clear();
+    }
+
+    pub fn minor_mode_enabled(&self, mode: &str) -> bool {
+        self.minor_modes.contains(mode)
+    }
+
     pub fn len_chars(&self) -> usize {
         self.text.len_chars()
     }
 
+    pub fn char_at(&self, char_idx: usize) -> Option<char> {
+        self.text.get_char(char_idx)
+    }
+
+    /// 範囲の文字列を取り出す
+    pub fn text_range(&self, range: Range<usize>) -> Result<String, String> {
+        let char_len = self.text.len_chars();
+        if range.end > char_len || range.start > range.end {
+            return Err(format!("Invalid range: {:?} (len: {})", range, char_len));
+        }
+        Ok(self.text.slice(range).to_string())
+    }
+
     pub fn len_lines(&self) -> usize {
         self.text.len_lines()
     }
@@ -97,34 +563,20 @@ impl Buffer {
         self.text.line_to_char(line_idx)
     }
 
-    pub fn to_string(&self) -> String {
-        String::from(&self.text)
-    }
-}
-
-#[derive(Debug, Default)]
-pub struct EditorState {
-    buffers: RwLock<HashMap<String, Arc<RwLock<Buffer>>>>,
-}
-
-impl EditorState {
-    pub fn new() -> Self {
-        Self::default()
-    }
-
-    pub async fn create_buffer(&self, name: String, text: &str) -> String {
-        let buffer = Buffer::new(name, text);
-        let id = Uuid::new_v4().to_string();
-        
-        let mut buffers = self.buffers.write().await;
-        buffers.insert(id.clone(), Arc::new(RwLock::new(buffer)));
-        
-        id
+    /// 行の文字数 (改行文字を含まない)
+    pub fn line_len(&self, line_idx: usize) -> usize {
+        let line = self.text.line(line_idx);
+        let len = line.len_chars();
+        match (len.checked_sub(2).map(|i| line.char(i)), len.checked_sub(1).map(|i| line.char(i))) {
+            (Some('\r'), Some('\n')) => len - 2,
+            (_, Some('\n')) => len - 1,
+            _ => len,
+        }
     }
 
-    pub async fn get_buffer(&self, id: &str) -> Option<Arc<RwLock<Buffer>>> {
-        let buffers = self.buffers.read().await;
-        buffers.get(id).cloned()
+    #[allow(clippy::inherent_to_string)]
+    pub fn to_string(&self) -> String {
+        String::from(&self.text)
     }
 }
 
@@ -133,6 +585,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_buffer_properties() {
         let mut buf = Buffer::new("scratch".into(), "Hello");
         assert_eq!(buf.name, "scratch");
@@ -170,6 +623,87 @@ mod tests {
         assert_eq!(buf.to_string(), "World");
     }
 
+    #[test]
+    fn test_revert_to_boundary() {
+        let mut buf = Buffer::new("test".into(), "Hello");
+        buf.insert(5, " World").unwrap();
+        buf.undo_boundary();
+        buf.modified = false;
+        buf.undo_boundary();
+        buf.delete(0..6).unwrap();
+        buf.insert(0, "Goodbye ").unwrap();
+        assert_eq!(buf.to_string(), "Goodbye World");
+
+        assert!(buf.revert_to_boundary());
+        assert_eq!(buf.to_string(), "Hello World");
+        assert!(!buf.modified);
+        assert!(!buf.revert_to_boundary());
+    }
+
+    #[test]
+    fn test_revert_owned_keeps_other_edits() {
+        let mut buf = Buffer::new("test".into(), "Hello World");
+        buf.replace_by(5..5, ",", Some(1)).unwrap();
+        buf.insert(0, ">> ").unwrap();
+        buf.replace_by(10..15, "Rust", Some(1)).unwrap();
+        buf.insert(14, "!").unwrap();
+        assert_eq!(buf.to_string(), ">> Hello, Rust!");
+
+        assert_eq!(buf.revert_owned(1, false), Ok(true));
+        assert_eq!(buf.to_string(), ">> Hello World!");
+        assert!(buf.modified);
+        assert_eq!(buf.revert_owned(1, false), Ok(false));
+        // 残した編集の記録は位置を合わせてあるので、続けて取り消せる
+        assert!(buf.revert_to_boundary());
+        assert_eq!(buf.to_string(), "Hello World");
+
+        // 他の編集が消した範囲に挿入した編集は戻せない
+        let mut buf = Buffer::new("test".into(), "abc");
+        buf.replace_by(1..1, "x", Some(2)).unwrap();
+        buf.delete(0..3).unwrap();
+        assert!(buf.revert_owned(2, false).is_err());
+        assert_eq!(buf.to_string(), "c");
+    }
+
+    #[test]
+    fn test_undo_limit_keeps_boundary() {
+        let mut buf = Buffer::new("test".into(), "");
+        for i in 0..UNDO_LIMIT {
+            if i == UNDO_LIMIT / 10 + 5 {
+                buf.undo_boundary();
+            } else {
+                buf.insert(0, "x").unwrap();
+            }
+        }
+        buf.insert(0, "x").unwrap();
+        assert_eq!(buf.undo_list[0], UndoEntry::Boundary { modified: true });
+        assert_eq!(buf.undo_list.len(), UNDO_LIMIT - UNDO_LIMIT / 10 - 4);
+    }
+
+    #[test]
+    fn test_deltas_since() {
+        let mut buf = Buffer::new("test".into(), "Hello");
+        buf.insert(5, " World").unwrap();
+        buf.replace(0..5, "Goodbye").unwrap();
+        assert_eq!(buf.version(), 3);
+        assert_eq!(buf.deltas_since(3), Some(vec![]));
+        assert_eq!(
+            buf.deltas_since(1),
+            Some(vec![
+                Delta { version: 2, start: 0, end: 5, text: String::new() },
+                Delta { version: 3, start: 0, end: 0, text: "Goodbye".into() },
+            ])
+        );
+        assert_eq!(buf.deltas_since(4), None);
+
+        for _ in 0..DELTA_LOG_LIMIT {
+            buf.insert(0, "x").unwrap();
+        }
+        assert_eq!(buf.deltas_since(2), None);
+        assert_eq!(buf.deltas_since(3).map(|d| d.len()), Some(DELTA_LOG_LIMIT));
+        assert_eq!(*buf.watch_version().borrow(), buf.version());
+    }
+
     #[test]
     fn test_lines() {
         let buf = Buffer::new("lines".into(), "One\nTwo\nThree");
@@ -177,14 +711,12 @@ mod tests {
         assert_eq!(buf.char_to_line(4), 1); 
     }
 
-    #[tokio::test]
-    async fn test_editor_state() {
-        let state = EditorState::new();
-        let id = state.create_buffer("test.txt".into(), "Content").await;
-        
-        let buf_arc = state.get_buffer(&id).await.expect("Buffer should exist");
-        let buf = buf_arc.read().await;
-        assert_eq!(buf.name, "test.txt");
-        assert_eq!(buf.to_string(), "Content");
+    #[test]
+    fn test_line_len() {
+        let buf = Buffer::new("lines".into(), "One\r\nTwo!\n\nFour");
+        assert_eq!(buf.line_len(0), 3);
+        assert_eq!(buf.line_len(1), 4);
+        assert_eq!(buf.line_len(2), 0);
+        assert_eq!(buf.line_len(3), 4);
     }
 }
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use tokio::sync::RwLock;
+
+use crate::buffer::{Buffer, BufferInfo};
+use crate::command::{CommandContext, CommandRegistry, CommandResult};
+use crate::state::EditorState;
+
+pub const BUFFER_LIST: &str = "*Buffer List*";
+pub const BUFFER_MENU_MODE: &str = "Buffer-menu-mode";
+
+/// 削除の印 (Buffer-menu-delete)
+const DELETE_MARK: char = 'D';
+const NAME_WIDTH: usize = 20;
+const MODE_WIDTH: usize = 16;
+/// 見出しの行数。以降の行が1行に1バッファ。
+const HEADER_LINES: usize = 1;
+
+/// 一覧の1行。先頭の4桁は 印・表示中 (.)・読み取り専用 (%)・変更あり (*)。
+fn format_line(flags: &str, name: &str, size: &str, mode: &str, file: &str) -> String {
+    let line = format!("{} {:<NAME_WIDTH$} {:>7} {:<MODE_WIDTH$} {}", flags, name, size, mode, file);
+    line.trim_end().to_string()
+}
+
+/// *Buffer List* の内容。flags はバッファIDごとの (印, 表示中か)。
+pub fn render(infos: &[BufferInfo], flags: &HashMap<String, (char, bool)>) -> String {
+    let mut text = format_line(" CRM", "Buffer", "Size", "Mode", "File");
+    text.push('\n');
+    for info in infos {
+        let (mark, current) = flags.get(&info.id).copied().unwrap_or((' ', false));
+        let flags: String = [
+            mark,
+            if current { '.' } else { ' ' },
+            if info.read_only { '%' } else { ' ' },
+            if info.modified { '*' } else { ' ' },
+        ]
+        .into_iter()
+        .collect();
+        let file = info.path.as_ref().map(|p| p.display().to_string()).unwrap_or_default();
+        text.push_str(&format_line(&flags, &info.name, &info.size.to_string(), &info.major_mode, &file));
+        text.push('\n');
+    }
+    text
+}
+
+/// 読み取り専用のバッファの内容を置き換える
+async fn set_contents(buffer: &RwLock<Buffer>, text: &str) -> Result<(), String> {
+    let mut buffer = buffer.write().await;
+    buffer.read_only = false;
+    let len = buffer.len_chars();
+    let result = buffer.delete(0..len).and_then(|_| buffer.insert(0, text));
+    buffer.read_only = true;
+    buffer.modified = false;
+    result
+}
+
+/// 現在表示している各行の (バッファID, 印, 表示中か)
+async fn entries(state: &EditorState, menu_id: &str) -> Result<Vec<(String, char, bool)>, String> {
+    let ids = state.buffer_menus().lock().await.get(menu_id).cloned().unwrap_or_default();
+    let buffer = state.get_buffer(menu_id).await.ok_or_else(|| format!("No such buffer: {}", menu_id))?;
+    let buffer = buffer.read().await;
+    Ok(ids
+        .into_iter()
+        .enumerate()
+        .map(|(i, id)| {
+            let start = buffer.line_to_char(i + HEADER_LINES);
+            let mark = buffer.char_at(start).unwrap_or(' ');
+            let current = buffer.char_at(start + 1) == Some('.');
+            (id, mark, current)
+        })
+        .collect())
+}
+
+/// 一覧を作り直す。付けた印と表示中の印は残す。
+async fn refresh(state: &EditorState, menu_id: &str, current: Option<&str>) -> Result<(), String> {
+    let mut flags: HashMap<_, _> = entries(state, menu_id)
+        .await?
+        .into_iter()
+        .map(|(id, mark, was_current)| (id, (mark, was_current && current.is_none())))
+        .collect();
+    if let Some(current) = current {
+        flags.entry(current.to_string()).or_insert((' ', false)).1 = true;
+    }
+    let infos = state.buffer_list().await;
+    let text = render(&infos, &flags);
+    let buffer = state.get_buffer(menu_id).await.ok_or_else(|| format!("No such buffer: {}", menu_id))?;
+    set_contents(&buffer, &text).await?;
+    let ids = infos.into_iter().map(|info| info.id).collect();
+    state.buffer_menus().lock().await.insert(menu_id.to_string(), ids);
+    Ok(())
+}
+
+pub(crate) fn register_commands(r: &mut CommandRegistry) -> Result<(), String> {
+    r.register("list-buffers", "Display a list of existing buffers.", "", list_buffers)?;
+    r.register("Buffer-menu-this-window", "Select this line's buffer in this window.", "", buffer_menu_this_window)?;
+    r.register("Buffer-menu-delete", "Mark the buffer on this line to be deleted by x.", "p", buffer_menu_delete)?;
+    r.register("Buffer-menu-unmark", "Cancel all requested operations on the buffer on this line.", "p", buffer_menu_unmark)?;
+    r.register("Buffer-menu-execute", "Delete buffers marked with D.", "", buffer_menu_execute)?;
+    r.register("Buffer-menu-revert", "Update the list of buffers.", "", buffer_menu_revert)?;
+    Ok(())
+}
+
+/// C-x C-b。選択中のViewに *Buffer List* を表示する。
+async fn list_buffers(ctx: CommandContext) -> CommandResult {
+    let current = ctx.selected_view().await?.buffer_id;
+    let menu_id = match ctx.state.find_buffer(BUFFER_LIST).await {
+        Some(id) => id,
+        None => {
+            let id = ctx.state.create_buffer(BUFFER_LIST.into(), "").await;
+            ctx.state.set_major_mode(&id, BUFFER_MENU_MODE).await?;
+            if let Some(buffer) = ctx.state.get_buffer(&id).await {
+                buffer.write().await.read_only = true;
+            }
+            id
+        }
+    };
+    refresh(&ctx.state, &menu_id, Some(&current)).await?;
+    ctx.state.switch_to_buffer(&ctx.window_id, &menu_id).await?;
+    ctx.set_point(0).await?;
+    Ok(None)
+}
+
+/// point の行に表示しているバッファの、一覧の中での位置
+async fn entry_at_point(ctx: &CommandContext) -> Result<(String, usize), String> {
+    let view = ctx.selected_view().await?;
+    let buffer = ctx.current_buffer().await?;
+    let line = buffer.read().await.char_to_line(view.point);
+    let ids = ctx.state.buffer_menus().lock().await.get(&view.buffer_id).cloned();
+    let ids = ids.ok_or("Not in a buffer menu")?;
+    line.checked_sub(HEADER_LINES)
+        .filter(|&i| i < ids.len())
+        .map(|i| (view.buffer_id, i))
+        .ok_or_else(|| "No buffer on this line".into())
+}
+
+async fn buffer_menu_this_window(ctx: CommandContext) -> CommandResult {
+    let (menu_id, index) = entry_at_point(&ctx).await?;
+    let (buffer_id, _, _) = entries(&ctx.state, &menu_id).await?.swap_remove(index);
+    if ctx.state.get_buffer(&buffer_id).await.is_none() {
+        return Err("This buffer has been killed".into());
+    }
+    ctx.state.switch_to_buffer(&ctx.window_id, &buffer_id).await?;
+    Ok(None)
+}
+
+/// point から n 行に印を付け、その次の行へ移る
+async fn set_marks(ctx: &CommandContext, mark: char, n: i64) -> CommandResult {
+    for _ in 0..n.max(0) {
+        let (menu_id, index) = entry_at_point(ctx).await?;
+        let buffer = ctx.state.get_buffer(&menu_id).await.ok_or("Not in a buffer menu")?;
+        let next = {
+            let mut buffer = buffer.write().await;
+            let line = index + HEADER_LINES;
+            let start = buffer.line_to_char(line);
+            buffer.read_only = false;
+            let result = buffer.delete(start..start + 1).and_then(|_| buffer.insert(start, &mark.to_string()));
+            buffer.read_only = true;
+            buffer.modified = false;
+            result?;
+            buffer.line_to_char((line + 1).min(buffer.len_lines() - 1))
+        };
+        ctx.set_point(next).await?;
+    }
+    Ok(None)
+}
+
+async fn buffer_menu_delete(ctx: CommandContext) -> CommandResult {
+    set_marks(&ctx, DELETE_MARK, ctx.number_arg(0)).await
+}
+
+async fn buffer_menu_unmark(ctx: CommandContext) -> CommandResult {
+    set_marks(&ctx, ' ', ctx.number_arg(0)).await
+}
+
+/// x。D の印を付けたバッファを削除する。未保存のファイルのバッファは1つずつ確認する。
+async fn buffer_menu_execute(ctx: CommandContext) -> CommandResult {
+    let menu_id = ctx.selected_view().await?.buffer_id;
+    let marked: Vec<_> = entries(&ctx.state, &menu_id)
+        .await?
+        .into_iter()
+        .filter(|(id, mark, _)| *mark == DELETE_MARK && *id != menu_id)
+        .map(|(id, _, _)| id)
+        .collect();
+
+    // 確認を全て終えてから削除する (答えるたびにコマンドが最初から再実行されるため)
+    let mut targets = Vec::new();
+    let mut questions = 0;
+    for id in marked {
+        let Some(buffer) = ctx.state.get_buffer(&id).await else {
+            continue;
+        };
+        let (name, unsaved) = {
+            let buffer = buffer.read().await;
+            (buffer.name.clone(), buffer.modified && buffer.path.is_some())
+        };
+        if unsaved {
+            let kill = ctx.yes_or_no_p(questions, &format!("Buffer {} modified; kill anyway? ", name))?;
+            questions += 1;
+            if !kill {
+                continue;
+            }
+        }
+        targets.push(id);
+    }
+    for id in &targets {
+        ctx.state.kill_buffer(id).await?;
+    }
+    refresh(&ctx.state, &menu_id, None).await?;
+    Ok(Some(format!("Killed {} buffer(s)", targets.len())))
+}
+
+async fn buffer_menu_revert(ctx: CommandContext) -> CommandResult {
+    let menu_id = ctx.selected_view().await?.buffer_id;
+    refresh(&ctx.state, &menu_id, None).await?;
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{execute_command, CommandError, CommandInvocation};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    struct Fixture {
+        state: Arc<EditorState>,
+        window_id: String,
+    }
+
+    impl Fixture {
+        async fn new() -> Self {
+            let state = Arc::new(EditorState::new());
+            let buffer_id = state.create_buffer("notes".into(), "").await;
+            let window_id = state.create_window(&buffer_id).await.unwrap();
+            Self { state, window_id }
+        }
+
+        async fn run(&self, name: &str, args: &[&str]) -> CommandResult {
+            let invocation = CommandInvocation { args: args.iter().map(|a| a.to_string()).collect(), ..Default::default() };
+            execute_command(self.state.clone(), &self.window_id, name, invocation).await
+        }
+
+        async fn current_text(&self) -> String {
+            let view = self.state.read_window(&self.window_id, |w| w.selected_view().clone()).await.unwrap();
+            let buffer = self.state.get_buffer(&view.buffer_id).await.unwrap();
+            buffer.read().await.to_string()
+        }
+
+        async fn goto_line(&self, line: usize) {
+            let view = self.state.read_window(&self.window_id, |w| w.selected_view().clone()).await.unwrap();
+            let buffer = self.state.get_buffer(&view.buffer_id).await.unwrap();
+            let point = buffer.read().await.line_to_char(line);
+            self.state
+                .update_window(&self.window_id, |w| {
+                    w.selected_view_mut().point = point;
+                    Ok(())
+                })
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_buffers() {
+        let f = Fixture::new().await;
+        let file_id = f.state.create_buffer("main".into(), "fn main() {}").await;
+        f.state.set_buffer_file(&file_id, PathBuf::from("/work/src/main.rs")).await.unwrap();
+
+        f.run("list-buffers", &[]).await.unwrap();
+        let text = f.current_text().await;
+        let lines: Vec<_> = text.lines().collect();
+        assert!(lines[0].starts_with(" CRM Buffer"));
+        // 直前に表示していたバッファが先頭に "." 付きで並ぶ
+        assert!(lines[1].starts_with(" .   notes"));
+        assert!(lines.iter().any(|l| l.contains("main.rs") && l.contains("rust-mode") && l.ends_with("/work/src/main.rs")));
+        assert!(lines.iter().any(|l| l.starts_with("  %  *Buffer List*")));
+
+        // RET で行のバッファへ切り替える
+        f.goto_line(1).await;
+        f.run("Buffer-menu-this-window", &[]).await.unwrap();
+        assert_eq!(f.current_text().await, "");
+    }
+
+    #[tokio::test]
+    async fn test_mark_and_execute() {
+        let f = Fixture::new().await;
+        let scratch = f.state.create_buffer("scratch".into(), "").await;
+        let file_id = f.state.create_buffer("lib".into(), "").await;
+        f.state.set_buffer_file(&file_id, PathBuf::from("/work/lib.rs")).await.unwrap();
+        f.state.get_buffer(&file_id).await.unwrap().write().await.insert(0, "x").unwrap();
+
+        f.run("list-buffers", &[]).await.unwrap();
+        let lines: Vec<String> = f.current_text().await.lines().map(str::to_string).collect();
+        let line_of = |name: &str| lines.iter().position(|l| l[5..].starts_with(name)).unwrap();
+
+        f.goto_line(line_of("scratch")).await;
+        f.run("Buffer-menu-delete", &[]).await.unwrap();
+        f.goto_line(line_of("lib.rs")).await;
+        f.run("Buffer-menu-delete", &[]).await.unwrap();
+        assert!(f.current_text().await.lines().nth(line_of("scratch")).unwrap().starts_with('D'));
+
+        // 未保存のファイルのバッファは確認する
+        let result = f.run("Buffer-menu-execute", &[]).await;
+        assert!(matches!(result, Err(CommandError::MissingArgument(r)) if r.prompt == "Buffer lib.rs modified; kill anyway? (yes or no) "));
+        f.run("Buffer-menu-execute", &["no"]).await.unwrap();
+        assert!(f.state.get_buffer(&scratch).await.is_none());
+        assert!(f.state.get_buffer(&file_id).await.is_some());
+        assert!(!f.current_text().await.contains("scratch"));
+
+        f.run("Buffer-menu-execute", &["yes"]).await.unwrap();
+        assert!(f.state.get_buffer(&file_id).await.is_none());
+    }
+}
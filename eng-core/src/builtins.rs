@@ -0,0 +1,725 @@
+use crate::buffer::Buffer;
+use crate::buffer_menu;
+use crate::command::{execute_command, CommandArg, CommandError, CommandContext, CommandInvocation, CommandRegistry, CommandResult, PrefixArg};
+use crate::command_loop::{self, digit_of};
+use crate::desktop;
+use crate::hook;
+use crate::timer;
+use crate::kmacro;
+use crate::minibuffer;
+use crate::layout::SplitDirection;
+
+/// 組み込みコマンドを登録したレジストリを作成する
+pub fn registry() -> CommandRegistry {
+    let mut registry = CommandRegistry::new();
+    register_builtins(&mut registry).expect("builtin interactive specs must be valid");
+    registry
+}
+
+fn register_builtins(r: &mut CommandRegistry) -> Result<(), String> {
+    // カーソル移動
+    r.register("forward-char", "Move point N characters forward.", "p", forward_char)?;
+    r.register("backward-char", "Move point N characters backward.", "p", backward_char)?;
+    r.register("next-line", "Move cursor vertically down N lines.", "p", next_line)?;
+    r.register("previous-line", "Move cursor vertically up N lines.", "p", previous_line)?;
+    r.register("move-beginning-of-line", "Move point to beginning of current line.", "", move_beginning_of_line)?;
+    r.register("move-end-of-line", "Move point to end of current line.", "", move_end_of_line)?;
+    r.register("beginning-of-buffer", "Move point to the beginning of the buffer.", "", beginning_of_buffer)?;
+    r.register("end-of-buffer", "Move point to the end of the buffer.", "", end_of_buffer)?;
+    r.register("forward-word", "Move point forward N words.", "p", forward_word)?;
+    r.register("backward-word", "Move backward until encountering the beginning of a word.", "p", backward_word)?;
+
+    // 編集
+    r.register("self-insert-command", "Insert the character you type.", "p", self_insert_command)?;
+    r.register("newline", "Insert N newlines at point.", "p", newline)?;
+    r.register("delete-char", "Delete the following N characters.", "p", delete_char)?;
+    r.register("delete-backward-char", "Delete the previous N characters.", "p", delete_backward_char)?;
+    r.register("delete-region", "Delete the text between point and mark.", "r", delete_region)?;
+    r.register("kill-word", "Kill characters forward until encountering the end of a word.", "p", kill_word)?;
+    r.register("backward-kill-word", "Kill characters backward until encountering the beginning of a word.", "p", backward_kill_word)?;
+
+    // マーク
+    r.register("set-mark-command", "Set the mark where point is.", "", set_mark_command)?;
+    r.register("exchange-point-and-mark", "Put the mark where point is now, and point where the mark is now.", "", exchange_point_and_mark)?;
+
+    // View操作
+    r.register("split-window-below", "Split the selected view into two views, one above the other.", "", split_window_below)?;
+    r.register("split-window-right", "Split the selected view into two side-by-side views.", "", split_window_right)?;
+    r.register("delete-window", "Delete the selected view.", "", delete_window)?;
+    r.register("delete-other-windows", "Make the selected view fill its window.", "", delete_other_windows)?;
+    r.register("other-window", "Select another view in cyclic ordering of views.", "p", other_window)?;
+    r.register("balance-windows", "Balance the sizes of views of the selected window.", "", balance_windows)?;
+    r.register("enlarge-window", "Make the selected view N lines taller.", "p", enlarge_window)?;
+    r.register("shrink-window", "Make the selected view N lines smaller.", "p", shrink_window)?;
+    r.register("enlarge-window-horizontally", "Make the selected view N columns wider.", "p", enlarge_window_horizontally)?;
+    r.register("shrink-window-horizontally", "Make the selected view N columns narrower.", "p", shrink_window_horizontally)?;
+
+    // ファイル
+    r.register("find-file", "Edit file FILENAME.", "sFind file: ", find_file)?;
+    r.register("save-buffer", "Save current buffer in visited file if modified.", "", save_buffer)?;
+
+    // バッファ
+    r.register("switch-to-buffer", "Display buffer BUFFER-OR-NAME in the selected view.", "bSwitch to buffer: ", switch_to_buffer)?;
+    r.register("kill-buffer", "Kill the buffer specified by BUFFER-OR-NAME.", "bKill buffer: ", kill_buffer)?;
+    r.register("rename-buffer", "Change current buffer's name to NEWNAME (a string).", "sRename buffer (to new name): \nP", rename_buffer)?;
+    r.register("quit-window", "Quit the selected view and bury its buffer.", "", quit_window)?;
+
+    // 前置引数と繰り返し
+    r.register("universal-argument", "Begin a numeric argument for the following command.", "", universal_argument)?;
+    r.register("digit-argument", "Part of the numeric argument for the next command.", "", digit_argument)?;
+    r.register("negative-argument", "Begin a negative numeric argument for the next command.", "", negative_argument)?;
+    r.register("repeat", "Repeat most recently executed command.", "", repeat)?;
+
+    r.register("execute-extended-command", "Read a command name, then call it.", "P\nCM-x ", execute_extended_command)?;
+    r.register("keyboard-quit", "Signal a quit condition.", "", keyboard_quit)?;
+    r.register("describe-key", "Display documentation of the function invoked by KEY.", "kDescribe key: ", describe_key)?;
+
+    kmacro::register_commands(r)?;
+    minibuffer::register_commands(r)?;
+    buffer_menu::register_commands(r)?;
+    desktop::register_commands(r)?;
+    hook::register_commands(r)?;
+    timer::register_commands(r)
+
+}
+
+/// point を f で計算した位置へ移動する。範囲外なら端で止めてエラーにする。
+async fn move_point<F>(ctx: &CommandContext, f: F) -> CommandResult
+where
+    F: FnOnce(&Buffer, usize) -> i64,
+{
+    let view = ctx.selected_view().await?;
+    let buffer = ctx.current_buffer().await?;
+    let (target, len) = {
+        let buffer = buffer.read().await;
+        let point = view.point.min(buffer.len_chars());
+        (f(&buffer, point), buffer.len_chars())
+    };
+    let clamped = target.clamp(0, len as i64) as usize;
+    ctx.set_point(clamped).await?;
+    if target < 0 {
+        Err("Beginning of buffer".into())
+    } else if target > len as i64 {
+        Err("End of buffer".into())
+    } else {
+        Ok(None)
+    }
+}
+
+/// 行を n 行移動した位置。桁は移動先の行の長さに収める。
+fn line_move_target(buffer: &Buffer, point: usize, n: i64) -> i64 {
+    let line = buffer.char_to_line(point);
+    let column = point - buffer.line_to_char(line);
+    let target = line as i64 + n;
+    let last = buffer.len_lines() as i64 - 1;
+    if target < 0 {
+        return -1;
+    }
+    if target > last {
+        return buffer.len_chars() as i64 + 1;
+    }
+    let target = target as usize;
+    (buffer.line_to_char(target) + column.min(buffer.line_len(target))) as i64
+}
+
+async fn forward_char(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    move_point(&ctx, |_, point| point as i64 + n).await
+}
+
+async fn backward_char(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    move_point(&ctx, |_, point| point as i64 - n).await
+}
+
+async fn next_line(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    move_point(&ctx, |buffer, point| line_move_target(buffer, point, n)).await
+}
+
+async fn previous_line(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    move_point(&ctx, |buffer, point| line_move_target(buffer, point, -n)).await
+}
+
+async fn move_beginning_of_line(ctx: CommandContext) -> CommandResult {
+    move_point(&ctx, |buffer, point| buffer.line_to_char(buffer.char_to_line(point)) as i64).await
+}
+
+async fn move_end_of_line(ctx: CommandContext) -> CommandResult {
+    move_point(&ctx, |buffer, point| {
+        let line = buffer.char_to_line(point);
+        (buffer.line_to_char(line) + buffer.line_len(line)) as i64
+    })
+    .await
+}
+
+async fn beginning_of_buffer(ctx: CommandContext) -> CommandResult {
+    move_point(&ctx, |_, _| 0).await
+}
+
+async fn end_of_buffer(ctx: CommandContext) -> CommandResult {
+    move_point(&ctx, |buffer, _| buffer.len_chars() as i64).await
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric()
+}
+
+/// point から n 単語 (負なら後方へ) 移動した位置。バッファの端で止まる。
+fn word_target(buffer: &Buffer, point: usize, n: i64) -> usize {
+    let len = buffer.len_chars();
+    let mut pos = point;
+    for _ in 0..n.unsigned_abs() {
+        if n > 0 {
+            while pos < len && !buffer.char_at(pos).is_some_and(is_word_char) {
+                pos += 1;
+            }
+            while pos < len && buffer.char_at(pos).is_some_and(is_word_char) {
+                pos += 1;
+            }
+        } else {
+            while pos > 0 && !buffer.char_at(pos - 1).is_some_and(is_word_char) {
+                pos -= 1;
+            }
+            while pos > 0 && buffer.char_at(pos - 1).is_some_and(is_word_char) {
+                pos -= 1;
+            }
+        }
+    }
+    pos
+}
+
+async fn forward_word(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    move_point(&ctx, |buffer, point| word_target(buffer, point, n) as i64).await
+}
+
+async fn backward_word(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    move_point(&ctx, |buffer, point| word_target(buffer, point, -n) as i64).await
+}
+
+/// 起動したキーの文字を N 回挿入する。overwrite-mode では行末までの文字を置き換える。
+async fn self_insert_command(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    if n < 0 {
+        return Err("Negative repetition argument".into());
+    }
+    let c = ctx
+        .keys
+        .last()
+        .and_then(|key| key.printable_char())
+        .ok_or("self-insert-command must be bound to a printable character")?;
+
+    let view = ctx.selected_view().await?;
+    let buffer = ctx.current_buffer().await?;
+    let overwritten = {
+        let buffer = buffer.read().await;
+        buffer.minor_mode_enabled("overwrite-mode").then(|| {
+            let point = view.point.min(buffer.len_chars());
+            let line = buffer.char_to_line(point);
+            let eol = buffer.line_to_char(line) + buffer.line_len(line);
+            point..(point + n as usize).min(eol)
+        })
+    };
+    if let Some(range) = overwritten {
+        ctx.edit(&view.buffer_id, range, "").await?;
+    }
+    insert_at_point(&ctx, &c.to_string().repeat(n as usize)).await
+}
+
+/// point に文字列を挿入し、point を挿入した文字列の後ろへ進める
+pub(crate) async fn insert_at_point(ctx: &CommandContext, text: &str) -> CommandResult {
+    let view = ctx.selected_view().await?;
+    let buffer = ctx.current_buffer().await?;
+    let point = view.point.min(buffer.read().await.len_chars());
+    ctx.edit(&view.buffer_id, point..point, text).await?;
+    ctx.set_point(point + text.chars().count()).await?;
+    Ok(None)
+}
+
+async fn newline(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    if n < 0 {
+        return Err("Repetition argument has to be non-negative".into());
+    }
+    insert_at_point(&ctx, &"\n".repeat(n as usize)).await
+}
+
+async fn delete_char(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    delete_chars(&ctx, n).await
+}
+
+async fn delete_backward_char(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    delete_chars(&ctx, -n).await
+}
+
+/// point から n 文字 (負なら後方へ) 削除する
+async fn delete_chars(ctx: &CommandContext, n: i64) -> CommandResult {
+    let view = ctx.selected_view().await?;
+    let buffer = ctx.current_buffer().await?;
+    let range = {
+        let buffer = buffer.read().await;
+        let point = view.point.min(buffer.len_chars()) as i64;
+        let other = point + n;
+        if other < 0 {
+            return Err("Beginning of buffer".into());
+        }
+        if other > buffer.len_chars() as i64 {
+            return Err("End of buffer".into());
+        }
+        point.min(other) as usize..point.max(other) as usize
+    };
+    ctx.edit(&view.buffer_id, range.clone(), "").await?;
+    ctx.set_point(range.start).await?;
+    Ok(None)
+}
+
+/// point から n 単語分を削除する (キルリングはまだ無いので削除のみ)
+async fn kill_words(ctx: &CommandContext, n: i64) -> CommandResult {
+    let view = ctx.selected_view().await?;
+    let buffer = ctx.current_buffer().await?;
+    let distance = {
+        let buffer = buffer.read().await;
+        let point = view.point.min(buffer.len_chars());
+        word_target(&buffer, point, n) as i64 - point as i64
+    };
+    delete_chars(ctx, distance).await
+}
+
+async fn kill_word(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    kill_words(&ctx, n).await
+}
+
+async fn backward_kill_word(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    kill_words(&ctx, -n).await
+}
+
+async fn delete_region(ctx: CommandContext) -> CommandResult {
+    let region = ctx.region_arg(0)?;
+    let view = ctx.selected_view().await?;
+    ctx.edit(&view.buffer_id, region.clone(), "").await?;
+    ctx.update_window(|w| {
+        let view = w.selected_view_mut();
+        view.point = region.start;
+        view.mark = None;
+        Ok(())
+    })
+    .await?;
+    Ok(None)
+}
+
+async fn set_mark_command(ctx: CommandContext) -> CommandResult {
+    ctx.update_window(|w| {
+        let view = w.selected_view_mut();
+        view.mark = Some(view.point);
+        Ok(())
+    })
+    .await?;
+    Ok(Some("Mark set".into()))
+}
+
+async fn exchange_point_and_mark(ctx: CommandContext) -> CommandResult {
+    ctx.update_window(|w| {
+        let view = w.selected_view_mut();
+        let mark = view.mark.ok_or("No mark set in this buffer")?;
+        view.mark = Some(view.point);
+        view.point = mark;
+        Ok(())
+    })
+    .await?;
+    Ok(None)
+}
+
+async fn split_window(ctx: &CommandContext, direction: SplitDirection) -> CommandResult {
+    ctx.update_window(|w| {
+        let selected = w.selected_view().id.clone();
+        w.split_view(&selected, direction)
+    })
+    .await?;
+    Ok(None)
+}
+
+async fn split_window_below(ctx: CommandContext) -> CommandResult {
+    split_window(&ctx, SplitDirection::Below).await
+}
+
+async fn split_window_right(ctx: CommandContext) -> CommandResult {
+    split_window(&ctx, SplitDirection::Right).await
+}
+
+async fn delete_window(ctx: CommandContext) -> CommandResult {
+    ctx.update_window(|w| {
+        let selected = w.selected_view().id.clone();
+        w.delete_view(&selected)
+    })
+    .await?;
+    Ok(None)
+}
+
+async fn delete_other_windows(ctx: CommandContext) -> CommandResult {
+    ctx.update_window(|w| {
+        let selected = w.selected_view().id.clone();
+        w.delete_other_views(&selected)
+    })
+    .await?;
+    Ok(None)
+}
+
+async fn other_window(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    ctx.update_window(|w| {
+        w.other_view(n as isize);
+        Ok(())
+    })
+    .await?;
+    Ok(None)
+}
+
+async fn balance_windows(ctx: CommandContext) -> CommandResult {
+    ctx.update_window(|w| {
+        w.balance();
+        Ok(())
+    })
+    .await?;
+    Ok(None)
+}
+
+async fn resize_window(ctx: &CommandContext, direction: SplitDirection, delta: i64) -> CommandResult {
+    ctx.update_window(|w| {
+        let selected = w.selected_view().id.clone();
+        w.resize_view(&selected, direction, delta as i32)
+    })
+    .await?;
+    Ok(None)
+}
+
+async fn enlarge_window(ctx: CommandContext) -> CommandResult {
+    resize_window(&ctx, SplitDirection::Below, ctx.number_arg(0)).await
+}
+
+async fn shrink_window(ctx: CommandContext) -> CommandResult {
+    resize_window(&ctx, SplitDirection::Below, -ctx.number_arg(0)).await
+}
+
+async fn enlarge_window_horizontally(ctx: CommandContext) -> CommandResult {
+    resize_window(&ctx, SplitDirection::Right, ctx.number_arg(0)).await
+}
+
+async fn shrink_window_horizontally(ctx: CommandContext) -> CommandResult {
+    resize_window(&ctx, SplitDirection::Right, -ctx.number_arg(0)).await
+}
+
+/// C-x C-f。開いていなければファイルを読み込み、選択中のViewに表示する。
+async fn find_file(ctx: CommandContext) -> CommandResult {
+    let path = std::path::Path::new(ctx.string_arg(0)?.trim());
+    let buffer_id = ctx.state.find_file(path).await?;
+    ctx.state.switch_to_buffer(&ctx.window_id, &buffer_id).await?;
+    Ok(None)
+}
+
+/// C-x C-s
+async fn save_buffer(ctx: CommandContext) -> CommandResult {
+    let view = ctx.selected_view().await?;
+    let buffer = ctx.state.get_buffer(&view.buffer_id).await.ok_or("No such buffer")?;
+    if !buffer.read().await.modified {
+        return Ok(Some("(No changes need to be saved)".into()));
+    }
+    let path = ctx.state.save_buffer(&view.buffer_id).await?;
+    Ok(Some(format!("Wrote {}", path.display())))
+}
+
+/// 名前で指定したバッファのID
+async fn buffer_named(ctx: &CommandContext, name: &str) -> Result<String, String> {
+    ctx.state
+        .find_buffer(name)
+        .await
+        .ok_or_else(|| format!("No such buffer: {}", name))
+}
+
+async fn switch_to_buffer(ctx: CommandContext) -> CommandResult {
+    let buffer_id = buffer_named(&ctx, ctx.string_arg(0)?).await?;
+    ctx.state.switch_to_buffer(&ctx.window_id, &buffer_id).await?;
+    Ok(None)
+}
+
+/// C-x k。ファイルを表示していて未保存の変更があるバッファは確認してから削除する。
+async fn kill_buffer(ctx: CommandContext) -> CommandResult {
+    let name = ctx.string_arg(0)?;
+    let buffer_id = buffer_named(&ctx, name).await?;
+    let buffer = ctx.state.get_buffer(&buffer_id).await.ok_or_else(|| format!("No such buffer: {}", name))?;
+    let unsaved = {
+        let buffer = buffer.read().await;
+        buffer.modified && buffer.path.is_some()
+    };
+    if unsaved && !ctx.yes_or_no_p(0, &format!("Buffer {} modified; kill anyway? ", name))? {
+        return Ok(None);
+    }
+    ctx.state.kill_buffer(&buffer_id).await?;
+    Ok(None)
+}
+
+/// 前置引数があれば、使用中の名前に番号を付けて重ならないようにする
+async fn rename_buffer(ctx: CommandContext) -> CommandResult {
+    let view = ctx.selected_view().await?;
+    let unique = !matches!(ctx.args.get(1), Some(CommandArg::Prefix(PrefixArg::None)));
+    let name = ctx.state.rename_buffer(&view.buffer_id, ctx.string_arg(0)?, unique).await?;
+    Ok(Some(format!("Renamed to {}", name)))
+}
+
+/// 選択中のViewに別のバッファを表示し、表示していたバッファを一覧の末尾へ移す
+async fn quit_window(ctx: CommandContext) -> CommandResult {
+    let view = ctx.selected_view().await?;
+    let other = ctx.state.other_buffer(&view.buffer_id).await.ok_or("No other buffer")?;
+    ctx.state.switch_to_buffer(&ctx.window_id, &other).await?;
+    ctx.state.bury_buffer(&view.buffer_id).await;
+    Ok(None)
+}
+
+/// M-x。前置引数はそのまま呼び出すコマンドへ渡す。
+async fn execute_extended_command(ctx: CommandContext) -> CommandResult {
+    let name = ctx.string_arg(1)?.to_string();
+    let invocation = CommandInvocation { prefix: ctx.prefix, ..Default::default() };
+    execute_command(ctx.state.clone(), &ctx.window_id, &name, invocation).await
+}
+
+/// C-u。押すたびに引数を4倍する。
+async fn universal_argument(ctx: CommandContext) -> CommandResult {
+    let prefix = match ctx.prefix {
+        PrefixArg::None => PrefixArg::Universal(4),
+        PrefixArg::Universal(n) => PrefixArg::Universal(n * 4),
+        other => other,
+    };
+    command_loop::set_next_prefix(&ctx.state, &ctx.window_id, prefix).await;
+    Ok(None)
+}
+
+/// M-0..M-9 など。起動したキーの数字を引数の末尾に追加する。
+async fn digit_argument(ctx: CommandContext) -> CommandResult {
+    let digit = ctx
+        .keys
+        .last()
+        .and_then(digit_of)
+        .ok_or("digit-argument must be bound to a digit key")?;
+    let prefix = match ctx.prefix {
+        PrefixArg::Number(n) if n < 0 => PrefixArg::Number(n * 10 - digit),
+        PrefixArg::Number(n) => PrefixArg::Number(n * 10 + digit),
+        PrefixArg::Minus => PrefixArg::Number(-digit),
+        PrefixArg::None | PrefixArg::Universal(_) => PrefixArg::Number(digit),
+    };
+    command_loop::set_next_prefix(&ctx.state, &ctx.window_id, prefix).await;
+    Ok(None)
+}
+
+async fn negative_argument(ctx: CommandContext) -> CommandResult {
+    let prefix = match ctx.prefix {
+        PrefixArg::Number(n) => PrefixArg::Number(-n),
+        PrefixArg::Minus => PrefixArg::None,
+        PrefixArg::None | PrefixArg::Universal(_) => PrefixArg::Minus,
+    };
+    command_loop::set_next_prefix(&ctx.state, &ctx.window_id, prefix).await;
+    Ok(None)
+}
+
+/// C-x z。前置引数を指定した場合は、直前のコマンドの前置引数の代わりに使う。
+async fn repeat(ctx: CommandContext) -> CommandResult {
+    let last = command_loop::last_command(&ctx.state, &ctx.window_id)
+        .await
+        .ok_or("No repeatable command")?;
+    let mut invocation = last.invocation;
+    if ctx.prefix != PrefixArg::None {
+        invocation.prefix = ctx.prefix;
+    }
+    execute_command(ctx.state.clone(), &ctx.window_id, &last.name, invocation).await
+}
+
+async fn keyboard_quit(_ctx: CommandContext) -> CommandResult {
+    Err(CommandError::Quit)
+}
+
+async fn describe_key(ctx: CommandContext) -> CommandResult {
+    let keys = ctx.keys_arg(0)?;
+    let view = ctx.selected_view().await?;
+    let description = ctx.state.describe_key(&view.buffer_id, keys).await;
+    let doc = match &description.command {
+        Some(command) => ctx.state.commands().read().await.get(command).map(|c| c.doc.clone()),
+        None => None,
+    };
+    Ok(Some(match doc {
+        Some(doc) => format!("{}\n\n{}", description, doc),
+        None => description.to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{CommandError, PrefixArg};
+    use crate::state::EditorState;
+    use std::sync::Arc;
+
+    struct Fixture {
+        state: Arc<EditorState>,
+        window_id: String,
+        buffer_id: String,
+    }
+
+    impl Fixture {
+        async fn new(text: &str) -> Self {
+            let state = Arc::new(EditorState::new());
+            let buffer_id = state.create_buffer("test".into(), text).await;
+            let window_id = state.create_window(&buffer_id).await.unwrap();
+            Self { state, window_id, buffer_id }
+        }
+
+        async fn run(&self, name: &str, prefix: PrefixArg) -> CommandResult {
+            let invocation = CommandInvocation { prefix, ..Default::default() };
+            execute_command(self.state.clone(), &self.window_id, name, invocation).await
+        }
+
+        async fn point(&self) -> usize {
+            self.state
+                .read_window(&self.window_id, |w| w.selected_view().point)
+                .await
+                .unwrap()
+        }
+
+        async fn text(&self) -> String {
+            let buffer = self.state.get_buffer(&self.buffer_id).await.unwrap();
+            buffer.read().await.to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_char_and_line_motion() {
+        let f = Fixture::new("abc\nde\nfghij").await;
+        f.run("forward-char", PrefixArg::Number(2)).await.unwrap();
+        assert_eq!(f.point().await, 2);
+
+        // 短い行では行末に収まる
+        f.run("next-line", PrefixArg::None).await.unwrap();
+        assert_eq!(f.point().await, 6);
+        f.run("next-line", PrefixArg::None).await.unwrap();
+        assert_eq!(f.point().await, 9);
+
+        f.run("move-end-of-line", PrefixArg::None).await.unwrap();
+        assert_eq!(f.point().await, 12);
+        assert!(f.run("forward-char", PrefixArg::Number(5)).await.is_err());
+        assert_eq!(f.point().await, 12);
+
+        f.run("previous-line", PrefixArg::Number(2)).await.unwrap();
+        assert_eq!(f.point().await, 3);
+        f.run("move-beginning-of-line", PrefixArg::None).await.unwrap();
+        assert_eq!(f.point().await, 0);
+        assert!(f.run("backward-char", PrefixArg::None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_editing_commands() {
+        let f = Fixture::new("Hello").await;
+        f.run("end-of-buffer", PrefixArg::None).await.unwrap();
+        f.run("newline", PrefixArg::Number(2)).await.unwrap();
+        assert_eq!(f.text().await, "Hello\n\n");
+        assert_eq!(f.point().await, 7);
+
+        f.run("delete-backward-char", PrefixArg::Number(3)).await.unwrap();
+        assert_eq!(f.text().await, "Hell");
+        f.run("beginning-of-buffer", PrefixArg::None).await.unwrap();
+        f.run("delete-char", PrefixArg::None).await.unwrap();
+        assert_eq!(f.text().await, "ell");
+    }
+
+    #[tokio::test]
+    async fn test_word_commands() {
+        let f = Fixture::new("foo, bar  baz").await;
+        f.run("forward-word", PrefixArg::Number(2)).await.unwrap();
+        assert_eq!(f.point().await, 8);
+        f.run("backward-word", PrefixArg::None).await.unwrap();
+        assert_eq!(f.point().await, 5);
+
+        f.run("kill-word", PrefixArg::Number(2)).await.unwrap();
+        assert_eq!(f.text().await, "foo, ");
+        f.run("backward-kill-word", PrefixArg::None).await.unwrap();
+        assert_eq!(f.text().await, "");
+        assert_eq!(f.point().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_overwrite_mode() {
+        let f = Fixture::new("abc\nd").await;
+        f.run("overwrite-mode", PrefixArg::None).await.unwrap();
+        let invocation = CommandInvocation {
+            prefix: PrefixArg::Number(4),
+            keys: vec![crate::keymap::Key::char('x')],
+            ..Default::default()
+        };
+        execute_command(f.state.clone(), &f.window_id, "self-insert-command", invocation)
+            .await
+            .unwrap();
+        // 改行は上書きしない
+        assert_eq!(f.text().await, "xxxx\nd");
+
+        f.run("overwrite-mode", PrefixArg::Number(-1)).await.unwrap();
+        f.run("overwrite-mode", PrefixArg::Number(-1)).await.unwrap();
+        let buffer = f.state.get_buffer(&f.buffer_id).await.unwrap();
+        assert!(!buffer.read().await.minor_mode_enabled("overwrite-mode"));
+    }
+
+    #[tokio::test]
+    async fn test_window_commands() {
+        let f = Fixture::new("").await;
+        f.run("split-window-below", PrefixArg::None).await.unwrap();
+        f.run("split-window-right", PrefixArg::None).await.unwrap();
+        let count = |f: &Fixture| {
+            let state = f.state.clone();
+            let id = f.window_id.clone();
+            async move { state.read_window(&id, |w| w.view_ids().len()).await.unwrap() }
+        };
+        assert_eq!(count(&f).await, 3);
+
+        f.run("other-window", PrefixArg::None).await.unwrap();
+        f.run("delete-window", PrefixArg::None).await.unwrap();
+        assert_eq!(count(&f).await, 2);
+        f.run("delete-other-windows", PrefixArg::None).await.unwrap();
+        assert_eq!(count(&f).await, 1);
+        assert!(f.run("delete-window", PrefixArg::None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_execute_extended_command_passes_prefix() {
+        let f = Fixture::new("abcdef").await;
+        let invocation = CommandInvocation {
+            prefix: PrefixArg::Number(4),
+            args: vec!["forward-char".into()],
+            ..Default::default()
+        };
+        execute_command(f.state.clone(), &f.window_id, "execute-extended-command", invocation)
+            .await
+            .unwrap();
+        assert_eq!(f.point().await, 4);
+    }
+
+    #[tokio::test]
+    async fn test_kill_buffer_confirms_unsaved_changes() {
+        let f = Fixture::new("").await;
+        f.state.set_buffer_file(&f.buffer_id, "/nonexistent/main.rs".into()).await.unwrap();
+        f.state.get_buffer(&f.buffer_id).await.unwrap().write().await.insert(0, "x").unwrap();
+
+        let kill = |answers: &[&str]| {
+            let args = std::iter::once("main.rs").chain(answers.iter().copied()).map(String::from).collect();
+            execute_command(f.state.clone(), &f.window_id, "kill-buffer", CommandInvocation { args, ..Default::default() })
+        };
+        let result = kill(&[]).await;
+        assert!(matches!(result, Err(CommandError::MissingArgument(r)) if r.prompt == "Buffer main.rs modified; kill anyway? (yes or no) "));
+        assert_eq!(kill(&["maybe"]).await, Err("Please answer yes or no".into()));
+        kill(&["no"]).await.unwrap();
+        assert!(f.state.get_buffer(&f.buffer_id).await.is_some());
+        kill(&["yes"]).await.unwrap();
+        assert!(f.state.get_buffer(&f.buffer_id).await.is_none());
+    }
+}
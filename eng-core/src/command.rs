@@ -0,0 +1,519 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::buffer::Buffer;
+use crate::keymap::{parse_kbd, Key};
+use crate::layout::{View, Window};
+use crate::quit::{self, EditLog, QuitSignal};
+use crate::sched::{self, Edit, EditError, Priority};
+use crate::state::EditorState;
+
+/// コマンドの戻り値。Some の場合はエコーエリアに表示するメッセージ。
+pub type CommandResult = Result<Option<String>, CommandError>;
+pub type CommandFuture = Pin<Box<dyn Future<Output = CommandResult> + Send>>;
+type CommandFn = Arc<dyn Fn(CommandContext) -> CommandFuture + Send + Sync>;
+
+/// 前置引数 (Emacs の raw prefix argument 相当)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PrefixArg {
+    #[default]
+    None,
+    /// C-u の繰り返し。値は 4, 16, 64...
+    Universal(i64),
+    /// M-5 や C-u 1 2 などの数値
+    Number(i64),
+    /// M-- または C-u - のみ
+    Minus,
+}
+
+impl PrefixArg {
+    /// 数値としての前置引数 (prefix-numeric-value)
+    pub fn numeric(&self) -> i64 {
+        match self {
+            PrefixArg::None => 1,
+            PrefixArg::Universal(n) | PrefixArg::Number(n) => *n,
+            PrefixArg::Minus => -1,
+        }
+    }
+}
+
+/// interactive 指定の各引数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InteractiveSpec {
+    /// `p`: 数値の前置引数
+    PrefixNumeric,
+    /// `P`: 生の前置引数
+    PrefixRaw,
+    /// `r`: リージョン
+    Region,
+    /// `s`: ミニバッファから読む文字列
+    String(String),
+    /// `n`: ミニバッファから読む数値
+    Number(String),
+    /// `b`: 既存バッファ名
+    Buffer(String),
+    /// `C`: コマンド名
+    Command(String),
+    /// `k`: キー列 (kbd 記法)
+    Keys(String),
+}
+
+impl InteractiveSpec {
+    /// Emacs の interactive 文字列 ("p", "r", "sSearch: " を改行で区切ったもの) を解析する
+    pub fn parse(spec: &str) -> Result<Vec<InteractiveSpec>, String> {
+        if spec.is_empty() {
+            return Ok(Vec::new());
+        }
+        spec.split('\n')
+            .map(|item| {
+                let mut chars = item.chars();
+                let code = chars.next().ok_or("Empty interactive spec item")?;
+                let prompt = chars.as_str().to_string();
+                match code {
+                    'p' => Ok(InteractiveSpec::PrefixNumeric),
+                    'P' => Ok(InteractiveSpec::PrefixRaw),
+                    'r' => Ok(InteractiveSpec::Region),
+                    's' => Ok(InteractiveSpec::String(prompt)),
+                    'n' => Ok(InteractiveSpec::Number(prompt)),
+                    'b' => Ok(InteractiveSpec::Buffer(prompt)),
+                    'C' => Ok(InteractiveSpec::Command(prompt)),
+                    'k' => Ok(InteractiveSpec::Keys(prompt)),
+                    other => Err(format!("Invalid interactive code: {}", other)),
+                }
+            })
+            .collect()
+    }
+}
+
+/// interactive 指定を解決した実引数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandArg {
+    Number(i64),
+    Prefix(PrefixArg),
+    Region(Range<usize>),
+    String(String),
+    Keys(Vec<Key>),
+}
+
+/// 名前と説明を持つコマンド
+pub struct Command {
+    pub name: String,
+    pub doc: String,
+    pub interactive: Vec<InteractiveSpec>,
+    handler: CommandFn,
+}
+
+impl fmt::Debug for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Command")
+            .field("name", &self.name)
+            .field("interactive", &self.interactive)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CommandRegistry {
+    commands: HashMap<String, Arc<Command>>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// コマンドを登録する。同名のコマンドは置き換えられる。
+    pub fn register<F, Fut>(&mut self, name: &str, doc: &str, interactive: &str, handler: F) -> Result<(), String>
+    where
+        F: Fn(CommandContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = CommandResult> + Send + 'static,
+    {
+        let command = Command {
+            name: name.to_string(),
+            doc: doc.to_string(),
+            interactive: InteractiveSpec::parse(interactive)?,
+            handler: Arc::new(move |ctx| Box::pin(handler(ctx))),
+        };
+        self.commands.insert(name.to_string(), Arc::new(command));
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Command>> {
+        self.commands.get(name).cloned()
+    }
+
+    /// 登録済みのコマンドを名前順で返す
+    pub fn commands(&self) -> Vec<Arc<Command>> {
+        let mut commands: Vec<_> = self.commands.values().cloned().collect();
+        commands.sort_by(|a, b| a.name.cmp(&b.name));
+        commands
+    }
+}
+
+/// UIから受け取る実行要求
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandInvocation {
+    pub prefix: PrefixArg,
+    /// ミニバッファで入力済みの引数 (interactive 指定の順)
+    pub args: Vec<String>,
+    /// コマンドを起動したキー列 (this-command-keys)。M-x やRPC経由では空。
+    pub keys: Vec<Key>,
+    /// コマンドの編集を適用する優先度。タイマーから実行したコマンドは Background。
+    pub priority: Priority,
+}
+
+/// ミニバッファから読む必要がある引数。入力を invocation.args に加えて command を再実行する。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArgumentRequest {
+    pub prompt: String,
+    pub spec: InteractiveSpec,
+    pub command: String,
+    pub invocation: CommandInvocation,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    UnknownCommand(String),
+    /// ミニバッファ入力が必要な引数が不足している
+    MissingArgument(Box<ArgumentRequest>),
+    /// C-g で中断した。途中までの編集は巻き戻してある。
+    Quit,
+    Failed(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::UnknownCommand(name) => write!(f, "Unknown command: {}", name),
+            CommandError::MissingArgument(request) => write!(f, "Missing argument: {}", request.prompt),
+            CommandError::Quit => f.write_str("Quit"),
+            CommandError::Failed(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<String> for CommandError {
+    fn from(message: String) -> Self {
+        CommandError::Failed(message)
+    }
+}
+
+impl From<&str> for CommandError {
+    fn from(message: &str) -> Self {
+        CommandError::Failed(message.to_string())
+    }
+}
+
+/// コマンド実行時に渡される文脈。対象Windowの選択中Viewを操作する。
+#[derive(Debug, Clone)]
+pub struct CommandContext {
+    pub state: Arc<EditorState>,
+    pub window_id: String,
+    pub prefix: PrefixArg,
+    pub args: Vec<CommandArg>,
+    pub keys: Vec<Key>,
+    /// 実行中のコマンド名
+    pub command: String,
+    /// C-g による中断要求。長い処理は check や interruptible で確認する。
+    pub quit: QuitSignal,
+    /// コマンドが行った編集。C-g で中断したときに巻き戻す。
+    log: EditLog,
+    /// コマンドを起動した要求。コマンド本体で追加の入力を読むときに使う。
+    invocation: CommandInvocation,
+    /// interactive 指定で消費されなかった入力 (yes-or-no-p などの答え)
+    extra_args: Vec<String>,
+}
+
+impl CommandContext {
+    pub fn number_arg(&self, index: usize) -> i64 {
+        match self.args.get(index) {
+            Some(CommandArg::Number(n)) => *n,
+            Some(CommandArg::Prefix(p)) => p.numeric(),
+            _ => 1,
+        }
+    }
+
+    pub fn string_arg(&self, index: usize) -> Result<&str, String> {
+        match self.args.get(index) {
+            Some(CommandArg::String(s)) => Ok(s),
+            _ => Err(format!("Argument {} is not a string", index)),
+        }
+    }
+
+    pub fn keys_arg(&self, index: usize) -> Result<&[Key], String> {
+        match self.args.get(index) {
+            Some(CommandArg::Keys(keys)) => Ok(keys),
+            _ => Err(format!("Argument {} is not a key sequence", index)),
+        }
+    }
+
+    pub fn region_arg(&self, index: usize) -> Result<Range<usize>, String> {
+        match self.args.get(index) {
+            Some(CommandArg::Region(r)) => Ok(r.clone()),
+            _ => Err(format!("Argument {} is not a region", index)),
+        }
+    }
+
+    /// コマンド本体から追加の入力を読む。index 番目の入力がまだ無ければミニバッファでの入力を要求する。
+    pub fn read_input(&self, index: usize, prompt: &str) -> Result<&str, CommandError> {
+        match self.extra_args.get(index) {
+            Some(input) => Ok(input),
+            None => Err(CommandError::MissingArgument(Box::new(ArgumentRequest {
+                prompt: prompt.to_string(),
+                spec: InteractiveSpec::String(prompt.to_string()),
+                command: self.command.clone(),
+                invocation: self.invocation.clone(),
+            }))),
+        }
+    }
+
+    /// "yes" か "no" で答える確認 (yes-or-no-p)
+    pub fn yes_or_no_p(&self, index: usize, prompt: &str) -> Result<bool, CommandError> {
+        match self.read_input(index, &format!("{}(yes or no) ", prompt))? {
+            "yes" => Ok(true),
+            "no" => Ok(false),
+            _ => Err("Please answer yes or no".into()),
+        }
+    }
+
+    pub async fn selected_view(&self) -> Result<View, String> {
+        self.state.read_window(&self.window_id, |w| w.selected_view().clone()).await
+    }
+
+    pub async fn update_window<F, R>(&self, f: F) -> Result<R, String>
+    where
+        F: FnOnce(&mut Window) -> Result<R, String>,
+    {
+        self.state.update_window(&self.window_id, f).await
+    }
+
+    /// 選択中Viewのバッファ
+    pub async fn current_buffer(&self) -> Result<Arc<RwLock<Buffer>>, String> {
+        let view = self.selected_view().await?;
+        self.state
+            .get_buffer(&view.buffer_id)
+            .await
+            .ok_or_else(|| format!("No such buffer: {}", view.buffer_id))
+    }
+
+    /// コマンドの編集として、バッファの range を text で置き換える。編集キューを通して起動元の優先度で
+    /// 適用し、C-g で中断したときは巻き戻す。巻き戻しを始めた後の編集は Quit になる。
+    pub async fn edit(&self, buffer_id: &str, range: Range<usize>, text: &str) -> Result<(), CommandError> {
+        let edit = Edit {
+            buffer_id: buffer_id.to_string(),
+            changes: vec![(range, text.to_string())],
+            base_version: None,
+            priority: self.invocation.priority,
+            log: Some(self.log.clone()),
+        };
+        sched::submit(&self.state, edit).await.map_err(|e| match e {
+            EditError::Quit => CommandError::Quit,
+            e => CommandError::Failed(e.to_string()),
+        })?;
+        Ok(())
+    }
+
+    pub async fn set_point(&self, point: usize) -> Result<(), String> {
+        self.update_window(|w| {
+            w.selected_view_mut().point = point;
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// コマンドを名前で実行する。interactive 指定に従って引数を解決してからハンドラを呼ぶ。
+/// window_id が空なら Window なしで実行する (eng-agent -e)。Window を使うコマンドは失敗する。
+pub async fn execute_command(
+    state: Arc<EditorState>,
+    window_id: &str,
+    name: &str,
+    invocation: CommandInvocation,
+) -> Result<Option<String>, CommandError> {
+    let command = state
+        .commands()
+        .read()
+        .await
+        .get(name)
+        .ok_or_else(|| CommandError::UnknownCommand(name.to_string()))?;
+
+    let view = match window_id {
+        "" => None,
+        _ => Some(state.read_window(window_id, |w| w.selected_view().clone()).await?),
+    };
+
+    let mut strings = invocation.args.clone().into_iter();
+    let mut args = Vec::with_capacity(command.interactive.len());
+    for spec in &command.interactive {
+        let missing = |prompt: &str| {
+            CommandError::MissingArgument(Box::new(ArgumentRequest {
+                prompt: prompt.to_string(),
+                spec: spec.clone(),
+                command: name.to_string(),
+                invocation: invocation.clone(),
+            }))
+        };
+        let arg = match spec {
+            InteractiveSpec::PrefixNumeric => CommandArg::Number(invocation.prefix.numeric()),
+            InteractiveSpec::PrefixRaw => CommandArg::Prefix(invocation.prefix),
+            InteractiveSpec::Region => {
+                let view = view.as_ref().ok_or_else(|| CommandError::Failed("No window to take the region from".into()))?;
+                CommandArg::Region(view.region().ok_or_else(|| {
+                    CommandError::Failed("The mark is not set now, so there is no region".into())
+                })?)
+            }
+            InteractiveSpec::String(prompt) | InteractiveSpec::Buffer(prompt) | InteractiveSpec::Command(prompt) => {
+                CommandArg::String(strings.next().ok_or_else(|| missing(prompt))?)
+            }
+            InteractiveSpec::Number(prompt) => {
+                let input = strings.next().ok_or_else(|| missing(prompt))?;
+                let n = input
+                    .trim()
+                    .parse()
+                    .map_err(|_| CommandError::Failed(format!("Not a number: {}", input)))?;
+                CommandArg::Number(n)
+            }
+            InteractiveSpec::Keys(prompt) => {
+                CommandArg::Keys(parse_kbd(&strings.next().ok_or_else(|| missing(prompt))?)?)
+            }
+        };
+        args.push(arg);
+    }
+
+    let extra_args = strings.collect();
+    let guard = quit::begin(&state, window_id, name);
+    let ctx = CommandContext {
+        state,
+        window_id: window_id.to_string(),
+        prefix: invocation.prefix,
+        args,
+        keys: invocation.keys.clone(),
+        command: name.to_string(),
+        quit: guard.signal.clone(),
+        log: guard.log.clone(),
+        invocation,
+        extra_args,
+    };
+    if !guard.top_level {
+        return (command.handler)(ctx).await;
+    }
+
+    // 中断要求があれば、コマンドが await している所で打ち切って編集を巻き戻す
+    let state = ctx.state.clone();
+    let result = guard.signal.interruptible(guard.scope((command.handler)(ctx))).await.and_then(|result| result);
+    if result == Err(CommandError::Quit) {
+        quit::rollback(&state, &guard.log).await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_interactive_spec() {
+        assert_eq!(InteractiveSpec::parse("").unwrap(), vec![]);
+        assert_eq!(
+            InteractiveSpec::parse("P\nr\nsSearch for: ").unwrap(),
+            vec![
+                InteractiveSpec::PrefixRaw,
+                InteractiveSpec::Region,
+                InteractiveSpec::String("Search for: ".into()),
+            ]
+        );
+        assert!(InteractiveSpec::parse("x").is_err());
+    }
+
+    #[test]
+    fn test_prefix_numeric_value() {
+        assert_eq!(PrefixArg::None.numeric(), 1);
+        assert_eq!(PrefixArg::Universal(16).numeric(), 16);
+        assert_eq!(PrefixArg::Number(-3).numeric(), -3);
+        assert_eq!(PrefixArg::Minus.numeric(), -1);
+    }
+
+    async fn setup(text: &str) -> (Arc<EditorState>, String) {
+        let state = Arc::new(EditorState::new());
+        let buffer_id = state.create_buffer("test".into(), text).await;
+        let window_id = state.create_window(&buffer_id).await.unwrap();
+        (state, window_id)
+    }
+
+    #[tokio::test]
+    async fn test_execute_resolves_arguments() {
+        let (state, window_id) = setup("Hello").await;
+        state
+            .commands()
+            .write()
+            .await
+            .register("echo", "Echo arguments.", "p\nsText: ", |ctx: CommandContext| async move {
+                Ok(Some(format!("{} {}", ctx.number_arg(0), ctx.string_arg(1)?)))
+            })
+            .unwrap();
+
+        let invocation = CommandInvocation {
+            prefix: PrefixArg::Number(3),
+            args: vec!["hi".into()],
+            ..Default::default()
+        };
+        let message = execute_command(state.clone(), &window_id, "echo", invocation).await.unwrap();
+        assert_eq!(message, Some("3 hi".into()));
+
+        let invocation = CommandInvocation { prefix: PrefixArg::Minus, ..Default::default() };
+        let result = execute_command(state.clone(), &window_id, "echo", invocation.clone()).await;
+        let expected = ArgumentRequest {
+            prompt: "Text: ".into(),
+            spec: InteractiveSpec::String("Text: ".into()),
+            command: "echo".into(),
+            invocation,
+        };
+        assert_eq!(result, Err(CommandError::MissingArgument(Box::new(expected))));
+
+        let result = execute_command(state, &window_id, "no-such-command", CommandInvocation::default()).await;
+        assert_eq!(result, Err(CommandError::UnknownCommand("no-such-command".into())));
+    }
+
+    #[tokio::test]
+    async fn test_region_requires_mark() {
+        let (state, window_id) = setup("Hello World").await;
+        let result = execute_command(state.clone(), &window_id, "delete-region", CommandInvocation::default()).await;
+        assert!(matches!(result, Err(CommandError::Failed(_))));
+
+        state
+            .update_window(&window_id, |w| {
+                let view = w.selected_view_mut();
+                view.mark = Some(5);
+                view.point = 11;
+                Ok(())
+            })
+            .await
+            .unwrap();
+        execute_command(state.clone(), &window_id, "delete-region", CommandInvocation::default())
+            .await
+            .unwrap();
+
+        let view = state.window_layout(&window_id).await.unwrap().views[0].view.clone();
+        let buffer = state.get_buffer(&view.buffer_id).await.unwrap();
+        assert_eq!(buffer.read().await.to_string(), "Hello");
+        assert_eq!(view.point, 5);
+    }
+
+    #[tokio::test]
+    async fn test_execute_without_window() {
+        let (state, _) = setup("Hello").await;
+        let result = execute_command(state.clone(), "", "forward-char", CommandInvocation::default()).await;
+        assert!(matches!(result, Err(CommandError::Failed(_))));
+
+        let invocation = CommandInvocation { args: vec!["test".into()], ..Default::default() };
+        execute_command(state.clone(), "", "kill-buffer", invocation).await.unwrap();
+        assert!(state.buffer_list().await.iter().all(|b| b.name != "test"));
+    }
+}
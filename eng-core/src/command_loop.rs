@@ -0,0 +1,380 @@
+use std::sync::Arc;
+
+use crate::command::{CommandError, CommandInvocation, PrefixArg};
+use crate::hook::{self, HookEvent};
+use crate::keymap::{key_description, Key, KeyCode, Lookup};
+use crate::minibuffer::{self, Interaction};
+use crate::state::EditorState;
+
+/// 前置引数を組み立てるコマンド。実行後も次のコマンドへ前置引数を引き継ぐ。
+const PREFIX_COMMANDS: &[&str] = &["universal-argument", "digit-argument", "negative-argument"];
+
+/// キーボードマクロの入れ子の上限。自分自身を呼ぶ名前付きマクロで止まらなくなるのを防ぐ。
+const MAX_MACRO_DEPTH: usize = 32;
+
+/// 直前に実行したコマンド (repeat で再実行する)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LastCommand {
+    pub name: String,
+    pub invocation: CommandInvocation,
+}
+
+/// Windowごとのキー入力状態。UIは生のキーイベントを送るだけで、コマンドへの解決はここで行う。
+#[derive(Debug, Default)]
+pub struct CommandLoop {
+    /// 入力途中のプレフィックスキー列
+    pending: Vec<Key>,
+    /// 組み立て中の前置引数。次に実行するコマンドに渡される。
+    next_prefix: Option<PrefixArg>,
+    /// 前置引数の入力に使ったキー列 (エコー表示用)
+    prefix_keys: Vec<Key>,
+    last_command: Option<LastCommand>,
+    /// repeat の直後に同じキーを押すと再度 repeat する (C-x z z z...)
+    repeat_key: Option<Key>,
+    /// 定義中のキーボードマクロ
+    recording: Option<Vec<Key>>,
+    /// 再生中のキーボードマクロの深さ。再生中のキーは記録しない。
+    executing_macro: usize,
+}
+
+impl CommandLoop {
+    /// 前置引数の入力中は、修飾なしの数字と (C-u 直後の) "-" を前置引数として扱う
+    fn prefix_override(&self, key: &Key) -> Option<&'static str> {
+        let prefix = self.next_prefix?;
+        match key.printable_char() {
+            Some(c) if c.is_ascii_digit() => Some("digit-argument"),
+            Some('-') if matches!(prefix, PrefixArg::Universal(_)) => Some("negative-argument"),
+            _ => None,
+        }
+    }
+}
+
+/// キー入力の処理結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyOutcome {
+    Executed { keys: String, command: String, message: Option<String> },
+    /// プレフィックスキーや前置引数の入力途中。UIはエコーエリアに keys を表示する。
+    Prefix { keys: String },
+    Undefined { keys: String },
+    /// 引数を読むミニバッファを開いた。入力内容は SubscribeMinibuffer で配信される。
+    Prompt { keys: String, command: String, prompt: String },
+    Failed { keys: String, command: String, error: CommandError },
+}
+
+/// キー入力を1つ処理する。キー列がコマンドに解決されたら実行する。
+pub async fn handle_key(state: &Arc<EditorState>, window_id: &str, key: Key) -> Result<KeyOutcome, String> {
+    state.timers().note_activity();
+    let buffer_id = state
+        .read_window(window_id, |w| w.selected_view().buffer_id.clone())
+        .await?;
+
+    // キー列を読んでいるミニバッファ (C-h k など) にはキーをそのまま渡す
+    if let Some(outcome) = minibuffer::read_key(state, window_id, &key).await? {
+        return Ok(outcome);
+    }
+    let in_minibuffer = minibuffer::depth(state, window_id).await > 0;
+
+    let is_quit = key == Key::ctrl('g');
+    let (keys, forced, prefix_keys) = {
+        let mut loops = state.command_loops().lock().await;
+        let command_loop = loops.entry(window_id.to_string()).or_default();
+        let forced = if !command_loop.pending.is_empty() {
+            None
+        } else if command_loop.repeat_key.as_ref() == Some(&key) {
+            Some("repeat")
+        } else {
+            command_loop.prefix_override(&key)
+        };
+        command_loop.repeat_key = None;
+        command_loop.pending.push(key);
+        (command_loop.pending.clone(), forced, command_loop.prefix_keys.clone())
+    };
+
+    // プレフィックス入力中の C-g は割り当てに関わらず入力を取り消す
+    let lookup = if let Some(command) = forced {
+        Lookup::Command(command.into())
+    } else if is_quit && keys.len() > 1 {
+        Lookup::Command("keyboard-quit".into())
+    } else if in_minibuffer {
+        state.lookup_minibuffer_key(&buffer_id, &keys).await
+    } else {
+        state.lookup_key(&buffer_id, &keys).await
+    };
+
+    let echo = |keys: &[Key]| key_description(&[prefix_keys.as_slice(), keys].concat());
+    let command = match lookup {
+        Lookup::Prefix => return Ok(KeyOutcome::Prefix { keys: echo(&keys) }),
+        Lookup::Undefined => {
+            reset(state, window_id).await;
+            return Ok(KeyOutcome::Undefined { keys: echo(&keys) });
+        }
+        Lookup::Command(command) => command,
+    };
+
+    let (prefix, was_recording) = {
+        let mut loops = state.command_loops().lock().await;
+        let command_loop = loops.entry(window_id.to_string()).or_default();
+        command_loop.pending.clear();
+        let was_recording = command_loop.recording.is_some() && command_loop.executing_macro == 0;
+        (command_loop.next_prefix.take().unwrap_or_default(), was_recording)
+    };
+
+    let description = key_description(&keys);
+    let invocation = CommandInvocation { prefix, keys: keys.clone(), ..Default::default() };
+    let pre_command = HookEvent::PreCommand { window_id: window_id.to_string(), command: command.clone() };
+    hook::run(state, pre_command).await;
+    let result = minibuffer::call_interactively(state, window_id, &command, invocation.clone()).await;
+    hook::command_finished(state, window_id, &command).await;
+
+    let mut loops = state.command_loops().lock().await;
+    let command_loop = loops.entry(window_id.to_string()).or_default();
+    if result.is_ok() && PREFIX_COMMANDS.contains(&command.as_str()) {
+        command_loop.prefix_keys.extend(keys);
+        return Ok(KeyOutcome::Prefix { keys: key_description(&command_loop.prefix_keys) });
+    }
+    // マクロ定義の開始・終了に使ったキーは記録しない (実行の前後とも定義中だったものだけ記録する)
+    if was_recording && result.is_ok() && let Some(recording) = &mut command_loop.recording {
+        recording.extend(command_loop.prefix_keys.iter().chain(&keys).cloned());
+    }
+    if command == "keyboard-quit" {
+        command_loop.recording = None;
+    }
+    command_loop.next_prefix = None;
+    command_loop.prefix_keys.clear();
+    // ミニバッファ内の編集や、引数を読み終えていないコマンドは repeat の対象にしない
+    // (引数を読み終えたコマンドは minibuffer::submit が記録する)
+    if let Ok(Interaction::Done(_)) = &result && !in_minibuffer {
+        if command == "repeat" {
+            command_loop.repeat_key = keys.last().cloned();
+        } else {
+            command_loop.last_command = Some(LastCommand { name: command.clone(), invocation });
+        }
+    }
+
+    Ok(match result {
+        Ok(Interaction::Done(message)) => KeyOutcome::Executed { keys: description, command, message },
+        Ok(Interaction::Prompt(prompt)) => KeyOutcome::Prompt { keys: description, command, prompt },
+        Err(error) => KeyOutcome::Failed { keys: description, command, error },
+    })
+}
+
+/// 入力途中のキー列と前置引数を破棄する
+async fn reset(state: &EditorState, window_id: &str) {
+    if let Some(command_loop) = state.command_loops().lock().await.get_mut(window_id) {
+        command_loop.pending.clear();
+        command_loop.next_prefix = None;
+        command_loop.prefix_keys.clear();
+    }
+}
+
+/// 次に実行するコマンドの前置引数を設定する (universal-argument などから使う)
+pub async fn set_next_prefix(state: &EditorState, window_id: &str, prefix: PrefixArg) {
+    let mut loops = state.command_loops().lock().await;
+    loops.entry(window_id.to_string()).or_default().next_prefix = Some(prefix);
+}
+
+pub async fn set_last_command(state: &EditorState, window_id: &str, name: &str, invocation: CommandInvocation) {
+    let mut loops = state.command_loops().lock().await;
+    loops.entry(window_id.to_string()).or_default().last_command = Some(LastCommand { name: name.to_string(), invocation });
+}
+
+pub async fn last_command(state: &EditorState, window_id: &str) -> Option<LastCommand> {
+    let loops = state.command_loops().lock().await;
+    loops.get(window_id).and_then(|l| l.last_command.clone())
+}
+
+/// キーボードマクロの定義を始める。initial は既存のマクロへ追記する場合の先頭部分。
+pub async fn start_kbd_macro(state: &EditorState, window_id: &str, initial: Vec<Key>) -> Result<(), String> {
+    let mut loops = state.command_loops().lock().await;
+    let command_loop = loops.entry(window_id.to_string()).or_default();
+    if command_loop.recording.is_some() {
+        return Err("Already defining keyboard macro".into());
+    }
+    command_loop.recording = Some(initial);
+    Ok(())
+}
+
+/// キーボードマクロの定義を終え、記録したキー列を返す
+pub async fn end_kbd_macro(state: &EditorState, window_id: &str) -> Result<Vec<Key>, String> {
+    let mut loops = state.command_loops().lock().await;
+    loops
+        .get_mut(window_id)
+        .and_then(|l| l.recording.take())
+        .ok_or_else(|| "Not defining kbd macro".into())
+}
+
+pub async fn defining_kbd_macro(state: &EditorState, window_id: &str) -> bool {
+    let loops = state.command_loops().lock().await;
+    loops.get(window_id).is_some_and(|l| l.recording.is_some())
+}
+
+pub async fn executing_kbd_macro(state: &EditorState, window_id: &str) -> bool {
+    let loops = state.command_loops().lock().await;
+    loops.get(window_id).is_some_and(|l| l.executing_macro > 0)
+}
+
+/// キー列を入力として1回実行する (キーボードマクロの再生)。途中でエラーになったらそこで止める。
+pub async fn execute_keys(state: &Arc<EditorState>, window_id: &str, keys: &[Key]) -> Result<(), CommandError> {
+    {
+        let mut loops = state.command_loops().lock().await;
+        let command_loop = loops.entry(window_id.to_string()).or_default();
+        if command_loop.executing_macro >= MAX_MACRO_DEPTH {
+            return Err("Keyboard macros nested too deeply".into());
+        }
+        command_loop.executing_macro += 1;
+    }
+
+    let mut result = Ok(());
+    for key in keys {
+        match handle_key(state, window_id, key.clone()).await {
+            Ok(KeyOutcome::Executed { .. } | KeyOutcome::Prefix { .. } | KeyOutcome::Prompt { .. }) => {}
+            Ok(KeyOutcome::Undefined { keys }) => {
+                result = Err(CommandError::Failed(format!("{} is undefined", keys)));
+                break;
+            }
+            Ok(KeyOutcome::Failed { error, .. }) => {
+                result = Err(error);
+                break;
+            }
+            Err(e) => {
+                result = Err(e.into());
+                break;
+            }
+        }
+    }
+
+    // 途中で終わったプレフィックスキーや前置引数はマクロの外へ持ち越さない
+    reset(state, window_id).await;
+    if let Some(command_loop) = state.command_loops().lock().await.get_mut(window_id) {
+        command_loop.executing_macro -= 1;
+    }
+    result
+}
+
+/// digit-argument の対象となる数字キーの値
+pub fn digit_of(key: &Key) -> Option<i64> {
+    match key.code {
+        KeyCode::Char(c) => c.to_digit(10).map(i64::from),
+        KeyCode::Named(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::parse_kbd;
+
+    async fn setup(text: &str) -> (Arc<EditorState>, String, String) {
+        let state = Arc::new(EditorState::new());
+        let buffer_id = state.create_buffer("test".into(), text).await;
+        let window_id = state.create_window(&buffer_id).await.unwrap();
+        (state, window_id, buffer_id)
+    }
+
+    async fn type_keys(state: &Arc<EditorState>, window_id: &str, keys: &str) -> Vec<KeyOutcome> {
+        let mut outcomes = Vec::new();
+        for key in parse_kbd(keys).unwrap() {
+            outcomes.push(handle_key(state, window_id, key).await.unwrap());
+        }
+        outcomes
+    }
+
+    async fn text_and_point(state: &Arc<EditorState>, window_id: &str, buffer_id: &str) -> (String, usize) {
+        let buffer = state.get_buffer(buffer_id).await.unwrap();
+        let text = buffer.read().await.to_string();
+        let point = state.read_window(window_id, |w| w.selected_view().point).await.unwrap();
+        (text, point)
+    }
+
+    #[tokio::test]
+    async fn test_self_insert_and_motion() {
+        let (state, window_id, buffer_id) = setup("").await;
+        type_keys(&state, &window_id, "h i RET C-a x").await;
+        let buffer = state.get_buffer(&buffer_id).await.unwrap();
+        assert_eq!(buffer.read().await.to_string(), "hi\nx");
+    }
+
+    #[tokio::test]
+    async fn test_prefix_key_sequence() {
+        let (state, window_id, _) = setup("").await;
+        let outcomes = type_keys(&state, &window_id, "C-x 2").await;
+        assert_eq!(outcomes[0], KeyOutcome::Prefix { keys: "C-x".into() });
+        assert!(matches!(&outcomes[1], KeyOutcome::Executed { command, .. } if command == "split-window-below"));
+        let views = state.read_window(&window_id, |w| w.view_ids().len()).await.unwrap();
+        assert_eq!(views, 2);
+    }
+
+    #[tokio::test]
+    async fn test_undefined_and_quit_reset_pending_keys() {
+        let (state, window_id, _) = setup("abc").await;
+        let outcomes = type_keys(&state, &window_id, "C-x C-q C-f").await;
+        assert_eq!(outcomes[1], KeyOutcome::Undefined { keys: "C-x C-q".into() });
+        assert!(matches!(&outcomes[2], KeyOutcome::Executed { command, .. } if command == "forward-char"));
+
+        let outcomes = type_keys(&state, &window_id, "C-x C-g").await;
+        assert!(matches!(&outcomes[1], KeyOutcome::Failed { command, .. } if command == "keyboard-quit"));
+        let outcomes = type_keys(&state, &window_id, "C-x o").await;
+        assert_eq!(outcomes[0], KeyOutcome::Prefix { keys: "C-x".into() });
+    }
+
+    #[tokio::test]
+    async fn test_universal_argument_multiplies() {
+        let text = (0..20).map(|i| format!("{}\n", i)).collect::<String>();
+        let (state, window_id, buffer_id) = setup(&text).await;
+        let outcomes = type_keys(&state, &window_id, "C-u C-u C-n").await;
+        assert_eq!(outcomes[0], KeyOutcome::Prefix { keys: "C-u".into() });
+        assert_eq!(outcomes[1], KeyOutcome::Prefix { keys: "C-u C-u".into() });
+
+        let (_, point) = text_and_point(&state, &window_id, &buffer_id).await;
+        let buffer = state.get_buffer(&buffer_id).await.unwrap();
+        assert_eq!(buffer.read().await.char_to_line(point), 16);
+    }
+
+    #[tokio::test]
+    async fn test_numeric_arguments() {
+        let (state, window_id, buffer_id) = setup("").await;
+        // C-u の後の数字は前置引数になる
+        type_keys(&state, &window_id, "C-u 1 2 x").await;
+        assert_eq!(text_and_point(&state, &window_id, &buffer_id).await.0, "x".repeat(12));
+
+        // M-数字 の後は修飾なしの数字も続けて入力できる
+        type_keys(&state, &window_id, "M-1 0 y").await;
+        assert_eq!(text_and_point(&state, &window_id, &buffer_id).await.0.matches('y').count(), 10);
+
+        // 前置引数が無ければ数字はそのまま挿入される
+        type_keys(&state, &window_id, "3").await;
+        assert!(text_and_point(&state, &window_id, &buffer_id).await.0.ends_with("y3"));
+    }
+
+    #[tokio::test]
+    async fn test_negative_arguments() {
+        let (state, window_id, buffer_id) = setup("foo bar baz").await;
+        type_keys(&state, &window_id, "M->").await;
+        // M-- M-d は直前の1単語を削除する
+        type_keys(&state, &window_id, "M-- M-d").await;
+        assert_eq!(text_and_point(&state, &window_id, &buffer_id).await, ("foo bar ".into(), 8));
+
+        let outcomes = type_keys(&state, &window_id, "C-u - 2 C-f").await;
+        assert_eq!(outcomes[1], KeyOutcome::Prefix { keys: "C-u -".into() });
+        assert_eq!(text_and_point(&state, &window_id, &buffer_id).await.1, 6);
+    }
+
+    #[tokio::test]
+    async fn test_repeat_last_command() {
+        let (state, window_id, buffer_id) = setup("").await;
+        type_keys(&state, &window_id, "C-u 3 a C-x z").await;
+        assert_eq!(text_and_point(&state, &window_id, &buffer_id).await.0, "aaaaaa");
+
+        // 続けて z を押すと繰り返す
+        type_keys(&state, &window_id, "z z").await;
+        assert_eq!(text_and_point(&state, &window_id, &buffer_id).await.0, "a".repeat(12));
+
+        // repeat に前置引数を渡すと、それを元のコマンドの引数にする
+        type_keys(&state, &window_id, "C-u 1 C-x z").await;
+        assert_eq!(text_and_point(&state, &window_id, &buffer_id).await.0, "a".repeat(13));
+
+        // repeat の直後以外の z は普通に挿入される
+        type_keys(&state, &window_id, "C-f z").await;
+        assert!(text_and_point(&state, &window_id, &buffer_id).await.0.ends_with('z'));
+    }
+}
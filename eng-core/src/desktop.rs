@@ -0,0 +1,761 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::command::{CommandContext, CommandRegistry, CommandResult};
+use crate::layout::{SplitDirection, ViewState, WindowState};
+use crate::state::{self, EditorState};
+use crate::uniquify;
+
+/// セッションファイルの形式の版。互換性の無い変更をしたら上げる。
+/// 2 で未保存の内容 (autosave 行) を加え、3 で autosave 行にファイルの更新時刻を加えた。古いファイルもそのまま読める。
+const DESKTOP_FILE_VERSION: u32 = 3;
+const DESKTOP_FILE_HEADER: &str = "eng-desktop";
+/// セッションファイルの拡張子
+const DESKTOP_FILE_EXTENSION: &str = "desktop";
+/// 変更があれば自動保存する間隔 (desktop-auto-save-timeout)
+pub const DESKTOP_AUTO_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// desktop-save-mode の設定。セッションは dir/<session>.desktop に保存する。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesktopConfig {
+    pub dir: PathBuf,
+    pub session: String,
+}
+
+impl DesktopConfig {
+    /// 環境変数から設定を作る。ENG_DESKTOP=0 なら無効。
+    /// ENG_DESKTOP_DIR で保存先、ENG_DESKTOP_SESSION でセッション名を指定できる (既定はプロジェクトごと)。
+    pub fn from_env() -> Option<Self> {
+        if std::env::var("ENG_DESKTOP").is_ok_and(|v| v == "0") {
+            return None;
+        }
+        let dir = match std::env::var_os("ENG_DESKTOP_DIR") {
+            Some(dir) => PathBuf::from(dir),
+            None => default_dir()?,
+        };
+        let session = match std::env::var("ENG_DESKTOP_SESSION") {
+            Ok(session) => session,
+            Err(_) => project_session_name(&std::env::current_dir().ok()?),
+        };
+        Some(Self { dir, session })
+    }
+
+    pub fn path(&self) -> Result<PathBuf, String> {
+        session_path(&self.dir, &self.session)
+    }
+}
+
+/// セッションの既定の保存先 ($XDG_DATA_HOME/eng/desktop または ~/.local/share/eng/desktop)
+pub fn default_dir() -> Option<PathBuf> {
+    let data = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/share"),
+    };
+    Some(data.join("eng").join("desktop"))
+}
+
+/// プロジェクトのルートとみなすディレクトリの目印
+const PROJECT_MARKERS: &[&str] = &[".git", ".hg", ".jj", ".eng-project", "Cargo.toml", "package.json", "go.mod"];
+
+/// path を含むプロジェクトのルート。目印が見つからなければ path のディレクトリ自体。
+/// Agent はプロジェクトごとにコアを起動するので、コアのセッションも同じ定義で分ける。
+pub fn project_root(path: &Path) -> PathBuf {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir = if path.is_dir() { path.as_path() } else { path.parent().unwrap_or(&path) };
+    dir.ancestors()
+        .find(|dir| PROJECT_MARKERS.iter().any(|marker| dir.join(marker).exists()))
+        .unwrap_or(dir)
+        .to_path_buf()
+}
+
+/// dir を含むプロジェクト (project_root) のセッション名。ルートのパスの "/" を "!" に置き換えた名前にする
+/// (例: "!home!me!project")。
+pub fn project_session_name(dir: &Path) -> String {
+    project_root(dir).to_string_lossy().replace('/', "!")
+}
+
+pub fn session_path(dir: &Path, session: &str) -> Result<PathBuf, String> {
+    if session.is_empty() || session.contains('/') || session.starts_with('.') {
+        return Err(format!("Invalid session name: {}", session));
+    }
+    Ok(dir.join(format!("{}.{}", session, DESKTOP_FILE_EXTENSION)))
+}
+
+/// 保存するファイルのバッファ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesktopBuffer {
+    pub path: PathBuf,
+    pub point: usize,
+    pub major_mode: String,
+    pub minor_modes: Vec<String>,
+    pub read_only: bool,
+    /// 保存していない変更があれば、その時点の内容。コアが落ちても次の起動で復元する。
+    pub contents: Option<String>,
+    /// 未保存の内容が元にしているファイルの更新時刻 (Buffer::file_mtime)。ファイルが無ければ None。
+    /// 復元するときにファイルの更新時刻が違えば、ファイルが外で変えられたので上書きしない。
+    pub file_mtime: Option<SystemTime>,
+}
+
+/// 保存するWindow。分割状態の buffer_id には buffers の番号を入れる (保存しないバッファは空)。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesktopWindow {
+    pub width: u16,
+    pub height: u16,
+    pub state: WindowState,
+}
+
+/// セッションファイルの内容 (desktop + savehist + saveplace)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Desktop {
+    /// 最近表示した順
+    pub buffers: Vec<DesktopBuffer>,
+    pub windows: Vec<DesktopWindow>,
+    pub histories: Vec<(String, Vec<String>)>,
+    pub places: Vec<(PathBuf, usize)>,
+}
+
+/// タブと改行をエスケープする。行は lines() で読むので、行末の \r が消えないよう \r もエスケープする
+fn escape(field: &str) -> String {
+    field.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('t') => out.push('\t'),
+                Some('n') => out.push('\n'),
+                Some('r') => out.push('\r'),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            },
+            (c, false) => out.push(c),
+        }
+    }
+    out
+}
+
+fn parse_number<T: std::str::FromStr>(field: &str) -> Result<T, String> {
+    field.parse().map_err(|_| format!("Invalid number in desktop file: {}", field))
+}
+
+/// 分割状態を空白区切りの前置記法にする。
+/// view <buffer|-> <point> <scroll> <selected> / below|right <n> (<size> <node>)*n
+fn write_window_state(state: &WindowState, out: &mut Vec<String>) {
+    match state {
+        WindowState::View(view) => {
+            let buffer = if view.buffer_id.is_empty() { "-".to_string() } else { view.buffer_id.clone() };
+            out.extend([
+                "view".to_string(),
+                buffer,
+                view.point.to_string(),
+                view.scroll_line.to_string(),
+                u8::from(view.selected).to_string(),
+            ]);
+        }
+        WindowState::Split { direction, children } => {
+            let direction = match direction {
+                SplitDirection::Below => "below",
+                SplitDirection::Right => "right",
+            };
+            out.extend([direction.to_string(), children.len().to_string()]);
+            for (child, size) in children {
+                out.push(size.to_string());
+                write_window_state(child, out);
+            }
+        }
+    }
+}
+
+fn read_window_state<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<WindowState, String> {
+    let mut next = || tokens.next().ok_or_else(|| "Truncated window state in desktop file".to_string());
+    match next()? {
+        "view" => {
+            let buffer = next()?;
+            Ok(WindowState::View(ViewState {
+                buffer_id: if buffer == "-" { String::new() } else { buffer.to_string() },
+                point: parse_number(next()?)?,
+                scroll_line: parse_number(next()?)?,
+                selected: next()? == "1",
+            }))
+        }
+        direction @ ("below" | "right") => {
+            let direction = if direction == "below" { SplitDirection::Below } else { SplitDirection::Right };
+            let count: usize = parse_number(next()?)?;
+            let mut children = Vec::with_capacity(count);
+            for _ in 0..count {
+                let size = parse_number(tokens.next().ok_or("Truncated window state in desktop file")?)?;
+                children.push((read_window_state(tokens)?, size));
+            }
+            Ok(WindowState::Split { direction, children })
+        }
+        other => Err(format!("Invalid window state in desktop file: {}", other)),
+    }
+}
+
+impl Desktop {
+    /// タブ区切りの行形式にする。先頭行は "eng-desktop <版>"。
+    pub fn serialize(&self) -> String {
+        let mut text = format!("{} {}\n", DESKTOP_FILE_HEADER, DESKTOP_FILE_VERSION);
+        for buffer in &self.buffers {
+            text.push_str(&format!(
+                "buffer\t{}\t{}\t{}\t{}\t{}\n",
+                escape(&buffer.path.to_string_lossy()),
+                buffer.point,
+                escape(&buffer.major_mode),
+                escape(&buffer.minor_modes.join(" ")),
+                u8::from(buffer.read_only),
+            ));
+        }
+        for (i, buffer) in self.buffers.iter().enumerate() {
+            if let Some(contents) = &buffer.contents {
+                let mtime = buffer.file_mtime.and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+                let mtime = mtime.map(|t| t.as_nanos().to_string()).unwrap_or_default();
+                text.push_str(&format!("autosave\t{}\t{}\t{}\n", i, mtime, escape(contents)));
+            }
+        }
+        for window in &self.windows {
+            let mut tokens = Vec::new();
+            write_window_state(&window.state, &mut tokens);
+            text.push_str(&format!("window\t{}\t{}\t{}\n", window.width, window.height, tokens.join(" ")));
+        }
+        for (name, items) in &self.histories {
+            for item in items {
+                text.push_str(&format!("history\t{}\t{}\n", escape(name), escape(item)));
+            }
+        }
+        for (path, point) in &self.places {
+            text.push_str(&format!("place\t{}\t{}\n", escape(&path.to_string_lossy()), point));
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines();
+        let header = lines.next().unwrap_or_default();
+        let version = header
+            .strip_prefix(DESKTOP_FILE_HEADER)
+            .map(str::trim)
+            .ok_or("Not a desktop file")?;
+        if !(1..=DESKTOP_FILE_VERSION).contains(&parse_number::<u32>(version)?) {
+            return Err(format!("Unsupported desktop file version: {}", version));
+        }
+
+        let mut desktop = Desktop::default();
+        let mut histories: Vec<(String, Vec<String>)> = Vec::new();
+        for line in lines.filter(|l| !l.is_empty()) {
+            let fields: Vec<_> = line.split('\t').collect();
+            match fields.as_slice() {
+                ["buffer", path, point, major_mode, minor_modes, read_only] => {
+                    desktop.buffers.push(DesktopBuffer {
+                        path: PathBuf::from(unescape(path)),
+                        point: parse_number(point)?,
+                        major_mode: unescape(major_mode),
+                        minor_modes: unescape(minor_modes).split_whitespace().map(str::to_string).collect(),
+                        read_only: *read_only == "1",
+                        contents: None,
+                        file_mtime: None,
+                    });
+                }
+                // 版 2 の行には更新時刻が無い
+                ["autosave", index, contents] | ["autosave", index, _, contents] => {
+                    let buffer = desktop
+                        .buffers
+                        .get_mut(parse_number::<usize>(index)?)
+                        .ok_or_else(|| format!("Invalid line in desktop file: {}", line))?;
+                    buffer.contents = Some(unescape(contents));
+                    if let ["autosave", _, mtime, _] = fields.as_slice()
+                        && !mtime.is_empty()
+                    {
+                        buffer.file_mtime = Some(UNIX_EPOCH + Duration::from_nanos(parse_number(mtime)?));
+                    }
+                }
+                ["window", width, height, state] => {
+                    let mut tokens = state.split_whitespace();
+                    desktop.windows.push(DesktopWindow {
+                        width: parse_number(width)?,
+                        height: parse_number(height)?,
+                        state: read_window_state(&mut tokens)?,
+                    });
+                }
+                ["history", name, item] => {
+                    let name = unescape(name);
+                    match histories.iter_mut().find(|(n, _)| *n == name) {
+                        Some((_, items)) => items.push(unescape(item)),
+                        None => histories.push((name, vec![unescape(item)])),
+                    }
+                }
+                ["place", path, point] => desktop.places.push((PathBuf::from(unescape(path)), parse_number(point)?)),
+                _ => return Err(format!("Invalid line in desktop file: {}", line)),
+            }
+        }
+        desktop.histories = histories;
+        Ok(desktop)
+    }
+}
+
+/// エディタの現在の状態を取り出す。ファイルのバッファだけを保存する。
+pub async fn capture(state: &Arc<EditorState>) -> Desktop {
+    let mut desktop = Desktop::default();
+    let mut indices = HashMap::new();
+    let window_states = state.window_states().await;
+
+    for info in state.buffer_list().await {
+        let Some(path) = info.path else {
+            continue;
+        };
+        let Some(buffer) = state.get_buffer(&info.id).await else {
+            continue;
+        };
+        let (minor_modes, contents, file_mtime) = {
+            let buffer = buffer.read().await;
+            let contents = buffer.modified.then(|| buffer.snapshot().to_string());
+            (buffer.minor_modes.iter().cloned().collect(), contents, buffer.file_mtime)
+        };
+        // 表示中なら選択中のViewの point を優先する
+        let mut points = window_states.iter().flat_map(|(s, _, _)| view_states(s)).filter(|v| v.buffer_id == info.id);
+        let point = match points.clone().find(|v| v.selected).or_else(|| points.next()) {
+            Some(view) => view.point,
+            None => state.saved_place(&info.id).await,
+        };
+        indices.insert(info.id.clone(), desktop.buffers.len().to_string());
+        desktop.buffers.push(DesktopBuffer {
+            path,
+            point,
+            major_mode: info.major_mode,
+            minor_modes,
+            read_only: info.read_only,
+            contents,
+            file_mtime,
+        });
+    }
+
+    for (mut window_state, width, height) in window_states {
+        for view in window_state.views_mut() {
+            view.buffer_id = indices.get(&view.buffer_id).cloned().unwrap_or_default();
+        }
+        desktop.windows.push(DesktopWindow { width, height, state: window_state });
+    }
+    desktop.histories = state.histories().await;
+    let mut places: Vec<_> = state.places().await.into_iter().collect();
+    places.sort();
+    desktop.places = places;
+    desktop
+}
+
+fn view_states(state: &WindowState) -> Vec<&ViewState> {
+    match state {
+        WindowState::View(view) => vec![view],
+        WindowState::Split { children, .. } => children.iter().flat_map(|(child, _)| view_states(child)).collect(),
+    }
+}
+
+/// 復元の結果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestoreReport {
+    pub restored: usize,
+    /// そのうち未保存の内容を戻したバッファの数
+    pub recovered: usize,
+    /// 自動保存した後にファイルが変えられていたので、未保存の内容を別のバッファに戻したファイル
+    pub conflicts: Vec<PathBuf>,
+    /// 開けなかったファイルと理由
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl std::fmt::Display for RestoreReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Desktop: {} buffers restored", self.restored)?;
+        if self.recovered > 0 {
+            write!(f, ", {} recovered from auto-save", self.recovered)?;
+        }
+        if !self.conflicts.is_empty() {
+            write!(f, ", {} changed on disk since auto-save (kept in separate buffers)", self.conflicts.len())?;
+        }
+        if !self.failed.is_empty() {
+            write!(f, ", {} failed to restore", self.failed.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// 保存した状態を復元する。開けないファイルは飛ばして報告する。
+/// Windowの分割状態は、次に作るWindowから順に使われる。
+pub async fn restore(state: &Arc<EditorState>, desktop: Desktop) -> RestoreReport {
+    let mut report = RestoreReport::default();
+    for (path, point) in desktop.places {
+        state.set_place(path, point).await;
+    }
+    let mut ids = HashMap::new();
+    for (i, saved) in desktop.buffers.into_iter().enumerate() {
+        let id = match open_saved(state, &saved).await {
+            Ok((id, Recovery::Recovered)) => {
+                report.recovered += 1;
+                id
+            }
+            Ok((id, Recovery::Conflict)) => {
+                report.conflicts.push(saved.path.clone());
+                id
+            }
+            Ok((id, Recovery::None)) => id,
+            Err(e) => {
+                report.failed.push((saved.path, e));
+                continue;
+            }
+        };
+        if let Some(buffer) = state.get_buffer(&id).await
+            && buffer.read().await.major_mode != saved.major_mode
+            && state.set_major_mode(&id, &saved.major_mode).await.is_err()
+        {
+            // 定義の無くなったモードは自動判定のままにする
+        }
+        for mode in &saved.minor_modes {
+            let _ = state.set_minor_mode(&id, mode, Some(true)).await;
+        }
+        if let Some(buffer) = state.get_buffer(&id).await {
+            buffer.write().await.read_only = saved.read_only;
+        }
+        state.set_place(saved.path, saved.point).await;
+        ids.insert(i.to_string(), id);
+        report.restored += 1;
+    }
+
+    let windows = desktop
+        .windows
+        .into_iter()
+        .map(|mut window| {
+            for view in window.state.views_mut() {
+                view.buffer_id = ids.get(&view.buffer_id).cloned().unwrap_or_default();
+            }
+            (window.state, window.width, window.height)
+        })
+        .collect();
+    state.restore_windows(windows).await;
+    for (name, items) in desktop.histories {
+        state.set_history(&name, items).await;
+    }
+    report
+}
+
+/// 未保存の内容を戻したか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Recovery {
+    /// 未保存の内容は無い
+    None,
+    Recovered,
+    /// ファイルが外で変えられていたので、未保存の内容は別のバッファに戻した
+    Conflict,
+}
+
+/// 保存したバッファのファイルを開き、未保存の内容があれば戻す (recover-file)。
+/// 一度も保存していないファイルは、未保存の内容があれば新しいバッファとして開く。
+/// 自動保存した後にファイルが変えられていれば、どちらも失わないよう、ファイルはそのまま開いて
+/// 未保存の内容は "<名前> (auto-save)" という別のバッファに戻す。
+async fn open_saved(state: &Arc<EditorState>, saved: &DesktopBuffer) -> Result<(String, Recovery), String> {
+    let id = match (state.find_file(&saved.path).await, &saved.contents) {
+        (Ok(id), _) => id,
+        (Err(_), Some(_)) if !saved.path.exists() => {
+            let name = uniquify::file_name(&saved.path).unwrap_or("untitled").to_string();
+            let id = state.create_buffer(name, "").await;
+            state.set_buffer_file(&id, saved.path.clone()).await?;
+            id
+        }
+        (Err(e), _) => return Err(e),
+    };
+    let (Some(contents), Some(buffer)) = (&saved.contents, state.get_buffer(&id).await) else {
+        return Ok((id, Recovery::None));
+    };
+    if state::file_mtime(&saved.path).await != saved.file_mtime {
+        let name = format!("{} (auto-save)", buffer.read().await.name);
+        let copy = state.create_buffer(name, contents).await;
+        if let Some(copy) = state.get_buffer(&copy).await {
+            copy.write().await.modified = true;
+        }
+        return Ok((id, Recovery::Conflict));
+    }
+    let mut buffer = buffer.write().await;
+    if buffer.snapshot().to_string() == *contents {
+        return Ok((id, Recovery::None));
+    }
+    // 元に戻せるよう、ファイルの内容を置き換える編集として入れる
+    let len = buffer.len_chars();
+    buffer.replace(0..len, contents)?;
+    Ok((id, Recovery::Recovered))
+}
+
+/// セッションファイルへ保存する。他のユーザーから読めないように作る。
+pub async fn save(state: &Arc<EditorState>, path: &Path) -> Result<(), String> {
+    let text = capture(state).await.serialize();
+    write_private(path, &text).await
+}
+
+async fn write_private(path: &Path, text: &str) -> Result<(), String> {
+    use tokio::io::AsyncWriteExt;
+
+    let error = |e: std::io::Error| format!("Failed to write {}: {}", path.display(), e);
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await.map_err(error)?;
+    }
+    // 書き込み途中で終了しても前回のファイルが壊れないよう、一時ファイルから置き換える
+    let tmp = path.with_extension("tmp");
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp).await.map_err(error)?;
+    file.write_all(text.as_bytes()).await.map_err(error)?;
+    file.sync_all().await.map_err(error)?;
+    tokio::fs::rename(&tmp, path).await.map_err(error)
+}
+
+/// セッションファイルを読んで復元する
+pub async fn read(state: &Arc<EditorState>, path: &Path) -> Result<RestoreReport, String> {
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Ok(restore(state, Desktop::parse(&text)?).await)
+}
+
+/// desktop-save-mode を有効にし、セッションファイルがあれば復元する。復元した結果は GetDesktopStatus で返す。
+pub async fn enable(state: &Arc<EditorState>, config: DesktopConfig) -> Result<Option<RestoreReport>, String> {
+    let path = config.path()?;
+    *state.desktop().lock().await = Some(config);
+    if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+        return Ok(None);
+    }
+    let report = read(state, &path).await?;
+    *state.restored().lock().await = Some(report.clone());
+    Ok(Some(report))
+}
+
+/// desktop-save-mode が有効なら現在のセッションを保存する。保存したファイルを返す。
+pub async fn save_current(state: &Arc<EditorState>) -> Result<Option<PathBuf>, String> {
+    let Some(config) = state.desktop().lock().await.clone() else {
+        return Ok(None);
+    };
+    let path = config.path()?;
+    save(state, &path).await?;
+    Ok(Some(path))
+}
+
+/// 入力が空なら現在のセッション名を使う
+async fn session_config(ctx: &CommandContext) -> Result<DesktopConfig, String> {
+    let name = ctx.string_arg(0)?.trim();
+    let current = ctx.state.desktop().lock().await.clone();
+    let mut config = match current {
+        Some(config) => config,
+        None => DesktopConfig::from_env().ok_or("Desktop sessions are disabled")?,
+    };
+    if !name.is_empty() {
+        config.session = name.to_string();
+    }
+    Ok(config)
+}
+
+pub(crate) fn register_commands(r: &mut CommandRegistry) -> Result<(), String> {
+    r.register("desktop-save", "Save the state of the editor to a named session.", "sSave desktop session (default current): ", desktop_save)?;
+    r.register("desktop-read", "Restore the editor state saved in a named session.", "sRead desktop session (default current): ", desktop_read)?;
+    Ok(())
+}
+
+/// 保存したセッションを以降の自動保存先にする
+async fn desktop_save(ctx: CommandContext) -> CommandResult {
+    let config = session_config(&ctx).await?;
+    let path = config.path()?;
+    save(&ctx.state, &path).await?;
+    *ctx.state.desktop().lock().await = Some(config);
+    Ok(Some(format!("Desktop saved in {}", path.display())))
+}
+
+async fn desktop_read(ctx: CommandContext) -> CommandResult {
+    let config = session_config(&ctx).await?;
+    let report = read(&ctx.state, &config.path()?).await?;
+    *ctx.state.desktop().lock().await = Some(config);
+    Ok(Some(report.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("eng-desktop-test-{}-{}", name, uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_serialize_roundtrip() {
+        let desktop = Desktop {
+            buffers: vec![DesktopBuffer {
+                path: PathBuf::from("/tmp/tab\tname.rs"),
+                point: 42,
+                major_mode: "rust-mode".into(),
+                minor_modes: vec!["auto-fill-mode".into(), "overwrite-mode".into()],
+                read_only: true,
+                contents: Some("fn main() {\n\t// unsaved\n}\n".into()),
+                file_mtime: Some(UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789)),
+            }],
+            windows: vec![DesktopWindow {
+                width: 120,
+                height: 40,
+                state: WindowState::Split {
+                    direction: SplitDirection::Right,
+                    children: vec![
+                        (WindowState::View(ViewState { buffer_id: "0".into(), point: 3, scroll_line: 1, selected: true }), 60),
+                        (WindowState::View(ViewState { buffer_id: String::new(), point: 0, scroll_line: 0, selected: false }), 60),
+                    ],
+                },
+            }],
+            histories: vec![("extended-command-history".into(), vec!["forward-char".into(), "line\nbreak".into()])],
+            places: vec![(PathBuf::from("/tmp/old.txt"), 7)],
+        };
+        assert_eq!(Desktop::parse(&desktop.serialize()).unwrap(), desktop);
+        // 版 2 の autosave 行には更新時刻が無い
+        let old = Desktop::parse("eng-desktop 2\nbuffer\t/tmp/a\t0\ttext-mode\t\t0\nautosave\t0\tdraft\n").unwrap();
+        assert_eq!((old.buffers[0].contents.as_deref(), old.buffers[0].file_mtime), (Some("draft"), None));
+        assert!(Desktop::parse("eng-desktop 99\n").is_err());
+        assert!(Desktop::parse("something else").is_err());
+    }
+
+    #[test]
+    fn test_carriage_return_roundtrip() {
+        let desktop = Desktop {
+            buffers: vec![DesktopBuffer {
+                path: PathBuf::from("/tmp/name\r"),
+                point: 0,
+                major_mode: "text-mode".into(),
+                minor_modes: Vec::new(),
+                read_only: false,
+                contents: Some("line 1\r\nline 2\r\n\\r\r".into()),
+                file_mtime: None,
+            }],
+            windows: Vec::new(),
+            histories: vec![("shell-command-history".into(), vec!["echo\r".into()])],
+            places: vec![(PathBuf::from("/tmp/place\r"), 1)],
+        };
+        let text = desktop.serialize();
+        assert!(!text.contains('\r'));
+        assert_eq!(Desktop::parse(&text).unwrap(), desktop);
+        assert_eq!(Desktop::parse(&text.replace('\n', "\r\n")).unwrap(), desktop);
+    }
+
+    #[test]
+    fn test_session_names() {
+        let dir = temp_dir("project");
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::create_dir_all(dir.join("src/sub")).unwrap();
+        let name = project_session_name(&dir.join("src/sub"));
+        assert_eq!(name, dir.to_string_lossy().replace('/', "!"));
+        // .git 以外の目印も、Agent がコアを分けるのと同じ定義でプロジェクトのルートとする
+        std::fs::write(dir.join("src/Cargo.toml"), "").unwrap();
+        assert_eq!(project_root(&dir.join("src/sub/main.rs")), dir.join("src"));
+        assert_eq!(project_session_name(&dir.join("src/sub")), dir.join("src").to_string_lossy().replace('/', "!"));
+        assert!(session_path(&dir, "../escape").is_err());
+        assert_eq!(session_path(&dir, "work").unwrap(), dir.join("work.desktop"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_save_and_restore() {
+        let dir = temp_dir("session");
+        let main_rs = dir.join("main.rs");
+        let notes = dir.join("notes.txt");
+        std::fs::write(&main_rs, "fn main() {\n    println!();\n}\n").unwrap();
+        std::fs::write(&notes, "hello\n").unwrap();
+
+        let state = Arc::new(EditorState::new());
+        let main_id = state.find_file(&main_rs).await.unwrap();
+        let notes_id = state.find_file(&notes).await.unwrap();
+        state.set_minor_mode(&notes_id, "auto-fill-mode", Some(true)).await.unwrap();
+        let window_id = state.create_window(&main_id).await.unwrap();
+        state
+            .update_window(&window_id, |w| {
+                let selected = w.selected_view().id.clone();
+                w.selected_view_mut().point = 16;
+                w.split_view(&selected, SplitDirection::Below)
+            })
+            .await
+            .unwrap();
+        state.add_history("extended-command-history", "forward-char").await;
+
+        let path = session_path(&dir, "work").unwrap();
+        save(&state, &path).await.unwrap();
+        std::fs::remove_file(&notes).unwrap();
+
+        // 新しく起動したエディタで復元する
+        let restored = Arc::new(EditorState::new());
+        let report = read(&restored, &path).await.unwrap();
+        assert_eq!(report.restored, 1);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(restored.history("extended-command-history").await, ["forward-char"]);
+
+        let main_id = restored.find_file_buffer(&main_rs).await.unwrap();
+        let scratch = restored.create_buffer("scratch".into(), "").await;
+        let window_id = restored.create_window(&scratch).await.unwrap();
+        let layout = restored.window_layout(&window_id).await.unwrap();
+        assert_eq!(layout.views.len(), 2);
+        assert!(layout.views.iter().all(|v| v.view.buffer_id == main_id && v.view.point == 16));
+        // 復元待ちの分割状態は一度だけ使う
+        let second = restored.create_window(&scratch).await.unwrap();
+        assert_eq!(restored.window_layout(&second).await.unwrap().views.len(), 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recover_unsaved_contents() {
+        let dir = temp_dir("recover");
+        let main_rs = dir.join("main.rs");
+        let draft = dir.join("draft.txt");
+        std::fs::write(&main_rs, "fn main() {}\n").unwrap();
+
+        let state = Arc::new(EditorState::new());
+        let main_id = state.find_file(&main_rs).await.unwrap();
+        state.get_buffer(&main_id).await.unwrap().write().await.insert(0, "// edited\n").unwrap();
+        // 一度も保存していないファイル
+        let draft_id = state.create_buffer("draft.txt".into(), "").await;
+        state.set_buffer_file(&draft_id, draft.clone()).await.unwrap();
+        state.get_buffer(&draft_id).await.unwrap().write().await.insert(0, "todo\n").unwrap();
+
+        let path = session_path(&dir, "crashed").unwrap();
+        save(&state, &path).await.unwrap();
+
+        // 落ちたコアの代わりに起動したエディタで復元する
+        let restored = Arc::new(EditorState::new());
+        let report = read(&restored, &path).await.unwrap();
+        assert_eq!((report.restored, report.recovered), (2, 2));
+        assert!(report.failed.is_empty());
+        for (file, text) in [(&main_rs, "// edited\nfn main() {}\n"), (&draft, "todo\n")] {
+            let id = restored.find_file_buffer(file).await.unwrap();
+            let buffer = restored.get_buffer(&id).await.unwrap();
+            let buffer = buffer.read().await;
+            assert_eq!(buffer.snapshot().to_string(), text);
+            assert!(buffer.modified);
+        }
+        // ファイル自体は書き換えない
+        assert_eq!(std::fs::read_to_string(&main_rs).unwrap(), "fn main() {}\n");
+        assert!(!draft.exists());
+
+        // 自動保存した後にファイルが変えられていれば、ファイルの内容は上書きせずに別のバッファへ戻す
+        std::fs::write(&main_rs, "fn main() { changed() }\n").unwrap();
+        let modified = std::time::SystemTime::now() + Duration::from_secs(5);
+        std::fs::File::options().write(true).open(&main_rs).unwrap().set_modified(modified).unwrap();
+        let restored = Arc::new(EditorState::new());
+        let report = read(&restored, &path).await.unwrap();
+        assert_eq!((report.restored, report.recovered), (2, 1));
+        assert_eq!(report.conflicts, vec![main_rs.clone()]);
+        let id = restored.find_file_buffer(&main_rs).await.unwrap();
+        let buffer = restored.get_buffer(&id).await.unwrap();
+        assert_eq!(buffer.read().await.snapshot().to_string(), "fn main() { changed() }\n");
+        assert!(!buffer.read().await.modified);
+        let copy = restored.buffer_list().await.into_iter().find(|info| info.name == "main.rs (auto-save)").unwrap();
+        let copy = restored.get_buffer(&copy.id).await.unwrap();
+        assert_eq!(copy.read().await.snapshot().to_string(), "// edited\nfn main() {}\n");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// 話せるプロトコルの最も新しい版。互換性の無い変更をしたら上げる。
+pub const PROTOCOL_VERSION: u32 = 1;
+/// 受け入れる最も古いプロトコルの版
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// 提供する機能。クライアントは使う前にハンドシェイクで確かめる。
+pub const CAPABILITIES: &[&str] = &[
+    "layout",
+    "minibuffer",
+    "buffer-service",
+    "buffer-subscription",
+    "quit",
+    "activity",
+    "latency-metrics",
+];
+/// テキストのエンコーディング (希望順)
+pub const ENCODINGS: &[&str] = &["utf-8"];
+
+/// 接続してきたクライアントの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientKind {
+    Gui,
+    Tui,
+    Web,
+    Cli,
+    Agent,
+}
+
+impl ClientKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ClientKind::Gui => "gui",
+            ClientKind::Tui => "tui",
+            ClientKind::Web => "web",
+            ClientKind::Cli => "cli",
+            ClientKind::Agent => "agent",
+        }
+    }
+}
+
+/// クライアントがハンドシェイクで送る情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    /// クライアントが話せる版の範囲
+    pub min_protocol_version: u32,
+    pub protocol_version: u32,
+    pub kind: ClientKind,
+    pub name: String,
+    pub capabilities: Vec<String>,
+    /// 希望順。空なら utf-8
+    pub encodings: Vec<String>,
+    /// 再接続時の前回のセッションID
+    pub session_id: Option<String>,
+}
+
+/// ハンドシェイクの結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Welcome {
+    /// 以降に使う版
+    pub protocol_version: u32,
+    pub client_id: String,
+    pub session_id: String,
+    /// 双方が対応している機能
+    pub capabilities: Vec<String>,
+    pub encoding: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// 最初のメッセージが届く前にストリームが閉じた
+    NoHello,
+    UnknownClientKind,
+    /// 話せる版の範囲が重ならない
+    IncompatibleVersion { min: u32, max: u32 },
+    /// 版を送ってこない旧プロトコルのクライアント
+    LegacyClient,
+    /// 版を返さない旧プロトコルのサーバー
+    LegacyServer,
+    NoCommonEncoding(Vec<String>),
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::NoHello => write!(f, "Handshake closed before the client sent its hello"),
+            HandshakeError::UnknownClientKind => write!(f, "Client kind is not specified"),
+            HandshakeError::IncompatibleVersion { min, max } => write!(
+                f,
+                "Incompatible protocol version: client speaks {}..={}, server speaks {}..={}",
+                min, max, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            HandshakeError::LegacyClient => write!(
+                f,
+                "Incompatible protocol version: client speaks the legacy protocol without version negotiation, server speaks {}..={}",
+                MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            HandshakeError::LegacyServer => write!(
+                f,
+                "Incompatible protocol version: server speaks the legacy protocol without version negotiation, client speaks {}..={}",
+                MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            HandshakeError::NoCommonEncoding(encodings) => write!(
+                f,
+                "No common encoding: client offers {}, server supports {}",
+                encodings.join(", "),
+                ENCODINGS.join(", ")
+            ),
+        }
+    }
+}
+
+/// 版・機能・エンコーディングを取り決める。クライアントIDはここで割り当てる。
+pub fn negotiate(hello: &Hello) -> Result<Welcome, HandshakeError> {
+    let protocol_version = hello.protocol_version.min(PROTOCOL_VERSION);
+    if protocol_version < hello.min_protocol_version.max(MIN_PROTOCOL_VERSION) {
+        return Err(HandshakeError::IncompatibleVersion {
+            min: hello.min_protocol_version,
+            max: hello.protocol_version,
+        });
+    }
+    let encoding = if hello.encodings.is_empty() {
+        ENCODINGS[0].to_string()
+    } else {
+        hello
+            .encodings
+            .iter()
+            .find(|e| ENCODINGS.contains(&e.to_ascii_lowercase().as_str()))
+            .map(|e| e.to_ascii_lowercase())
+            .ok_or_else(|| HandshakeError::NoCommonEncoding(hello.encodings.clone()))?
+    };
+    // クライアントが何も挙げなければ、提供する機能を全て知らせる
+    let capabilities = CAPABILITIES
+        .iter()
+        .filter(|c| hello.capabilities.is_empty() || hello.capabilities.iter().any(|h| h == *c))
+        .map(|c| c.to_string())
+        .collect();
+    Ok(Welcome {
+        protocol_version,
+        client_id: uuid::Uuid::new_v4().to_string(),
+        session_id: hello
+            .session_id
+            .clone()
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+        capabilities,
+        encoding,
+    })
+}
+
+/// サーバーが応答で選んだ版を確かめる。版が無ければ旧プロトコルのサーバー。
+pub fn check_server_version(protocol_version: u32) -> Result<u32, HandshakeError> {
+    match protocol_version {
+        0 => Err(HandshakeError::LegacyServer),
+        version => Ok(version),
+    }
+}
+
+/// 接続中のクライアント
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub session_id: String,
+    pub kind: ClientKind,
+    pub name: String,
+    pub protocol_version: u32,
+}
+
+/// ハンドシェイクを済ませて接続中のクライアントの一覧。await をまたいでロックを保持しない。
+#[derive(Debug, Default)]
+pub struct Clients {
+    clients: Mutex<HashMap<String, ClientInfo>>,
+}
+
+impl Clients {
+    fn clients(&self) -> std::sync::MutexGuard<'_, HashMap<String, ClientInfo>> {
+        self.clients.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 接続を記録する。返した ClientGuard を drop すると (切断すると) 一覧から外れる。
+    pub fn connect(self: &Arc<Self>, hello: &Hello, welcome: &Welcome) -> ClientGuard {
+        let info = ClientInfo {
+            client_id: welcome.client_id.clone(),
+            session_id: welcome.session_id.clone(),
+            kind: hello.kind,
+            name: hello.name.clone(),
+            protocol_version: welcome.protocol_version,
+        };
+        self.clients().insert(info.client_id.clone(), info);
+        ClientGuard { clients: self.clone(), client_id: welcome.client_id.clone() }
+    }
+
+    /// 接続した順ではなくIDの順
+    pub fn list(&self) -> Vec<ClientInfo> {
+        let mut clients: Vec<_> = self.clients().values().cloned().collect();
+        clients.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        clients
+    }
+
+    pub fn get(&self, client_id: &str) -> Option<ClientInfo> {
+        self.clients().get(client_id).cloned()
+    }
+}
+
+#[derive(Debug)]
+pub struct ClientGuard {
+    clients: Arc<Clients>,
+    pub client_id: String,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.clients.clients().remove(&self.client_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(min: u32, max: u32) -> Hello {
+        Hello {
+            min_protocol_version: min,
+            protocol_version: max,
+            kind: ClientKind::Tui,
+            name: "test".into(),
+            capabilities: vec!["layout".into(), "telepathy".into()],
+            encodings: vec!["latin-1".into(), "UTF-8".into()],
+            session_id: None,
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let welcome = negotiate(&hello(1, PROTOCOL_VERSION + 3)).unwrap();
+        assert_eq!(welcome.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(welcome.capabilities, ["layout"]);
+        assert_eq!(welcome.encoding, "utf-8");
+        assert_ne!(welcome.client_id, welcome.session_id);
+
+        let resumed = negotiate(&Hello { session_id: Some(welcome.session_id.clone()), ..hello(1, 1) }).unwrap();
+        assert_eq!(resumed.session_id, welcome.session_id);
+        assert_ne!(resumed.client_id, welcome.client_id);
+        let all = negotiate(&Hello { capabilities: vec![], encodings: vec![], ..hello(1, 1) }).unwrap();
+        assert_eq!(all.capabilities.len(), CAPABILITIES.len());
+
+        let newer = PROTOCOL_VERSION + 1;
+        let error = negotiate(&hello(newer, newer + 1)).unwrap_err();
+        assert_eq!(error, HandshakeError::IncompatibleVersion { min: newer, max: newer + 1 });
+        assert!(error.to_string().starts_with("Incompatible protocol version"));
+        let error = negotiate(&Hello { encodings: vec!["latin-1".into()], ..hello(1, 1) }).unwrap_err();
+        assert_eq!(error, HandshakeError::NoCommonEncoding(vec!["latin-1".into()]));
+    }
+
+    #[test]
+    fn test_clients() {
+        let clients = Arc::new(Clients::default());
+        let hello = hello(1, 1);
+        let welcome = negotiate(&hello).unwrap();
+        let guard = clients.connect(&hello, &welcome);
+        let info = clients.get(&guard.client_id).unwrap();
+        assert_eq!((info.kind, info.name.as_str()), (ClientKind::Tui, "test"));
+        assert_eq!(clients.list().len(), 1);
+        drop(guard);
+        assert!(clients.list().is_empty());
+    }
+}
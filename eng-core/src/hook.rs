@@ -0,0 +1,448 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use futures_util::FutureExt;
+
+use crate::command::{execute_command, CommandArg, CommandContext, CommandError, CommandInvocation, CommandRegistry, CommandResult, PrefixArg};
+use crate::state::EditorState;
+
+/// 保持するフックのエラーの数。古いものから捨てる。
+const HOOK_ERROR_LOG_LENGTH: usize = 100;
+
+/// フックの種類 (Emacs のフック変数に相当)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Hook {
+    BeforeSave,
+    AfterSave,
+    AfterChange,
+    FindFile,
+    KillBuffer,
+    PreCommand,
+    PostCommand,
+}
+
+impl Hook {
+    pub const ALL: [Hook; 7] = [
+        Hook::BeforeSave,
+        Hook::AfterSave,
+        Hook::AfterChange,
+        Hook::FindFile,
+        Hook::KillBuffer,
+        Hook::PreCommand,
+        Hook::PostCommand,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::BeforeSave => "before-save-hook",
+            Hook::AfterSave => "after-save-hook",
+            Hook::AfterChange => "after-change-functions",
+            Hook::FindFile => "find-file-hook",
+            Hook::KillBuffer => "kill-buffer-hook",
+            Hook::PreCommand => "pre-command-hook",
+            Hook::PostCommand => "post-command-hook",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Hook> {
+        Hook::ALL.into_iter().find(|hook| hook.name() == name)
+    }
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// フックに渡すイベント。位置は文字単位。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookEvent {
+    /// 保存の直前。ハンドラはバッファを書き換えてよい (末尾の空白の削除など)。
+    BeforeSave { buffer_id: String },
+    AfterSave { buffer_id: String },
+    /// start..end が変更後のテキスト、old_len は置き換えられた元のテキストの長さ
+    AfterChange { buffer_id: String, start: usize, end: usize, old_len: usize },
+    /// ファイルを新しく開いた後
+    FindFile { buffer_id: String },
+    /// バッファを削除する直前
+    KillBuffer { buffer_id: String },
+    PreCommand { window_id: String, command: String },
+    PostCommand { window_id: String, command: String },
+}
+
+impl HookEvent {
+    pub fn hook(&self) -> Hook {
+        match self {
+            HookEvent::BeforeSave { .. } => Hook::BeforeSave,
+            HookEvent::AfterSave { .. } => Hook::AfterSave,
+            HookEvent::AfterChange { .. } => Hook::AfterChange,
+            HookEvent::FindFile { .. } => Hook::FindFile,
+            HookEvent::KillBuffer { .. } => Hook::KillBuffer,
+            HookEvent::PreCommand { .. } => Hook::PreCommand,
+            HookEvent::PostCommand { .. } => Hook::PostCommand,
+        }
+    }
+
+    pub fn buffer_id(&self) -> Option<&str> {
+        match self {
+            HookEvent::BeforeSave { buffer_id }
+            | HookEvent::AfterSave { buffer_id }
+            | HookEvent::AfterChange { buffer_id, .. }
+            | HookEvent::FindFile { buffer_id }
+            | HookEvent::KillBuffer { buffer_id } => Some(buffer_id),
+            HookEvent::PreCommand { .. } | HookEvent::PostCommand { .. } => None,
+        }
+    }
+}
+
+pub type HookFuture = Pin<Box<dyn Future<Output = Result<(), String>> + Send>>;
+type HookFn = Arc<dyn Fn(Arc<EditorState>, HookEvent) -> HookFuture + Send + Sync>;
+
+/// subscribe の戻り値。unsubscribe に使う。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+#[derive(Clone)]
+struct Subscriber {
+    id: SubscriptionId,
+    name: String,
+    priority: i32,
+    handler: HookFn,
+}
+
+/// フックのハンドラが失敗した記録
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookError {
+    pub hook: Hook,
+    /// 失敗したハンドラの名前
+    pub subscriber: String,
+    pub message: String,
+}
+
+impl fmt::Display for HookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Error in {} ({}): {}", self.hook, self.subscriber, self.message)
+    }
+}
+
+/// フックごとのハンドラの一覧。Rustのモジュールはハンドラを直接、
+/// 拡張はコマンド名を add-hook で登録する。
+/// ハンドラのエラーやパニックは他のハンドラや呼び出し元へ波及させず、記録して報告する。
+#[derive(Default)]
+pub struct HookBus {
+    /// priority の昇順 (同じなら登録順)。ロックは await をまたいで保持しない。
+    subscribers: RwLock<HashMap<Hook, Vec<Subscriber>>>,
+    next_id: AtomicU64,
+    errors: Mutex<VecDeque<HookError>>,
+}
+
+impl fmt::Debug for HookBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HookBus")
+            .field("subscribers", &self.subscribers().iter().map(|(hook, names)| (hook.name(), names.len())).collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+impl HookBus {
+    /// ハンドラを登録する。priority の小さいものから順に実行する (add-hook の DEPTH と同じ)。
+    /// 同じフックに同じ名前で登録すると置き換える。
+    pub fn subscribe<F, Fut>(&self, hook: Hook, name: &str, priority: i32, handler: F) -> SubscriptionId
+    where
+        F: Fn(Arc<EditorState>, HookEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let id = SubscriptionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let subscriber = Subscriber {
+            id,
+            name: name.to_string(),
+            priority,
+            handler: Arc::new(move |state, event| Box::pin(handler(state, event))),
+        };
+        let mut subscribers = self.subscribers.write().unwrap_or_else(|e| e.into_inner());
+        let list = subscribers.entry(hook).or_default();
+        list.retain(|s| s.name != name);
+        let index = list.partition_point(|s| s.priority <= priority);
+        list.insert(index, subscriber);
+        id
+    }
+
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut subscribers = self.subscribers.write().unwrap_or_else(|e| e.into_inner());
+        subscribers.values_mut().any(|list| {
+            let len = list.len();
+            list.retain(|s| s.id != id);
+            list.len() != len
+        })
+    }
+
+    /// 名前で登録を外す (remove-hook)
+    pub fn remove(&self, hook: Hook, name: &str) -> bool {
+        let mut subscribers = self.subscribers.write().unwrap_or_else(|e| e.into_inner());
+        let Some(list) = subscribers.get_mut(&hook) else {
+            return false;
+        };
+        let len = list.len();
+        list.retain(|s| s.name != name);
+        list.len() != len
+    }
+
+    /// フックごとの登録済みハンドラの名前 (実行順)
+    pub fn subscribers(&self) -> Vec<(Hook, Vec<String>)> {
+        let subscribers = self.subscribers.read().unwrap_or_else(|e| e.into_inner());
+        let mut result: Vec<_> = subscribers
+            .iter()
+            .filter(|(_, list)| !list.is_empty())
+            .map(|(hook, list)| (*hook, list.iter().map(|s| s.name.clone()).collect()))
+            .collect();
+        result.sort();
+        result
+    }
+
+    pub fn has_subscribers(&self, hook: Hook) -> bool {
+        let subscribers = self.subscribers.read().unwrap_or_else(|e| e.into_inner());
+        subscribers.get(&hook).is_some_and(|list| !list.is_empty())
+    }
+
+    /// 最近のハンドラのエラー (古い順)
+    pub fn errors(&self) -> Vec<HookError> {
+        self.errors.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+    }
+
+    fn record_error(&self, error: HookError) {
+        eprintln!("{}", error);
+        let mut errors = self.errors.lock().unwrap_or_else(|e| e.into_inner());
+        if errors.len() == HOOK_ERROR_LOG_LENGTH {
+            errors.pop_front();
+        }
+        errors.push_back(error);
+    }
+
+    fn handlers(&self, hook: Hook) -> Vec<Subscriber> {
+        let subscribers = self.subscribers.read().unwrap_or_else(|e| e.into_inner());
+        subscribers.get(&hook).cloned().unwrap_or_default()
+    }
+}
+
+/// イベントのフックを実行する (run-hooks)。
+/// 失敗したハンドラがあっても残りのハンドラは実行し、失敗したものの一覧を返す。
+/// 呼び出し元はバッファやWindowのロックを保持していてはならない。
+pub async fn run(state: &Arc<EditorState>, event: HookEvent) -> Vec<HookError> {
+    let hook = event.hook();
+    let mut errors = Vec::new();
+    for subscriber in state.hooks().handlers(hook) {
+        let future = (subscriber.handler)(state.clone(), event.clone());
+        let message = match AssertUnwindSafe(future).catch_unwind().await {
+            Ok(Ok(())) => continue,
+            Ok(Err(message)) => message,
+            Err(panic) => match panic.downcast_ref::<&str>() {
+                Some(message) => format!("panicked: {}", message),
+                None => match panic.downcast_ref::<String>() {
+                    Some(message) => format!("panicked: {}", message),
+                    None => "panicked".to_string(),
+                },
+            },
+        };
+        let error = HookError { hook, subscriber: subscriber.name, message };
+        state.hooks().record_error(error.clone());
+        errors.push(error);
+    }
+    errors
+}
+
+/// 前回からのバッファの変更を after-change-functions に渡す。
+/// ハンドラ自身による変更では after-change-functions を実行しない (inhibit-modification-hooks)。
+pub async fn run_after_change(state: &Arc<EditorState>) -> Vec<HookError> {
+    let changes = state.take_changes().await;
+    if changes.is_empty() || !state.hooks().has_subscribers(Hook::AfterChange) {
+        return Vec::new();
+    }
+    let mut errors = Vec::new();
+    for (buffer_id, change) in changes {
+        let event = HookEvent::AfterChange { buffer_id, start: change.start, end: change.end, old_len: change.old_len };
+        errors.extend(run(state, event).await);
+    }
+    state.take_changes().await;
+    errors
+}
+
+/// コマンドの実行後の処理。after-change-functions と post-command-hook を実行する。
+pub async fn command_finished(state: &Arc<EditorState>, window_id: &str, command: &str) -> Vec<HookError> {
+    let mut errors = run_after_change(state).await;
+    let event = HookEvent::PostCommand { window_id: window_id.to_string(), command: command.to_string() };
+    errors.extend(run(state, event).await);
+    errors
+}
+
+/// コマンドをフックのハンドラとして実行するWindow。
+/// バッファのイベントなら、そのバッファを表示しているWindowを優先する。
+async fn hook_window(state: &EditorState, event: &HookEvent) -> Result<String, String> {
+    if let HookEvent::PreCommand { window_id, .. } | HookEvent::PostCommand { window_id, .. } = event {
+        return Ok(window_id.clone());
+    }
+    let windows = state.window_ids().await;
+    for window_id in &windows {
+        let shown = state.read_window(window_id, |w| w.selected_view().buffer_id.clone()).await?;
+        if Some(shown.as_str()) == event.buffer_id() {
+            return Ok(window_id.clone());
+        }
+    }
+    windows.into_iter().next().ok_or_else(|| "No window to run the hook command in".to_string())
+}
+
+/// コマンドをフックに追加する。コマンドは引数なしで呼ばれる。
+pub fn add_command_hook(state: &EditorState, hook: Hook, command: &str, priority: i32) -> SubscriptionId {
+    let name = command.to_string();
+    state.hooks().subscribe(hook, command, priority, move |state, event| {
+        let name = name.clone();
+        async move {
+            let window_id = hook_window(&state, &event).await?;
+            match execute_command(state, &window_id, &name, CommandInvocation::default()).await {
+                Ok(_) => Ok(()),
+                Err(CommandError::MissingArgument(request)) => {
+                    Err(format!("{} reads an argument ({})", name, request.prompt.trim()))
+                }
+                Err(e) => Err(e.to_string()),
+            }
+        }
+    })
+}
+
+fn hook_arg(ctx: &CommandContext) -> Result<Hook, String> {
+    let name = ctx.string_arg(0)?.trim();
+    Hook::from_name(name).ok_or_else(|| format!("Unknown hook: {}", name))
+}
+
+pub(crate) fn register_commands(r: &mut CommandRegistry) -> Result<(), String> {
+    r.register("add-hook", "Add a command to a hook. A numeric prefix gives its priority.", "sAdd to hook: \nCAdd command: \nP", add_hook)?;
+    r.register("remove-hook", "Remove a command from a hook.", "sRemove from hook: \nCRemove command: ", remove_hook)?;
+    Ok(())
+}
+
+async fn add_hook(ctx: CommandContext) -> CommandResult {
+    let hook = hook_arg(&ctx)?;
+    let command = ctx.string_arg(1)?.trim().to_string();
+    if ctx.state.commands().read().await.get(&command).is_none() {
+        return Err(CommandError::UnknownCommand(command));
+    }
+    let priority = match ctx.args.get(2) {
+        Some(CommandArg::Prefix(PrefixArg::None)) | None => 0,
+        _ => ctx.number_arg(2) as i32,
+    };
+    add_command_hook(&ctx.state, hook, &command, priority);
+    Ok(Some(format!("Added {} to {}", command, hook)))
+}
+
+async fn remove_hook(ctx: CommandContext) -> CommandResult {
+    let hook = hook_arg(&ctx)?;
+    let command = ctx.string_arg(1)?.trim();
+    if !ctx.state.hooks().remove(hook, command) {
+        return Err(format!("{} is not in {}", command, hook).into());
+    }
+    Ok(Some(format!("Removed {} from {}", command, hook)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+
+    fn recorder(log: &Arc<StdMutex<Vec<String>>>, label: &'static str) -> impl Fn(Arc<EditorState>, HookEvent) -> HookFuture + Send + Sync + 'static {
+        let log = log.clone();
+        move |_, _| {
+            log.lock().unwrap().push(label.to_string());
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_priority_and_error_isolation() {
+        let state = Arc::new(EditorState::new());
+        let log = Arc::new(StdMutex::new(Vec::new()));
+        let hooks = state.hooks();
+        hooks.subscribe(Hook::KillBuffer, "late", 10, recorder(&log, "late"));
+        hooks.subscribe(Hook::KillBuffer, "early", -10, recorder(&log, "early"));
+        hooks.subscribe(Hook::KillBuffer, "failing", 0, |_, _| async { Err("boom".to_string()) });
+        hooks.subscribe(Hook::KillBuffer, "panicking", 1, |_, _| async { panic!("oops") });
+        let id = hooks.subscribe(Hook::KillBuffer, "middle", 0, recorder(&log, "middle"));
+
+        let event = HookEvent::KillBuffer { buffer_id: "b".into() };
+        let errors = run(&state, event.clone()).await;
+        assert_eq!(*log.lock().unwrap(), ["early", "middle", "late"]);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].to_string(), "Error in kill-buffer-hook (failing): boom");
+        assert_eq!(errors[1].message, "panicked: oops");
+        assert_eq!(hooks.errors(), errors);
+
+        assert!(hooks.unsubscribe(id));
+        assert!(hooks.remove(Hook::KillBuffer, "failing"));
+        log.lock().unwrap().clear();
+        run(&state, event).await;
+        assert_eq!(*log.lock().unwrap(), ["early", "late"]);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_hooks() {
+        let state = Arc::new(EditorState::new());
+        let events = Arc::new(StdMutex::new(Vec::new()));
+        for hook in Hook::ALL {
+            let events = events.clone();
+            state.hooks().subscribe(hook, "record", 0, move |_, event| {
+                events.lock().unwrap().push(event);
+                async { Ok(()) }
+            });
+        }
+        let dir = std::env::temp_dir().join(format!("eng-hook-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.txt");
+        std::fs::write(&path, "abc").unwrap();
+
+        let id = state.find_file(&path).await.unwrap();
+        let window_id = state.create_window(&id).await.unwrap();
+        state.get_buffer(&id).await.unwrap().write().await.insert(1, "xy").unwrap();
+        state.get_buffer(&id).await.unwrap().write().await.delete(0..1).unwrap();
+        command_finished(&state, &window_id, "self-insert-command").await;
+        state.save_buffer(&id).await.unwrap();
+        state.kill_buffer(&id).await.unwrap();
+
+        let id = id.clone();
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                HookEvent::FindFile { buffer_id: id.clone() },
+                HookEvent::AfterChange { buffer_id: id.clone(), start: 1, end: 3, old_len: 0 },
+                HookEvent::AfterChange { buffer_id: id.clone(), start: 0, end: 0, old_len: 1 },
+                HookEvent::PostCommand { window_id, command: "self-insert-command".into() },
+                HookEvent::BeforeSave { buffer_id: id.clone() },
+                HookEvent::AfterSave { buffer_id: id.clone() },
+                HookEvent::KillBuffer { buffer_id: id },
+            ]
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "xybc");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_command_hook() {
+        let state = Arc::new(EditorState::new());
+        let buffer_id = state.create_buffer("test".into(), "").await;
+        let window_id = state.create_window(&buffer_id).await.unwrap();
+        let invocation = CommandInvocation { args: vec!["post-command-hook".into(), "end-of-buffer".into()], ..Default::default() };
+        execute_command(state.clone(), &window_id, "add-hook", invocation).await.unwrap();
+        assert_eq!(state.hooks().subscribers(), [(Hook::PostCommand, vec!["end-of-buffer".to_string()])]);
+
+        state.get_buffer(&buffer_id).await.unwrap().write().await.insert(0, "hello").unwrap();
+        let errors = command_finished(&state, &window_id, "ignore").await;
+        assert!(errors.is_empty());
+        assert_eq!(state.read_window(&window_id, |w| w.selected_view().point).await.unwrap(), 5);
+
+        let invocation = CommandInvocation { args: vec!["no-such-hook".into(), "end-of-buffer".into()], ..Default::default() };
+        assert!(execute_command(state.clone(), &window_id, "add-hook", invocation).await.is_err());
+    }
+}
@@ -0,0 +1,597 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::buffer_menu::BUFFER_MENU_MODE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Modifiers {
+    pub ctrl: bool,
+    pub meta: bool,
+    pub shift: bool,
+    pub super_key: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum KeyCode {
+    Char(char),
+    /// ファンクションキーなど名前を持つキー ("return", "f1", "left" など)
+    Named(String),
+}
+
+/// 1回のキー入力。文字キーの Shift は大文字に畳み込む。
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub modifiers: Modifiers,
+    pub code: KeyCode,
+}
+
+impl Key {
+    pub fn new(modifiers: Modifiers, code: KeyCode) -> Self {
+        let mut key = Self { modifiers, code };
+        if let KeyCode::Char(c) = key.code
+            && key.modifiers.shift
+            && c.is_alphabetic()
+        {
+            key.code = KeyCode::Char(c.to_uppercase().next().unwrap_or(c));
+            key.modifiers.shift = false;
+        }
+        key
+    }
+
+    pub fn char(c: char) -> Self {
+        Self::new(Modifiers::default(), KeyCode::Char(c))
+    }
+
+    pub fn ctrl(c: char) -> Self {
+        Self::new(Modifiers { ctrl: true, ..Default::default() }, KeyCode::Char(c))
+    }
+
+    pub fn meta(c: char) -> Self {
+        Self::new(Modifiers { meta: true, ..Default::default() }, KeyCode::Char(c))
+    }
+
+    /// 修飾キーなしの印字可能文字なら、その文字を返す (self-insert-command の対象)
+    pub fn printable_char(&self) -> Option<char> {
+        match self.code {
+            KeyCode::Char(c) if !self.modifiers.ctrl && !self.modifiers.meta && !self.modifiers.super_key && !c.is_control() => Some(c),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Emacs の key-description と同じ順序で修飾子を並べる
+        if self.modifiers.ctrl {
+            f.write_str("C-")?;
+        }
+        if self.modifiers.meta {
+            f.write_str("M-")?;
+        }
+        if self.modifiers.shift {
+            f.write_str("S-")?;
+        }
+        if self.modifiers.super_key {
+            f.write_str("s-")?;
+        }
+        match &self.code {
+            KeyCode::Char(' ') => f.write_str("SPC"),
+            KeyCode::Char(c) => write!(f, "{}", c),
+            KeyCode::Named(name) => match name.as_str() {
+                "return" => f.write_str("RET"),
+                "tab" => f.write_str("TAB"),
+                "escape" => f.write_str("ESC"),
+                "backspace" => f.write_str("DEL"),
+                other => write!(f, "<{}>", other),
+            },
+        }
+    }
+}
+
+/// キー列を kbd 記法の文字列にする ("C-x C-f")
+pub fn key_description(keys: &[Key]) -> String {
+    keys.iter().map(Key::to_string).collect::<Vec<_>>().join(" ")
+}
+
+/// Emacs の kbd 記法 ("C-x C-f", "M-<return>", "C-c RET") を解析する。
+/// UIは Enter キーを "return" として送るため、RET と <return> は区別しない。
+pub fn parse_kbd(keys: &str) -> Result<Vec<Key>, String> {
+    let keys: Vec<Key> = keys.split_whitespace().map(parse_key).collect::<Result<_, _>>()?;
+    if keys.is_empty() {
+        return Err("Empty key sequence".into());
+    }
+    Ok(keys)
+}
+
+fn parse_key(token: &str) -> Result<Key, String> {
+    let mut modifiers = Modifiers::default();
+    let mut rest = token;
+    // "C--" のように修飾子の後の "-" 自体もキーになり得るので、残りが2文字以上の間だけ剥がす
+    while rest.len() > 2 && rest.as_bytes()[1] == b'-' {
+        match rest.as_bytes()[0] {
+            b'C' => modifiers.ctrl = true,
+            b'M' => modifiers.meta = true,
+            b'S' => modifiers.shift = true,
+            b's' => modifiers.super_key = true,
+            _ => break,
+        }
+        rest = &rest[2..];
+    }
+
+    let code = match rest {
+        "RET" => KeyCode::Named("return".into()),
+        "TAB" => KeyCode::Named("tab".into()),
+        "ESC" => KeyCode::Named("escape".into()),
+        "DEL" => KeyCode::Named("backspace".into()),
+        "SPC" => KeyCode::Char(' '),
+        _ if rest.len() > 2 && rest.starts_with('<') && rest.ends_with('>') => {
+            KeyCode::Named(rest[1..rest.len() - 1].to_string())
+        }
+        _ => {
+            let mut chars = rest.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => KeyCode::Char(c),
+                _ => return Err(format!("Invalid key: {}", token)),
+            }
+        }
+    };
+    Ok(Key::new(modifiers, code))
+}
+
+#[derive(Debug, Clone)]
+pub enum Binding {
+    Command(String),
+    Prefix(Keymap),
+}
+
+/// キー列の検索結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup {
+    Command(String),
+    /// 続きのキー入力が必要
+    Prefix,
+    Undefined,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Keymap {
+    bindings: HashMap<Key, Binding>,
+    /// 明示的な割り当てのない印字可能文字に使うコマンド (self-insert-command)
+    default_command: Option<String>,
+}
+
+impl Keymap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// kbd 記法のキー列にコマンドを割り当てる (define-key)
+    pub fn bind(&mut self, keys: &str, command: &str) -> Result<(), String> {
+        self.bind_keys(&parse_kbd(keys)?, command)
+    }
+
+    pub fn bind_keys(&mut self, keys: &[Key], command: &str) -> Result<(), String> {
+        let (last, prefix) = keys.split_last().ok_or("Empty key sequence")?;
+        let mut map = self;
+        for (i, key) in prefix.iter().enumerate() {
+            let binding = map
+                .bindings
+                .entry(key.clone())
+                .or_insert_with(|| Binding::Prefix(Keymap::new()));
+            map = match binding {
+                Binding::Prefix(nested) => nested,
+                Binding::Command(_) => {
+                    return Err(format!(
+                        "Key sequence {} starts with non-prefix key {}",
+                        key_description(keys),
+                        key_description(&keys[..=i])
+                    ));
+                }
+            };
+        }
+        map.bindings.insert(last.clone(), Binding::Command(command.to_string()));
+        Ok(())
+    }
+
+    pub fn unbind(&mut self, keys: &str) -> Result<(), String> {
+        let keys = parse_kbd(keys)?;
+        let (last, prefix) = keys.split_last().ok_or("Empty key sequence")?;
+        let mut map = self;
+        for key in prefix {
+            map = match map.bindings.get_mut(key) {
+                Some(Binding::Prefix(nested)) => nested,
+                _ => return Ok(()),
+            };
+        }
+        map.bindings.remove(last);
+        Ok(())
+    }
+
+    pub fn set_default_command(&mut self, command: Option<&str>) {
+        self.default_command = command.map(str::to_string);
+    }
+
+    pub fn lookup(&self, keys: &[Key]) -> Lookup {
+        let Some((first, rest)) = keys.split_first() else {
+            return Lookup::Prefix;
+        };
+        match self.bindings.get(first) {
+            Some(Binding::Command(command)) if rest.is_empty() => Lookup::Command(command.clone()),
+            Some(Binding::Command(_)) => Lookup::Undefined,
+            Some(Binding::Prefix(nested)) => nested.lookup(rest),
+            None => match (&self.default_command, rest.is_empty() && first.printable_char().is_some()) {
+                (Some(command), true) => Lookup::Command(command.clone()),
+                _ => Lookup::Undefined,
+            },
+        }
+    }
+}
+
+/// キーマップの優先度の区分
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeymapLevel {
+    /// バッファ固有のキーマップ (local-set-key)
+    Local,
+    Minor(String),
+    Major(String),
+    Global,
+}
+
+impl fmt::Display for KeymapLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeymapLevel::Local => f.write_str("local map"),
+            KeymapLevel::Minor(mode) => write!(f, "{}-map", mode),
+            KeymapLevel::Major(mode) => write!(f, "{}-map", mode),
+            KeymapLevel::Global => f.write_str("global-map"),
+        }
+    }
+}
+
+/// 有効なキーマップの集合。local > minor > major > global の順に検索する。
+#[derive(Debug, Default)]
+pub struct KeymapStack<'a> {
+    local: Option<&'a Keymap>,
+    minor: Vec<(String, &'a Keymap)>,
+    major: Option<(String, &'a Keymap)>,
+    global: Option<&'a Keymap>,
+}
+
+impl<'a> KeymapStack<'a> {
+    pub fn new(global: &'a Keymap) -> Self {
+        Self { global: Some(global), ..Default::default() }
+    }
+
+    pub fn with_major(mut self, mode: &str, keymap: &'a Keymap) -> Self {
+        self.major = Some((mode.to_string(), keymap));
+        self
+    }
+
+    /// マイナーモードは後から追加したものほど優先される
+    pub fn with_minor(mut self, mode: &str, keymap: &'a Keymap) -> Self {
+        self.minor.insert(0, (mode.to_string(), keymap));
+        self
+    }
+
+    pub fn with_local(mut self, keymap: &'a Keymap) -> Self {
+        self.local = Some(keymap);
+        self
+    }
+
+    fn maps(&self) -> Vec<(KeymapLevel, &'a Keymap)> {
+        let mut maps = Vec::new();
+        if let Some(local) = self.local {
+            maps.push((KeymapLevel::Local, local));
+        }
+        for (mode, map) in &self.minor {
+            maps.push((KeymapLevel::Minor(mode.clone()), *map));
+        }
+        if let Some((mode, map)) = &self.major {
+            maps.push((KeymapLevel::Major(mode.clone()), *map));
+        }
+        if let Some(global) = self.global {
+            maps.push((KeymapLevel::Global, global));
+        }
+        maps
+    }
+
+    /// 優先度順に検索し、最初に見つかった割り当て (コマンドまたはプレフィックス) を返す。
+    /// 上位のプレフィックスに無いキーは下位のキーマップから探されるので、プレフィックスは合成される。
+    pub fn lookup(&self, keys: &[Key]) -> (Lookup, Option<KeymapLevel>) {
+        for (level, map) in self.maps() {
+            match map.lookup(keys) {
+                Lookup::Undefined => continue,
+                found => return (found, Some(level)),
+            }
+        }
+        (Lookup::Undefined, None)
+    }
+}
+
+/// describe-key の結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyDescription {
+    pub keys: String,
+    pub command: Option<String>,
+    pub level: Option<KeymapLevel>,
+}
+
+impl fmt::Display for KeyDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.command, &self.level) {
+            (Some(command), Some(level)) => {
+                write!(f, "{} runs the command {} (found in {})", self.keys, command, level)
+            }
+            _ => write!(f, "{} is undefined", self.keys),
+        }
+    }
+}
+
+pub fn describe_key(stack: &KeymapStack, keys: &[Key]) -> KeyDescription {
+    let (lookup, level) = stack.lookup(keys);
+    let command = match lookup {
+        Lookup::Command(command) => Some(command),
+        _ => None,
+    };
+    KeyDescription {
+        keys: key_description(keys),
+        level: command.as_ref().and(level),
+        command,
+    }
+}
+
+/// エディタ全体のキーマップ。モードごとのキーマップとバッファ固有のキーマップを保持する。
+#[derive(Debug, Clone)]
+pub struct Keymaps {
+    pub global: Keymap,
+    pub major: HashMap<String, Keymap>,
+    pub minor: HashMap<String, Keymap>,
+    pub local: HashMap<String, Keymap>,
+    /// ミニバッファ内で最優先するキーマップ (minibuffer-local-map)
+    pub minibuffer: Keymap,
+}
+
+impl Default for Keymaps {
+    fn default() -> Self {
+        Self {
+            global: default_global_keymap(),
+            minibuffer: default_minibuffer_keymap(),
+            major: HashMap::from([(BUFFER_MENU_MODE.to_string(), default_buffer_menu_keymap())]),
+            minor: HashMap::new(),
+            local: HashMap::new(),
+        }
+    }
+}
+
+impl Keymaps {
+    /// バッファで有効なキーマップを優先度順に組み立てる
+    pub fn active<'a>(&'a self, buffer_id: &str, major_mode: Option<&str>, minor_modes: &[String]) -> KeymapStack<'a> {
+        let mut stack = KeymapStack::new(&self.global);
+        if let Some(mode) = major_mode
+            && let Some(map) = self.major.get(mode)
+        {
+            stack = stack.with_major(mode, map);
+        }
+        for mode in minor_modes {
+            if let Some(map) = self.minor.get(mode) {
+                stack = stack.with_minor(mode, map);
+            }
+        }
+        if let Some(map) = self.local.get(buffer_id) {
+            stack = stack.with_local(map);
+        }
+        stack
+    }
+}
+
+fn default_global_keymap() -> Keymap {
+    const BINDINGS: &[(&str, &str)] = &[
+        ("C-f", "forward-char"),
+        ("<right>", "forward-char"),
+        ("C-b", "backward-char"),
+        ("<left>", "backward-char"),
+        ("C-n", "next-line"),
+        ("<down>", "next-line"),
+        ("C-p", "previous-line"),
+        ("<up>", "previous-line"),
+        ("C-a", "move-beginning-of-line"),
+        ("<home>", "move-beginning-of-line"),
+        ("C-e", "move-end-of-line"),
+        ("<end>", "move-end-of-line"),
+        ("M-<", "beginning-of-buffer"),
+        ("M->", "end-of-buffer"),
+        ("RET", "newline"),
+        ("C-d", "delete-char"),
+        ("<delete>", "delete-char"),
+        ("DEL", "delete-backward-char"),
+        ("C-SPC", "set-mark-command"),
+        ("C-x C-x", "exchange-point-and-mark"),
+        ("C-x 0", "delete-window"),
+        ("C-x 1", "delete-other-windows"),
+        ("C-x 2", "split-window-below"),
+        ("C-x 3", "split-window-right"),
+        ("C-x o", "other-window"),
+        ("C-x +", "balance-windows"),
+        ("C-x ^", "enlarge-window"),
+        ("C-x }", "enlarge-window-horizontally"),
+        ("C-x {", "shrink-window-horizontally"),
+        ("C-x C-f", "find-file"),
+        ("C-x C-s", "save-buffer"),
+        ("C-x b", "switch-to-buffer"),
+        ("C-x k", "kill-buffer"),
+        ("C-x C-b", "list-buffers"),
+        ("M-f", "forward-word"),
+        ("M-b", "backward-word"),
+        ("M-d", "kill-word"),
+        ("M-DEL", "backward-kill-word"),
+        ("C-u", "universal-argument"),
+        ("M--", "negative-argument"),
+        ("C--", "negative-argument"),
+        ("C-x z", "repeat"),
+        ("C-x (", "kmacro-start-macro"),
+        ("C-x )", "kmacro-end-macro"),
+        ("C-x e", "kmacro-end-and-call-macro"),
+        ("<f3>", "kmacro-start-macro-or-insert-counter"),
+        ("<f4>", "kmacro-end-or-call-macro"),
+        ("C-x C-k C-i", "kmacro-insert-counter"),
+        ("C-x C-k C-c", "kmacro-set-counter"),
+        ("C-x C-k C-a", "kmacro-add-counter"),
+        ("C-x C-k C-f", "kmacro-set-format"),
+        ("C-x C-k n", "kmacro-name-last-macro"),
+        ("C-x C-k r", "apply-macro-to-region-lines"),
+        ("M-x", "execute-extended-command"),
+        ("C-h k", "describe-key"),
+        ("C-g", "keyboard-quit"),
+    ];
+    let mut map = Keymap::new();
+    for (keys, command) in BINDINGS {
+        map.bind(keys, command).expect("default global bindings must be valid");
+    }
+    for digit in '0'..='9' {
+        for key in [Key::meta(digit), Key::ctrl(digit)] {
+            map.bind_keys(&[key], "digit-argument").expect("digit bindings must be valid");
+        }
+    }
+    map.set_default_command(Some("self-insert-command"));
+    map
+}
+
+fn default_buffer_menu_keymap() -> Keymap {
+    const BINDINGS: &[(&str, &str)] = &[
+        ("RET", "Buffer-menu-this-window"),
+        ("f", "Buffer-menu-this-window"),
+        ("d", "Buffer-menu-delete"),
+        ("k", "Buffer-menu-delete"),
+        ("u", "Buffer-menu-unmark"),
+        ("x", "Buffer-menu-execute"),
+        ("g", "Buffer-menu-revert"),
+        ("q", "quit-window"),
+        ("n", "next-line"),
+        ("SPC", "next-line"),
+        ("p", "previous-line"),
+    ];
+    let mut map = Keymap::new();
+    for (keys, command) in BINDINGS {
+        map.bind(keys, command).expect("default buffer menu bindings must be valid");
+    }
+    map
+}
+
+fn default_minibuffer_keymap() -> Keymap {
+    const BINDINGS: &[(&str, &str)] = &[
+        ("RET", "exit-minibuffer"),
+        ("C-g", "abort-recursive-edit"),
+        ("TAB", "minibuffer-complete"),
+        ("M-p", "previous-history-element"),
+        ("M-n", "next-history-element"),
+        ("C-n", "minibuffer-next-completion"),
+        ("<down>", "minibuffer-next-completion"),
+        ("C-p", "minibuffer-previous-completion"),
+        ("<up>", "minibuffer-previous-completion"),
+        ("C-f", "minibuffer-forward-char"),
+        ("<right>", "minibuffer-forward-char"),
+        ("C-b", "minibuffer-backward-char"),
+        ("<left>", "minibuffer-backward-char"),
+        ("C-a", "minibuffer-beginning-of-line"),
+        ("C-e", "minibuffer-end-of-line"),
+        ("C-d", "minibuffer-delete-char"),
+        ("DEL", "minibuffer-delete-backward-char"),
+    ];
+    let mut map = Keymap::new();
+    for (keys, command) in BINDINGS {
+        map.bind(keys, command).expect("default minibuffer bindings must be valid");
+    }
+    map.set_default_command(Some("minibuffer-self-insert"));
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_kbd() {
+        assert_eq!(parse_kbd("C-x C-f").unwrap(), vec![Key::ctrl('x'), Key::ctrl('f')]);
+        assert_eq!(
+            parse_kbd("M-<return>").unwrap(),
+            vec![Key::new(Modifiers { meta: true, ..Default::default() }, KeyCode::Named("return".into()))]
+        );
+        assert_eq!(parse_kbd("C--").unwrap(), vec![Key::ctrl('-')]);
+        assert_eq!(parse_kbd("SPC -").unwrap(), vec![Key::char(' '), Key::char('-')]);
+        // 文字キーの Shift は大文字として扱う
+        assert_eq!(parse_kbd("S-a").unwrap(), vec![Key::char('A')]);
+        assert!(parse_kbd("").is_err());
+        assert!(parse_kbd("C-foo").is_err());
+    }
+
+    #[test]
+    fn test_key_description_roundtrip() {
+        for keys in ["C-x C-f", "M-RET", "C-c <f5>", "C-M-%", "SPC", "<f1> DEL", "s-a"] {
+            assert_eq!(key_description(&parse_kbd(keys).unwrap()), keys);
+        }
+        // RET と <return> は同じキーとして扱う
+        assert_eq!(parse_kbd("M-<return>").unwrap(), parse_kbd("M-RET").unwrap());
+    }
+
+    #[test]
+    fn test_prefix_keymaps() {
+        let mut map = Keymap::new();
+        map.bind("C-x C-f", "find-file").unwrap();
+        map.bind("C-x 4 f", "find-file-other-window").unwrap();
+
+        assert_eq!(map.lookup(&parse_kbd("C-x").unwrap()), Lookup::Prefix);
+        assert_eq!(map.lookup(&parse_kbd("C-x 4").unwrap()), Lookup::Prefix);
+        assert_eq!(map.lookup(&parse_kbd("C-x C-f").unwrap()), Lookup::Command("find-file".into()));
+        assert_eq!(map.lookup(&parse_kbd("C-x C-q").unwrap()), Lookup::Undefined);
+        assert_eq!(map.lookup(&parse_kbd("C-x C-f C-a").unwrap()), Lookup::Undefined);
+
+        assert!(map.bind("C-x C-f C-a", "bad").is_err());
+        map.unbind("C-x C-f").unwrap();
+        assert_eq!(map.lookup(&parse_kbd("C-x C-f").unwrap()), Lookup::Undefined);
+    }
+
+    #[test]
+    fn test_default_command_for_printable_chars() {
+        let mut map = Keymap::new();
+        map.set_default_command(Some("self-insert-command"));
+        assert_eq!(map.lookup(&[Key::char('a')]), Lookup::Command("self-insert-command".into()));
+        assert_eq!(map.lookup(&[Key::ctrl('a')]), Lookup::Undefined);
+    }
+
+    #[test]
+    fn test_keymap_precedence() {
+        let mut global = Keymap::new();
+        global.bind("C-c a", "global-a").unwrap();
+        global.bind("C-c b", "global-b").unwrap();
+        global.bind("C-j", "global-j").unwrap();
+        let mut major = Keymap::new();
+        major.bind("C-c a", "major-a").unwrap();
+        major.bind("C-j", "major-j").unwrap();
+        let mut minor = Keymap::new();
+        minor.bind("C-j", "minor-j").unwrap();
+        let mut local = Keymap::new();
+        local.bind("C-c", "local-c").unwrap();
+
+        let stack = KeymapStack::new(&global).with_major("rust-mode", &major).with_minor("foo-mode", &minor);
+        assert_eq!(stack.lookup(&parse_kbd("C-j").unwrap()).0, Lookup::Command("minor-j".into()));
+        assert_eq!(stack.lookup(&parse_kbd("C-c a").unwrap()).0, Lookup::Command("major-a".into()));
+        // 上位のプレフィックスに無いキーは下位から探す
+        assert_eq!(stack.lookup(&parse_kbd("C-c b").unwrap()).0, Lookup::Command("global-b".into()));
+
+        let stack = stack.with_local(&local);
+        assert_eq!(stack.lookup(&parse_kbd("C-c").unwrap()).0, Lookup::Command("local-c".into()));
+    }
+
+    #[test]
+    fn test_describe_key() {
+        let keymaps = Keymaps::default();
+        let stack = keymaps.active("buffer", None, &[]);
+        let description = describe_key(&stack, &parse_kbd("C-x 2").unwrap());
+        assert_eq!(
+            description.to_string(),
+            "C-x 2 runs the command split-window-below (found in global-map)"
+        );
+        let description = describe_key(&stack, &parse_kbd("C-x C-q").unwrap());
+        assert_eq!(description.to_string(), "C-x C-q is undefined");
+    }
+}
@@ -0,0 +1,518 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::builtins::insert_at_point;
+use crate::command::{CommandContext, CommandError, CommandRegistry, CommandResult, PrefixArg};
+use crate::command_loop;
+use crate::keymap::{key_description, parse_kbd, Key};
+use crate::state::EditorState;
+
+pub const DEFAULT_COUNTER_FORMAT: &str = "%d";
+
+/// 定義済みのキーボードマクロとマクロカウンタ。マクロの記録自体はWindowごとの CommandLoop で行う。
+#[derive(Debug)]
+pub struct Kmacros {
+    last: Option<Vec<Key>>,
+    named: BTreeMap<String, Vec<Key>>,
+    counter: i64,
+    format: String,
+}
+
+impl Default for Kmacros {
+    fn default() -> Self {
+        Self {
+            last: None,
+            named: BTreeMap::new(),
+            counter: 0,
+            format: DEFAULT_COUNTER_FORMAT.to_string(),
+        }
+    }
+}
+
+impl Kmacros {
+    pub fn last(&self) -> Option<&[Key]> {
+        self.last.as_deref()
+    }
+
+    pub fn named(&self, name: &str) -> Option<&[Key]> {
+        self.named.get(name).map(Vec::as_slice)
+    }
+
+    pub fn counter(&self) -> i64 {
+        self.counter
+    }
+
+    pub fn format(&self) -> &str {
+        &self.format
+    }
+
+    /// 現在のカウンタ値を書式化する
+    pub fn formatted_counter(&self) -> Result<String, String> {
+        format_counter(&self.format, self.counter)
+    }
+}
+
+/// マクロカウンタを書式化する。書式は printf 形式で、%d %o %x %X のいずれか1つと
+/// フラグ ("-", "0") と幅を受け付ける。
+pub fn format_counter(format: &str, value: i64) -> Result<String, String> {
+    let invalid = || format!("Invalid macro counter format: {}", format);
+    let mut out = String::new();
+    let mut chars = format.chars().peekable();
+    let mut converted = false;
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        if chars.next_if_eq(&'%').is_some() {
+            out.push('%');
+            continue;
+        }
+
+        let (mut left, mut zero) = (false, false);
+        while let Some(flag) = chars.next_if(|c| *c == '-' || *c == '0') {
+            if flag == '-' {
+                left = true;
+            } else {
+                zero = true;
+            }
+        }
+        let mut width = 0;
+        while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+            width = width * 10 + digit.to_digit(10).unwrap_or(0) as usize;
+        }
+
+        let magnitude = value.unsigned_abs();
+        let digits = match chars.next() {
+            Some('d') => magnitude.to_string(),
+            Some('o') => format!("{:o}", magnitude),
+            Some('x') => format!("{:x}", magnitude),
+            Some('X') => format!("{:X}", magnitude),
+            _ => return Err(invalid()),
+        };
+        if converted {
+            return Err(invalid());
+        }
+        converted = true;
+
+        let sign = if value < 0 { "-" } else { "" };
+        let padding = width.saturating_sub(sign.len() + digits.len());
+        if left {
+            out.push_str(&format!("{}{}{}", sign, digits, " ".repeat(padding)));
+        } else if zero {
+            out.push_str(&format!("{}{}{}", sign, "0".repeat(padding), digits));
+        } else {
+            out.push_str(&format!("{}{}{}", " ".repeat(padding), sign, digits));
+        }
+    }
+    Ok(out)
+}
+
+/// 名前付きマクロの保存形式: 1行に "名前<TAB>キー列" (キー列は kbd 形式)。"#" で始まる行は無視する。
+pub fn serialize_macros<'a>(macros: impl IntoIterator<Item = (&'a str, &'a [Key])>) -> String {
+    let mut out = String::from("# Keyboard macros\n");
+    for (name, keys) in macros {
+        out.push_str(&format!("{}\t{}\n", name, key_description(keys)));
+    }
+    out
+}
+
+pub fn parse_macros(text: &str) -> Result<Vec<(String, Vec<Key>)>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            let (name, keys) = line
+                .split_once('\t')
+                .ok_or_else(|| format!("Invalid keyboard macro on line {}", i + 1))?;
+            Ok((name.trim().to_string(), parse_kbd(keys)?))
+        })
+        .collect()
+}
+
+pub(crate) fn register_commands(r: &mut CommandRegistry) -> Result<(), String> {
+    r.register("kmacro-start-macro", "Record subsequent keyboard input, defining a keyboard macro.", "P", kmacro_start_macro)?;
+    r.register("kmacro-end-macro", "Finish defining a keyboard macro.", "p", kmacro_end_macro)?;
+    r.register("call-last-kbd-macro", "Call the last keyboard macro that you defined.", "p", call_last_kbd_macro)?;
+    r.register("kmacro-end-and-call-macro", "Call last keyboard macro, ending it first if currently being defined.", "p", kmacro_end_and_call_macro)?;
+    r.register("kmacro-start-macro-or-insert-counter", "Record subsequent keyboard input, or insert the macro counter while defining.", "P", kmacro_start_macro_or_insert_counter)?;
+    r.register("kmacro-end-or-call-macro", "End kbd macro if currently being defined; else call last kbd macro.", "p", kmacro_end_or_call_macro)?;
+    r.register("kmacro-insert-counter", "Insert current value of the macro counter, then increment it by N.", "P", kmacro_insert_counter)?;
+    r.register("kmacro-set-counter", "Set the value of the macro counter.", "nMacro counter value: ", kmacro_set_counter)?;
+    r.register("kmacro-add-counter", "Add N to the value of the macro counter.", "nAdd to macro counter: ", kmacro_add_counter)?;
+    r.register("kmacro-set-format", "Set the format of the macro counter.", "sMacro counter format: ", kmacro_set_format)?;
+    r.register("kmacro-name-last-macro", "Assign a name to the last keyboard macro defined.", "sName for last kbd macro: ", kmacro_name_last_macro)?;
+    r.register("kmacro-save-macros", "Save all named keyboard macros to a file.", "sSave keyboard macros to file: ", kmacro_save_macros)?;
+    r.register("kmacro-load-macros", "Load named keyboard macros from a file.", "sLoad keyboard macros from file: ", kmacro_load_macros)?;
+    r.register("apply-macro-to-region-lines", "Apply the last keyboard macro to all lines in the region.", "r", apply_macro_to_region_lines)?;
+    Ok(())
+}
+
+/// マクロを count 回実行する。count が0ならエラーになるまで繰り返す。
+async fn execute_macro(ctx: &CommandContext, keys: &[Key], count: i64) -> CommandResult {
+    if count < 0 {
+        return Err("Negative repetition argument".into());
+    }
+    let mut executed = 0;
+    while count == 0 || executed < count {
+        match command_loop::execute_keys(&ctx.state, &ctx.window_id, keys).await {
+            Ok(()) => executed += 1,
+            Err(CommandError::Quit) => return Err(CommandError::Quit),
+            Err(_) if count == 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+async fn last_macro(ctx: &CommandContext) -> Result<Vec<Key>, String> {
+    ctx.state
+        .kmacros()
+        .lock()
+        .await
+        .last()
+        .map(<[Key]>::to_vec)
+        .ok_or_else(|| "No kbd macro has been defined".into())
+}
+
+/// C-x (。C-u 付きなら直前のマクロに追記し、数値引数ならカウンタをその値にする。
+async fn kmacro_start_macro(ctx: CommandContext) -> CommandResult {
+    let initial = match ctx.prefix {
+        PrefixArg::Universal(_) => last_macro(&ctx).await?,
+        _ => Vec::new(),
+    };
+    command_loop::start_kbd_macro(&ctx.state, &ctx.window_id, initial).await?;
+    if !matches!(ctx.prefix, PrefixArg::Universal(_)) {
+        ctx.state.kmacros().lock().await.counter = match ctx.prefix {
+            PrefixArg::Number(n) => n,
+            PrefixArg::Minus => -1,
+            _ => 0,
+        };
+    }
+    Ok(Some("Defining kbd macro...".into()))
+}
+
+/// C-x )。数値引数 N を付けると、定義を終えた後さらに N-1 回実行する。
+async fn kmacro_end_macro(ctx: CommandContext) -> CommandResult {
+    let keys = command_loop::end_kbd_macro(&ctx.state, &ctx.window_id).await?;
+    if keys.is_empty() {
+        return Ok(Some("Ignore empty macro".into()));
+    }
+    ctx.state.kmacros().lock().await.last = Some(keys.clone());
+    let n = ctx.number_arg(0);
+    if n != 1 {
+        // 定義中に1回実行済み
+        execute_macro(&ctx, &keys, if n == 0 { 0 } else { n - 1 }).await?;
+    }
+    Ok(Some("Keyboard macro defined".into()))
+}
+
+async fn call_last_kbd_macro(ctx: CommandContext) -> CommandResult {
+    if command_loop::defining_kbd_macro(&ctx.state, &ctx.window_id).await {
+        return Err("Can't execute anonymous macro while defining one".into());
+    }
+    let keys = last_macro(&ctx).await?;
+    execute_macro(&ctx, &keys, ctx.number_arg(0)).await
+}
+
+/// C-x e
+async fn kmacro_end_and_call_macro(ctx: CommandContext) -> CommandResult {
+    if command_loop::defining_kbd_macro(&ctx.state, &ctx.window_id).await {
+        let keys = command_loop::end_kbd_macro(&ctx.state, &ctx.window_id).await?;
+        if keys.is_empty() {
+            return Ok(Some("Ignore empty macro".into()));
+        }
+        ctx.state.kmacros().lock().await.last = Some(keys);
+    }
+    let keys = last_macro(&ctx).await?;
+    execute_macro(&ctx, &keys, ctx.number_arg(0)).await
+}
+
+/// F3。マクロの再生中もカウンタの挿入として扱う。
+async fn kmacro_start_macro_or_insert_counter(ctx: CommandContext) -> CommandResult {
+    if command_loop::defining_kbd_macro(&ctx.state, &ctx.window_id).await
+        || command_loop::executing_kbd_macro(&ctx.state, &ctx.window_id).await
+    {
+        kmacro_insert_counter(ctx).await
+    } else {
+        kmacro_start_macro(ctx).await
+    }
+}
+
+/// F4
+async fn kmacro_end_or_call_macro(ctx: CommandContext) -> CommandResult {
+    if command_loop::defining_kbd_macro(&ctx.state, &ctx.window_id).await {
+        kmacro_end_macro(ctx).await
+    } else {
+        call_last_kbd_macro(ctx).await
+    }
+}
+
+/// カウンタを挿入して N 増やす。C-u 付きなら増やさずに挿入だけする。
+async fn kmacro_insert_counter(ctx: CommandContext) -> CommandResult {
+    let text = {
+        let mut kmacros = ctx.state.kmacros().lock().await;
+        let text = kmacros.formatted_counter()?;
+        if !matches!(ctx.prefix, PrefixArg::Universal(_)) {
+            kmacros.counter += ctx.prefix.numeric();
+        }
+        text
+    };
+    insert_at_point(&ctx, &text).await
+}
+
+async fn kmacro_set_counter(ctx: CommandContext) -> CommandResult {
+    ctx.state.kmacros().lock().await.counter = ctx.number_arg(0);
+    Ok(None)
+}
+
+async fn kmacro_add_counter(ctx: CommandContext) -> CommandResult {
+    ctx.state.kmacros().lock().await.counter += ctx.number_arg(0);
+    Ok(None)
+}
+
+/// 空文字列を指定すると既定の書式に戻す
+async fn kmacro_set_format(ctx: CommandContext) -> CommandResult {
+    let format = match ctx.string_arg(0)? {
+        "" => DEFAULT_COUNTER_FORMAT,
+        format => format,
+    };
+    format_counter(format, 0)?;
+    ctx.state.kmacros().lock().await.format = format.to_string();
+    Ok(None)
+}
+
+/// 名前付きマクロをコマンドとして登録する。数値引数は繰り返し回数になる。
+pub async fn define_named_macro(state: &EditorState, name: &str, keys: Vec<Key>) -> Result<(), String> {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(format!("Invalid macro name: {:?}", name));
+    }
+    let mut commands = state.commands().write().await;
+    let mut kmacros = state.kmacros().lock().await;
+    if commands.get(name).is_some() && !kmacros.named.contains_key(name) {
+        return Err(format!("Cannot give a macro the name of an existing command: {}", name));
+    }
+
+    let doc = format!("Keyboard macro.\n\n{}", key_description(&keys));
+    let macro_keys = keys.clone();
+    commands.register(name, &doc, "p", move |ctx| {
+        let keys = macro_keys.clone();
+        async move { execute_macro(&ctx, &keys, ctx.number_arg(0)).await }
+    })?;
+    kmacros.named.insert(name.to_string(), keys);
+    Ok(())
+}
+
+async fn kmacro_name_last_macro(ctx: CommandContext) -> CommandResult {
+    let keys = last_macro(&ctx).await?;
+    define_named_macro(&ctx.state, ctx.string_arg(0)?, keys).await?;
+    Ok(None)
+}
+
+async fn kmacro_save_macros(ctx: CommandContext) -> CommandResult {
+    let path = Path::new(ctx.string_arg(0)?);
+    let text = {
+        let kmacros = ctx.state.kmacros().lock().await;
+        if kmacros.named.is_empty() {
+            return Err("No named keyboard macros".into());
+        }
+        serialize_macros(kmacros.named.iter().map(|(name, keys)| (name.as_str(), keys.as_slice())))
+    };
+    tokio::fs::write(path, text)
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(Some(format!("Wrote {}", path.display())))
+}
+
+async fn kmacro_load_macros(ctx: CommandContext) -> CommandResult {
+    let path = Path::new(ctx.string_arg(0)?);
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let macros = parse_macros(&text)?;
+    let count = macros.len();
+    for (name, keys) in macros {
+        define_named_macro(&ctx.state, &name, keys).await?;
+    }
+    Ok(Some(format!("Loaded {} keyboard macros", count)))
+}
+
+/// 領域内の各行の先頭でマクロを実行する。マクロによる行の増減に追従するため、
+/// 次の行の位置はバッファ末尾からの距離で覚えておく。
+async fn apply_macro_to_region_lines(ctx: CommandContext) -> CommandResult {
+    let region = ctx.region_arg(0)?;
+    let keys = last_macro(&ctx).await?;
+    let buffer = ctx.current_buffer().await?;
+
+    let (mut pos, end_from_tail) = {
+        let buffer = buffer.read().await;
+        let start = buffer.line_to_char(buffer.char_to_line(region.start));
+        (start, buffer.len_chars() - region.end.min(buffer.len_chars()))
+    };
+    loop {
+        let next_from_tail = {
+            let buffer = buffer.read().await;
+            let len = buffer.len_chars();
+            if pos >= len.saturating_sub(end_from_tail) {
+                break;
+            }
+            let line = buffer.char_to_line(pos);
+            let next = if line + 1 < buffer.len_lines() { buffer.line_to_char(line + 1) } else { len };
+            len - next
+        };
+        ctx.set_point(pos).await?;
+        command_loop::execute_keys(&ctx.state, &ctx.window_id, &keys).await?;
+        if next_from_tail == 0 {
+            break;
+        }
+        let len = buffer.read().await.len_chars();
+        pos = len.saturating_sub(next_from_tail);
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_loop::handle_key;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_format_counter() {
+        assert_eq!(format_counter("%d", 7).unwrap(), "7");
+        assert_eq!(format_counter("%03d. ", 7).unwrap(), "007. ");
+        assert_eq!(format_counter("[%-3d]", -1).unwrap(), "[-1 ]");
+        assert_eq!(format_counter("%4x", 255).unwrap(), "  ff");
+        assert_eq!(format_counter("100%% %o", 8).unwrap(), "100% 10");
+        assert!(format_counter("%s", 1).is_err());
+        assert!(format_counter("%d %d", 1).is_err());
+    }
+
+    #[test]
+    fn test_macro_file_roundtrip() {
+        let keys = parse_kbd("C-a - SPC C-n").unwrap();
+        let text = serialize_macros([("bullet", keys.as_slice())]);
+        assert_eq!(parse_macros(&text).unwrap(), vec![("bullet".to_string(), keys)]);
+        assert!(parse_macros("no-tab-here").is_err());
+    }
+
+    struct Fixture {
+        state: Arc<EditorState>,
+        window_id: String,
+        buffer_id: String,
+    }
+
+    impl Fixture {
+        async fn new(text: &str) -> Self {
+            let state = Arc::new(EditorState::new());
+            let buffer_id = state.create_buffer("test".into(), text).await;
+            let window_id = state.create_window(&buffer_id).await.unwrap();
+            Self { state, window_id, buffer_id }
+        }
+
+        async fn keys(&self, keys: &str) {
+            for key in parse_kbd(keys).unwrap() {
+                handle_key(&self.state, &self.window_id, key).await.unwrap();
+            }
+        }
+
+        async fn text(&self) -> String {
+            let buffer = self.state.get_buffer(&self.buffer_id).await.unwrap();
+            buffer.read().await.to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_and_call_macro() {
+        let f = Fixture::new("").await;
+        f.keys("C-x ( a C-u 2 b C-x )").await;
+        assert_eq!(f.text().await, "abb");
+        assert_eq!(
+            f.state.kmacros().lock().await.last().unwrap(),
+            parse_kbd("a C-u 2 b").unwrap().as_slice()
+        );
+
+        f.keys("C-x e").await;
+        assert_eq!(f.text().await, "abbabb");
+        f.keys("C-u 2 C-x e").await;
+        assert_eq!(f.text().await, "abb".repeat(4));
+
+        // 失敗したコマンドやC-gは記録に影響しない
+        f.keys("C-x ( C-f C-g").await;
+        assert!(!command_loop::defining_kbd_macro(&f.state, &f.window_id).await);
+        assert_eq!(
+            f.state.kmacros().lock().await.last().unwrap(),
+            parse_kbd("a C-u 2 b").unwrap().as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_call_until_error() {
+        let f = Fixture::new("a\nb\nc").await;
+        // 各行の先頭に "*" を付けて次の行へ。最終行で C-n が失敗して止まる。
+        f.keys("<f3> * C-a C-n <f4>").await;
+        f.keys("C-u 0 <f4>").await;
+        assert_eq!(f.text().await, "*a\n*b\n*c");
+    }
+
+    #[tokio::test]
+    async fn test_macro_counter() {
+        let f = Fixture::new("").await;
+        let invocation = crate::command::CommandInvocation { args: vec!["%02d) ".into()], ..Default::default() };
+        crate::command::execute_command(f.state.clone(), &f.window_id, "kmacro-set-format", invocation)
+            .await
+            .unwrap();
+        f.keys("<f3> <f3> x RET <f4> C-u 2 <f4>").await;
+        assert_eq!(f.text().await, "00) x\n01) x\n02) x\n");
+        assert_eq!(f.state.kmacros().lock().await.counter(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_apply_macro_to_region_lines() {
+        let f = Fixture::new("one\ntwo\nthree\nfour\n").await;
+        f.keys("C-x ( - SPC C-x )").await;
+        // 追加した "- " を消して、2〜3行目を領域にする
+        f.keys("DEL DEL C-n C-SPC C-n C-n C-x C-k r").await;
+        assert_eq!(f.text().await, "one\n- two\n- three\nfour\n");
+
+        // 行を増やすマクロでも各行に1回ずつ適用される
+        f.keys("C-x ( C-e RET = C-x ) DEL DEL").await;
+        f.keys("M-< C-SPC M-> C-x C-k r").await;
+        assert_eq!(f.text().await, "one\n=\n- two\n=\n- three\n=\nfour\n=\n");
+    }
+
+    #[tokio::test]
+    async fn test_named_macros_can_be_saved_and_loaded() {
+        let f = Fixture::new("").await;
+        f.keys("C-x ( h i C-x )").await;
+        let run = |name: &str, args: Vec<String>| {
+            let state = f.state.clone();
+            let window_id = f.window_id.clone();
+            let name = name.to_string();
+            async move {
+                let invocation = crate::command::CommandInvocation { args, ..Default::default() };
+                crate::command::execute_command(state, &window_id, &name, invocation).await
+            }
+        };
+        run("kmacro-name-last-macro", vec!["greet".into()]).await.unwrap();
+        run("greet", vec![]).await.unwrap();
+        assert_eq!(f.text().await, "hihi");
+        assert!(run("kmacro-name-last-macro", vec!["forward-char".into()]).await.is_err());
+
+        let path = std::env::temp_dir().join(format!("kmacros-{}.txt", uuid::Uuid::new_v4()));
+        let path_arg = path.to_string_lossy().to_string();
+        run("kmacro-save-macros", vec![path_arg.clone()]).await.unwrap();
+
+        let g = Fixture::new("").await;
+        let invocation = crate::command::CommandInvocation { args: vec![path_arg], ..Default::default() };
+        crate::command::execute_command(g.state.clone(), &g.window_id, "kmacro-load-macros", invocation)
+            .await
+            .unwrap();
+        let invocation = crate::command::CommandInvocation { prefix: PrefixArg::Number(2), ..Default::default() };
+        crate::command::execute_command(g.state.clone(), &g.window_id, "greet", invocation)
+            .await
+            .unwrap();
+        assert_eq!(g.text().await, "hihi");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,732 @@
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Viewの最小サイズ (Emacs の window-min-height / window-min-width 相当)
+pub const MIN_VIEW_HEIGHT: u16 = 4;
+pub const MIN_VIEW_WIDTH: u16 = 10;
+
+/// UIからサイズが通知されるまでのWindowの既定サイズ
+pub const DEFAULT_WINDOW_WIDTH: u16 = 80;
+pub const DEFAULT_WINDOW_HEIGHT: u16 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitDirection {
+    /// 上下に並べる (split-window-below)
+    Below,
+    /// 左右に並べる (split-window-right)
+    Right,
+}
+
+impl SplitDirection {
+    fn min_extent(self) -> u16 {
+        match self {
+            SplitDirection::Below => MIN_VIEW_HEIGHT,
+            SplitDirection::Right => MIN_VIEW_WIDTH,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Rect {
+    fn extent(&self, direction: SplitDirection) -> u16 {
+        match direction {
+            SplitDirection::Below => self.height,
+            SplitDirection::Right => self.width,
+        }
+    }
+}
+
+/// バッファを表示する矩形領域 (Emacs の Window 相当)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct View {
+    pub id: String,
+    pub buffer_id: String,
+    /// カーソル位置 (文字オフセット)
+    pub point: usize,
+    /// 表示先頭行 (Emacs の window-start 相当)
+    pub scroll_line: usize,
+    /// リージョンの起点。set-mark-command で設定される。
+    pub mark: Option<usize>,
+}
+
+impl View {
+    fn new(buffer_id: String) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            buffer_id,
+            point: 0,
+            scroll_line: 0,
+            mark: None,
+        }
+    }
+
+    /// 表示するバッファを切り替える。位置情報はリセットされる。
+    pub fn set_buffer(&mut self, buffer_id: String) {
+        self.buffer_id = buffer_id;
+        self.point = 0;
+        self.scroll_line = 0;
+        self.mark = None;
+    }
+
+    /// point と mark で囲まれた範囲
+    pub fn region(&self) -> Option<std::ops::Range<usize>> {
+        self.mark.map(|mark| mark.min(self.point)..mark.max(self.point))
+    }
+}
+
+/// 分割ツリー。Split の子には分割方向に沿ったサイズ (行数/桁数) を持たせる。
+/// 同じ方向の Split が直接入れ子にならないよう常に平坦化しておく。
+#[derive(Debug, Clone)]
+enum LayoutNode {
+    Leaf(String),
+    Split {
+        direction: SplitDirection,
+        children: Vec<(LayoutNode, u16)>,
+    },
+}
+
+impl LayoutNode {
+    fn find_path(&self, view_id: &str) -> Option<Vec<usize>> {
+        match self {
+            LayoutNode::Leaf(id) => (id == view_id).then(Vec::new),
+            LayoutNode::Split { children, .. } => {
+                children.iter().enumerate().find_map(|(i, (child, _))| {
+                    child.find_path(view_id).map(|mut path| {
+                        path.insert(0, i);
+                        path
+                    })
+                })
+            }
+        }
+    }
+
+    fn node_at_mut(&mut self, path: &[usize]) -> &mut LayoutNode {
+        match (path.split_first(), self) {
+            (None, node) => node,
+            (Some((&i, rest)), LayoutNode::Split { children, .. }) => children[i].0.node_at_mut(rest),
+            (Some(_), LayoutNode::Leaf(_)) => unreachable!("layout path points below a leaf"),
+        }
+    }
+
+    fn leaves(&self, out: &mut Vec<String>) {
+        match self {
+            LayoutNode::Leaf(id) => out.push(id.clone()),
+            LayoutNode::Split { children, .. } => {
+                for (child, _) in children {
+                    child.leaves(out);
+                }
+            }
+        }
+    }
+
+    fn first_leaf(&self) -> &str {
+        match self {
+            LayoutNode::Leaf(id) => id,
+            LayoutNode::Split { children, .. } => children[0].0.first_leaf(),
+        }
+    }
+
+    fn collect_rects(&self, rect: Rect, out: &mut Vec<(String, Rect)>) {
+        match self {
+            LayoutNode::Leaf(id) => out.push((id.clone(), rect)),
+            LayoutNode::Split { direction, children } => {
+                let mut offset = 0;
+                for (child, size) in children {
+                    let child_rect = match direction {
+                        SplitDirection::Below => Rect { y: rect.y + offset, height: *size, ..rect },
+                        SplitDirection::Right => Rect { x: rect.x + offset, width: *size, ..rect },
+                    };
+                    child.collect_rects(child_rect, out);
+                    offset += size;
+                }
+            }
+        }
+    }
+
+    /// 指定方向に並ぶViewの数 (balance-windows の重み)
+    fn span(&self, direction: SplitDirection) -> u32 {
+        match self {
+            LayoutNode::Leaf(_) => 1,
+            LayoutNode::Split { direction: d, children } if *d == direction => {
+                children.iter().map(|(c, _)| c.span(direction)).sum()
+            }
+            LayoutNode::Split { children, .. } => {
+                children.iter().map(|(c, _)| c.span(direction)).max().unwrap_or(1)
+            }
+        }
+    }
+
+    /// 指定方向に必要な最小サイズ
+    fn min_extent(&self, direction: SplitDirection) -> u16 {
+        match self {
+            LayoutNode::Leaf(_) => direction.min_extent(),
+            LayoutNode::Split { direction: d, children } if *d == direction => {
+                children.iter().map(|(c, _)| c.min_extent(direction)).sum()
+            }
+            LayoutNode::Split { children, .. } => {
+                children.iter().map(|(c, _)| c.min_extent(direction)).max().unwrap_or(0)
+            }
+        }
+    }
+
+    /// 指定方向のサイズを extent に合わせ、子孫のサイズを比例配分し直す
+    fn scale(&mut self, direction: SplitDirection, extent: u16) {
+        if let LayoutNode::Split { direction: d, children } = self {
+            if *d == direction {
+                let weights: Vec<u32> = children.iter().map(|(_, size)| *size as u32).collect();
+                let sizes = distribute(extent, &weights);
+                for ((child, size), new_size) in children.iter_mut().zip(sizes) {
+                    *size = new_size;
+                    child.scale(direction, new_size);
+                }
+            } else {
+                for (child, _) in children.iter_mut() {
+                    child.scale(direction, extent);
+                }
+            }
+        }
+    }
+
+    fn balance(&mut self) {
+        if let LayoutNode::Split { direction, children } = self {
+            let direction = *direction;
+            let total = children.iter().map(|(_, size)| *size).sum();
+            let weights: Vec<u32> = children.iter().map(|(c, _)| c.span(direction)).collect();
+            let sizes = distribute(total, &weights);
+            for ((child, size), new_size) in children.iter_mut().zip(sizes) {
+                *size = new_size;
+                child.scale(direction, new_size);
+                child.balance();
+            }
+        }
+    }
+
+    /// 子が1つだけの Split を畳み、同方向の入れ子を平坦化する
+    fn normalize(self) -> LayoutNode {
+        match self {
+            LayoutNode::Leaf(_) => self,
+            LayoutNode::Split { direction, children } => {
+                let mut flat = Vec::with_capacity(children.len());
+                for (child, size) in children {
+                    match child.normalize() {
+                        LayoutNode::Split { direction: d, children: nested } if d == direction => {
+                            flat.extend(nested);
+                        }
+                        child => flat.push((child, size)),
+                    }
+                }
+                if flat.len() == 1 {
+                    flat.pop().map(|(child, _)| child).unwrap()
+                } else {
+                    LayoutNode::Split { direction, children: flat }
+                }
+            }
+        }
+    }
+}
+
+/// total を weights に比例して配分する (最大剰余法)
+fn distribute(total: u16, weights: &[u32]) -> Vec<u16> {
+    let weight_sum: u64 = weights.iter().map(|w| *w as u64).sum();
+    if weight_sum == 0 {
+        return vec![0; weights.len()];
+    }
+    let mut sizes: Vec<u16> = weights
+        .iter()
+        .map(|w| (total as u64 * *w as u64 / weight_sum) as u16)
+        .collect();
+    let mut remainders: Vec<(usize, u64)> = weights
+        .iter()
+        .enumerate()
+        .map(|(i, w)| (i, total as u64 * *w as u64 % weight_sum))
+        .collect();
+    remainders.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let assigned: u16 = sizes.iter().sum();
+    for (i, _) in remainders.into_iter().take((total - assigned) as usize) {
+        sizes[i] += 1;
+    }
+    sizes
+}
+
+/// 保存用のViewの状態
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ViewState {
+    pub buffer_id: String,
+    pub point: usize,
+    pub scroll_line: usize,
+    pub selected: bool,
+}
+
+/// Windowの分割状態を保存・復元するための木 (window-state-get / window-state-put 相当)。
+/// Split の子には分割方向に沿ったサイズを持たせる。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WindowState {
+    View(ViewState),
+    Split {
+        direction: SplitDirection,
+        children: Vec<(WindowState, u16)>,
+    },
+}
+
+impl WindowState {
+    /// 各Viewの状態をツリー順で変更する
+    pub fn views_mut(&mut self) -> Vec<&mut ViewState> {
+        match self {
+            WindowState::View(view) => vec![view],
+            WindowState::Split { children, .. } => children.iter_mut().flat_map(|(child, _)| child.views_mut()).collect(),
+        }
+    }
+}
+
+/// UIへ配信するViewの配置情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ViewLayout {
+    pub view: View,
+    pub rect: Rect,
+}
+
+/// UIへ配信するWindowのスナップショット
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowLayout {
+    pub window_id: String,
+    pub width: u16,
+    pub height: u16,
+    pub selected_view_id: String,
+    pub views: Vec<ViewLayout>,
+}
+
+/// OS上のアプリケーションウィンドウ (Emacs の Frame 相当)。内部にViewの分割ツリーを持つ。
+#[derive(Debug, Clone)]
+pub struct Window {
+    pub id: String,
+    root: LayoutNode,
+    views: HashMap<String, View>,
+    selected: String,
+    width: u16,
+    height: u16,
+}
+
+impl Window {
+    pub fn new(buffer_id: String) -> Self {
+        let view = View::new(buffer_id);
+        let selected = view.id.clone();
+        Self {
+            id: Uuid::new_v4().to_string(),
+            root: LayoutNode::Leaf(view.id.clone()),
+            views: HashMap::from([(view.id.clone(), view)]),
+            selected,
+            width: DEFAULT_WINDOW_WIDTH,
+            height: DEFAULT_WINDOW_HEIGHT,
+        }
+    }
+
+    pub fn size(&self) -> (u16, u16) {
+        (self.width, self.height)
+    }
+
+    /// UIから通知されたサイズに合わせて各Viewを比例配分する
+    pub fn set_size(&mut self, width: u16, height: u16) {
+        self.width = width;
+        self.height = height;
+        self.root.scale(SplitDirection::Right, width);
+        self.root.scale(SplitDirection::Below, height);
+    }
+
+    pub fn view(&self, view_id: &str) -> Option<&View> {
+        self.views.get(view_id)
+    }
+
+    pub fn view_mut(&mut self, view_id: &str) -> Option<&mut View> {
+        self.views.get_mut(view_id)
+    }
+
+    pub fn selected_view(&self) -> &View {
+        &self.views[&self.selected]
+    }
+
+    pub fn selected_view_mut(&mut self) -> &mut View {
+        self.views.get_mut(&self.selected).expect("selected view must exist")
+    }
+
+    /// ツリー順 (左上から右下) のView ID一覧
+    pub fn view_ids(&self) -> Vec<String> {
+        let mut ids = Vec::with_capacity(self.views.len());
+        self.root.leaves(&mut ids);
+        ids
+    }
+
+    pub fn select_view(&mut self, view_id: &str) -> Result<(), String> {
+        if !self.views.contains_key(view_id) {
+            return Err(format!("No such view: {}", view_id));
+        }
+        self.selected = view_id.to_string();
+        Ok(())
+    }
+
+    /// ツリー順で count 個先のViewを選択する (other-window)
+    pub fn other_view(&mut self, count: isize) -> &View {
+        let ids = self.view_ids();
+        let pos = ids.iter().position(|id| *id == self.selected).unwrap_or(0) as isize;
+        let next = (pos + count).rem_euclid(ids.len() as isize) as usize;
+        self.selected = ids[next].clone();
+        self.selected_view()
+    }
+
+    /// Viewを分割し、新しいView IDを返す。新しいViewは同じバッファと位置を引き継ぐ。
+    pub fn split_view(&mut self, view_id: &str, direction: SplitDirection) -> Result<String, String> {
+        let path = self
+            .root
+            .find_path(view_id)
+            .ok_or_else(|| format!("No such view: {}", view_id))?;
+        let rect = self.view_rect(view_id).expect("view in tree must have a rect");
+        let extent = rect.extent(direction);
+        let (first, second) = (extent - extent / 2, extent / 2);
+        if second < direction.min_extent() {
+            return Err(format!("View {} too small for splitting", view_id));
+        }
+
+        let mut new_view = self.views[view_id].clone();
+        new_view.id = Uuid::new_v4().to_string();
+        let new_id = new_view.id.clone();
+
+        let parent_split = path.split_last().and_then(|(&idx, parent_path)| {
+            match self.root.node_at_mut(parent_path) {
+                LayoutNode::Split { direction: d, children } if *d == direction => Some((children, idx)),
+                _ => None,
+            }
+        });
+        match parent_split {
+            Some((children, idx)) => {
+                children[idx].1 = first;
+                children.insert(idx + 1, (LayoutNode::Leaf(new_id.clone()), second));
+            }
+            None => {
+                let node = self.root.node_at_mut(&path);
+                *node = LayoutNode::Split {
+                    direction,
+                    children: vec![
+                        (LayoutNode::Leaf(view_id.to_string()), first),
+                        (LayoutNode::Leaf(new_id.clone()), second),
+                    ],
+                };
+            }
+        }
+
+        self.views.insert(new_id.clone(), new_view);
+        Ok(new_id)
+    }
+
+    /// Viewを削除し、その領域を隣のViewに与える (delete-window)
+    pub fn delete_view(&mut self, view_id: &str) -> Result<(), String> {
+        let path = self
+            .root
+            .find_path(view_id)
+            .ok_or_else(|| format!("No such view: {}", view_id))?;
+        let Some((&idx, parent_path)) = path.split_last() else {
+            return Err("Attempt to delete sole ordinary view".into());
+        };
+
+        let LayoutNode::Split { children, .. } = self.root.node_at_mut(parent_path) else {
+            unreachable!("parent of a view must be a split");
+        };
+        let (_, freed) = children.remove(idx);
+        let receiver = idx.saturating_sub(1).min(children.len() - 1);
+        children[receiver].1 += freed;
+        let next_selected = children[receiver].0.first_leaf().to_string();
+
+        let root = std::mem::replace(&mut self.root, LayoutNode::Leaf(String::new()));
+        self.root = root.normalize();
+        // 受け取った側の子孫を新しいサイズに合わせる
+        let (width, height) = (self.width, self.height);
+        self.set_size(width, height);
+
+        self.views.remove(view_id);
+        if self.selected == view_id {
+            self.selected = next_selected;
+        }
+        Ok(())
+    }
+
+    /// 指定したView以外を全て削除する (delete-other-windows)
+    pub fn delete_other_views(&mut self, view_id: &str) -> Result<(), String> {
+        if !self.views.contains_key(view_id) {
+            return Err(format!("No such view: {}", view_id));
+        }
+        self.views.retain(|id, _| id == view_id);
+        self.root = LayoutNode::Leaf(view_id.to_string());
+        self.selected = view_id.to_string();
+        Ok(())
+    }
+
+    /// 全Viewのサイズを均等にする (balance-windows)
+    pub fn balance(&mut self) {
+        self.root.balance();
+    }
+
+    /// Viewを指定方向に delta だけ広げる (負なら縮める)。隣接するViewから領域を取る。
+    pub fn resize_view(&mut self, view_id: &str, direction: SplitDirection, delta: i32) -> Result<(), String> {
+        let path = self
+            .root
+            .find_path(view_id)
+            .ok_or_else(|| format!("No such view: {}", view_id))?;
+
+        // 指定方向に分割している最も近い祖先を探す
+        let depth = (0..path.len())
+            .rev()
+            .find(|&depth| {
+                matches!(
+                    self.root.node_at_mut(&path[..depth]),
+                    LayoutNode::Split { direction: d, .. } if *d == direction
+                )
+            })
+            .ok_or_else(|| "No other view to resize against".to_string())?;
+
+        let LayoutNode::Split { children, .. } = self.root.node_at_mut(&path[..depth]) else {
+            unreachable!();
+        };
+        let target = path[depth];
+        let neighbour = if target + 1 < children.len() { target + 1 } else { target - 1 };
+
+        let new_target = children[target].1 as i32 + delta;
+        let new_neighbour = children[neighbour].1 as i32 - delta;
+        if new_target < children[target].0.min_extent(direction) as i32
+            || new_neighbour < children[neighbour].0.min_extent(direction) as i32
+        {
+            return Err("Cannot resize view beyond its minimum size".into());
+        }
+
+        children[target].1 = new_target as u16;
+        children[neighbour].1 = new_neighbour as u16;
+        children[target].0.scale(direction, new_target as u16);
+        children[neighbour].0.scale(direction, new_neighbour as u16);
+        Ok(())
+    }
+
+    fn view_rect(&self, view_id: &str) -> Option<Rect> {
+        self.layout()
+            .into_iter()
+            .find_map(|(id, rect)| (id == view_id).then_some(rect))
+    }
+
+    /// 各Viewの矩形をツリー順で返す
+    pub fn layout(&self) -> Vec<(String, Rect)> {
+        let mut out = Vec::with_capacity(self.views.len());
+        let frame = Rect { x: 0, y: 0, width: self.width, height: self.height };
+        self.root.collect_rects(frame, &mut out);
+        out
+    }
+
+    /// 分割状態を取り出す (Viewの ID は含めない)
+    pub fn state(&self) -> WindowState {
+        fn walk(window: &Window, node: &LayoutNode) -> WindowState {
+            match node {
+                LayoutNode::Leaf(id) => {
+                    let view = &window.views[id];
+                    WindowState::View(ViewState {
+                        buffer_id: view.buffer_id.clone(),
+                        point: view.point,
+                        scroll_line: view.scroll_line,
+                        selected: *id == window.selected,
+                    })
+                }
+                LayoutNode::Split { direction, children } => WindowState::Split {
+                    direction: *direction,
+                    children: children.iter().map(|(child, size)| (walk(window, child), *size)).collect(),
+                },
+            }
+        }
+        walk(self, &self.root)
+    }
+
+    /// 保存した分割状態から新しいWindowを作る。Viewには新しい ID を振り、サイズは width/height に合わせる。
+    pub fn from_state(state: &WindowState, width: u16, height: u16) -> Self {
+        fn build(state: &WindowState, views: &mut HashMap<String, View>, selected: &mut Option<String>) -> LayoutNode {
+            match state {
+                WindowState::View(saved) => {
+                    let mut view = View::new(saved.buffer_id.clone());
+                    view.point = saved.point;
+                    view.scroll_line = saved.scroll_line;
+                    let id = view.id.clone();
+                    if saved.selected {
+                        *selected = Some(id.clone());
+                    }
+                    views.insert(id.clone(), view);
+                    LayoutNode::Leaf(id)
+                }
+                WindowState::Split { direction, children } => LayoutNode::Split {
+                    direction: *direction,
+                    children: children.iter().map(|(child, size)| (build(child, views, selected), *size)).collect(),
+                },
+            }
+        }
+        let mut views = HashMap::new();
+        let mut selected = None;
+        let root = build(state, &mut views, &mut selected).normalize();
+        let selected = selected.unwrap_or_else(|| root.first_leaf().to_string());
+        let mut window = Self { id: Uuid::new_v4().to_string(), root, views, selected, width, height };
+        window.set_size(width, height);
+        window
+    }
+
+    pub fn snapshot(&self) -> WindowLayout {
+        WindowLayout {
+            window_id: self.id.clone(),
+            width: self.width,
+            height: self.height,
+            selected_view_id: self.selected.clone(),
+            views: self
+                .layout()
+                .into_iter()
+                .map(|(id, rect)| ViewLayout { view: self.views[&id].clone(), rect })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rects(window: &Window) -> Vec<Rect> {
+        window.layout().into_iter().map(|(_, rect)| rect).collect()
+    }
+
+    #[test]
+    fn test_split_below_and_right() {
+        let mut window = Window::new("buf".into());
+        let first = window.selected_view().id.clone();
+
+        let below = window.split_view(&first, SplitDirection::Below).unwrap();
+        assert_eq!(window.view_ids(), vec![first.clone(), below.clone()]);
+        assert_eq!(
+            rects(&window),
+            vec![
+                Rect { x: 0, y: 0, width: 80, height: 12 },
+                Rect { x: 0, y: 12, width: 80, height: 12 },
+            ]
+        );
+        // 新しいViewは同じバッファを表示し、選択は元のViewに残る
+        assert_eq!(window.view(&below).unwrap().buffer_id, "buf");
+        assert_eq!(window.selected_view().id, first);
+
+        let right = window.split_view(&below, SplitDirection::Right).unwrap();
+        assert_eq!(window.view_ids(), vec![first, below, right]);
+        assert_eq!(rects(&window)[1], Rect { x: 0, y: 12, width: 40, height: 12 });
+        assert_eq!(rects(&window)[2], Rect { x: 40, y: 12, width: 40, height: 12 });
+    }
+
+    #[test]
+    fn test_split_too_small() {
+        let mut window = Window::new("buf".into());
+        window.set_size(80, 6);
+        let id = window.selected_view().id.clone();
+        assert!(window.split_view(&id, SplitDirection::Below).is_err());
+        assert_eq!(window.view_ids().len(), 1);
+    }
+
+    #[test]
+    fn test_delete_view_gives_space_to_neighbour() {
+        let mut window = Window::new("buf".into());
+        let a = window.selected_view().id.clone();
+        let b = window.split_view(&a, SplitDirection::Right).unwrap();
+        let c = window.split_view(&b, SplitDirection::Below).unwrap();
+
+        window.select_view(&c).unwrap();
+        window.delete_view(&c).unwrap();
+        assert_eq!(window.view_ids(), vec![a.clone(), b.clone()]);
+        assert_eq!(window.selected_view().id, b);
+        assert_eq!(rects(&window)[1], Rect { x: 40, y: 0, width: 40, height: 24 });
+
+        window.delete_view(&a).unwrap();
+        assert_eq!(rects(&window), vec![Rect { x: 0, y: 0, width: 80, height: 24 }]);
+        assert!(window.delete_view(&b).is_err());
+    }
+
+    #[test]
+    fn test_delete_flattens_nested_splits() {
+        let mut window = Window::new("buf".into());
+        let a = window.selected_view().id.clone();
+        let b = window.split_view(&a, SplitDirection::Right).unwrap();
+        let c = window.split_view(&b, SplitDirection::Below).unwrap();
+        let d = window.split_view(&c, SplitDirection::Right).unwrap();
+
+        // b を消すと c|d の左右分割がルートの左右分割に合流する
+        window.delete_view(&b).unwrap();
+        assert_eq!(window.view_ids(), vec![a, c, d]);
+        let widths: Vec<u16> = rects(&window).iter().map(|r| r.width).collect();
+        assert_eq!(widths.iter().sum::<u16>(), 80);
+        assert!(rects(&window).iter().all(|r| r.height == 24));
+    }
+
+    #[test]
+    fn test_other_view_cycles() {
+        let mut window = Window::new("buf".into());
+        let a = window.selected_view().id.clone();
+        let b = window.split_view(&a, SplitDirection::Below).unwrap();
+        let c = window.split_view(&b, SplitDirection::Below).unwrap();
+
+        assert_eq!(window.other_view(1).id, b);
+        assert_eq!(window.other_view(1).id, c);
+        assert_eq!(window.other_view(1).id, a);
+        assert_eq!(window.other_view(-1).id, c);
+    }
+
+    #[test]
+    fn test_balance_and_resize() {
+        let mut window = Window::new("buf".into());
+        let a = window.selected_view().id.clone();
+        let b = window.split_view(&a, SplitDirection::Below).unwrap();
+        window.split_view(&b, SplitDirection::Below).unwrap();
+        let heights = |w: &Window| rects(w).iter().map(|r| r.height).collect::<Vec<_>>();
+        assert_eq!(heights(&window), vec![12, 6, 6]);
+
+        window.balance();
+        assert_eq!(heights(&window), vec![8, 8, 8]);
+
+        window.resize_view(&a, SplitDirection::Below, 2).unwrap();
+        assert_eq!(heights(&window), vec![10, 6, 8]);
+        assert!(window.resize_view(&a, SplitDirection::Below, 10).is_err());
+        assert!(window.resize_view(&a, SplitDirection::Right, 1).is_err());
+    }
+
+    #[test]
+    fn test_set_size_rescales_views() {
+        let mut window = Window::new("buf".into());
+        let a = window.selected_view().id.clone();
+        window.split_view(&a, SplitDirection::Right).unwrap();
+        window.set_size(120, 40);
+        assert_eq!(
+            rects(&window),
+            vec![
+                Rect { x: 0, y: 0, width: 60, height: 40 },
+                Rect { x: 60, y: 0, width: 60, height: 40 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_window_state_roundtrip() {
+        let mut window = Window::new("a".into());
+        let first = window.selected_view().id.clone();
+        let second = window.split_view(&first, SplitDirection::Right).unwrap();
+        window.split_view(&second, SplitDirection::Below).unwrap();
+        window.select_view(&second).unwrap();
+        window.view_mut(&second).unwrap().set_buffer("b".into());
+        window.view_mut(&second).unwrap().point = 7;
+
+        let state = window.state();
+        let restored = Window::from_state(&state, 80, 24);
+        assert_ne!(restored.id, window.id);
+        assert_eq!(restored.state(), state);
+        assert_eq!(restored.selected_view().buffer_id, "b");
+        assert_eq!(restored.selected_view().point, 7);
+        assert_eq!(rects(&restored), rects(&window));
+    }
+}
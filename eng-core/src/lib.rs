@@ -1,44 +1,550 @@
 pub mod auth;
 pub mod buffer;
+pub mod buffer_menu;
+pub mod builtins;
+pub mod command;
+pub mod command_loop;
+pub mod desktop;
+pub mod handshake;
+pub mod hook;
+pub mod keymap;
+pub mod kmacro;
+pub mod layout;
+pub mod minibuffer;
+pub mod mode;
+pub mod quit;
+pub mod remote;
+pub mod sched;
+pub mod state;
+pub mod subscription;
+pub mod timer;
+pub mod tls;
+pub mod transport;
+pub mod uniquify;
 
 // 自動生成されたコードをインポート
 pub mod editor {
     tonic::include_proto!("editor.v1");
 }
+use command::{execute_command, CommandError, CommandInvocation, PrefixArg};
+use command_loop::{handle_key, KeyOutcome};
+use hook::HookEvent;
 use editor::{
-    editor_service_server::EditorService,
-    HandshakeRequest, HandshakeResponse,
+    buffer_service_server::BufferService, editor_service_server::EditorService, execute_command_response,
+    pairing_service_server::PairingService, PairRequest, PairResponse, key_event,
+    key_event_response, prefix_arg, ApplyEditsRequest, ApplyEditsResponse, CommandInfo, CreateBufferRequest,
+    CreateWindowRequest, CreateWindowResponse, DescribeKeyRequest, DescribeKeyResponse, ExecuteCommandRequest,
+    ExecuteCommandResponse, GetDesktopStatusRequest, GetDesktopStatusResponse, GetLatencyMetricsRequest,
+    GetLatencyMetricsResponse, GetLinesRequest, GetLinesResponse, GetTextRequest, GetTextResponse, HandshakeRequest,
+    HandshakeResponse, KeyEventRequest, KeyEventResponse, LatencyMetric, LayoutUpdate, ListBuffersRequest, ListBuffersResponse, ListCommandsRequest, ListCommandsResponse,
+    MinibufferUpdate, NotifyActivityRequest, NotifyActivityResponse, OpenFileRequest, QuitRequest, QuitResponse,
+    SaveBufferRequest, SaveBufferResponse, SetWindowSizeRequest, SetWindowSizeResponse, SubscribeBufferRequest,
+    SubscribeLayoutRequest, SubscribeMinibufferRequest,
 };
+use keymap::{parse_kbd, Key, KeyCode, Modifiers};
+use layout::WindowLayout;
+use sched::{Edit, EditError, Priority};
+use state::EditorState;
+use std::sync::Arc;
+use subscription::Subscription;
+use tokio::sync::broadcast::error::RecvError;
 use tokio_stream::{Stream, StreamExt};
 use tonic::Status;
 
 #[derive(Debug, Default)]
-pub struct MyEditorService {}
+pub struct MyEditorService {
+    state: Arc<EditorState>,
+}
+
+impl MyEditorService {
+    pub fn new(state: Arc<EditorState>) -> Self {
+        Self { state }
+    }
+}
+
+/// バッファを直接読み書きするサービス (BufferService)
+#[derive(Debug)]
+pub struct MyBufferService {
+    state: Arc<EditorState>,
+}
+
+impl MyBufferService {
+    pub fn new(state: Arc<EditorState>) -> Self {
+        Self { state }
+    }
+
+    async fn snapshot(&self, buffer_id: &str) -> Result<buffer::BufferSnapshot, Status> {
+        self.state
+            .snapshot(buffer_id)
+            .await
+            .ok_or_else(|| Status::not_found(format!("No such buffer: {}", buffer_id)))
+    }
+
+    async fn info(&self, buffer_id: &str) -> Result<editor::BufferInfo, Status> {
+        let buffer = self
+            .state
+            .get_buffer(buffer_id)
+            .await
+            .ok_or_else(|| Status::not_found(format!("No such buffer: {}", buffer_id)))?;
+        let info = buffer.read().await.info(buffer_id);
+        Ok(info.into())
+    }
+}
+
+impl From<buffer::BufferInfo> for editor::BufferInfo {
+    fn from(info: buffer::BufferInfo) -> Self {
+        editor::BufferInfo {
+            buffer_id: info.id,
+            name: info.name,
+            path: info.path.map(|p| p.display().to_string()).unwrap_or_default(),
+            modified: info.modified,
+            read_only: info.read_only,
+            major_mode: info.major_mode,
+            size: info.size as u64,
+            version: info.version,
+        }
+    }
+}
+
+impl From<EditError> for Status {
+    fn from(error: EditError) -> Self {
+        match error {
+            EditError::NoSuchBuffer(_) => Status::not_found(error.to_string()),
+            EditError::Stale { .. } => Status::failed_precondition(error.to_string()),
+            EditError::Rejected(_) => Status::invalid_argument(error.to_string()),
+            EditError::Quit => Status::cancelled(error.to_string()),
+        }
+    }
+}
+
+impl From<subscription::Update> for editor::BufferUpdate {
+    fn from(update: subscription::Update) -> Self {
+        use editor::buffer_update::Update;
+        let update = match update {
+            subscription::Update::Snapshot(snapshot) => Update::Snapshot(editor::BufferText {
+                text: snapshot.to_string(),
+                version: snapshot.version,
+            }),
+            subscription::Update::Lines { version, start_line, lines, total_lines } => {
+                Update::Lines(editor::ViewportLines {
+                    start_line: start_line as u64,
+                    lines,
+                    total_lines: total_lines as u64,
+                    version,
+                })
+            }
+            subscription::Update::Deltas(deltas) => Update::Deltas(editor::BufferDeltas {
+                deltas: deltas
+                    .into_iter()
+                    .map(|d| editor::BufferDelta {
+                        start: d.start as u64,
+                        end: d.end as u64,
+                        text: d.text,
+                        version: d.version,
+                    })
+                    .collect(),
+            }),
+        };
+        editor::BufferUpdate { update: Some(update) }
+    }
+}
+
+impl From<minibuffer::Candidate> for editor::Candidate {
+    fn from(candidate: minibuffer::Candidate) -> Self {
+        editor::Candidate { text: candidate.text, annotation: candidate.annotation }
+    }
+}
+
+impl From<minibuffer::MinibufferUpdate> for MinibufferUpdate {
+    fn from(update: minibuffer::MinibufferUpdate) -> Self {
+        MinibufferUpdate {
+            window_id: update.window_id,
+            depth: update.depth as u32,
+            prompt: update.prompt,
+            input: update.input,
+            cursor: update.cursor as u32,
+            candidates: update.candidates.into_iter().map(Into::into).collect(),
+            selected: update.selected.map(|s| s as u32),
+            total_candidates: update.total_candidates as u32,
+            default_value: update.default,
+        }
+    }
+}
+
+impl From<WindowLayout> for LayoutUpdate {
+    fn from(layout: WindowLayout) -> Self {
+        LayoutUpdate {
+            window_id: layout.window_id,
+            width: layout.width as u32,
+            height: layout.height as u32,
+            selected_view_id: layout.selected_view_id,
+            views: layout
+                .views
+                .into_iter()
+                .map(|v| editor::ViewLayout {
+                    view_id: v.view.id,
+                    buffer_id: v.view.buffer_id,
+                    point: v.view.point as u64,
+                    scroll_line: v.view.scroll_line as u64,
+                    mark: v.view.mark.map(|m| m as u64),
+                    rect: Some(editor::Rect {
+                        x: v.rect.x as u32,
+                        y: v.rect.y as u32,
+                        width: v.rect.width as u32,
+                        height: v.rect.height as u32,
+                    }),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<Option<editor::PrefixArg>> for PrefixArg {
+    fn from(prefix: Option<editor::PrefixArg>) -> Self {
+        let Some(prefix) = prefix else {
+            return PrefixArg::None;
+        };
+        match prefix.kind() {
+            prefix_arg::Kind::None => PrefixArg::None,
+            prefix_arg::Kind::Universal => PrefixArg::Universal(prefix.value),
+            prefix_arg::Kind::Number => PrefixArg::Number(prefix.value),
+            prefix_arg::Kind::Minus => PrefixArg::Minus,
+        }
+    }
+}
+
+impl TryFrom<editor::KeyEvent> for Key {
+    type Error = String;
+
+    fn try_from(event: editor::KeyEvent) -> Result<Self, Self::Error> {
+        let modifiers = Modifiers {
+            ctrl: event.ctrl,
+            meta: event.meta,
+            shift: event.shift,
+            super_key: event.super_key,
+        };
+        let code = match event.code {
+            Some(key_event::Code::Char(c)) => {
+                KeyCode::Char(char::from_u32(c).ok_or_else(|| format!("Invalid character: {}", c))?)
+            }
+            Some(key_event::Code::Named(name)) => KeyCode::Named(name),
+            None => return Err("Key event has no key code".into()),
+        };
+        Ok(Key::new(modifiers, code))
+    }
+}
+
+impl From<KeyOutcome> for KeyEventResponse {
+    fn from(outcome: KeyOutcome) -> Self {
+        use key_event_response::Status as KeyStatus;
+        let mut response = KeyEventResponse::default();
+        match outcome {
+            KeyOutcome::Executed { keys, command, message } => {
+                response.status = KeyStatus::Executed as i32;
+                response.keys = keys;
+                response.command = command;
+                response.message = message.unwrap_or_default();
+            }
+            KeyOutcome::Prefix { keys } => {
+                response.status = KeyStatus::Prefix as i32;
+                response.keys = keys;
+            }
+            KeyOutcome::Undefined { keys } => {
+                response.status = KeyStatus::Undefined as i32;
+                response.message = format!("{} is undefined", keys);
+                response.keys = keys;
+            }
+            KeyOutcome::Prompt { keys, command, prompt } => {
+                response.status = KeyStatus::Minibuffer as i32;
+                response.keys = keys;
+                response.command = command;
+                response.prompt = prompt;
+            }
+            KeyOutcome::Failed { keys, command, error } => {
+                response.keys = keys;
+                response.command = command;
+                match error {
+                    CommandError::MissingArgument(request) => {
+                        response.status = KeyStatus::MissingArgument as i32;
+                        response.prompt = request.prompt;
+                    }
+                    CommandError::Quit => {
+                        response.status = KeyStatus::Quit as i32;
+                        response.message = CommandError::Quit.to_string();
+                    }
+                    error => {
+                        response.status = KeyStatus::Error as i32;
+                        response.message = error.to_string();
+                    }
+                }
+            }
+        }
+        response
+    }
+}
+
+#[tonic::async_trait]
+impl BufferService for MyBufferService {
+    async fn create_buffer(
+        &self,
+        request: tonic::Request<CreateBufferRequest>,
+    ) -> Result<tonic::Response<editor::BufferInfo>, Status> {
+        let req = request.into_inner();
+        if req.name.is_empty() {
+            return Err(Status::invalid_argument("name is required"));
+        }
+        let buffer_id = self.state.create_buffer(req.name, &req.text).await;
+        Ok(tonic::Response::new(self.info(&buffer_id).await?))
+    }
+
+    async fn open_file(
+        &self,
+        request: tonic::Request<OpenFileRequest>,
+    ) -> Result<tonic::Response<editor::BufferInfo>, Status> {
+        let path = std::path::PathBuf::from(request.into_inner().path);
+        let buffer_id = self.state.find_file(&path).await.map_err(Status::not_found)?;
+        Ok(tonic::Response::new(self.info(&buffer_id).await?))
+    }
+
+    async fn get_text(
+        &self,
+        request: tonic::Request<GetTextRequest>,
+    ) -> Result<tonic::Response<GetTextResponse>, Status> {
+        let req = request.into_inner();
+        let snapshot = self.snapshot(&req.buffer_id).await?;
+        let end = req.end.map_or(snapshot.len_chars(), |end| end as usize);
+        let text = snapshot
+            .text_range(req.start as usize..end)
+            .map_err(Status::out_of_range)?;
+        Ok(tonic::Response::new(GetTextResponse { text, version: snapshot.version }))
+    }
+
+    async fn get_lines(
+        &self,
+        request: tonic::Request<GetLinesRequest>,
+    ) -> Result<tonic::Response<GetLinesResponse>, Status> {
+        let req = request.into_inner();
+        let snapshot = self.snapshot(&req.buffer_id).await?;
+        let start = req.start_line as usize;
+        let lines = (start..start.saturating_add(req.count as usize))
+            .map_while(|line| snapshot.line(line))
+            .collect();
+        Ok(tonic::Response::new(GetLinesResponse {
+            lines,
+            total_lines: snapshot.len_lines() as u64,
+            version: snapshot.version,
+        }))
+    }
+
+    async fn apply_edits(
+        &self,
+        request: tonic::Request<ApplyEditsRequest>,
+    ) -> Result<tonic::Response<ApplyEditsResponse>, Status> {
+        let req = request.into_inner();
+        let _input = self.state.scheduler().interactive("edit-rpc");
+        let version = self.snapshot(&req.buffer_id).await?.version;
+        if req.edits.is_empty() {
+            return Ok(tonic::Response::new(ApplyEditsResponse { version }));
+        }
+        let mut changes = Vec::with_capacity(req.edits.len());
+        for text_edit in req.edits {
+            if text_edit.start > text_edit.end {
+                return Err(Status::invalid_argument(format!("Invalid range: {}..{}", text_edit.start, text_edit.end)));
+            }
+            changes.push((text_edit.start as usize..text_edit.end as usize, text_edit.text));
+        }
+        // 全ての編集を1つの書き込みで適用する。どれかが不正なら何も適用しない。
+        let edit = Edit {
+            buffer_id: req.buffer_id.clone(),
+            changes,
+            base_version: req.base_version,
+            priority: Priority::Interactive,
+            log: None,
+        };
+        let version = sched::submit(&self.state, edit).await?;
+        hook::run_after_change(&self.state).await;
+        Ok(tonic::Response::new(ApplyEditsResponse { version }))
+    }
+
+    async fn save_buffer(
+        &self,
+        request: tonic::Request<SaveBufferRequest>,
+    ) -> Result<tonic::Response<SaveBufferResponse>, Status> {
+        let req = request.into_inner();
+        if !req.path.is_empty() {
+            self.state
+                .set_buffer_file(&req.buffer_id, req.path.into())
+                .await
+                .map_err(Status::not_found)?;
+        }
+        let path = self
+            .state
+            .save_buffer(&req.buffer_id)
+            .await
+            .map_err(Status::failed_precondition)?;
+        let version = self.snapshot(&req.buffer_id).await?.version;
+        Ok(tonic::Response::new(SaveBufferResponse { path: path.display().to_string(), version }))
+    }
+
+    async fn list_buffers(
+        &self,
+        _request: tonic::Request<ListBuffersRequest>,
+    ) -> Result<tonic::Response<ListBuffersResponse>, Status> {
+        let buffers = self.state.buffer_list().await.into_iter().map(Into::into).collect();
+        Ok(tonic::Response::new(ListBuffersResponse { buffers }))
+    }
+
+    type SubscribeBufferStream =
+        std::pin::Pin<Box<dyn Stream<Item = Result<editor::BufferUpdate, Status>> + Send + Sync + 'static>>;
+
+    async fn subscribe_buffer(
+        &self,
+        request: tonic::Request<SubscribeBufferRequest>,
+    ) -> Result<tonic::Response<Self::SubscribeBufferStream>, Status> {
+        let req = request.into_inner();
+        let viewport = req.viewport.map(|v| subscription::Viewport {
+            start_line: v.start_line as usize,
+            count: v.count as usize,
+        });
+        let mut subscription = Subscription::new(&self.state, &req.buffer_id, req.resume_from_version, viewport)
+            .await
+            .map_err(Status::not_found)?;
+
+        // 送り終えてから次の更新を作るので、読むのが遅いクライアントには溜まった変更がまとめて届く
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                let permit = match tx.reserve().await {
+                    Ok(permit) => permit,
+                    Err(_) => break,
+                };
+                // 変更が無いまま切断されたら終わる
+                let update = tokio::select! {
+                    update = subscription.next() => update,
+                    _ = tx.closed() => None,
+                };
+                match update {
+                    Some(update) => permit.send(Ok(update.into())),
+                    None => break,
+                }
+            }
+        });
+
+        Ok(tonic::Response::new(Box::pin(tokio_stream::wrappers::ReceiverStream::new(rx))))
+    }
+}
+
+/// TLS で待ち受けているときのペアリング (PairingService)。core と agent の両方が使う。
+#[derive(Debug)]
+pub struct MyPairingService {
+    pins: Arc<tls::Pins>,
+    pairing: Arc<tls::Pairing>,
+    server_name: String,
+}
+
+impl MyPairingService {
+    pub fn new(pins: Arc<tls::Pins>, pairing: Arc<tls::Pairing>, server_name: String) -> Self {
+        Self { pins, pairing, server_name }
+    }
+}
+
+#[tonic::async_trait]
+impl PairingService for MyPairingService {
+    async fn pair(&self, request: tonic::Request<PairRequest>) -> Result<tonic::Response<PairResponse>, Status> {
+        let Some(peer) = request.extensions().get::<tls::PeerCertificate>().cloned() else {
+            return Err(Status::failed_precondition("Pairing requires a TLS connection with a client certificate"));
+        };
+        let request = request.into_inner();
+        if !self.pairing.redeem(&request.code) {
+            return Err(Status::permission_denied("Invalid or expired pairing code"));
+        }
+        let name = match request.client_name.trim() {
+            "" => peer.remote_addr.map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string()),
+            name => name.to_string(),
+        };
+        self.pins.add(&peer.fingerprint, &name).map_err(Status::internal)?;
+        eprintln!("Paired client {:?} ({})", name, peer.fingerprint);
+        Ok(tonic::Response::new(PairResponse { server_name: self.server_name.clone(), fingerprint: peer.fingerprint }))
+    }
+}
+
+impl TryFrom<HandshakeRequest> for handshake::Hello {
+    type Error = handshake::HandshakeError;
 
-// ロジックを分離してテスト可能にする
-fn handle_handshake_logic<S>(mut in_stream: S) -> impl Stream<Item = Result<HandshakeResponse, Status>>
+    fn try_from(req: HandshakeRequest) -> Result<Self, Self::Error> {
+        use editor::handshake_request::ClientKind as Kind;
+        // 旧プロトコルのクライアントは版も種類も送ってこないので、版を先に確かめる
+        if req.protocol_version == 0 {
+            return Err(handshake::HandshakeError::LegacyClient);
+        }
+        let kind = match Kind::try_from(req.client_kind) {
+            Ok(Kind::Gui) => handshake::ClientKind::Gui,
+            Ok(Kind::Tui) => handshake::ClientKind::Tui,
+            Ok(Kind::Web) => handshake::ClientKind::Web,
+            Ok(Kind::Cli) => handshake::ClientKind::Cli,
+            Ok(Kind::Agent) => handshake::ClientKind::Agent,
+            Ok(Kind::Unspecified) | Err(_) => return Err(handshake::HandshakeError::UnknownClientKind),
+        };
+        let min_protocol_version = match req.min_protocol_version {
+            0 => req.protocol_version,
+            min => min,
+        };
+        Ok(handshake::Hello {
+            min_protocol_version,
+            protocol_version: req.protocol_version,
+            kind,
+            name: req.client_name,
+            capabilities: req.capabilities,
+            encodings: req.encodings,
+            session_id: Some(req.session_id).filter(|id| !id.is_empty()),
+        })
+    }
+}
+
+/// ハンドシェイクのストリームを処理する。最初のメッセージで取り決め、以降はクライアントが
+/// ストリームを閉じるまで接続中として clients に記録しておく。
+/// ロジックを分離してテスト可能にする (Agent も同じものを使う)。
+pub fn handle_handshake_logic<S>(
+    clients: Arc<handshake::Clients>,
+    server_name: String,
+    mut in_stream: S,
+) -> impl Stream<Item = Result<HandshakeResponse, Status>>
 where
     S: Stream<Item = Result<HandshakeRequest, Status>> + Unpin + Send + 'static,
 {
-    let (tx, rx) = tokio::sync::mpsc::channel(128);
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
 
     tokio::spawn(async move {
-        while let Some(result) = in_stream.next().await {
-            match result {
-                Ok(req) => {
-                    let response = HandshakeResponse {
-                        server_message: format!("Echo: {}", req.client_message),
-                    };
-                    if tx.send(Ok(response)).await.is_err() {
-                        break;
-                    }
-                }
-                Err(status) => {
-                    eprintln!("Handshake stream error: {:?}", status);
-                    break;
-                }
+        let hello = match in_stream.next().await {
+            Some(Ok(req)) => handshake::Hello::try_from(req),
+            Some(Err(status)) => {
+                eprintln!("Handshake stream error: {:?}", status);
+                return;
+            }
+            None => Err(handshake::HandshakeError::NoHello),
+        };
+        let negotiated = hello.and_then(|hello| handshake::negotiate(&hello).map(|welcome| (hello, welcome)));
+        let (hello, welcome) = match negotiated {
+            Ok(negotiated) => negotiated,
+            Err(error) => {
+                let _ = tx.send(Err(Status::failed_precondition(error.to_string()))).await;
+                return;
             }
+        };
+        let _guard = clients.connect(&hello, &welcome);
+        let response = HandshakeResponse {
+            protocol_version: welcome.protocol_version,
+            client_id: welcome.client_id,
+            session_id: welcome.session_id,
+            capabilities: welcome.capabilities,
+            encoding: welcome.encoding,
+            server_name,
+        };
+        if tx.send(Ok(response)).await.is_err() {
+            return;
         }
+        // 以降のメッセージは今のところ使わない。閉じるまで読み続ける。
+        while let Some(Ok(_)) = in_stream.next().await {}
     });
 
     tokio_stream::wrappers::ReceiverStream::new(rx)
@@ -53,9 +559,277 @@ impl EditorService for MyEditorService {
         request: tonic::Request<tonic::Streaming<HandshakeRequest>>,
     ) -> Result<tonic::Response<Self::HandshakeStream>, Status> {
         let in_stream = request.into_inner();
-        let out_stream = handle_handshake_logic(in_stream);
+        let server_name = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        let out_stream = handle_handshake_logic(self.state.clients().clone(), server_name, in_stream);
         Ok(tonic::Response::new(Box::pin(out_stream)))
     }
+
+    async fn create_window(
+        &self,
+        request: tonic::Request<CreateWindowRequest>,
+    ) -> Result<tonic::Response<CreateWindowResponse>, Status> {
+        let req = request.into_inner();
+        let window_id = self
+            .state
+            .create_window(&req.buffer_id)
+            .await
+            .map_err(Status::not_found)?;
+        Ok(tonic::Response::new(CreateWindowResponse { window_id }))
+    }
+
+    async fn set_window_size(
+        &self,
+        request: tonic::Request<SetWindowSizeRequest>,
+    ) -> Result<tonic::Response<SetWindowSizeResponse>, Status> {
+        let req = request.into_inner();
+        let width = u16::try_from(req.width).map_err(|_| Status::invalid_argument("width too large"))?;
+        let height = u16::try_from(req.height).map_err(|_| Status::invalid_argument("height too large"))?;
+        self.state
+            .update_window(&req.window_id, |w| {
+                w.set_size(width, height);
+                Ok(())
+            })
+            .await
+            .map_err(Status::not_found)?;
+        Ok(tonic::Response::new(SetWindowSizeResponse {}))
+    }
+
+    type SubscribeLayoutStream = std::pin::Pin<Box<dyn Stream<Item = Result<LayoutUpdate, Status>> + Send + Sync + 'static>>;
+
+    async fn subscribe_layout(
+        &self,
+        request: tonic::Request<SubscribeLayoutRequest>,
+    ) -> Result<tonic::Response<Self::SubscribeLayoutStream>, Status> {
+        let window_id = request.into_inner().window_id;
+        // スナップショット取得前に購読を開始し、その間の更新を取りこぼさないようにする
+        let mut updates = self.state.subscribe_layout();
+        let initial = self
+            .state
+            .window_layout(&window_id)
+            .await
+            .ok_or_else(|| Status::not_found(format!("No such window: {}", window_id)))?;
+
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move {
+            if tx.send(Ok(initial.into())).await.is_err() {
+                return;
+            }
+            loop {
+                match updates.recv().await {
+                    Ok(layout) if layout.window_id == window_id => {
+                        if tx.send(Ok(layout.into())).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    // 更新は常に全体のスナップショットなので、遅れた分は読み飛ばしてよい
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        Ok(tonic::Response::new(Box::pin(tokio_stream::wrappers::ReceiverStream::new(rx))))
+    }
+
+    async fn execute_command(
+        &self,
+        request: tonic::Request<ExecuteCommandRequest>,
+    ) -> Result<tonic::Response<ExecuteCommandResponse>, Status> {
+        let req = request.into_inner();
+        let invocation = CommandInvocation { prefix: req.prefix.into(), args: req.args, ..Default::default() };
+        self.state.timers().note_activity();
+        let _input = self.state.scheduler().interactive("command");
+        let pre_command = HookEvent::PreCommand { window_id: req.window_id.clone(), command: req.command.clone() };
+        hook::run(&self.state, pre_command).await;
+        let result = execute_command(self.state.clone(), &req.window_id, &req.command, invocation).await;
+        hook::command_finished(&self.state, &req.window_id, &req.command).await;
+
+        use execute_command_response::Status as CommandStatus;
+        let response = match result {
+            Ok(message) => ExecuteCommandResponse {
+                status: CommandStatus::Ok as i32,
+                message: message.unwrap_or_default(),
+                prompt: String::new(),
+            },
+            Err(CommandError::UnknownCommand(name)) => {
+                return Err(Status::not_found(format!("Unknown command: {}", name)));
+            }
+            Err(CommandError::MissingArgument(request)) => ExecuteCommandResponse {
+                status: CommandStatus::MissingArgument as i32,
+                message: String::new(),
+                prompt: request.prompt,
+            },
+            Err(CommandError::Quit) => ExecuteCommandResponse {
+                status: CommandStatus::Quit as i32,
+                message: CommandError::Quit.to_string(),
+                prompt: String::new(),
+            },
+            Err(CommandError::Failed(message)) => ExecuteCommandResponse {
+                status: CommandStatus::Error as i32,
+                message,
+                prompt: String::new(),
+            },
+        };
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn list_commands(
+        &self,
+        _request: tonic::Request<ListCommandsRequest>,
+    ) -> Result<tonic::Response<ListCommandsResponse>, Status> {
+        let commands = self
+            .state
+            .commands()
+            .read()
+            .await
+            .commands()
+            .iter()
+            .map(|c| CommandInfo { name: c.name.clone(), doc: c.doc.clone() })
+            .collect();
+        Ok(tonic::Response::new(ListCommandsResponse { commands }))
+    }
+
+    async fn send_key(
+        &self,
+        request: tonic::Request<KeyEventRequest>,
+    ) -> Result<tonic::Response<KeyEventResponse>, Status> {
+        let req = request.into_inner();
+        let event = req.key.ok_or_else(|| Status::invalid_argument("key is required"))?;
+        let key = Key::try_from(event).map_err(Status::invalid_argument)?;
+        let _input = self.state.scheduler().interactive("key");
+        // C-g は実行中のコマンドの完了を待たずに中断を要求する。
+        // 中断されたコマンドを送ったRPCは QUIT で返る。
+        if key == Key::ctrl('g') && self.state.running().quit(&req.window_id) {
+            let command = self.state.running().command(&req.window_id).unwrap_or_default();
+            let outcome = KeyOutcome::Failed { keys: key.to_string(), command, error: CommandError::Quit };
+            return Ok(tonic::Response::new(outcome.into()));
+        }
+        let outcome = handle_key(&self.state, &req.window_id, key)
+            .await
+            .map_err(Status::not_found)?;
+        Ok(tonic::Response::new(outcome.into()))
+    }
+
+    async fn describe_key(
+        &self,
+        request: tonic::Request<DescribeKeyRequest>,
+    ) -> Result<tonic::Response<DescribeKeyResponse>, Status> {
+        let req = request.into_inner();
+        let keys = parse_kbd(&req.keys).map_err(Status::invalid_argument)?;
+        let buffer_id = self
+            .state
+            .read_window(&req.window_id, |w| w.selected_view().buffer_id.clone())
+            .await
+            .map_err(Status::not_found)?;
+        let description = self.state.describe_key(&buffer_id, &keys).await;
+        let doc = match &description.command {
+            Some(command) => self.state.commands().read().await.get(command).map(|c| c.doc.clone()),
+            None => None,
+        };
+        Ok(tonic::Response::new(DescribeKeyResponse {
+            keys: description.keys,
+            command: description.command.unwrap_or_default(),
+            keymap: description.level.map(|l| l.to_string()).unwrap_or_default(),
+            doc: doc.unwrap_or_default(),
+        }))
+    }
+
+    type SubscribeMinibufferStream =
+        std::pin::Pin<Box<dyn Stream<Item = Result<MinibufferUpdate, Status>> + Send + Sync + 'static>>;
+
+    async fn subscribe_minibuffer(
+        &self,
+        request: tonic::Request<SubscribeMinibufferRequest>,
+    ) -> Result<tonic::Response<Self::SubscribeMinibufferStream>, Status> {
+        let window_id = request.into_inner().window_id;
+        let mut updates = self.state.subscribe_minibuffer();
+        self.state
+            .read_window(&window_id, |_| ())
+            .await
+            .map_err(Status::not_found)?;
+        let initial = minibuffer::current_update(&self.state, &window_id).await;
+
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move {
+            if tx.send(Ok(initial.into())).await.is_err() {
+                return;
+            }
+            loop {
+                match updates.recv().await {
+                    Ok(update) if update.window_id == window_id => {
+                        if tx.send(Ok(update.into())).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    // 更新は常に全体のスナップショットなので、遅れた分は読み飛ばしてよい
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        Ok(tonic::Response::new(Box::pin(tokio_stream::wrappers::ReceiverStream::new(rx))))
+    }
+
+    async fn quit(
+        &self,
+        request: tonic::Request<QuitRequest>,
+    ) -> Result<tonic::Response<QuitResponse>, Status> {
+        let window_id = request.into_inner().window_id;
+        let command = self.state.running().command(&window_id).unwrap_or_default();
+        let quit = self.state.running().quit(&window_id);
+        Ok(tonic::Response::new(QuitResponse { quit, command }))
+    }
+
+    async fn get_latency_metrics(
+        &self,
+        _request: tonic::Request<GetLatencyMetricsRequest>,
+    ) -> Result<tonic::Response<GetLatencyMetricsResponse>, Status> {
+        let metrics = self
+            .state
+            .scheduler()
+            .metrics()
+            .into_iter()
+            .map(|(kind, stats)| LatencyMetric {
+                kind,
+                count: stats.count,
+                mean_us: stats.mean().as_micros() as u64,
+                max_us: stats.max.as_micros() as u64,
+                over_budget: stats.over_budget,
+                buckets: stats.buckets.to_vec(),
+            })
+            .collect();
+        Ok(tonic::Response::new(GetLatencyMetricsResponse {
+            budget_us: sched::INPUT_LATENCY_BUDGET.as_micros() as u64,
+            metrics,
+        }))
+    }
+
+    async fn get_desktop_status(
+        &self,
+        _request: tonic::Request<GetDesktopStatusRequest>,
+    ) -> Result<tonic::Response<GetDesktopStatusResponse>, Status> {
+        let Some(report) = self.state.restored().lock().await.clone() else {
+            return Ok(tonic::Response::new(GetDesktopStatusResponse::default()));
+        };
+        Ok(tonic::Response::new(GetDesktopStatusResponse {
+            restored: true,
+            buffers: report.restored as u32,
+            recovered: report.recovered as u32,
+            conflicts: report.conflicts.iter().map(|path| path.display().to_string()).collect(),
+            failed: report.failed.iter().map(|(path, _)| path.display().to_string()).collect(),
+        }))
+    }
+
+    async fn notify_activity(
+        &self,
+        _request: tonic::Request<NotifyActivityRequest>,
+    ) -> Result<tonic::Response<NotifyActivityResponse>, Status> {
+        self.state.timers().note_activity();
+        Ok(tonic::Response::new(NotifyActivityResponse {}))
+    }
 }
 
 
@@ -66,18 +840,28 @@ mod tests {
     use tonic::Status;
     use tokio_stream::{Stream, StreamExt};
     use tokio::sync::mpsc;
-    use crate::editor::HandshakeRequest;
+    use crate::editor::handshake_request::ClientKind;
+    use crate::editor::{HandshakeRequest, HandshakeResponse};
     use crate::handle_handshake_logic;
+    use crate::handshake::{self, Clients, PROTOCOL_VERSION};
+    use std::sync::Arc;
 
-    // モックのストリームを作成するためのヘルパー関数
-    fn create_mock_request_stream(messages: Vec<String>) -> impl Stream<Item = Result<HandshakeRequest, Status>> {
+    // モックのストリームを作成するためのヘルパー関数。送信側を返すので、閉じる時機をテストで決められる。
+    fn create_mock_request_stream() -> (mpsc::Sender<HandshakeRequest>, impl Stream<Item = Result<HandshakeRequest, Status>>) {
         let (tx, rx) = mpsc::channel(4);
-        tokio::spawn(async move {
-            for msg in messages {
-                tx.send(HandshakeRequest { client_message: msg }).await.unwrap();
-            }
-        });
-        tokio_stream::wrappers::ReceiverStream::new(rx).map(Ok)
+        (tx, tokio_stream::wrappers::ReceiverStream::new(rx).map(Ok))
+    }
+
+    fn hello(protocol_version: u32) -> HandshakeRequest {
+        HandshakeRequest {
+            protocol_version,
+            min_protocol_version: 0,
+            client_kind: ClientKind::Gui.into(),
+            client_name: "test-ui".into(),
+            capabilities: vec!["layout".into()],
+            encodings: vec![],
+            session_id: String::new(),
+        }
     }
 
     #[test]
@@ -121,20 +905,127 @@ mod tests {
     #[tokio::test]
     async fn test_handshake_server_logic() {
         // サービス経由ではなくロジック関数を直接テストする
-        let client_messages = vec!["Hello".to_string(), "World".to_string()];
-        let request_stream = create_mock_request_stream(client_messages.clone());
+        let clients = Arc::new(Clients::default());
+        let (tx, request_stream) = create_mock_request_stream();
+        let mut response_stream = handle_handshake_logic(clients.clone(), "core".into(), request_stream);
+        tx.send(hello(PROTOCOL_VERSION)).await.unwrap();
 
-        let mut response_stream = handle_handshake_logic(request_stream);
+        let response = response_stream.next().await.unwrap().unwrap();
+        assert_eq!(response.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(response.capabilities, ["layout"]);
+        assert_eq!((response.encoding.as_str(), response.server_name.as_str()), ("utf-8", "core"));
+        let client = clients.get(&response.client_id).unwrap();
+        assert_eq!((client.name.as_str(), client.session_id), ("test-ui", response.session_id));
 
-        // 最初のメッセージを検証
-        let first_response = response_stream.next().await.unwrap().unwrap();
-        assert_eq!(first_response.server_message, "Echo: Hello".to_string());
-        
-        // 2番目のメッセージを検証
-        let second_response = response_stream.next().await.unwrap().unwrap();
-        assert_eq!(second_response.server_message, "Echo: World".to_string());
-
-        // ストリームが終了したことを確認
+        // クライアントがストリームを閉じると接続中ではなくなる
+        drop(tx);
         assert!(response_stream.next().await.is_none());
+        assert!(clients.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_incompatible_client() {
+        let clients = Arc::new(Clients::default());
+        for request in [hello(PROTOCOL_VERSION + 1), HandshakeRequest { client_kind: 0, ..hello(PROTOCOL_VERSION) }] {
+            let min_protocol_version = request.protocol_version;
+            let request = HandshakeRequest { min_protocol_version, ..request };
+            let (tx, request_stream) = create_mock_request_stream();
+            let mut response_stream = handle_handshake_logic(clients.clone(), "core".into(), request_stream);
+            tx.send(request).await.unwrap();
+            let status = response_stream.next().await.unwrap().unwrap_err();
+            assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+            assert!(response_stream.next().await.is_none());
+        }
+        assert!(clients.list().is_empty());
     }
-}
\ No newline at end of file
+
+    /// 版の取り決めの無い旧プロトコルのメッセージ
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct LegacyMessage {
+        #[prost(string, tag = "1")]
+        message: String,
+    }
+
+    #[tokio::test]
+    async fn test_handshake_detects_legacy_peer() {
+        use prost::Message;
+
+        let legacy = LegacyMessage { message: "Hello, Core! from UI-PID-12345".into() }.encode_to_vec();
+        let request = HandshakeRequest::decode(legacy.as_slice()).unwrap();
+        let (tx, request_stream) = create_mock_request_stream();
+        let mut response_stream = handle_handshake_logic(Arc::new(Clients::default()), "core".into(), request_stream);
+        tx.send(request).await.unwrap();
+        let status = response_stream.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        assert!(status.message().contains("client speaks the legacy protocol"), "{}", status.message());
+
+        let response = HandshakeResponse::decode(legacy.as_slice()).unwrap();
+        assert_eq!(handshake::check_server_version(response.protocol_version), Err(handshake::HandshakeError::LegacyServer));
+        assert_eq!(handshake::check_server_version(PROTOCOL_VERSION), Ok(PROTOCOL_VERSION));
+    }
+
+    #[tokio::test]
+    async fn test_buffer_service() {
+        use crate::editor::buffer_service_server::BufferService;
+        use crate::editor::*;
+        use crate::state::EditorState;
+        use tonic::{Code, Request};
+
+        let state = Arc::new(EditorState::new());
+        tokio::spawn(crate::sched::run(state.clone()));
+        let service = crate::MyBufferService::new(state);
+
+        let dir = std::env::temp_dir().join(format!("eng-buffer-service-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.txt");
+        std::fs::write(&path, "one\ntwo\nthree\n").unwrap();
+        let request = OpenFileRequest { path: path.display().to_string() };
+        let info = service.open_file(Request::new(request)).await.unwrap().into_inner();
+        assert_eq!((info.name.as_str(), info.size, info.modified), ("a.txt", 14, false));
+        let id = info.buffer_id;
+
+        let request = GetLinesRequest { buffer_id: id.clone(), start_line: 1, count: 10 };
+        let lines = service.get_lines(Request::new(request)).await.unwrap().into_inner();
+        assert_eq!(lines.lines, ["two", "three", ""]);
+        assert_eq!(lines.total_lines, 4);
+
+        // 2つ目の編集の位置は、1つ目を適用した後のテキストに対するもの
+        let edits = vec![
+            TextEdit { start: 0, end: 3, text: "ONE".into() },
+            TextEdit { start: 3, end: 3, text: "!".into() },
+        ];
+        let request = ApplyEditsRequest { buffer_id: id.clone(), edits, base_version: Some(lines.version) };
+        let version = service.apply_edits(Request::new(request)).await.unwrap().into_inner().version;
+        // 古い版に対する編集は拒否される
+        let edits = vec![TextEdit { start: 0, end: 0, text: "x".into() }];
+        let request = ApplyEditsRequest { buffer_id: id.clone(), edits, base_version: Some(lines.version) };
+        let status = service.apply_edits(Request::new(request)).await.unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        // 2つ目が範囲外なら1つ目も適用しない
+        let edits = vec![
+            TextEdit { start: 0, end: 0, text: "x".into() },
+            TextEdit { start: 100, end: 100, text: "y".into() },
+        ];
+        let request = ApplyEditsRequest { buffer_id: id.clone(), edits, base_version: None };
+        let status = service.apply_edits(Request::new(request)).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let request = GetTextRequest { buffer_id: id.clone(), start: 0, end: Some(8) };
+        let text = service.get_text(Request::new(request)).await.unwrap().into_inner();
+        assert_eq!((text.text.as_str(), text.version), ("ONE!\ntwo", version));
+        let request = GetTextRequest { buffer_id: id.clone(), start: 0, end: Some(100) };
+        assert_eq!(service.get_text(Request::new(request)).await.unwrap_err().code(), Code::OutOfRange);
+
+        let request = SaveBufferRequest { buffer_id: id.clone(), path: String::new() };
+        service.save_buffer(Request::new(request)).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "ONE!\ntwo\nthree\n");
+
+        let request = CreateBufferRequest { name: "notes".into(), text: "hi".into() };
+        service.create_buffer(Request::new(request)).await.unwrap();
+        let buffers = service.list_buffers(Request::new(ListBuffersRequest {})).await.unwrap().into_inner().buffers;
+        let mut names: Vec<_> = buffers.iter().map(|b| (b.name.as_str(), b.modified, b.size)).collect();
+        names.sort();
+        assert_eq!(names, [("a.txt", false, 15), ("notes", false, 2)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,14 +1,126 @@
-use std::net::SocketAddr;
 use tokio::net::TcpListener;
-use tokio_stream::wrappers::TcpListenerStream;
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+use tonic::transport::server::Connected;
+use tonic::codegen::InterceptedService;
 use tonic::transport::Server;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
 use eng_core::auth::AuthInterceptor;
+use eng_core::desktop::{self, DesktopConfig, DESKTOP_AUTO_SAVE_INTERVAL};
+use eng_core::sched;
+use eng_core::timer;
+use eng_core::editor::buffer_service_server::BufferServiceServer;
 use eng_core::editor::editor_service_server::EditorServiceServer;
-use eng_core::MyEditorService;
+use eng_core::editor::pairing_service_server::PairingServiceServer;
+use eng_core::state::EditorState;
+use eng_core::tls;
+use eng_core::transport::{self, Address};
+use eng_core::{MyBufferService, MyEditorService, MyPairingService};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::rustls::ServerConfig;
+use std::future::Future;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_stream::{Stream, StreamExt};
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // 認証トークンを標準入力から読み込む
+enum Mode {
+    /// 待ち受けて、起動した側に待ち受け先を知らせる
+    Listen(Address),
+    /// 標準入出力の上で gRPC を話す (ssh host eng-core --stdio など)。待ち受けない。
+    Stdio,
+    /// localhost の外から接続させる。TLS で待ち受け、ペアリングしたクライアント証明書だけを受け入れる。
+    ListenTls(SocketAddr),
+}
+
+/// --listen unix:PATH または --listen tcp:HOST:PORT で待ち受け先を、--stdio で標準入出力を使うことを指定する。
+/// --listen tcp:HOST:PORT --tls で TLS を使う。省略すると実行時ディレクトリの Unix ソケットで待ち受ける。
+fn parse_mode() -> Result<Mode, Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => Ok(Mode::Listen(Address::Unix(transport::socket_path(&format!("core-{}", std::process::id()))?))),
+        [flag] if flag == "--stdio" => Ok(Mode::Stdio),
+        [flag, value] if flag == "--listen" => Ok(Mode::Listen(value.parse()?)),
+        [flag, value, tls] if flag == "--listen" && tls == "--tls" => match value.parse()? {
+            Address::Tcp(addr) => Ok(Mode::ListenTls(addr)),
+            Address::Unix(_) => Err("--tls requires a tcp: address".into()),
+        },
+        [flag] if flag == "--listen" => Err("--listen requires an address".into()),
+        [arg, ..] => Err(format!("Unknown argument: {}", arg).into()),
+    }
+}
+
+/// エディタの状態を読めるかを確かめる間隔と、応答が無いとみなすまでの時間
+const HEALTH_PROBE_INTERVAL: Duration = Duration::from_secs(2);
+const HEALTH_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+enum Listener {
+    Unix(tokio::net::UnixListener, std::path::PathBuf),
+    Tcp(TcpListener),
+    Tls(TcpListener, Arc<ServerConfig>, MyPairingService),
+    Stdio,
+}
+
+async fn serve<I, IO, IE>(
+    state: Arc<EditorState>,
+    interceptor: AuthInterceptor,
+    pairing: Option<MyPairingService>,
+    incoming: I,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), tonic::transport::Error>
+where
+    I: Stream<Item = Result<IO, IE>>,
+    IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+    IE: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let service = MyEditorService::new(state.clone());
+    let buffer_service = MyBufferService::new(state.clone());
+    let (reporter, health_service) = tonic_health::server::health_reporter();
+    let probe = tokio::spawn(probe_health(state, reporter));
+
+    let result = Server::builder()
+        .http2_keepalive_interval(Some(std::time::Duration::from_secs(10)))
+        .http2_keepalive_timeout(Some(std::time::Duration::from_secs(5)))
+        .add_service(EditorServiceServer::with_interceptor(service, interceptor.clone()))
+        .add_service(BufferServiceServer::with_interceptor(buffer_service, interceptor.clone()))
+        .add_service(InterceptedService::new(health_service, interceptor))
+        .add_optional_service(pairing.map(PairingServiceServer::new))
+        .serve_with_incoming_shutdown(incoming, async {
+            // SIGTERM は Agent が StopCore で送る。どちらでも desktop を保存してから終わる。
+            let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).ok();
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = async {
+                    match &mut terminate {
+                        Some(terminate) => terminate.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {}
+                _ = shutdown => {}
+            }
+        })
+        .await;
+    probe.abort();
+    result
+}
+
+/// gRPC のヘルスチェック (grpc.health.v1) の全体の状態を、エディタの状態を読めるかどうかで更新し続ける。
+/// 接続には応じても中が固まっているコアを、Agent がこれで見つけて起動し直す。
+async fn probe_health(state: Arc<EditorState>, mut reporter: HealthReporter) {
+    let mut serving = true;
+    loop {
+        tokio::time::sleep(HEALTH_PROBE_INTERVAL).await;
+        let responsive = tokio::time::timeout(HEALTH_PROBE_TIMEOUT, state.buffer_list()).await.is_ok();
+        if responsive != serving {
+            serving = responsive;
+            let status = if responsive { ServingStatus::Serving } else { ServingStatus::NotServing };
+            reporter.set_service_status("", status).await;
+        }
+    }
+}
+
+/// 認証トークンを標準入力から読み込む
+fn read_auth_token() -> Result<AuthInterceptor, Box<dyn std::error::Error>> {
     let mut auth_token = String::new();
     std::io::stdin().read_line(&mut auth_token)?;
     let auth_token = auth_token.trim().to_string();
@@ -19,26 +131,95 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     // AuthInterceptorの初期化
-    let interceptor = AuthInterceptor::new(auth_token)?;
+    Ok(AuthInterceptor::new(auth_token)?)
+}
 
-    let addr: SocketAddr = "[::1]:0".parse()?; // 動的にポートを割り当てる
-    let listener = TcpListener::bind(addr).await?;
-    let local_addr = listener.local_addr()?;
-    
-    // ポート番号のみを標準出力に書き出す（UIプロセスがこれを読み取る）
-    println!("{}", local_addr.port());
-    
-    eprintln!("Core server listening on {}", local_addr);
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (listener, interceptor) = match parse_mode()? {
+        // 標準入出力は gRPC が使うので、トークンも待ち受け先もやり取りしない。
+        // パイプを持っているのは起動した側だけなので、トークンの検査は要らない。
+        Mode::Stdio => {
+            eprintln!("Core server serving on stdio");
+            (Listener::Stdio, AuthInterceptor::trusted())
+        }
+        Mode::Listen(address) => {
+            let interceptor = read_auth_token()?;
+            let listener = match &address {
+                Address::Unix(path) => Listener::Unix(transport::bind_unix(path)?, path.clone()),
+                // ポート 0 なら動的に割り当てる
+                Address::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr).await?),
+            };
+            let address = match &listener {
+                Listener::Tcp(listener) => Address::Tcp(listener.local_addr()?),
+                _ => address,
+            };
 
-    let incoming = TcpListenerStream::new(listener);
-    let service = MyEditorService::default();
+            // 待ち受け先を標準出力に書き出す (起動したAgentがこれを読み取る)
+            println!("{}", address);
+            eprintln!("Core server listening on {}", address);
+            (listener, interceptor)
+        }
+        Mode::ListenTls(addr) => {
+            let dir = tls::tls_dir()?;
+            let identity = tls::Identity::load_or_create(&dir, "core")?;
+            let pins = Arc::new(tls::Pins::load(&dir.join("core.clients"))?);
+            let pairing = Arc::new(tls::Pairing::default());
+            let listener = TcpListener::bind(addr).await?;
+            let address = Address::Tcp(listener.local_addr()?);
+            let server_name = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            let ticket = tls::PairingTicket { code: pairing.issue(), fingerprint: identity.fingerprint() };
 
-    Server::builder()
-        .http2_keepalive_interval(Some(std::time::Duration::from_secs(10)))
-        .http2_keepalive_timeout(Some(std::time::Duration::from_secs(5)))
-        .add_service(EditorServiceServer::with_interceptor(service, interceptor))
-        .serve_with_incoming(incoming)
-        .await?;
+            println!("{}", address);
+            eprintln!("Core server listening on {} (TLS)", address);
+            eprintln!("Pair a new client within {} minutes with: {}", tls::PAIRING_CODE_TTL.as_secs() / 60, ticket);
+            let pairing = MyPairingService::new(pins.clone(), pairing, server_name);
+            (Listener::Tls(listener, tls::server_config(&identity)?, pairing), AuthInterceptor::pinned(pins))
+        }
+    };
+
+    let state = Arc::new(EditorState::new());
+
+    // 前回のセッションを復元し、定期的に保存する (desktop-save-mode)
+    if let Some(config) = DesktopConfig::from_env() {
+        match desktop::enable(&state, config).await {
+            Ok(Some(report)) => eprintln!("{}", report),
+            Ok(None) => {}
+            Err(e) => eprintln!("Failed to restore desktop: {}", e),
+        }
+        let interval = DESKTOP_AUTO_SAVE_INTERVAL;
+        state.timers().run_with_timer("desktop-auto-save", interval, Some(interval), |state| async move {
+            desktop::save_current(&state).await.map(|_| ())
+        });
+    }
+    tokio::spawn(timer::run(state.clone()));
+    tokio::spawn(sched::run(state.clone()));
+
+    let never = std::future::pending();
+    match listener {
+        Listener::Unix(listener, path) => {
+            let result = serve(state.clone(), interceptor, None, UnixListenerStream::new(listener), never).await;
+            let _ = std::fs::remove_file(path);
+            result?;
+        }
+        Listener::Tcp(listener) => serve(state.clone(), interceptor, None, TcpListenerStream::new(listener), never).await?,
+        Listener::Tls(listener, config, pairing) => {
+            serve(state.clone(), interceptor, Some(pairing), tls::incoming(listener, config), never).await?
+        }
+        Listener::Stdio => {
+            // 接続は1本だけ。相手が標準入力を閉じたら終わる。
+            let (stream, closed) = transport::PipeStream::new(tokio::io::stdin(), tokio::io::stdout());
+            let incoming = tokio_stream::once(Ok::<_, std::io::Error>(stream)).chain(tokio_stream::pending());
+            serve(state.clone(), interceptor, None, incoming, async {
+                let _ = closed.await;
+            })
+            .await?;
+        }
+    }
+
+    if let Err(e) = desktop::save_current(&state).await {
+        eprintln!("Failed to save desktop: {}", e);
+    }
 
     Ok(())
 }
\ No newline at end of file
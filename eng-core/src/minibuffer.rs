@@ -0,0 +1,874 @@
+use std::sync::Arc;
+
+use crate::command::{
+    execute_command, ArgumentRequest, CommandContext, CommandError, CommandInvocation, CommandRegistry, CommandResult,
+    InteractiveSpec,
+};
+use crate::command_loop::{self, KeyOutcome};
+use crate::keymap::{key_description, Key, Lookup};
+use crate::state::EditorState;
+
+/// UIへ送る候補の最大数
+pub const MAX_VISIBLE_CANDIDATES: usize = 50;
+/// 1つの履歴リストに残す入力の数 (history-length)
+pub const HISTORY_LENGTH: usize = 100;
+pub const DEFAULT_HISTORY: &str = "minibuffer-history";
+/// completion-styles 変数の既定値
+pub const DEFAULT_COMPLETION_STYLES: &str = "basic substring flex";
+
+/// 補完候補。annotation は候補の横に表示する補足 (コマンドの説明など)。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub text: String,
+    pub annotation: Option<String>,
+}
+
+impl Candidate {
+    pub fn new(text: impl Into<String>) -> Self {
+        Self { text: text.into(), annotation: None }
+    }
+
+    pub fn with_annotation(mut self, annotation: impl Into<String>) -> Self {
+        self.annotation = Some(annotation.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionStyle {
+    /// 入力で始まる候補
+    Basic,
+    /// 入力を含む候補
+    Substring,
+    /// 入力の文字を順番通りに含む候補 (間に他の文字があってよい)
+    Flex,
+    /// 空白で区切った各語を順不同で含む候補
+    Orderless,
+}
+
+impl CompletionStyle {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "basic" => Some(CompletionStyle::Basic),
+            "substring" => Some(CompletionStyle::Substring),
+            "flex" => Some(CompletionStyle::Flex),
+            "orderless" => Some(CompletionStyle::Orderless),
+            _ => None,
+        }
+    }
+
+    /// 一致すれば並べ替えに使うスコア (小さいほど良い) を返す
+    fn score(self, candidate: &str, input: &str) -> Option<usize> {
+        match self {
+            CompletionStyle::Basic => candidate.starts_with(input).then_some(0),
+            CompletionStyle::Substring => candidate.find(input),
+            CompletionStyle::Flex => {
+                // 一致した文字の間の隙間の合計。詰まっているほど良い。
+                let mut chars = candidate.char_indices();
+                let mut score = 0;
+                let mut last = None;
+                for c in input.chars() {
+                    let (i, _) = chars.find(|(_, d)| *d == c)?;
+                    score += last.map_or(i, |last| i - last - 1);
+                    last = Some(i);
+                }
+                Some(score)
+            }
+            CompletionStyle::Orderless => input
+                .split_whitespace()
+                .all(|word| candidate.contains(word))
+                .then_some(0),
+        }
+    }
+}
+
+/// "basic substring flex" のような completion-styles の値を解釈する。不明な名前は無視する。
+pub fn parse_completion_styles(value: &str) -> Vec<CompletionStyle> {
+    let styles: Vec<_> = value.split_whitespace().filter_map(CompletionStyle::parse).collect();
+    if styles.is_empty() {
+        parse_completion_styles(DEFAULT_COMPLETION_STYLES)
+    } else {
+        styles
+    }
+}
+
+/// 入力に一致する候補を返す。スタイルを順に試し、最初に候補が見つかったスタイルの結果を使う。
+/// 入力に大文字が無ければ大文字小文字を区別しない。
+pub fn filter_candidates<'a>(candidates: &'a [Candidate], input: &str, styles: &[CompletionStyle]) -> Vec<&'a Candidate> {
+    if input.is_empty() {
+        return candidates.iter().collect();
+    }
+    let ignore_case = !input.chars().any(char::is_uppercase);
+    let input = if ignore_case { input.to_lowercase() } else { input.to_string() };
+    for style in styles {
+        // 完全一致を先頭に、残りはスコア順 (同じスコアなら元の順)
+        let mut scored: Vec<_> = candidates
+            .iter()
+            .filter_map(|c| {
+                let text = if ignore_case { c.text.to_lowercase() } else { c.text.clone() };
+                style.score(&text, &input).map(|score| ((text != input, score), c))
+            })
+            .collect();
+        if !scored.is_empty() {
+            scored.sort_by_key(|(key, _)| *key);
+            return scored.into_iter().map(|(_, c)| c).collect();
+        }
+    }
+    Vec::new()
+}
+
+fn common_prefix<'a>(mut strings: impl Iterator<Item = &'a str>) -> String {
+    let Some(first) = strings.next() else {
+        return String::new();
+    };
+    let mut prefix = first.to_string();
+    for s in strings {
+        let len = prefix.chars().zip(s.chars()).take_while(|(a, b)| a == b).map(|(a, _)| a.len_utf8()).sum();
+        prefix.truncate(len);
+    }
+    prefix
+}
+
+/// UIへ送るミニバッファの状態。depth が0ならミニバッファは閉じている。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MinibufferUpdate {
+    pub window_id: String,
+    pub depth: usize,
+    pub prompt: String,
+    pub input: String,
+    pub cursor: usize,
+    /// 表示する候補 (選択中の候補が入るように切り出したもの)
+    pub candidates: Vec<Candidate>,
+    /// candidates 内での選択中の位置
+    pub selected: Option<usize>,
+    pub total_candidates: usize,
+    pub default: Option<String>,
+}
+
+impl MinibufferUpdate {
+    pub fn closed(window_id: &str) -> Self {
+        Self {
+            window_id: window_id.to_string(),
+            depth: 0,
+            prompt: String::new(),
+            input: String::new(),
+            cursor: 0,
+            candidates: Vec::new(),
+            selected: None,
+            total_candidates: 0,
+            default: None,
+        }
+    }
+}
+
+/// 1回分のミニバッファ入力。確定したら入力を引数に加えて request のコマンドを再実行する。
+#[derive(Debug, Clone)]
+pub struct MinibufferSession {
+    pub request: ArgumentRequest,
+    pub input: String,
+    /// 入力中のカーソル位置 (文字単位)
+    pub cursor: usize,
+    pub default: Option<String>,
+    /// 履歴リストの名前
+    pub history: String,
+    history_items: Vec<String>,
+    history_position: Option<usize>,
+    candidates: Vec<Candidate>,
+    matches: Vec<Candidate>,
+    pub selected: Option<usize>,
+    /// 候補以外の入力を受け付けない
+    pub require_match: bool,
+    /// 文字列ではなくキー列を読む (interactive の "k")
+    pub read_keys: bool,
+    keys: Vec<Key>,
+    styles: Vec<CompletionStyle>,
+}
+
+impl MinibufferSession {
+    pub fn new(request: ArgumentRequest) -> Self {
+        Self {
+            request,
+            input: String::new(),
+            cursor: 0,
+            default: None,
+            history: DEFAULT_HISTORY.to_string(),
+            history_items: Vec::new(),
+            history_position: None,
+            candidates: Vec::new(),
+            matches: Vec::new(),
+            selected: None,
+            require_match: false,
+            read_keys: false,
+            keys: Vec::new(),
+            styles: parse_completion_styles(DEFAULT_COMPLETION_STYLES),
+        }
+    }
+
+    pub fn with_candidates(mut self, candidates: Vec<Candidate>, require_match: bool) -> Self {
+        self.candidates = candidates;
+        self.require_match = require_match;
+        self.refresh();
+        self
+    }
+
+    pub fn with_default(mut self, default: Option<String>) -> Self {
+        self.default = default;
+        self.refresh();
+        self
+    }
+
+    /// 履歴は新しいものが先頭
+    pub fn with_history(mut self, name: &str, items: Vec<String>) -> Self {
+        self.history = name.to_string();
+        self.history_items = items;
+        self
+    }
+
+    pub fn with_styles(mut self, styles: Vec<CompletionStyle>) -> Self {
+        self.styles = styles;
+        self.refresh();
+        self
+    }
+
+    pub fn reading_keys(mut self) -> Self {
+        self.read_keys = true;
+        self
+    }
+
+    pub fn prompt(&self) -> &str {
+        &self.request.prompt
+    }
+
+    pub fn matches(&self) -> &[Candidate] {
+        &self.matches
+    }
+
+    fn refresh(&mut self) {
+        self.matches = filter_candidates(&self.candidates, &self.input, &self.styles)
+            .into_iter()
+            .cloned()
+            .collect();
+        // 入力が空なら既定値を先頭に出す
+        if self.input.is_empty()
+            && let Some(default) = &self.default
+            && let Some(i) = self.matches.iter().position(|c| &c.text == default)
+        {
+            let candidate = self.matches.remove(i);
+            self.matches.insert(0, candidate);
+        }
+        self.selected = (!self.matches.is_empty()).then_some(0);
+    }
+
+    fn byte_offset(&self, cursor: usize) -> usize {
+        self.input.char_indices().nth(cursor).map_or(self.input.len(), |(i, _)| i)
+    }
+
+    pub fn insert(&mut self, text: &str) {
+        let offset = self.byte_offset(self.cursor);
+        self.input.insert_str(offset, text);
+        self.cursor += text.chars().count();
+        self.refresh();
+    }
+
+    /// カーソルから n 文字 (負なら後方へ) 削除する
+    pub fn delete(&mut self, n: i64) -> Result<(), String> {
+        let other = self.cursor as i64 + n;
+        if other < 0 {
+            return Err("Beginning of buffer".into());
+        }
+        if other > self.input.chars().count() as i64 {
+            return Err("End of buffer".into());
+        }
+        let (start, end) = (self.cursor.min(other as usize), self.cursor.max(other as usize));
+        let range = self.byte_offset(start)..self.byte_offset(end);
+        self.input.replace_range(range, "");
+        self.cursor = start;
+        self.refresh();
+        Ok(())
+    }
+
+    pub fn move_cursor(&mut self, n: i64) {
+        let len = self.input.chars().count() as i64;
+        self.cursor = (self.cursor as i64 + n).clamp(0, len) as usize;
+    }
+
+    pub fn set_cursor(&mut self, cursor: usize) {
+        self.cursor = cursor.min(self.input.chars().count());
+    }
+
+    pub fn set_input(&mut self, input: &str) {
+        self.input = input.to_string();
+        self.cursor = input.chars().count();
+        self.refresh();
+    }
+
+    /// 候補の選択を n 個動かす (端で折り返す)
+    pub fn select(&mut self, n: i64) {
+        if let Some(selected) = self.selected {
+            let len = self.matches.len() as i64;
+            self.selected = Some((selected as i64 + n).rem_euclid(len) as usize);
+        }
+    }
+
+    /// M-p。n 個前の履歴を入力にする。
+    pub fn previous_history(&mut self, n: i64) -> Result<(), String> {
+        let position = match self.history_position {
+            Some(p) => p as i64 + n,
+            None => n - 1,
+        };
+        if position < -1 {
+            // 履歴より先 (M-n の先) は既定値
+            let default = self.default.clone().ok_or("End of history; no default available")?;
+            self.history_position = None;
+            self.set_input(&default);
+            return Ok(());
+        }
+        if position >= self.history_items.len() as i64 {
+            return Err(format!("Beginning of history; no preceding item in {}", self.history));
+        }
+        if position == -1 {
+            self.history_position = None;
+            self.set_input("");
+        } else {
+            self.history_position = Some(position as usize);
+            let item = self.history_items[position as usize].clone();
+            self.set_input(&item);
+        }
+        Ok(())
+    }
+
+    /// TAB。一致する候補が1つならそれに、複数なら共通の接頭辞まで入力を補う。
+    pub fn complete(&mut self) -> Result<Option<String>, String> {
+        match self.matches.as_slice() {
+            [] => Err("No match".into()),
+            [only] => {
+                let text = only.text.clone();
+                self.set_input(&text);
+                Ok(Some("Sole completion".into()))
+            }
+            matches => {
+                let prefix = common_prefix(matches.iter().map(|c| c.text.as_str()));
+                if prefix.chars().count() > self.input.chars().count() && prefix.starts_with(&self.input) {
+                    self.set_input(&prefix);
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// RET で確定する値。候補があれば選択中の候補、入力が空なら既定値を使う。
+    pub fn value(&self) -> Result<String, String> {
+        let value = match self.selected {
+            Some(i) => self.matches[i].text.clone(),
+            None if self.input.is_empty() => self.default.clone().unwrap_or_default(),
+            None => self.input.clone(),
+        };
+        if self.require_match && !self.candidates.iter().any(|c| c.text == value) {
+            return Err("[No match]".into());
+        }
+        Ok(value)
+    }
+
+    pub fn update(&self, window_id: &str, depth: usize) -> MinibufferUpdate {
+        let start = self
+            .selected
+            .map_or(0, |selected| (selected + 1).saturating_sub(MAX_VISIBLE_CANDIDATES));
+        let candidates: Vec<_> = self.matches.iter().skip(start).take(MAX_VISIBLE_CANDIDATES).cloned().collect();
+        let input = if self.read_keys { key_description(&self.keys) } else { self.input.clone() };
+        MinibufferUpdate {
+            window_id: window_id.to_string(),
+            depth,
+            prompt: self.request.prompt.clone(),
+            cursor: if self.read_keys { input.chars().count() } else { self.cursor },
+            input,
+            candidates,
+            selected: self.selected.map(|selected| selected - start),
+            total_candidates: self.matches.len(),
+            default: self.default.clone(),
+        }
+    }
+}
+
+/// 最も内側のミニバッファの現在の状態
+pub async fn current_update(state: &EditorState, window_id: &str) -> MinibufferUpdate {
+    let minibuffers = state.minibuffers().lock().await;
+    let stack = minibuffers.get(window_id);
+    match stack.and_then(|s| s.last().map(|session| (session, s.len()))) {
+        Some((session, depth)) => session.update(window_id, depth),
+        None => MinibufferUpdate::closed(window_id),
+    }
+}
+
+/// ミニバッファの状態をUIへ配信する
+async fn notify(state: &EditorState, window_id: &str) {
+    let update = current_update(state, window_id).await;
+    state.notify_minibuffer(update);
+}
+
+/// ミニバッファの深さ (再帰的に開いている数)
+pub async fn depth(state: &EditorState, window_id: &str) -> usize {
+    state.minibuffers().lock().await.get(window_id).map_or(0, Vec::len)
+}
+
+/// 最も内側のミニバッファを操作し、変更をUIへ配信する
+pub async fn with_session<F, R>(state: &EditorState, window_id: &str, f: F) -> Result<R, String>
+where
+    F: FnOnce(&mut MinibufferSession) -> Result<R, String>,
+{
+    let result = {
+        let mut minibuffers = state.minibuffers().lock().await;
+        let session = minibuffers
+            .get_mut(window_id)
+            .and_then(|stack| stack.last_mut())
+            .ok_or("Not in a minibuffer")?;
+        f(session)
+    };
+    notify(state, window_id).await;
+    result
+}
+
+pub async fn open(state: &EditorState, window_id: &str, session: MinibufferSession) {
+    state.minibuffers().lock().await.entry(window_id.to_string()).or_default().push(session);
+    notify(state, window_id).await;
+}
+
+/// 最も内側のミニバッファを閉じる
+pub async fn close(state: &EditorState, window_id: &str) -> Result<MinibufferSession, String> {
+    let session = state
+        .minibuffers()
+        .lock()
+        .await
+        .get_mut(window_id)
+        .and_then(Vec::pop)
+        .ok_or("Not in a minibuffer")?;
+    notify(state, window_id).await;
+    Ok(session)
+}
+
+/// 不足している引数を読むミニバッファを開き、プロンプトを返す
+pub async fn read_argument(state: &EditorState, window_id: &str, request: ArgumentRequest) -> Result<String, String> {
+    let buffer_id = state
+        .read_window(window_id, |w| w.selected_view().buffer_id.clone())
+        .await?;
+    let styles = match state.variable(&buffer_id, "completion-styles").await {
+        Some(value) => parse_completion_styles(value.as_str().unwrap_or_default()),
+        None => parse_completion_styles(DEFAULT_COMPLETION_STYLES),
+    };
+    let prompt = request.prompt.clone();
+
+    let session = match &request.spec {
+        InteractiveSpec::Keys(_) => MinibufferSession::new(request).reading_keys(),
+        InteractiveSpec::Command(_) => {
+            let candidates = state
+                .commands()
+                .read()
+                .await
+                .commands()
+                .iter()
+                .map(|c| Candidate::new(&c.name).with_annotation(c.doc.lines().next().unwrap_or_default()))
+                .collect();
+            let history = state.history("extended-command-history").await;
+            MinibufferSession::new(request)
+                .with_history("extended-command-history", history)
+                .with_candidates(candidates, true)
+        }
+        InteractiveSpec::Buffer(_) => {
+            let candidates = state
+                .buffer_list()
+                .await
+                .into_iter()
+                .map(|info| {
+                    let annotation = match &info.path {
+                        Some(path) => format!("{}  {}", info.major_mode, path.display()),
+                        None => info.major_mode.clone(),
+                    };
+                    Candidate::new(info.name).with_annotation(annotation)
+                })
+                .collect();
+            let current = match state.get_buffer(&buffer_id).await {
+                Some(buffer) => Some(buffer.read().await.name.clone()),
+                None => None,
+            };
+            let history = state.history("buffer-name-history").await;
+            MinibufferSession::new(request)
+                .with_history("buffer-name-history", history)
+                .with_candidates(candidates, true)
+                .with_default(current)
+        }
+        _ => {
+            let history = state.history(DEFAULT_HISTORY).await;
+            MinibufferSession::new(request).with_history(DEFAULT_HISTORY, history)
+        }
+    };
+    open(state, window_id, session.with_styles(styles)).await;
+    Ok(prompt)
+}
+
+/// call_interactively の結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Interaction {
+    /// コマンドを実行した
+    Done(Option<String>),
+    /// 引数を読むミニバッファを開いた (値はプロンプト)
+    Prompt(String),
+}
+
+/// コマンドを実行し、引数が足りなければミニバッファを開く
+pub async fn call_interactively(
+    state: &Arc<EditorState>,
+    window_id: &str,
+    name: &str,
+    invocation: CommandInvocation,
+) -> Result<Interaction, CommandError> {
+    match execute_command(state.clone(), window_id, name, invocation).await {
+        Ok(message) => Ok(Interaction::Done(message)),
+        Err(CommandError::MissingArgument(request)) => {
+            Ok(Interaction::Prompt(read_argument(state, window_id, *request).await?))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// ミニバッファの入力を確定し、入力を引数に加えてコマンドを再実行する
+pub async fn submit(state: &Arc<EditorState>, window_id: &str, value: String) -> CommandResult {
+    let session = close(state, window_id).await?;
+    if !session.read_keys && !value.is_empty() {
+        state.add_history(&session.history, &value).await;
+    }
+    let mut request = session.request;
+    request.invocation.args.push(value);
+    match call_interactively(state, window_id, &request.command, request.invocation.clone()).await? {
+        Interaction::Done(message) => {
+            if depth(state, window_id).await == 0 {
+                command_loop::set_last_command(state, window_id, &request.command, request.invocation).await;
+            }
+            Ok(message)
+        }
+        // 続けて次の引数を読むミニバッファを開いた
+        Interaction::Prompt(_) => Ok(None),
+    }
+}
+
+/// キー列を読んでいるミニバッファにキーを渡す。そのようなミニバッファが無ければ None を返す。
+pub async fn read_key(state: &Arc<EditorState>, window_id: &str, key: &Key) -> Result<Option<KeyOutcome>, String> {
+    let (keys, command) = {
+        let mut minibuffers = state.minibuffers().lock().await;
+        let Some(session) = minibuffers.get_mut(window_id).and_then(|s| s.last_mut()) else {
+            return Ok(None);
+        };
+        if !session.read_keys {
+            return Ok(None);
+        }
+        session.keys.push(key.clone());
+        (session.keys.clone(), session.request.command.clone())
+    };
+    notify(state, window_id).await;
+    let description = key_description(&keys);
+
+    if *key == Key::ctrl('g') {
+        close(state, window_id).await?;
+        return Ok(Some(KeyOutcome::Failed { keys: description, command, error: CommandError::Quit }));
+    }
+    let buffer_id = state
+        .read_window(window_id, |w| w.selected_view().buffer_id.clone())
+        .await?;
+    if state.lookup_key(&buffer_id, &keys).await == Lookup::Prefix {
+        return Ok(Some(KeyOutcome::Prefix { keys: description }));
+    }
+    Ok(Some(match submit(state, window_id, description.clone()).await {
+        Ok(message) => KeyOutcome::Executed { keys: description, command, message },
+        Err(error) => KeyOutcome::Failed { keys: description, command, error },
+    }))
+}
+
+pub(crate) fn register_commands(r: &mut CommandRegistry) -> Result<(), String> {
+    r.register("exit-minibuffer", "Terminate this minibuffer argument.", "", exit_minibuffer)?;
+    r.register("abort-recursive-edit", "Abort the innermost minibuffer.", "", abort_recursive_edit)?;
+    r.register("minibuffer-complete", "Complete the minibuffer contents as far as possible.", "", minibuffer_complete)?;
+    r.register("minibuffer-self-insert", "Insert the character you type into the minibuffer.", "p", minibuffer_self_insert)?;
+    r.register("minibuffer-delete-backward-char", "Delete the previous N characters of the minibuffer input.", "p", minibuffer_delete_backward_char)?;
+    r.register("minibuffer-delete-char", "Delete the following N characters of the minibuffer input.", "p", minibuffer_delete_char)?;
+    r.register("minibuffer-forward-char", "Move the minibuffer cursor N characters forward.", "p", minibuffer_forward_char)?;
+    r.register("minibuffer-backward-char", "Move the minibuffer cursor N characters backward.", "p", minibuffer_backward_char)?;
+    r.register("minibuffer-beginning-of-line", "Move the minibuffer cursor to the beginning of the input.", "", minibuffer_beginning_of_line)?;
+    r.register("minibuffer-end-of-line", "Move the minibuffer cursor to the end of the input.", "", minibuffer_end_of_line)?;
+    r.register("previous-history-element", "Put the previous element of the minibuffer history in the minibuffer.", "p", previous_history_element)?;
+    r.register("next-history-element", "Put the next element of the minibuffer history in the minibuffer.", "p", next_history_element)?;
+    r.register("minibuffer-next-completion", "Select the next completion candidate.", "p", minibuffer_next_completion)?;
+    r.register("minibuffer-previous-completion", "Select the previous completion candidate.", "p", minibuffer_previous_completion)?;
+    Ok(())
+}
+
+async fn exit_minibuffer(ctx: CommandContext) -> CommandResult {
+    let value = with_session(&ctx.state, &ctx.window_id, |s| s.value()).await?;
+    submit(&ctx.state, &ctx.window_id, value).await
+}
+
+async fn abort_recursive_edit(ctx: CommandContext) -> CommandResult {
+    close(&ctx.state, &ctx.window_id).await?;
+    Err(CommandError::Quit)
+}
+
+async fn minibuffer_complete(ctx: CommandContext) -> CommandResult {
+    Ok(with_session(&ctx.state, &ctx.window_id, |s| s.complete()).await?)
+}
+
+/// ミニバッファの入力や選択を操作するコマンドの共通部分
+async fn edit_session<F>(ctx: &CommandContext, f: F) -> CommandResult
+where
+    F: FnOnce(&mut MinibufferSession) -> Result<(), String>,
+{
+    with_session(&ctx.state, &ctx.window_id, f).await?;
+    Ok(None)
+}
+
+async fn minibuffer_self_insert(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    if n < 0 {
+        return Err("Negative repetition argument".into());
+    }
+    let c = ctx
+        .keys
+        .last()
+        .and_then(|key| key.printable_char())
+        .ok_or("minibuffer-self-insert must be bound to a printable character")?;
+    edit_session(&ctx, |s| {
+        s.insert(&c.to_string().repeat(n as usize));
+        Ok(())
+    })
+    .await
+}
+
+async fn minibuffer_delete_backward_char(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    edit_session(&ctx, |s| s.delete(-n)).await
+}
+
+async fn minibuffer_delete_char(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    edit_session(&ctx, |s| s.delete(n)).await
+}
+
+async fn minibuffer_forward_char(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    edit_session(&ctx, |s| {
+        s.move_cursor(n);
+        Ok(())
+    })
+    .await
+}
+
+async fn minibuffer_backward_char(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    edit_session(&ctx, |s| {
+        s.move_cursor(-n);
+        Ok(())
+    })
+    .await
+}
+
+async fn minibuffer_beginning_of_line(ctx: CommandContext) -> CommandResult {
+    edit_session(&ctx, |s| {
+        s.set_cursor(0);
+        Ok(())
+    })
+    .await
+}
+
+async fn minibuffer_end_of_line(ctx: CommandContext) -> CommandResult {
+    edit_session(&ctx, |s| {
+        s.set_cursor(usize::MAX);
+        Ok(())
+    })
+    .await
+}
+
+async fn previous_history_element(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    edit_session(&ctx, |s| s.previous_history(n)).await
+}
+
+async fn next_history_element(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    edit_session(&ctx, |s| s.previous_history(-n)).await
+}
+
+async fn minibuffer_next_completion(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    edit_session(&ctx, |s| {
+        s.select(n);
+        Ok(())
+    })
+    .await
+}
+
+async fn minibuffer_previous_completion(ctx: CommandContext) -> CommandResult {
+    let n = ctx.number_arg(0);
+    edit_session(&ctx, |s| {
+        s.select(-n);
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_loop::handle_key;
+    use crate::keymap::parse_kbd;
+
+    fn candidates(texts: &[&str]) -> Vec<Candidate> {
+        texts.iter().map(|t| Candidate::new(*t)).collect()
+    }
+
+    fn texts(matches: Vec<&Candidate>) -> Vec<&str> {
+        matches.into_iter().map(|c| c.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_completion_styles() {
+        let all = candidates(&["backward-char", "find-file", "forward-char", "forward-word"]);
+        let styles = parse_completion_styles(DEFAULT_COMPLETION_STYLES);
+        assert_eq!(texts(filter_candidates(&all, "forw", &styles)), ["forward-char", "forward-word"]);
+        // basic で一致しなければ substring (一致位置が前の候補を先に)、それでも無ければ flex
+        assert_eq!(texts(filter_candidates(&all, "char", &styles)), ["forward-char", "backward-char"]);
+        assert_eq!(texts(filter_candidates(&all, "ffl", &styles)), ["find-file"]);
+        // 大文字を含む入力は大文字小文字を区別する
+        assert!(filter_candidates(&all, "Find", &styles).is_empty());
+
+        let orderless = parse_completion_styles("orderless");
+        assert_eq!(texts(filter_candidates(&all, "char back", &orderless)), ["backward-char"]);
+    }
+
+    #[test]
+    fn test_session_complete_and_value() {
+        let request = ArgumentRequest {
+            prompt: "M-x ".into(),
+            spec: InteractiveSpec::Command("M-x ".into()),
+            command: "execute-extended-command".into(),
+            invocation: CommandInvocation::default(),
+        };
+        let mut session = MinibufferSession::new(request)
+            .with_styles(parse_completion_styles(DEFAULT_COMPLETION_STYLES))
+            .with_history("extended-command-history", vec!["find-file".into(), "forward-word".into()])
+            .with_candidates(candidates(&["find-file", "forward-char", "forward-word"]), true);
+
+        session.insert("fo");
+        session.complete().unwrap();
+        assert_eq!(session.input, "forward-");
+        session.insert("c");
+        assert_eq!(session.complete().unwrap(), Some("Sole completion".into()));
+        assert_eq!(session.value().unwrap(), "forward-char");
+
+        session.set_input("zzz");
+        assert_eq!(session.value(), Err("[No match]".into()));
+
+        session.previous_history(2).unwrap();
+        assert_eq!(session.input, "forward-word");
+        session.previous_history(-1).unwrap();
+        assert_eq!(session.input, "find-file");
+        assert!(session.previous_history(5).is_err());
+    }
+
+    struct Fixture {
+        state: Arc<EditorState>,
+        window_id: String,
+        buffer_id: String,
+    }
+
+    impl Fixture {
+        async fn new(text: &str) -> Self {
+            let state = Arc::new(EditorState::new());
+            let buffer_id = state.create_buffer("test".into(), text).await;
+            let window_id = state.create_window(&buffer_id).await.unwrap();
+            Self { state, window_id, buffer_id }
+        }
+
+        async fn keys(&self, keys: &str) -> Vec<KeyOutcome> {
+            let mut outcomes = Vec::new();
+            for key in parse_kbd(keys).unwrap() {
+                outcomes.push(handle_key(&self.state, &self.window_id, key).await.unwrap());
+            }
+            outcomes
+        }
+
+        async fn point(&self) -> usize {
+            self.state.read_window(&self.window_id, |w| w.selected_view().point).await.unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_extended_command_with_prefix() {
+        let f = Fixture::new("abcdefghij").await;
+        let outcomes = f.keys("C-u 3 M-x").await;
+        assert!(matches!(&outcomes[2], KeyOutcome::Prompt { prompt, .. } if prompt == "M-x "));
+        assert_eq!(depth(&f.state, &f.window_id).await, 1);
+
+        f.keys("f o r w a r d - c RET").await;
+        assert_eq!(depth(&f.state, &f.window_id).await, 0);
+        assert_eq!(f.point().await, 3);
+
+        // 引数を読み終えたコマンドが repeat の対象になる
+        f.keys("C-x z").await;
+        assert_eq!(f.point().await, 6);
+    }
+
+    #[tokio::test]
+    async fn test_history_reuse() {
+        let f = Fixture::new("abcdefghij").await;
+        f.keys("M-x f o r w a r d - c h a r RET").await;
+        assert_eq!(f.state.history("extended-command-history").await, ["forward-char"]);
+        f.keys("M-x M-p RET").await;
+        assert_eq!(f.point().await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_recursive_minibuffer_and_quit() {
+        let f = Fixture::new("").await;
+        f.keys("M-x f o").await;
+        f.keys("M-x").await;
+        assert_eq!(depth(&f.state, &f.window_id).await, 2);
+
+        let outcomes = f.keys("C-g").await;
+        assert!(matches!(&outcomes[0], KeyOutcome::Failed { command, .. } if command == "abort-recursive-edit"));
+        let session = f.state.minibuffers().lock().await[&f.window_id][0].clone();
+        assert_eq!(session.input, "fo");
+
+        f.keys("C-g").await;
+        assert_eq!(depth(&f.state, &f.window_id).await, 0);
+        // ミニバッファの外ではキーがバッファに挿入される
+        f.keys("x").await;
+        let buffer = f.state.get_buffer(&f.buffer_id).await.unwrap();
+        assert_eq!(buffer.read().await.to_string(), "x");
+    }
+
+    #[tokio::test]
+    async fn test_read_key_sequence() {
+        let f = Fixture::new("").await;
+        let outcomes = f.keys("C-h k C-x 2").await;
+        assert!(matches!(&outcomes[1], KeyOutcome::Prompt { prompt, .. } if prompt == "Describe key: "));
+        assert_eq!(outcomes[2], KeyOutcome::Prefix { keys: "C-x".into() });
+        assert!(matches!(&outcomes[3], KeyOutcome::Executed { message: Some(m), .. } if m.contains("split-window-below")));
+        // キー列は読まれるだけで実行されない
+        let views = f.state.read_window(&f.window_id, |w| w.view_ids().len()).await.unwrap();
+        assert_eq!(views, 1);
+    }
+
+    #[tokio::test]
+    async fn test_updates_are_broadcast() {
+        let f = Fixture::new("").await;
+        let mut updates = f.state.subscribe_minibuffer();
+        f.keys("M-x f i n d - f").await;
+
+        let mut last = None;
+        while let Ok(update) = updates.try_recv() {
+            last = Some(update);
+        }
+        let last = last.unwrap();
+        assert_eq!((last.depth, last.input.as_str(), last.cursor), (1, "find-f", 6));
+        assert!(last.candidates.iter().all(|c| c.text.contains('f')));
+
+        f.keys("C-g").await;
+        assert_eq!(updates.try_recv().unwrap(), MinibufferUpdate::closed(&f.window_id));
+    }
+}
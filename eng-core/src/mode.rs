@@ -0,0 +1,597 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::buffer::Buffer;
+use crate::buffer_menu::BUFFER_MENU_MODE;
+use crate::command::{CommandContext, CommandRegistry, CommandResult, PrefixArg};
+use crate::minibuffer::DEFAULT_COMPLETION_STYLES;
+
+pub const FUNDAMENTAL_MODE: &str = "fundamental-mode";
+
+/// 親モードをたどる深さの上限 (循環した定義で止まらなくなるのを防ぐ)
+const MAX_MODE_DEPTH: usize = 16;
+
+/// バッファローカル変数の値
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Bool(bool),
+    Int(i64),
+    String(String),
+}
+
+impl Value {
+    /// "-*-" 行などに書かれた値を解釈する。t と nil は真偽値、整数はそのまま、
+    /// 引用符で囲まれたものは文字列、それ以外はシンボル名として文字列にする。
+    pub fn parse(s: &str) -> Value {
+        let s = s.trim();
+        match s {
+            "t" => Value::Bool(true),
+            "nil" => Value::Bool(false),
+            _ => {
+                if let Ok(n) = s.parse() {
+                    Value::Int(n)
+                } else if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+                    Value::String(s[1..s.len() - 1].to_string())
+                } else {
+                    Value::String(s.to_string())
+                }
+            }
+        }
+    }
+
+    pub fn as_bool(&self) -> bool {
+        !matches!(self, Value::Bool(false))
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Bool(true) => f.write_str("t"),
+            Value::Bool(false) => f.write_str("nil"),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{:?}", s),
+        }
+    }
+}
+
+/// モードを有効にした (マイナーモードは無効にした時も) 後に呼ばれる関数
+pub type ModeHook = Arc<dyn Fn(&mut Buffer) + Send + Sync>;
+
+#[derive(Debug, Clone, Default)]
+pub struct MajorMode {
+    pub name: String,
+    pub doc: String,
+    /// 親モード。変数とフックは親から順に適用する。
+    pub parent: Option<String>,
+    /// このモードを選ぶファイルの拡張子
+    pub extensions: Vec<String>,
+    /// このモードを選ぶシバンのインタプリタ名 (末尾のバージョン番号は除く)
+    pub interpreters: Vec<String>,
+    /// モードを有効にした時に設定するバッファローカル変数
+    pub variables: Vec<(String, Value)>,
+}
+
+impl MajorMode {
+    pub fn new(name: &str, doc: &str) -> Self {
+        Self { name: name.to_string(), doc: doc.to_string(), ..Default::default() }
+    }
+
+    pub fn parent(mut self, parent: &str) -> Self {
+        self.parent = Some(parent.to_string());
+        self
+    }
+
+    pub fn extensions(mut self, extensions: &[&str]) -> Self {
+        self.extensions = extensions.iter().map(|e| e.to_string()).collect();
+        self
+    }
+
+    pub fn interpreters(mut self, interpreters: &[&str]) -> Self {
+        self.interpreters = interpreters.iter().map(|i| i.to_string()).collect();
+        self
+    }
+
+    pub fn variable(mut self, name: &str, value: Value) -> Self {
+        self.variables.push((name.to_string(), value));
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MinorMode {
+    pub name: String,
+    pub doc: String,
+}
+
+impl MinorMode {
+    pub fn new(name: &str, doc: &str) -> Self {
+        Self { name: name.to_string(), doc: doc.to_string() }
+    }
+}
+
+/// ファイル先頭の "-*- ... -*-" 行の内容
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModeCookie {
+    pub mode: Option<String>,
+    pub variables: Vec<(String, Value)>,
+}
+
+/// 先頭行 (シバンがあれば2行目) の "-*- mode -*-" または "-*- mode: x; var: value -*-" を解釈する
+pub fn parse_mode_cookie(text: &str) -> Option<ModeCookie> {
+    let mut lines = text.lines();
+    let mut line = lines.next()?;
+    if line.starts_with("#!") {
+        line = lines.next()?;
+    }
+    let start = line.find("-*-")? + 3;
+    let end = line[start..].find("-*-")? + start;
+    let body = line[start..end].trim();
+
+    let mut cookie = ModeCookie::default();
+    if !body.contains(':') {
+        cookie.mode = Some(mode_name(body));
+        return Some(cookie);
+    }
+    for entry in body.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, value) = entry.split_once(':')?;
+        let name = name.trim();
+        if name.eq_ignore_ascii_case("mode") {
+            cookie.mode = Some(mode_name(value));
+        } else {
+            cookie.variables.push((name.to_string(), Value::parse(value)));
+        }
+    }
+    Some(cookie)
+}
+
+/// "Rust" や "rust-mode" をモード名 "rust-mode" にする
+fn mode_name(s: &str) -> String {
+    let name = s.trim().to_lowercase();
+    if name.ends_with("-mode") { name } else { format!("{}-mode", name) }
+}
+
+/// シバンのインタプリタ名。"#!/usr/bin/env -S python3.11 -u" なら "python"。
+pub fn shebang_interpreter(text: &str) -> Option<String> {
+    let line = text.lines().next()?.strip_prefix("#!")?;
+    let mut words = line.split_whitespace();
+    let basename = |path: &str| path.rsplit('/').next().unwrap_or(path).to_string();
+    let mut program = basename(words.next()?);
+    if program == "env" {
+        program = basename(words.find(|w| !w.starts_with('-') && !w.contains('='))?);
+    }
+    let name = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// メジャーモード・マイナーモード・フック・変数の既定値の定義
+pub struct Modes {
+    major: BTreeMap<String, MajorMode>,
+    minor: BTreeMap<String, MinorMode>,
+    hooks: HashMap<String, Vec<ModeHook>>,
+    defaults: HashMap<String, Value>,
+}
+
+impl fmt::Debug for Modes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Modes")
+            .field("major", &self.major.keys().collect::<Vec<_>>())
+            .field("minor", &self.minor.keys().collect::<Vec<_>>())
+            .field("defaults", &self.defaults)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Modes {
+    fn default() -> Self {
+        let mut modes = Self {
+            major: BTreeMap::new(),
+            minor: BTreeMap::new(),
+            hooks: HashMap::new(),
+            defaults: HashMap::new(),
+        };
+        for (name, value) in [
+            ("tab-width", Value::Int(8)),
+            ("indent-tabs-mode", Value::Bool(true)),
+            ("standard-indent", Value::Int(4)),
+            ("fill-column", Value::Int(70)),
+            ("comment-start", Value::String(String::new())),
+            ("comment-end", Value::String(String::new())),
+            ("completion-styles", Value::String(DEFAULT_COMPLETION_STYLES.to_string())),
+        ] {
+            modes.set_default(name, value);
+        }
+        for mode in builtin_major_modes() {
+            modes.define_major_mode(mode);
+        }
+        for mode in builtin_minor_modes() {
+            modes.define_minor_mode(mode);
+        }
+        modes
+    }
+}
+
+fn builtin_major_modes() -> Vec<MajorMode> {
+    let line_comment = |start: &str| Value::String(start.to_string());
+    vec![
+        MajorMode::new(FUNDAMENTAL_MODE, "Major mode not specialized for anything in particular."),
+        MajorMode::new("text-mode", "Major mode for editing text written for humans to read.")
+            .extensions(&["txt", "text"]),
+        MajorMode::new("prog-mode", "Major mode for editing programming language source code."),
+        MajorMode::new("rust-mode", "Major mode for editing Rust code.")
+            .parent("prog-mode")
+            .extensions(&["rs"])
+            .variable("comment-start", line_comment("// "))
+            .variable("indent-tabs-mode", Value::Bool(false))
+            .variable("fill-column", Value::Int(100)),
+        MajorMode::new("python-mode", "Major mode for editing Python files.")
+            .parent("prog-mode")
+            .extensions(&["py", "pyi"])
+            .interpreters(&["python"])
+            .variable("comment-start", line_comment("# "))
+            .variable("indent-tabs-mode", Value::Bool(false)),
+        MajorMode::new("sh-mode", "Major mode for editing shell scripts.")
+            .parent("prog-mode")
+            .extensions(&["sh", "bash", "zsh"])
+            .interpreters(&["sh", "bash", "zsh", "dash"])
+            .variable("comment-start", line_comment("# ")),
+        MajorMode::new("c-mode", "Major mode for editing C code.")
+            .parent("prog-mode")
+            .extensions(&["c", "h"])
+            .variable("comment-start", line_comment("/* "))
+            .variable("comment-end", line_comment(" */")),
+        MajorMode::new(BUFFER_MENU_MODE, "Major mode for Buffer Menu buffers."),
+        MajorMode::new("js-mode", "Major mode for editing JavaScript.")
+            .parent("prog-mode")
+            .extensions(&["js", "mjs", "cjs"])
+            .interpreters(&["node"])
+            .variable("comment-start", line_comment("// "))
+            .variable("indent-tabs-mode", Value::Bool(false)),
+    ]
+}
+
+fn builtin_minor_modes() -> Vec<MinorMode> {
+    vec![
+        MinorMode::new("overwrite-mode", "Toggle Overwrite mode: typed characters replace existing text."),
+        MinorMode::new("auto-fill-mode", "Toggle automatic line breaking (Auto Fill mode)."),
+        MinorMode::new("visual-line-mode", "Toggle visual line based editing (Visual Line mode)."),
+    ]
+}
+
+impl Modes {
+    /// メジャーモードを定義する。同名のモードは置き換えられる。
+    pub fn define_major_mode(&mut self, mode: MajorMode) {
+        self.major.insert(mode.name.clone(), mode);
+    }
+
+    pub fn define_minor_mode(&mut self, mode: MinorMode) {
+        self.minor.insert(mode.name.clone(), mode);
+    }
+
+    pub fn major_mode(&self, name: &str) -> Option<&MajorMode> {
+        self.major.get(name)
+    }
+
+    pub fn minor_mode(&self, name: &str) -> Option<&MinorMode> {
+        self.minor.get(name)
+    }
+
+    pub fn major_modes(&self) -> impl Iterator<Item = &MajorMode> {
+        self.major.values()
+    }
+
+    pub fn minor_modes(&self) -> impl Iterator<Item = &MinorMode> {
+        self.minor.values()
+    }
+
+    /// モードフック (<mode>-hook) を追加する
+    pub fn add_hook(&mut self, mode: &str, hook: ModeHook) {
+        self.hooks.entry(mode.to_string()).or_default().push(hook);
+    }
+
+    pub fn default_value(&self, name: &str) -> Option<&Value> {
+        self.defaults.get(name)
+    }
+
+    pub fn set_default(&mut self, name: &str, value: Value) {
+        self.defaults.insert(name.to_string(), value);
+    }
+
+    /// 変数の値。バッファローカルな値が無ければ既定値を返す。マイナーモード名はその有効状態を返す。
+    pub fn variable(&self, buffer: &Buffer, name: &str) -> Option<Value> {
+        if self.minor.contains_key(name) {
+            return Some(Value::Bool(buffer.minor_mode_enabled(name)));
+        }
+        buffer.local(name).or_else(|| self.defaults.get(name)).cloned()
+    }
+
+    /// 親モードから順に並べたモードの系列
+    fn lineage(&self, name: &str) -> Result<Vec<&MajorMode>, String> {
+        let mut lineage = Vec::new();
+        let mut next = Some(name);
+        while let Some(name) = next {
+            if lineage.len() >= MAX_MODE_DEPTH {
+                return Err(format!("Mode inheritance is too deep: {}", name));
+            }
+            let mode = self.major.get(name).ok_or_else(|| format!("No such major mode: {}", name))?;
+            lineage.push(mode);
+            next = mode.parent.as_deref();
+        }
+        lineage.reverse();
+        Ok(lineage)
+    }
+
+    /// メジャーモードを切り替える。ローカル変数を初期化し、親モードから順に変数とフックを適用する。
+    pub fn set_major_mode(&self, buffer: &mut Buffer, name: &str) -> Result<(), String> {
+        let lineage = self.lineage(name)?;
+        buffer.kill_all_local_variables();
+        buffer.major_mode = name.to_string();
+        for mode in &lineage {
+            for (variable, value) in &mode.variables {
+                buffer.set_local(variable, value.clone());
+            }
+        }
+        for mode in &lineage {
+            self.run_hooks(&mode.name, buffer);
+        }
+        Ok(())
+    }
+
+    /// マイナーモードを有効・無効にする。状態が変わらなくてもフックは実行する。
+    pub fn set_minor_mode(&self, buffer: &mut Buffer, name: &str, enable: bool) -> Result<(), String> {
+        if !self.minor.contains_key(name) {
+            return Err(format!("No such minor mode: {}", name));
+        }
+        if enable {
+            buffer.minor_modes.insert(name.to_string());
+        } else {
+            buffer.minor_modes.remove(name);
+        }
+        self.run_hooks(name, buffer);
+        Ok(())
+    }
+
+    fn run_hooks(&self, mode: &str, buffer: &mut Buffer) {
+        for hook in self.hooks.get(mode).into_iter().flatten() {
+            hook(buffer);
+        }
+    }
+
+    /// ファイル名と内容からメジャーモードを選ぶ。
+    /// "-*-" 行、シバン、拡張子の順に調べ、どれにも当たらなければ fundamental-mode にする。
+    pub fn detect_major_mode(&self, file_name: &str, text: &str) -> String {
+        if let Some(mode) = parse_mode_cookie(text).and_then(|c| c.mode)
+            && self.major.contains_key(&mode)
+        {
+            return mode;
+        }
+        if let Some(interpreter) = shebang_interpreter(text)
+            && let Some(mode) = self.major.values().find(|m| m.interpreters.contains(&interpreter))
+        {
+            return mode.name.clone();
+        }
+        let extension = Path::new(file_name).extension().and_then(|e| e.to_str());
+        if let Some(extension) = extension
+            && let Some(mode) = self.major.values().find(|m| m.extensions.iter().any(|e| e == extension))
+        {
+            return mode.name.clone();
+        }
+        FUNDAMENTAL_MODE.to_string()
+    }
+
+    /// バッファのファイル名 (無ければバッファ名) と内容からメジャーモードを設定し、
+    /// "-*-" 行に書かれた変数をバッファローカルに設定する
+    pub fn set_auto_mode(&self, buffer: &mut Buffer) {
+        let file_name = match &buffer.path {
+            Some(path) => path.to_string_lossy().to_string(),
+            None => buffer.name.clone(),
+        };
+        // 判定に使うのは先頭の2行 ("-*-" 行はシバンの次の行にも書ける) だけ
+        let head_end = buffer.line_to_char(buffer.len_lines().min(2));
+        let head = buffer.text_range(0..head_end).unwrap_or_default();
+        let mode = self.detect_major_mode(&file_name, &head);
+        if self.set_major_mode(buffer, &mode).is_err() {
+            // 検出したモードは定義済みなので、失敗するのは親モードが未定義の場合だけ
+            let _ = self.set_major_mode(buffer, FUNDAMENTAL_MODE);
+        }
+        for (name, value) in parse_mode_cookie(&head).map(|c| c.variables).unwrap_or_default() {
+            buffer.set_local(&name, value);
+        }
+    }
+}
+
+/// モードを切り替えるコマンド (rust-mode, overwrite-mode など) と変数操作のコマンドを登録する
+pub(crate) fn register_commands(r: &mut CommandRegistry, modes: &Modes) -> Result<(), String> {
+    for mode in modes.major_modes() {
+        register_major_mode_command(r, mode)?;
+    }
+    for mode in modes.minor_modes() {
+        register_minor_mode_command(r, mode)?;
+    }
+    r.register("set-variable", "Set VARIABLE to VALUE in the current buffer if it is buffer-local, else globally.", "sSet variable: \nsSet to value: ", set_variable)?;
+    r.register("describe-variable", "Display the buffer-local and default value of VARIABLE.", "sDescribe variable: ", describe_variable)?;
+    Ok(())
+}
+
+pub(crate) fn register_major_mode_command(r: &mut CommandRegistry, mode: &MajorMode) -> Result<(), String> {
+    let name = mode.name.clone();
+    r.register(&mode.name, &mode.doc, "", move |ctx: CommandContext| {
+        let name = name.clone();
+        async move {
+            let view = ctx.selected_view().await?;
+            ctx.state.set_major_mode(&view.buffer_id, &name).await?;
+            Ok(None)
+        }
+    })
+}
+
+/// 前置引数なしなら切り替え、正の数なら有効、それ以外なら無効にする
+pub(crate) fn register_minor_mode_command(r: &mut CommandRegistry, mode: &MinorMode) -> Result<(), String> {
+    let name = mode.name.clone();
+    r.register(&mode.name, &mode.doc, "P", move |ctx: CommandContext| {
+        let name = name.clone();
+        async move {
+            let view = ctx.selected_view().await?;
+            let enable = match ctx.prefix {
+                PrefixArg::None => None,
+                prefix => Some(prefix.numeric() > 0),
+            };
+            let enabled = ctx.state.set_minor_mode(&view.buffer_id, &name, enable).await?;
+            let state = if enabled { "enabled" } else { "disabled" };
+            Ok(Some(format!("{} {} in current buffer", name, state)))
+        }
+    })
+}
+
+async fn set_variable(ctx: CommandContext) -> CommandResult {
+    let name = ctx.string_arg(0)?;
+    let value = Value::parse(ctx.string_arg(1)?);
+    let buffer = ctx.current_buffer().await?;
+    // ロックは Modes、バッファの順に取る
+    let mut modes = ctx.state.modes().write().await;
+    let mut buffer = buffer.write().await;
+    if buffer.local(name).is_some() {
+        buffer.set_local(name, value);
+    } else {
+        modes.set_default(name, value);
+    }
+    Ok(None)
+}
+
+async fn describe_variable(ctx: CommandContext) -> CommandResult {
+    let name = ctx.string_arg(0)?;
+    let buffer = ctx.current_buffer().await?;
+    let modes = ctx.state.modes().read().await;
+    let buffer = buffer.read().await;
+    let description = match (buffer.local(name), modes.default_value(name)) {
+        (Some(local), Some(default)) => format!(
+            "{} is a variable.\nIts value is {}\nLocal in buffer {}; global value is {}",
+            name, local, buffer.name, default
+        ),
+        (Some(local), None) => format!("{} is a variable.\nIts value is {}\nLocal in buffer {}", name, local, buffer.name),
+        (None, _) => match modes.variable(&buffer, name) {
+            Some(value) => format!("{} is a variable.\nIts value is {}", name, value),
+            None => return Err(format!("Symbol's value as variable is void: {}", name).into()),
+        },
+    };
+    Ok(Some(description))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_parse_mode_cookie() {
+        assert_eq!(parse_mode_cookie("// -*- Rust -*-\n").unwrap().mode.as_deref(), Some("rust-mode"));
+        let cookie = parse_mode_cookie("#!/bin/sh\n# -*- mode: python; indent-tabs-mode: t; fill-column: 79 -*-").unwrap();
+        assert_eq!(cookie.mode.as_deref(), Some("python-mode"));
+        assert_eq!(
+            cookie.variables,
+            vec![("indent-tabs-mode".into(), Value::Bool(true)), ("fill-column".into(), Value::Int(79))]
+        );
+        assert_eq!(parse_mode_cookie("no cookie here"), None);
+        assert_eq!(parse_mode_cookie("\n// -*- rust -*-"), None);
+    }
+
+    #[test]
+    fn test_shebang_interpreter() {
+        assert_eq!(shebang_interpreter("#!/bin/bash\n").as_deref(), Some("bash"));
+        assert_eq!(shebang_interpreter("#!/usr/bin/env python3.11\n").as_deref(), Some("python"));
+        assert_eq!(shebang_interpreter("#!/usr/bin/env -S FOO=1 node --flag").as_deref(), Some("node"));
+        assert_eq!(shebang_interpreter("print('hi')"), None);
+    }
+
+    #[test]
+    fn test_detect_major_mode() {
+        let modes = Modes::default();
+        assert_eq!(modes.detect_major_mode("main.rs", ""), "rust-mode");
+        assert_eq!(modes.detect_major_mode("script", "#!/usr/bin/env python3\n"), "python-mode");
+        // "-*-" 行 > シバン > 拡張子
+        assert_eq!(modes.detect_major_mode("run.py", "#!/bin/sh\n"), "sh-mode");
+        assert_eq!(modes.detect_major_mode("notes.rs", "/* -*- mode: c -*- */"), "c-mode");
+        // 未定義のモード名は無視する
+        assert_eq!(modes.detect_major_mode("notes.txt", "-*- cobol -*-"), "text-mode");
+        assert_eq!(modes.detect_major_mode("README", ""), FUNDAMENTAL_MODE);
+    }
+
+    #[test]
+    fn test_major_mode_sets_locals_and_runs_hooks() {
+        let mut modes = Modes::default();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        for mode in ["prog-mode", "rust-mode"] {
+            let calls = calls.clone();
+            modes.add_hook(mode, Arc::new(move |buffer: &mut Buffer| {
+                calls.lock().unwrap().push(format!("{}:{}", mode, buffer.major_mode));
+            }));
+        }
+
+        let mut buffer = Buffer::new("lib.rs".into(), "fn main() {}\n");
+        buffer.set_local("fill-column", Value::Int(10));
+        modes.set_auto_mode(&mut buffer);
+        assert_eq!(buffer.major_mode, "rust-mode");
+        assert_eq!(modes.variable(&buffer, "comment-start"), Some(Value::String("// ".into())));
+        assert_eq!(modes.variable(&buffer, "fill-column"), Some(Value::Int(100)));
+        assert_eq!(modes.variable(&buffer, "tab-width"), Some(Value::Int(8)));
+        assert_eq!(*calls.lock().unwrap(), vec!["prog-mode:rust-mode", "rust-mode:rust-mode"]);
+
+        // 別のモードに切り替えると前のモードのローカル変数は消える
+        modes.set_major_mode(&mut buffer, "text-mode").unwrap();
+        assert_eq!(modes.variable(&buffer, "comment-start"), Some(Value::String(String::new())));
+        assert!(modes.set_major_mode(&mut buffer, "no-such-mode").is_err());
+    }
+
+    #[test]
+    fn test_cookie_variables_become_local() {
+        let modes = Modes::default();
+        let mut buffer = Buffer::new("x".into(), "# -*- mode: sh; tab-width: 4 -*-\necho hi\n");
+        modes.set_auto_mode(&mut buffer);
+        assert_eq!(buffer.major_mode, "sh-mode");
+        assert_eq!(buffer.local("tab-width"), Some(&Value::Int(4)));
+    }
+
+    #[test]
+    fn test_minor_modes() {
+        let mut modes = Modes::default();
+        let runs = Arc::new(Mutex::new(0));
+        let counter = runs.clone();
+        modes.add_hook("overwrite-mode", Arc::new(move |_: &mut Buffer| *counter.lock().unwrap() += 1));
+
+        let mut buffer = Buffer::new("x".into(), "");
+        modes.set_minor_mode(&mut buffer, "overwrite-mode", true).unwrap();
+        assert_eq!(modes.variable(&buffer, "overwrite-mode"), Some(Value::Bool(true)));
+        // マイナーモードはメジャーモードを切り替えても残る
+        modes.set_major_mode(&mut buffer, "text-mode").unwrap();
+        assert!(buffer.minor_mode_enabled("overwrite-mode"));
+        modes.set_minor_mode(&mut buffer, "overwrite-mode", false).unwrap();
+        assert!(!buffer.minor_mode_enabled("overwrite-mode"));
+        assert_eq!(*runs.lock().unwrap(), 2);
+        assert!(modes.set_minor_mode(&mut buffer, "no-such-mode", true).is_err());
+    }
+
+    #[test]
+    fn test_value_parse() {
+        assert_eq!(Value::parse("nil"), Value::Bool(false));
+        assert_eq!(Value::parse(" -3 "), Value::Int(-3));
+        assert_eq!(Value::parse("\"a b\""), Value::String("a b".into()));
+        assert_eq!(Value::parse("utf-8"), Value::String("utf-8".into()));
+        assert_eq!(Value::String("x".into()).to_string(), "\"x\"");
+    }
+}
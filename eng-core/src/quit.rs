@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use crate::buffer::Buffer;
+use crate::command::CommandError;
+use crate::state::EditorState;
+
+/// 実行中のコマンドへの中断要求 (quit-flag)。UIの C-g で立つ。
+#[derive(Debug, Clone, Default)]
+pub struct QuitSignal(Arc<QuitInner>);
+
+#[derive(Debug, Default)]
+struct QuitInner {
+    quit: AtomicBool,
+    notify: Notify,
+}
+
+impl QuitSignal {
+    pub fn quit(&self) {
+        self.0.quit.store(true, Ordering::SeqCst);
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn is_quit(&self) -> bool {
+        self.0.quit.load(Ordering::SeqCst)
+    }
+
+    /// 中断要求があるまで待つ
+    pub async fn quitted(&self) {
+        loop {
+            let notified = self.0.notify.notified();
+            if self.is_quit() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// 中断要求があれば Quit を返す (maybe_quit)。長いループの途中で呼ぶ。
+    pub fn check(&self) -> Result<(), CommandError> {
+        if self.is_quit() {
+            return Err(CommandError::Quit);
+        }
+        Ok(())
+    }
+
+    /// future を中断可能にして待つ。外部プロセスの終了待ちなど、await が長く続く処理に使う。
+    pub async fn interruptible<F: Future>(&self, future: F) -> Result<F::Output, CommandError> {
+        tokio::select! {
+            biased;
+            _ = self.quitted() => Err(CommandError::Quit),
+            output = future => Ok(output),
+        }
+    }
+}
+
+static NEXT_EDIT_LOG: AtomicU64 = AtomicU64::new(1);
+
+/// 実行中のコマンドが編集したバッファ。中断したときは、ここに記録したバッファでこのコマンドの編集だけを巻き戻す。
+/// 同時に実行している他のコマンドや、ApplyEdits などの他の編集は巻き戻さない。
+#[derive(Debug, Clone)]
+pub struct EditLog(Arc<EditLogInner>);
+
+#[derive(Debug)]
+struct EditLogInner {
+    /// 取り消しの記録に付ける、コマンドの実行ごとの ID
+    id: u64,
+    touched: Mutex<Touched>,
+}
+
+#[derive(Debug, Default)]
+struct Touched {
+    /// バッファID → 最初に編集する前に変更済みだったか
+    buffers: HashMap<String, bool>,
+    /// 巻き戻しを始めた。これより後の編集は受け付けない。
+    closed: bool,
+}
+
+impl PartialEq for EditLog {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.id == other.0.id
+    }
+}
+
+impl Eq for EditLog {}
+
+impl Default for EditLog {
+    fn default() -> Self {
+        Self(Arc::new(EditLogInner {
+            id: NEXT_EDIT_LOG.fetch_add(1, Ordering::Relaxed),
+            touched: Mutex::new(Touched::default()),
+        }))
+    }
+}
+
+impl EditLog {
+    fn touched(&self) -> std::sync::MutexGuard<'_, Touched> {
+        self.0.touched.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn id(&self) -> u64 {
+        self.0.id
+    }
+
+    /// コマンドの編集として buffer の range を text で置き換える。書き込みロックを取ったバッファを渡す。
+    /// 最初に編集するバッファには取り消しの区切りを付ける。巻き戻しを始めた後なら Quit を返す。
+    pub fn apply(&self, buffer_id: &str, buffer: &mut Buffer, range: std::ops::Range<usize>, text: &str) -> Result<(), CommandError> {
+        let mut touched = self.touched();
+        if touched.closed {
+            return Err(CommandError::Quit);
+        }
+        if !touched.buffers.contains_key(buffer_id) {
+            buffer.undo_boundary();
+            touched.buffers.insert(buffer_id.to_string(), buffer.modified);
+        }
+        buffer.replace_by(range, text, Some(self.0.id))?;
+        Ok(())
+    }
+
+    /// 以後の編集を止め、編集したバッファを返す
+    fn close(&self) -> HashMap<String, bool> {
+        let mut touched = self.touched();
+        touched.closed = true;
+        touched.buffers.clone()
+    }
+}
+
+#[derive(Debug)]
+struct Running {
+    window_id: String,
+    command: String,
+    signal: QuitSignal,
+}
+
+/// 実行中のコマンド。コマンドの実行ごと (EditLog の ID ごと) に記録するので、同じWindowや
+/// Window なしで同時に実行したコマンド同士は、中断要求も編集の記録も分け合わない。
+/// await をまたいでロックを保持しない。
+#[derive(Debug, Default)]
+pub struct RunningCommands {
+    commands: Mutex<HashMap<u64, Running>>,
+}
+
+impl RunningCommands {
+    fn commands(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Running>> {
+        self.commands.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Windowで実行中のコマンド (一番外側のもの)。複数あれば最後に始めたもの
+    pub fn command(&self, window_id: &str) -> Option<String> {
+        let commands = self.commands();
+        let latest = commands.iter().filter(|(_, r)| r.window_id == window_id).max_by_key(|(id, _)| **id);
+        latest.map(|(_, r)| r.command.clone())
+    }
+
+    /// Windowで実行中のコマンドに中断を要求する。実行中のコマンドが無ければ false を返す。
+    pub fn quit(&self, window_id: &str) -> bool {
+        let mut quit = false;
+        for running in self.commands().values().filter(|r| r.window_id == window_id) {
+            running.signal.quit();
+            quit = true;
+        }
+        quit
+    }
+}
+
+tokio::task_local! {
+    /// このタスクで実行しているコマンド。その中から実行したコマンドは入れ子になる。
+    static CURRENT: (QuitSignal, EditLog);
+}
+
+/// コマンドの実行中であることを表す。drop すると実行中の記録を外す
+/// (RPCが切断されて実行中の future が捨てられた場合も外れる)。
+#[derive(Debug)]
+pub struct CommandGuard {
+    state: Arc<EditorState>,
+    pub signal: QuitSignal,
+    /// このコマンドの編集。入れ子のコマンドは一番外側のコマンドと共有する。
+    pub log: EditLog,
+    /// 一番外側のコマンドか。巻き戻しは一番外側のコマンドだけが行う。
+    pub top_level: bool,
+}
+
+impl CommandGuard {
+    /// future をこのコマンドの中で実行する。その中から始めたコマンドは入れ子になる。
+    pub async fn scope<F: Future>(&self, future: F) -> F::Output {
+        CURRENT.scope((self.signal.clone(), self.log.clone()), future).await
+    }
+}
+
+impl Drop for CommandGuard {
+    fn drop(&mut self) {
+        if self.top_level {
+            self.state.running().commands().remove(&self.log.id());
+        }
+    }
+}
+
+/// Windowでコマンドの実行を始める。実行中のコマンドの中から呼ばれたなら、その中断要求と編集の記録を共有する。
+/// 別のRPCやタスクから始めたコマンドは、同じWindowでも別に記録する。
+pub fn begin(state: &Arc<EditorState>, window_id: &str, command: &str) -> CommandGuard {
+    if let Ok((signal, log)) = CURRENT.try_with(Clone::clone) {
+        return CommandGuard { state: state.clone(), signal, log, top_level: false };
+    }
+    let (signal, log) = (QuitSignal::default(), EditLog::default());
+    let running = Running { window_id: window_id.to_string(), command: command.to_string(), signal: signal.clone() };
+    state.running().commands().insert(log.id(), running);
+    CommandGuard { state: state.clone(), signal, log, top_level: true }
+}
+
+/// 中断したコマンドが途中まで行った編集を、そのコマンドが編集したバッファでだけ巻き戻す
+pub async fn rollback(state: &EditorState, log: &EditLog) {
+    for (id, modified) in log.close() {
+        if let Some(buffer) = state.get_buffer(&id).await
+            && let Err(e) = buffer.write().await.revert_owned(log.id(), modified)
+        {
+            eprintln!("Failed to roll back the quit command: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{execute_command, CommandContext, CommandInvocation, CommandResult};
+
+    /// 挿入した後、中断されるまで終わらないコマンド
+    async fn insert_and_wait(ctx: CommandContext) -> CommandResult {
+        let view = ctx.selected_view().await?;
+        ctx.edit(&view.buffer_id, view.point..view.point, "partial").await?;
+        ctx.quit.interruptible(std::future::pending::<()>()).await?;
+        Ok(None)
+    }
+
+    /// 挿入した後、中断要求を確認しながらループするコマンド
+    async fn insert_and_loop(ctx: CommandContext) -> CommandResult {
+        let view = ctx.selected_view().await?;
+        ctx.edit(&view.buffer_id, 0..0, "x").await?;
+        loop {
+            ctx.quit.check()?;
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// バッファの末尾に挿入してすぐ終わるコマンド
+    async fn append(ctx: CommandContext) -> CommandResult {
+        let view = ctx.selected_view().await?;
+        let len = ctx.current_buffer().await?.read().await.len_chars();
+        ctx.edit(&view.buffer_id, len..len, "!").await?;
+        Ok(None)
+    }
+
+    #[tokio::test]
+    async fn test_quit_rolls_back_only_own_edits() {
+        let state = Arc::new(EditorState::new());
+        let buffer_id = state.create_buffer("test".into(), "abc").await;
+        let waiting = state.create_window(&buffer_id).await.unwrap();
+        let other = state.create_window(&buffer_id).await.unwrap();
+        {
+            let mut commands = state.commands().write().await;
+            commands.register("insert-and-wait", "", "", insert_and_wait).unwrap();
+            commands.register("append", "", "", append).unwrap();
+        }
+
+        let (task_state, task_window) = (state.clone(), waiting.clone());
+        let task = tokio::spawn(async move {
+            execute_command(task_state, &task_window, "insert-and-wait", CommandInvocation::default()).await
+        });
+        while state.running().command(&waiting).is_none() {
+            tokio::task::yield_now().await;
+        }
+        // 実行中に他のWindowのコマンドと他のクライアントがバッファを編集する
+        execute_command(state.clone(), &other, "append", CommandInvocation::default()).await.unwrap();
+        let buffer = state.get_buffer(&buffer_id).await.unwrap();
+        buffer.write().await.insert(0, ">").unwrap();
+        assert_eq!(buffer.read().await.to_string(), ">partialabc!");
+
+        assert!(state.running().quit(&waiting));
+        assert_eq!(task.await.unwrap(), Err(CommandError::Quit));
+        assert_eq!(buffer.read().await.to_string(), ">abc!");
+        assert!(buffer.read().await.modified);
+    }
+
+    /// "notes" バッファに挿入した後、中断されるまで終わらないコマンド (Window なしでも動く)
+    async fn notes_and_wait(ctx: CommandContext) -> CommandResult {
+        let notes = ctx.state.find_buffer("notes").await.ok_or("No notes")?;
+        ctx.edit(&notes, 0..0, "wait ").await?;
+        ctx.quit.interruptible(std::future::pending::<()>()).await?;
+        Ok(None)
+    }
+
+    /// "notes" バッファの末尾に挿入してすぐ終わるコマンド
+    async fn notes_append(ctx: CommandContext) -> CommandResult {
+        let notes = ctx.state.find_buffer("notes").await.ok_or("No notes")?;
+        let len = ctx.state.get_buffer(&notes).await.ok_or("No notes")?.read().await.len_chars();
+        ctx.edit(&notes, len..len, " done").await?;
+        Ok(None)
+    }
+
+    /// 挿入してから、入れ子で insert-and-wait を実行するコマンド
+    async fn insert_then_nested(ctx: CommandContext) -> CommandResult {
+        let view = ctx.selected_view().await?;
+        ctx.edit(&view.buffer_id, 0..0, "outer ").await?;
+        execute_command(ctx.state.clone(), &ctx.window_id, "insert-and-wait", CommandInvocation::default()).await
+    }
+
+    async fn register_test_commands(state: &EditorState) {
+        let mut commands = state.commands().write().await;
+        commands.register("insert-and-wait", "", "", insert_and_wait).unwrap();
+        commands.register("append", "", "", append).unwrap();
+        commands.register("notes-and-wait", "", "", notes_and_wait).unwrap();
+        commands.register("notes-append", "", "", notes_append).unwrap();
+        commands.register("insert-then-nested", "", "", insert_then_nested).unwrap();
+    }
+
+    /// command を別のタスクで実行し、実行を始めるまで待つ
+    async fn spawn_command(
+        state: &Arc<EditorState>,
+        window_id: &str,
+        command: &'static str,
+    ) -> tokio::task::JoinHandle<Result<Option<String>, CommandError>> {
+        let (task_state, task_window) = (state.clone(), window_id.to_string());
+        let task = tokio::spawn(async move {
+            execute_command(task_state, &task_window, command, CommandInvocation::default()).await
+        });
+        while state.running().command(window_id).is_none() {
+            tokio::task::yield_now().await;
+        }
+        task
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_commands_are_separate() {
+        let state = Arc::new(EditorState::new());
+        let buffer_id = state.create_buffer("test".into(), "abc").await;
+        let window_id = state.create_window(&buffer_id).await.unwrap();
+        let notes = state.create_buffer("notes".into(), "notes").await;
+        register_test_commands(&state).await;
+
+        // 同じWindowで別のRPCから実行したコマンドは入れ子にならず、中断した方の編集だけを巻き戻す
+        let waiting = spawn_command(&state, &window_id, "insert-and-wait").await;
+        execute_command(state.clone(), &window_id, "append", CommandInvocation::default()).await.unwrap();
+        assert!(state.running().quit(&window_id));
+        assert_eq!(waiting.await.unwrap(), Err(CommandError::Quit));
+        assert_eq!(state.get_buffer(&buffer_id).await.unwrap().read().await.to_string(), "abc!");
+
+        // Window なしのコマンド (eng-agent -e) 同士も同じ
+        let waiting = spawn_command(&state, "", "notes-and-wait").await;
+        execute_command(state.clone(), "", "notes-append", CommandInvocation::default()).await.unwrap();
+        assert_eq!(state.get_buffer(&notes).await.unwrap().read().await.to_string(), "wait notes done");
+        assert!(state.running().quit(""));
+        assert_eq!(waiting.await.unwrap(), Err(CommandError::Quit));
+        assert_eq!(state.get_buffer(&notes).await.unwrap().read().await.to_string(), "notes done");
+        assert_eq!(state.running().command(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_nested_command_shares_quit_and_rollback() {
+        let state = Arc::new(EditorState::new());
+        let buffer_id = state.create_buffer("test".into(), "abc").await;
+        let window_id = state.create_window(&buffer_id).await.unwrap();
+        register_test_commands(&state).await;
+
+        // コマンドの中から実行したコマンドは入れ子になり、一番外側のコマンドとまとめて中断・巻き戻す
+        let task = spawn_command(&state, &window_id, "insert-then-nested").await;
+        let buffer = state.get_buffer(&buffer_id).await.unwrap();
+        while !buffer.read().await.to_string().contains("partial") {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(buffer.read().await.to_string(), "partialouter abc");
+        assert_eq!(state.running().command(&window_id).as_deref(), Some("insert-then-nested"));
+        assert!(state.running().quit(&window_id));
+        assert_eq!(task.await.unwrap(), Err(CommandError::Quit));
+        assert_eq!(buffer.read().await.to_string(), "abc");
+        assert_eq!(state.running().command(&window_id), None);
+    }
+
+    #[tokio::test]
+    async fn test_edit_after_rollback_is_quit() {
+        let state = Arc::new(EditorState::new());
+        let buffer_id = state.create_buffer("test".into(), "abc").await;
+        let window_id = state.create_window(&buffer_id).await.unwrap();
+        let (go, (result_tx, mut result_rx)) = (Arc::new(Notify::new()), tokio::sync::mpsc::channel(1));
+        {
+            let go = go.clone();
+            let mut commands = state.commands().write().await;
+            // 中断された後も、コマンドが始めた処理が編集しようとする
+            commands
+                .register("edit-later", "", "", move |ctx: CommandContext| {
+                    let (go, result_tx) = (go.clone(), result_tx.clone());
+                    async move {
+                        let task_ctx = ctx.clone();
+                        tokio::spawn(async move {
+                            go.notified().await;
+                            let _ = result_tx.send(task_ctx.edit(&buffer_id_of(&task_ctx).await, 0..0, "late").await).await;
+                        });
+                        ctx.edit(&buffer_id_of(&ctx).await, 0..0, "x").await?;
+                        ctx.quit.interruptible(std::future::pending::<()>()).await?;
+                        Ok(None)
+                    }
+                })
+                .unwrap();
+        }
+
+        let (task_state, task_window) = (state.clone(), window_id.clone());
+        let task = tokio::spawn(async move {
+            execute_command(task_state, &task_window, "edit-later", CommandInvocation::default()).await
+        });
+        while state.running().command(&window_id).is_none() {
+            tokio::task::yield_now().await;
+        }
+        assert!(state.running().quit(&window_id));
+        assert_eq!(task.await.unwrap(), Err(CommandError::Quit));
+        go.notify_one();
+        assert_eq!(result_rx.recv().await.unwrap(), Err(CommandError::Quit));
+        assert_eq!(state.get_buffer(&buffer_id).await.unwrap().read().await.to_string(), "abc");
+    }
+
+    async fn buffer_id_of(ctx: &CommandContext) -> String {
+        ctx.selected_view().await.map(|view| view.buffer_id).unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn test_quit_rolls_back_partial_edits() {
+        let state = Arc::new(EditorState::new());
+        let buffer_id = state.create_buffer("test".into(), "abc").await;
+        let window_id = state.create_window(&buffer_id).await.unwrap();
+        {
+            let mut commands = state.commands().write().await;
+            commands.register("insert-and-wait", "", "", insert_and_wait).unwrap();
+            commands.register("insert-and-loop", "", "", insert_and_loop).unwrap();
+        }
+        assert!(!state.running().quit(&window_id));
+
+        for command in ["insert-and-wait", "insert-and-loop"] {
+            let (task_state, task_window) = (state.clone(), window_id.clone());
+            let task = tokio::spawn(async move {
+                execute_command(task_state, &task_window, command, CommandInvocation::default()).await
+            });
+            while state.running().command(&window_id).is_none() {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(state.running().command(&window_id).as_deref(), Some(command));
+            assert!(state.running().quit(&window_id));
+            assert_eq!(task.await.unwrap(), Err(CommandError::Quit));
+
+            let buffer = state.get_buffer(&buffer_id).await.unwrap();
+            assert_eq!(buffer.read().await.to_string(), "abc");
+            assert!(!buffer.read().await.modified);
+            assert_eq!(state.running().command(&window_id), None);
+        }
+
+        assert_eq!(execute_command(state.clone(), &window_id, "keyboard-quit", CommandInvocation::default()).await, Err(CommandError::Quit));
+    }
+}
@@ -0,0 +1,720 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::time::Duration;
+
+use ropey::Rope;
+use tokio::process::{Child, Command};
+use tonic::transport::Channel;
+use tonic::{Code, Request, Status};
+
+use crate::buffer::{BufferSnapshot, Delta, DELTA_LOG_LIMIT};
+use crate::editor::buffer_service_client::BufferServiceClient;
+use crate::editor::editor_service_client::EditorServiceClient;
+use crate::editor::handshake_request::ClientKind;
+use crate::editor::{
+    ApplyEditsRequest, BufferInfo, GetTextRequest, HandshakeRequest, OpenFileRequest, SaveBufferRequest, TextEdit,
+};
+use crate::handshake::{self, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::sched::EditError;
+use crate::subscription::Versioned;
+use crate::transport;
+
+/// 再接続を試みる間隔。全て失敗したら諦める。
+pub const RECONNECT_DELAYS: &[Duration] = &[
+    Duration::ZERO,
+    Duration::from_millis(200),
+    Duration::from_secs(1),
+    Duration::from_secs(3),
+];
+
+/// 1回の flush で接続し直す回数の上限
+const MAX_FLUSH_ATTEMPTS: usize = 3;
+
+/// コアとハンドシェイクして、同じプロトコルを話せることを確かめる。コアの名前を返す。
+pub async fn handshake(channel: Channel, kind: ClientKind, name: &str) -> Result<String, String> {
+    let mut client = EditorServiceClient::new(channel);
+    let hello = HandshakeRequest {
+        protocol_version: PROTOCOL_VERSION,
+        min_protocol_version: MIN_PROTOCOL_VERSION,
+        client_kind: kind.into(),
+        client_name: name.to_string(),
+        capabilities: vec![],
+        encodings: vec![],
+        session_id: String::new(),
+    };
+    let mut responses = client
+        .handshake(Request::new(tokio_stream::iter(vec![hello])))
+        .await
+        .map_err(|e| format!("Handshake failed: {}", e.message()))?
+        .into_inner();
+    match responses.message().await {
+        Ok(Some(welcome)) => {
+            handshake::check_server_version(welcome.protocol_version).map_err(|e| e.to_string())?;
+            Ok(welcome.server_name)
+        }
+        Ok(None) => Err("Core closed the handshake without a response".into()),
+        Err(e) => Err(format!("Handshake failed: {}", e.message())),
+    }
+}
+
+/// command を標準入出力をつないで起動し、その上で gRPC を話す
+pub async fn spawn_stdio(command: &mut Command) -> Result<(Child, Channel), String> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Failed to launch core: {}", e))?;
+    let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        return Err("Failed to open core stdio".into());
+    };
+    match transport::connect_pipe(stdout, stdin).await {
+        Ok(channel) => Ok((child, channel)),
+        Err(e) => {
+            let _ = child.kill().await;
+            Err(format!("Failed to connect to core: {}", e))
+        }
+    }
+}
+
+/// コアへの1本の接続。子プロセス経由なら子プロセスも持ち、drop すると終了させる。
+#[derive(Debug)]
+pub struct Connection {
+    pub channel: Channel,
+    pub server_name: String,
+    child: Option<Child>,
+}
+
+impl Connection {
+    pub fn new(channel: Channel, server_name: String, child: Option<Child>) -> Self {
+        Self { channel, server_name, child }
+    }
+
+    /// 子プロセスが終了していれば true
+    pub fn exited(&mut self) -> bool {
+        self.child.as_mut().is_some_and(|child| !matches!(child.try_wait(), Ok(None)))
+    }
+}
+
+/// 任意のコマンドでコアを起動し、その標準入出力の上でプロトコルを話す (リモート開発)。
+/// コマンドは sh -c で実行するので、コアを --stdio で起動するところまで含めて書く。
+/// 例: "ssh host eng-core --stdio"、"docker exec -i dev eng-core --stdio"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreCommand {
+    pub command: String,
+}
+
+impl CoreCommand {
+    pub fn new(command: impl Into<String>) -> Self {
+        Self { command: command.into() }
+    }
+
+    /// ENG_CORE_COMMAND で指定されたコマンド
+    pub fn from_env() -> Option<Self> {
+        std::env::var("ENG_CORE_COMMAND").ok().filter(|c| !c.trim().is_empty()).map(Self::new)
+    }
+
+    pub async fn connect(&self) -> Result<Connection, String> {
+        let (child, channel) = spawn_stdio(Command::new("sh").arg("-c").arg(&self.command)).await?;
+        let name = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        let server_name = handshake(channel.clone(), ClientKind::Agent, &name)
+            .await
+            .map_err(|e| format!("{} (command: {})", e, self.command))?;
+        Ok(Connection::new(channel, server_name, Some(child)))
+    }
+}
+
+type ConnectFuture = Pin<Box<dyn Future<Output = Result<Connection, String>> + Send>>;
+
+/// 切れたら繋ぎ直すコアへの接続。繋ぎ直すとコアは別のプロセスになるので、
+/// バッファは RemoteBuffer が開き直す。
+pub struct RemoteSession {
+    connect: Box<dyn Fn() -> ConnectFuture + Send + Sync>,
+    connection: Option<Connection>,
+    /// 接続した回数 (最初の接続を含む)
+    pub connects: u32,
+}
+
+impl std::fmt::Debug for RemoteSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RemoteSession")
+            .field("connection", &self.connection)
+            .field("connects", &self.connects)
+            .finish()
+    }
+}
+
+impl RemoteSession {
+    pub fn new<F, Fut>(connect: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Connection, String>> + Send + 'static,
+    {
+        Self { connect: Box::new(move || Box::pin(connect())), connection: None, connects: 0 }
+    }
+
+    pub fn command(command: CoreCommand) -> Self {
+        Self::new(move || {
+            let command = command.clone();
+            async move { command.connect().await }
+        })
+    }
+
+    /// 接続されていれば、その接続
+    pub fn connection(&mut self) -> Option<&mut Connection> {
+        self.connection.as_mut()
+    }
+
+    /// 接続を返す。切れていれば RECONNECT_DELAYS の間隔で繋ぎ直す。
+    pub async fn channel(&mut self) -> Result<Channel, String> {
+        if let Some(connection) = &mut self.connection
+            && !connection.exited()
+        {
+            return Ok(connection.channel.clone());
+        }
+        self.connection = None;
+        let mut last_error = String::new();
+        for delay in RECONNECT_DELAYS {
+            tokio::time::sleep(*delay).await;
+            match (self.connect)().await {
+                Ok(connection) => {
+                    self.connects += 1;
+                    let channel = connection.channel.clone();
+                    self.connection = Some(connection);
+                    return Ok(channel);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(format!("Failed to reconnect to core: {}", last_error))
+    }
+
+    /// 接続が切れたことを知らせる。次の channel で繋ぎ直す。
+    pub fn disconnect(&mut self) {
+        self.connection = None;
+    }
+}
+
+/// 接続が切れたことを表す状態か
+pub fn is_disconnected(status: &Status) -> bool {
+    matches!(status.code(), Code::Unavailable | Code::Unknown | Code::Cancelled)
+}
+
+/// a を b にする、変わったところだけの置き換え (a の範囲と、そこを置き換えるテキスト)。同じなら None
+fn diff(a: &Rope, b: &Rope) -> Option<(Range<usize>, String)> {
+    let (a_len, b_len) = (a.len_chars(), b.len_chars());
+    let prefix = a.chars().zip(b.chars()).take_while(|(x, y)| x == y).count();
+    if prefix == a_len && prefix == b_len {
+        return None;
+    }
+    let suffix = a
+        .chars_at(a_len)
+        .reversed()
+        .zip(b.chars_at(b_len).reversed())
+        .take(a_len.min(b_len) - prefix)
+        .take_while(|(x, y)| x == y)
+        .count();
+    Some((prefix..a_len - suffix, b.slice(prefix..b_len - suffix).to_string()))
+}
+
+/// ancestor から theirs への変更と ours への変更を合わせたテキスト。変えた範囲が重なれば None (衝突)
+fn merge(ancestor: &Rope, theirs: &Rope, ours: &Rope) -> Option<Rope> {
+    let Some(their_change) = diff(ancestor, theirs) else {
+        return Some(ours.clone());
+    };
+    let Some(our_change) = diff(ancestor, ours) else {
+        return Some(theirs.clone());
+    };
+    if theirs == ours {
+        return Some(ours.clone());
+    }
+    // 同じ位置への挿入は、どちらを先にするか決められないので衝突とする
+    let (front, back) = if our_change.0 == their_change.0 {
+        return None;
+    } else if our_change.0.end <= their_change.0.start {
+        (our_change, their_change)
+    } else if their_change.0.end <= our_change.0.start {
+        (their_change, our_change)
+    } else {
+        return None;
+    };
+    // 後ろから当てれば、前の変更の位置はずれない
+    let mut merged = ancestor.clone();
+    for (range, text) in [back, front] {
+        merged.remove(range.clone());
+        merged.insert(range.start, &text);
+    }
+    Some(merged)
+}
+
+/// 開いたファイルのリモートでの状態
+struct Opened {
+    info: BufferInfo,
+    text: Rope,
+    version: u64,
+}
+
+async fn open_remote(client: &mut BufferServiceClient<Channel>, path: &Path) -> Result<Opened, Status> {
+    let info = client.open_file(OpenFileRequest { path: path.display().to_string() }).await?.into_inner();
+    let request = GetTextRequest { buffer_id: info.buffer_id.clone(), start: 0, end: None };
+    let remote = client.get_text(request).await?.into_inner();
+    Ok(Opened { info, text: Rope::from_str(&remote.text), version: remote.version })
+}
+
+/// リモートのコアで開いたファイル。編集は手元のテキストにすぐ反映して溜めておき (ローカルの応答速度)、
+/// flush でまとめて送る。接続が切れたら繋ぎ直してファイルを開き直し、送っていない編集を載せ直す。
+/// 他のクライアントが同じ場所を変えていたら、どちらも上書きせずに衝突として失敗する。
+#[derive(Debug, Clone)]
+pub struct RemoteBuffer {
+    path: PathBuf,
+    /// 溜めている編集も反映した手元のテキスト
+    text: Rope,
+    /// 手元のテキストの版。編集するか、リモートの変更を取り込むたびに増える
+    revision: u64,
+    /// 最後に開いたときのバッファの情報
+    info: BufferInfo,
+    /// None なら、今の接続ではまだ開いていない
+    buffer_id: Option<String>,
+    /// 開いたときの RemoteSession::connects。変わっていれば繋ぎ直したので開き直す
+    connects: u32,
+    /// 送り終えた編集を全て含むリモートの版と、そのテキスト
+    version: u64,
+    base: Rope,
+    /// 最後に開いたか保存したときのテキスト (ファイルの内容)
+    saved: Rope,
+    /// 送っていない編集 (古い順)。base に順に当てると text になる
+    pending: VecDeque<TextEdit>,
+    /// 手元のテキストの版ごとの差分 (購読者に送る)。1つの版に1つ
+    deltas: VecDeque<Delta>,
+}
+
+impl RemoteBuffer {
+    pub async fn open(session: &mut RemoteSession, path: &Path) -> Result<Self, Status> {
+        let channel = session.channel().await.map_err(Status::unavailable)?;
+        let opened = open_remote(&mut BufferServiceClient::new(channel), path).await?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            text: opened.text.clone(),
+            revision: 0,
+            buffer_id: Some(opened.info.buffer_id.clone()),
+            info: opened.info,
+            connects: session.connects,
+            version: opened.version,
+            base: opened.text.clone(),
+            saved: opened.text,
+            pending: VecDeque::new(),
+            deltas: VecDeque::new(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn text(&self) -> &Rope {
+        &self.text
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// 手元のテキストのスナップショット。版は手元の版
+    pub fn snapshot(&self) -> BufferSnapshot {
+        let path = Some(self.path.clone());
+        BufferSnapshot::from_rope(self.text.clone(), self.revision, self.info.name.clone(), path, self.info.major_mode.clone())
+    }
+
+    /// 手元のテキストについてのバッファの情報。バッファIDはリモートのコアのもの
+    pub fn info(&self) -> BufferInfo {
+        BufferInfo {
+            modified: self.text != self.saved,
+            size: self.text.len_chars() as u64,
+            version: self.revision,
+            ..self.info.clone()
+        }
+    }
+
+    /// 今の接続で開いているリモートのバッファID。まだ開いていなければ None
+    pub fn buffer_id(&self) -> Option<&str> {
+        self.buffer_id.as_deref()
+    }
+
+    /// まだ送っていない編集の数
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// 編集を順に手元へ反映する。各編集の位置は前の編集を反映した後のテキストに対するもの (ApplyEdits と同じ)。
+    /// 送るのは flush まで待つ。どれかが不正なら何も反映しない。
+    pub fn apply(&mut self, edits: &[TextEdit], base_version: Option<u64>) -> Result<u64, EditError> {
+        if let Some(base_version) = base_version
+            && base_version != self.revision
+        {
+            return Err(EditError::Stale { version: self.revision, base_version });
+        }
+        if self.info.read_only {
+            return Err(EditError::Rejected("Buffer is read-only".into()));
+        }
+        let mut len = self.text.len_chars();
+        for edit in edits {
+            let range = edit.start as usize..edit.end as usize;
+            if range.start > range.end || range.end > len {
+                return Err(EditError::Rejected(format!("Invalid range: {:?} (len: {})", range, len)));
+            }
+            len = len - range.len() + edit.text.chars().count();
+        }
+        if edits.is_empty() {
+            return Ok(self.revision);
+        }
+        let mut text = self.text.clone();
+        for edit in edits {
+            text.remove(edit.start as usize..edit.end as usize);
+            text.insert(edit.start as usize, &edit.text);
+            self.pending.push_back(edit.clone());
+        }
+        self.replace_text(text);
+        Ok(self.revision)
+    }
+
+    /// 手元のテキストを置き換えて版を進め、前の版からの差分を記録する
+    fn replace_text(&mut self, text: Rope) {
+        let before = std::mem::replace(&mut self.text, text);
+        self.revision += 1;
+        let (range, text) = diff(&before, &self.text).unwrap_or_default();
+        if self.deltas.len() >= DELTA_LOG_LIMIT {
+            self.deltas.pop_front();
+        }
+        self.deltas.push_back(Delta { version: self.revision, start: range.start, end: range.end, text });
+    }
+
+    /// version より後の手元の差分 (古い順)。保持している差分で足りなければ None
+    pub fn deltas_since(&self, version: u64) -> Option<Vec<Delta>> {
+        if version > self.revision {
+            return None;
+        }
+        if version == self.revision {
+            return Some(Vec::new());
+        }
+        let oldest = self.deltas.front()?.version;
+        if oldest > version + 1 {
+            return None;
+        }
+        Some(self.deltas.iter().filter(|d| d.version > version).cloned().collect())
+    }
+
+    /// range を text で置き換える。手元にすぐ反映し、送るのは flush まで待つ。
+    pub fn edit(&mut self, range: Range<usize>, text: &str) -> Result<(), String> {
+        let edit = TextEdit { start: range.start as u64, end: range.end as u64, text: text.to_string() };
+        self.apply(&[edit], None).map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    /// 溜めている編集を送る。接続が切れていたら繋ぎ直し、開き直してから送る。
+    pub async fn flush(&mut self, session: &mut RemoteSession) -> Result<u64, Status> {
+        let mut last_error = String::new();
+        for _ in 0..MAX_FLUSH_ATTEMPTS {
+            let channel = session.channel().await.map_err(Status::unavailable)?;
+            // 他のバッファが繋ぎ直していれば、このバッファも開き直す
+            if self.connects != session.connects {
+                self.connects = session.connects;
+                self.buffer_id = None;
+            }
+            match self.send(channel).await {
+                Ok(version) => return Ok(version),
+                Err(status) if is_disconnected(&status) => {
+                    session.disconnect();
+                    self.buffer_id = None;
+                    last_error = status.message().to_string();
+                }
+                Err(status) => return Err(status),
+            }
+        }
+        Err(Status::unavailable(format!("Failed to send edits: {}", last_error)))
+    }
+
+    async fn send(&mut self, channel: Channel) -> Result<u64, Status> {
+        let mut client = BufferServiceClient::new(channel);
+        if self.buffer_id.is_none() {
+            let opened = open_remote(&mut client, &self.path).await?;
+            // 自動保存から復元したなら送り終えた編集を含み、ファイルから読んだなら含まない
+            let ancestor = if opened.info.modified { self.base.clone() } else { self.saved.clone() };
+            self.rebase(&ancestor, opened.text, opened.version)?;
+            self.buffer_id = Some(opened.info.buffer_id.clone());
+            self.info = opened.info;
+        }
+        if self.pending.is_empty() {
+            return Ok(self.version);
+        }
+        let buffer_id = self.buffer_id.clone().unwrap_or_default();
+        let edits = self.pending.iter().cloned().collect();
+        let request = ApplyEditsRequest { buffer_id: buffer_id.clone(), edits, base_version: Some(self.version) };
+        let response = match client.apply_edits(request).await {
+            // 他のクライアントが先に編集していた
+            Err(status) if status.code() == Code::FailedPrecondition => {
+                let request = GetTextRequest { buffer_id: buffer_id.clone(), start: 0, end: None };
+                let remote = client.get_text(request).await?.into_inner();
+                let base = self.base.clone();
+                self.rebase(&base, Rope::from_str(&remote.text), remote.version)?;
+                if self.pending.is_empty() {
+                    return Ok(self.version);
+                }
+                let edits = self.pending.iter().cloned().collect();
+                let request = ApplyEditsRequest { buffer_id, edits, base_version: Some(self.version) };
+                client.apply_edits(request).await?
+            }
+            result => result?,
+        };
+        self.version = response.into_inner().version;
+        self.base = self.text.clone();
+        self.pending.clear();
+        Ok(self.version)
+    }
+
+    /// ancestor からリモートへの変更 (他のクライアントの編集) と手元の変更を合わせ、
+    /// 送っていない編集をリモートの版 version の上に載せ直す
+    #[allow(clippy::result_large_err)]
+    fn rebase(&mut self, ancestor: &Rope, remote: Rope, version: u64) -> Result<(), Status> {
+        let merged = merge(ancestor, &remote, &self.text).ok_or_else(|| self.conflict())?;
+        self.pending = diff(&remote, &merged)
+            .map(|(range, text)| TextEdit { start: range.start as u64, end: range.end as u64, text })
+            .into_iter()
+            .collect();
+        if merged != self.text {
+            self.replace_text(merged);
+        }
+        self.base = remote;
+        self.version = version;
+        Ok(())
+    }
+
+    fn conflict(&self) -> Status {
+        Status::aborted(format!(
+            "Conflict: {} was changed on the core where it has unsent edits",
+            self.path.display()
+        ))
+    }
+
+    /// 溜めている編集を送ってからファイルへ保存する
+    pub async fn save(&mut self, session: &mut RemoteSession) -> Result<u64, Status> {
+        for _ in 0..MAX_FLUSH_ATTEMPTS {
+            self.flush(session).await?;
+            let channel = session.channel().await.map_err(Status::unavailable)?;
+            let buffer_id = self.buffer_id.clone().unwrap_or_default();
+            let request = SaveBufferRequest { buffer_id, path: String::new() };
+            match BufferServiceClient::new(channel).save_buffer(request).await {
+                Ok(response) => {
+                    self.saved = self.text.clone();
+                    return Ok(response.into_inner().version);
+                }
+                // 保存の前に切れたら、繋ぎ直して送り直すところからやり直す
+                Err(status) if is_disconnected(&status) => {
+                    session.disconnect();
+                    self.buffer_id = None;
+                }
+                Err(status) => return Err(status),
+            }
+        }
+        Err(Status::unavailable("Failed to save: connection keeps dropping"))
+    }
+
+    /// flush と save は送り終えるまで &mut self を取る。その間も手元で編集を受け付けるときは、
+    /// 複製 (before) を送り、送り終えた複製 (after) をこれで取り込む。
+    /// 複製してから手元に加えた編集は、after が取り込んだリモートの変更の上に載せ直す。
+    #[allow(clippy::result_large_err)]
+    pub fn absorb(&mut self, before: &RemoteBuffer, after: RemoteBuffer) -> Result<(), Status> {
+        let later: Vec<TextEdit> = self.pending.iter().skip(before.pending.len()).cloned().collect();
+        let local = std::mem::replace(self, after);
+        if self.text == before.text {
+            self.text = local.text;
+            self.revision = local.revision;
+            self.deltas = local.deltas;
+            self.pending.extend(later);
+            return Ok(());
+        }
+        match merge(&before.text, &self.text, &local.text) {
+            Some(merged) => {
+                if let Some((range, text)) = diff(&self.text, &merged) {
+                    self.pending.push_back(TextEdit { start: range.start as u64, end: range.end as u64, text });
+                }
+                // 版と差分は手元のものに続ける
+                self.text = local.text;
+                self.revision = local.revision;
+                self.deltas = local.deltas;
+                self.replace_text(merged);
+                Ok(())
+            }
+            None => {
+                // 手元の編集は捨てない。次の flush で開き直し、同じところを変えていればまた衝突とする
+                *self = local;
+                self.buffer_id = None;
+                Err(self.conflict())
+            }
+        }
+    }
+}
+
+/// 購読者に送るのは手元の版 (revision)。リモートの版 (version) ではない
+impl Versioned for RemoteBuffer {
+    #[allow(clippy::misnamed_getters)]
+    fn version(&self) -> u64 {
+        self.revision
+    }
+
+    fn snapshot(&self) -> BufferSnapshot {
+        RemoteBuffer::snapshot(self)
+    }
+
+    fn deltas_since(&self, version: u64) -> Option<Vec<Delta>> {
+        RemoteBuffer::deltas_since(self, version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::buffer_service_server::BufferServiceServer;
+    use crate::editor::editor_service_server::EditorServiceServer;
+    use crate::state::EditorState;
+    use crate::subscription::{Cursor, Update};
+    use std::sync::{Arc, Mutex};
+    use tokio::task::JoinHandle;
+    use tokio_stream::StreamExt;
+
+    /// 接続のたびに新しいコアを作る (リモートのコアプロセスの代わり)。
+    /// 中継するタスクを止めると、その接続が切れる。
+    fn in_process_session() -> (RemoteSession, Arc<Mutex<Option<JoinHandle<()>>>>) {
+        let relay = Arc::new(Mutex::new(None));
+        let current = relay.clone();
+        let session = RemoteSession::new(move || {
+            let relay = relay.clone();
+            async move {
+                let state = Arc::new(EditorState::new());
+                let (client_end, mut relay_a) = tokio::io::duplex(64 * 1024);
+                let (mut relay_b, server_end) = tokio::io::duplex(64 * 1024);
+                let (server_reader, server_writer) = tokio::io::split(server_end);
+                let (stream, closed) = transport::PipeStream::new(server_reader, server_writer);
+                tokio::spawn(
+                    tonic::transport::Server::builder()
+                        .add_service(EditorServiceServer::new(crate::MyEditorService::new(state.clone())))
+                        .add_service(BufferServiceServer::new(crate::MyBufferService::new(state.clone())))
+                        .serve_with_incoming_shutdown(
+                            tokio_stream::once(Ok::<_, std::io::Error>(stream)).chain(tokio_stream::pending()),
+                            async {
+                                let _ = closed.await;
+                            },
+                        ),
+                );
+                tokio::spawn(crate::sched::run(state));
+                let task = tokio::spawn(async move {
+                    let _ = tokio::io::copy_bidirectional(&mut relay_a, &mut relay_b).await;
+                });
+                *relay.lock().unwrap() = Some(task);
+                let (client_reader, client_writer) = tokio::io::split(client_end);
+                let channel = transport::connect_pipe(client_reader, client_writer).await.map_err(|e| e.to_string())?;
+                let server_name = handshake(channel.clone(), ClientKind::Agent, "test").await?;
+                Ok(Connection::new(channel, server_name, None))
+            }
+        });
+        (session, current)
+    }
+
+    #[tokio::test]
+    async fn test_remote_buffer_survives_reconnect() {
+        let dir = std::env::temp_dir().join(format!("eng-remote-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("remote.txt");
+        std::fs::write(&path, "hello\n").unwrap();
+
+        let (mut session, relay) = in_process_session();
+        let mut buffer = RemoteBuffer::open(&mut session, &path).await.unwrap();
+        assert!(session.connection().unwrap().server_name.starts_with("eng-core"));
+        assert_eq!(buffer.text().to_string(), "hello\n");
+
+        // 編集はすぐ手元に反映され、flush で送られる
+        buffer.edit(0..1, "H").unwrap();
+        buffer.edit(5..5, ", world").unwrap();
+        assert_eq!((buffer.text().to_string().as_str(), buffer.pending()), ("Hello, world\n", 2));
+        assert!(buffer.edit(3..100, "x").is_err());
+        buffer.flush(&mut session).await.unwrap();
+        assert_eq!(buffer.pending(), 0);
+
+        // 接続が切れている間の編集も失われない
+        relay.lock().unwrap().take().unwrap().abort();
+        buffer.edit(12..12, "!").unwrap();
+        buffer.save(&mut session).await.unwrap();
+        assert_eq!(session.connects, 2);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "Hello, world!\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remote_buffer_rebases_concurrent_edit() {
+        let dir = std::env::temp_dir().join(format!("eng-remote-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("remote.txt");
+        std::fs::write(&path, "one\ntwo\n").unwrap();
+
+        let (mut session, _relay) = in_process_session();
+        let mut buffer = RemoteBuffer::open(&mut session, &path).await.unwrap();
+        let mut other = BufferServiceClient::new(session.channel().await.unwrap());
+        let buffer_id = other.open_file(OpenFileRequest { path: path.display().to_string() }).await.unwrap().into_inner().buffer_id;
+        let edit = |start, end, text: &str| ApplyEditsRequest {
+            buffer_id: buffer_id.clone(),
+            edits: vec![TextEdit { start, end, text: text.to_string() }],
+            base_version: None,
+        };
+        let request = GetTextRequest { buffer_id: buffer_id.clone(), start: 0, end: None };
+
+        // 他のクライアントが別の行を先に変えていれば、送っていない編集をその上に載せ直す
+        other.apply_edits(edit(4, 7, "TWO")).await.unwrap();
+        buffer.edit(0..3, "ONE").unwrap();
+        buffer.flush(&mut session).await.unwrap();
+        assert_eq!((buffer.text().to_string().as_str(), buffer.pending()), ("ONE\nTWO\n", 0));
+        assert_eq!(other.get_text(request.clone()).await.unwrap().into_inner().text, "ONE\nTWO\n");
+
+        // 取り込んだ他のクライアントの編集も、手元の版の差分として購読者に送る
+        let deltas = buffer.deltas_since(0).unwrap();
+        let expected = [
+            Delta { version: 1, start: 0, end: 3, text: "ONE".into() },
+            Delta { version: 2, start: 4, end: 7, text: "TWO".into() },
+        ];
+        assert_eq!(deltas, expected);
+        let mut cursor = Cursor::new(Some(1), None);
+        assert!(matches!(cursor.update(&buffer), Some(Update::Deltas(d)) if d == expected[1..]));
+        assert!(cursor.update(&buffer).is_none());
+        assert!(matches!(Cursor::new(Some(5), None).update(&buffer), Some(Update::Snapshot(s)) if s.version == 2));
+
+        // 同じところを変えていれば、どちらも上書きしない
+        other.apply_edits(edit(0, 3, "uno")).await.unwrap();
+        buffer.edit(0..3, "eins").unwrap();
+        let error = buffer.flush(&mut session).await.unwrap_err();
+        assert_eq!(error.code(), Code::Aborted);
+        assert!(error.message().starts_with("Conflict"));
+        assert_eq!((buffer.text().to_string().as_str(), buffer.pending()), ("eins\nTWO\n", 1));
+        assert_eq!(other.get_text(request).await.unwrap().into_inner().text, "uno\nTWO\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge() {
+        let rope = Rope::from_str;
+        assert_eq!(merge(&rope("abc"), &rope("aXbc"), &rope("abYc")), Some(rope("aXbYc")));
+        assert_eq!(merge(&rope("abc"), &rope("abc"), &rope("abYc")), Some(rope("abYc")));
+        // 隣り合う変更は重ならない
+        assert_eq!(merge(&rope("abcd"), &rope("aXcd"), &rope("abYd")), Some(rope("aXYd")));
+        // 同じ位置への挿入と、重なる変更は衝突
+        assert_eq!(merge(&rope("abc"), &rope("aXbc"), &rope("aYbc")), None);
+        assert_eq!(merge(&rope("abcd"), &rope("aXd"), &rope("abYd")), None);
+    }
+
+    #[tokio::test]
+    async fn test_core_command() {
+        let session = RemoteSession::command(CoreCommand::new("exit 3")).channel().await;
+        assert!(session.unwrap_err().starts_with("Failed to reconnect to core"));
+    }
+}
@@ -0,0 +1,426 @@
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{oneshot, Notify};
+use tokio::time::Instant;
+
+use crate::command::CommandError;
+use crate::quit::EditLog;
+use crate::state::EditorState;
+
+/// 入力に応答するまでの目標時間 (1フレーム)
+pub const INPUT_LATENCY_BUDGET: Duration = Duration::from_millis(16);
+/// 入力の処理中に後回しにしたバックグラウンドの処理を、それ以上は待たせない時間
+const BACKGROUND_MAX_DEFER: Duration = Duration::from_millis(200);
+/// ヒストグラムの区切り (ミリ秒)。最後の区切りより遅いものは最後の区間に数える。
+const LATENCY_BUCKETS_MS: [u64; 6] = [1, 4, 16, 64, 256, 1024];
+
+/// 編集の優先度。入力による編集はバックグラウンドの編集より先に適用する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    /// タイマーから実行したコマンドなど
+    Background,
+    #[default]
+    Interactive,
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Priority::Background => f.write_str("background"),
+            Priority::Interactive => f.write_str("interactive"),
+        }
+    }
+}
+
+/// 編集キューに入れる編集。changes の各 range を順に text で置き換える。
+/// 全ての範囲を確かめてから適用するので、途中の1つが不正なら何も適用しない。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub buffer_id: String,
+    /// それぞれの範囲は、前の置き換えを適用した後のテキストに対するもの
+    pub changes: Vec<(Range<usize>, String)>,
+    /// 編集を計算したスナップショットの版。適用時に版が進んでいれば古い編集として捨てる。
+    pub base_version: Option<u64>,
+    pub priority: Priority,
+    /// コマンドの編集なら、そのコマンドの記録。C-g で中断したときに巻き戻す。
+    pub log: Option<EditLog>,
+}
+
+/// 編集を適用できなかった理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditError {
+    NoSuchBuffer(String),
+    /// 編集を作った後にバッファが変更された
+    Stale { version: u64, base_version: u64 },
+    /// 範囲外や読み取り専用など
+    Rejected(String),
+    /// コマンドの編集だが、そのコマンドは C-g で中断して巻き戻しを始めている
+    Quit,
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EditError::NoSuchBuffer(id) => write!(f, "No such buffer: {}", id),
+            EditError::Stale { version, base_version } => {
+                write!(f, "Stale edit: buffer is at version {}, edit was made for {}", version, base_version)
+            }
+            EditError::Rejected(message) => f.write_str(message),
+            EditError::Quit => f.write_str("Quit"),
+        }
+    }
+}
+
+impl std::error::Error for EditError {}
+
+/// 処理時間の集計
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+    /// INPUT_LATENCY_BUDGET を超えた回数
+    pub over_budget: u64,
+    /// LATENCY_BUCKETS_MS の区間ごとの回数
+    pub buckets: [u64; LATENCY_BUCKETS_MS.len()],
+}
+
+impl LatencyStats {
+    fn record(&mut self, latency: Duration) {
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+        if latency > INPUT_LATENCY_BUDGET {
+            self.over_budget += 1;
+        }
+        let ms = latency.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS.iter().position(|&limit| ms < limit).unwrap_or(LATENCY_BUCKETS_MS.len() - 1);
+        self.buckets[bucket] += 1;
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            n => self.total / n as u32,
+        }
+    }
+}
+
+impl fmt::Display for LatencyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "n={} mean={:.2}ms max={:.2}ms over-budget={}",
+            self.count,
+            self.mean().as_secs_f64() * 1000.0,
+            self.max.as_secs_f64() * 1000.0,
+            self.over_budget
+        )
+    }
+}
+
+struct Queued {
+    edit: Edit,
+    seq: u64,
+    enqueued: Instant,
+    reply: oneshot::Sender<Result<u64, EditError>>,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    /// 優先度の高いもの、同じ優先度なら先に入れたものが大きい (BinaryHeap から先に出る)
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.edit.priority.cmp(&other.edit.priority).then(other.seq.cmp(&self.seq))
+    }
+}
+
+/// 入力の処理とバックグラウンドの処理の順序を決める。
+/// 入力の処理中はバックグラウンドの編集を後回しにし、処理時間を集計する。
+#[derive(Default)]
+pub struct Scheduler {
+    /// 処理中の入力 (SendKey など) の数
+    interactive: AtomicUsize,
+    input_done: Notify,
+    queue: Mutex<BinaryHeap<Queued>>,
+    next_seq: AtomicU64,
+    queued: Notify,
+    /// run がキューを処理している
+    running: AtomicBool,
+    metrics: Mutex<BTreeMap<String, LatencyStats>>,
+}
+
+impl fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scheduler")
+            .field("interactive", &self.interactive.load(Ordering::SeqCst))
+            .field("queued", &self.queue().len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// 入力の処理中であることを表す。drop すると後回しにしていた処理を再開する。
+#[derive(Debug)]
+pub struct InteractiveGuard<'a> {
+    scheduler: &'a Scheduler,
+    kind: &'static str,
+    started: Instant,
+}
+
+impl Drop for InteractiveGuard<'_> {
+    fn drop(&mut self) {
+        self.scheduler.record(self.kind, self.started.elapsed());
+        if self.scheduler.interactive.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.scheduler.input_done.notify_waiters();
+        }
+    }
+}
+
+impl Scheduler {
+    fn queue(&self) -> std::sync::MutexGuard<'_, BinaryHeap<Queued>> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 入力の処理を始める。guard を drop するまでの時間を kind の処理時間として記録する。
+    pub fn interactive(&self, kind: &'static str) -> InteractiveGuard<'_> {
+        self.interactive.fetch_add(1, Ordering::SeqCst);
+        InteractiveGuard { scheduler: self, kind, started: Instant::now() }
+    }
+
+    pub fn is_interactive(&self) -> bool {
+        self.interactive.load(Ordering::SeqCst) > 0
+    }
+
+    /// 入力の処理中なら終わるまで待つ。バックグラウンドの処理が区切りごとに呼ぶ。
+    /// 入力が続いても BACKGROUND_MAX_DEFER より長くは待たない。
+    pub async fn yield_to_input(&self) {
+        let deadline = Instant::now() + BACKGROUND_MAX_DEFER;
+        loop {
+            let done = self.input_done.notified();
+            if !self.is_interactive() {
+                return;
+            }
+            if tokio::time::timeout_at(deadline, done).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// キューの先頭のバックグラウンドの編集を後回しにしている間、入力の処理が終わるか、新しい編集が
+    /// キューに入るまで待つ。新しい入力の編集は、処理中の入力が終わるのを待たずに先に適用する。
+    async fn wait_for_input(&self, deadline: Instant) {
+        let done = self.input_done.notified();
+        let queued = self.queued.notified();
+        if !self.is_interactive() {
+            return;
+        }
+        let _ = tokio::time::timeout_at(deadline, async {
+            tokio::select! {
+                _ = done => {}
+                _ = queued => {}
+            }
+        })
+        .await;
+    }
+
+    pub fn record(&self, kind: &str, latency: Duration) {
+        let mut metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+        metrics.entry(kind.to_string()).or_default().record(latency);
+    }
+
+    /// 種類ごとの処理時間の集計 (名前順)
+    pub fn metrics(&self) -> Vec<(String, LatencyStats)> {
+        let metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+        metrics.iter().map(|(kind, stats)| (kind.clone(), stats.clone())).collect()
+    }
+
+    pub fn reset_metrics(&self) {
+        self.metrics.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    fn enqueue(&self, edit: Edit) -> oneshot::Receiver<Result<u64, EditError>> {
+        let (reply, rx) = oneshot::channel();
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        self.queue().push(Queued { edit, seq, enqueued: Instant::now(), reply });
+        self.queued.notify_one();
+        rx
+    }
+
+    fn pop(&self) -> Option<Queued> {
+        self.queue().pop()
+    }
+}
+
+/// 編集をキューに入れ、適用されるまで待つ。適用後のバッファの版を返す。
+/// キューを処理する run が動いていなければ (テストなど) その場で適用する。
+pub async fn submit(state: &EditorState, edit: Edit) -> Result<u64, EditError> {
+    if !state.scheduler().running.load(Ordering::SeqCst) {
+        return apply(state, &edit).await;
+    }
+    let rx = state.scheduler().enqueue(edit);
+    rx.await.map_err(|_| EditError::Rejected("Edit queue stopped".into()))?
+}
+
+/// 編集を1つ適用する。書き込みロックは置き換えの間だけ取る。
+async fn apply(state: &EditorState, edit: &Edit) -> Result<u64, EditError> {
+    let buffer = state
+        .get_buffer(&edit.buffer_id)
+        .await
+        .ok_or_else(|| EditError::NoSuchBuffer(edit.buffer_id.clone()))?;
+    let mut buffer = buffer.write().await;
+    if let Some(base_version) = edit.base_version
+        && base_version != buffer.version()
+    {
+        return Err(EditError::Stale { version: buffer.version(), base_version });
+    }
+    if buffer.read_only {
+        return Err(EditError::Rejected("Buffer is read-only".into()));
+    }
+    let mut len = buffer.len_chars();
+    for (range, text) in &edit.changes {
+        if range.start > range.end || range.end > len {
+            return Err(EditError::Rejected(format!("Invalid range: {:?} (len: {})", range, len)));
+        }
+        len = len - range.len() + text.chars().count();
+    }
+    for (range, text) in &edit.changes {
+        match &edit.log {
+            Some(log) => log
+                .apply(&edit.buffer_id, &mut buffer, range.clone(), text)
+                .map_err(|e| match e {
+                    CommandError::Quit => EditError::Quit,
+                    e => EditError::Rejected(e.to_string()),
+                })?,
+            None => buffer.replace(range.clone(), text).map_err(EditError::Rejected)?,
+        }
+    }
+    Ok(buffer.version())
+}
+
+/// キューにある編集を優先度順に全て適用する。
+/// バックグラウンドの編集は、入力の処理中なら終わるまで (キューに入れてから BACKGROUND_MAX_DEFER まで) 待ってから適用する。
+pub async fn process_queue(state: &EditorState) -> usize {
+    let scheduler = state.scheduler();
+    let mut processed = 0;
+    while let Some(queued) = scheduler.pop() {
+        let deadline = queued.enqueued + BACKGROUND_MAX_DEFER;
+        if queued.edit.priority == Priority::Background && scheduler.is_interactive() && Instant::now() < deadline {
+            // 待っている間に入力の編集が入れば、キューから先に出てくるのでそちらを先に適用する
+            scheduler.queue().push(queued);
+            scheduler.wait_for_input(deadline).await;
+            continue;
+        }
+        processed += apply_queued(state, queued).await;
+    }
+    processed
+}
+
+async fn apply_queued(state: &EditorState, queued: Queued) -> usize {
+    let result = apply(state, &queued.edit).await;
+    let kind = format!("edit-{}", queued.edit.priority);
+    state.scheduler().record(&kind, queued.enqueued.elapsed());
+    let _ = queued.reply.send(result);
+    1
+}
+
+/// 編集キューを処理し続ける。コアの起動時に spawn する。
+pub async fn run(state: Arc<EditorState>) {
+    state.scheduler().running.store(true, Ordering::SeqCst);
+    loop {
+        let queued = state.scheduler().queued.notified();
+        process_queue(&state).await;
+        queued.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(buffer_id: &str, at: usize, text: &str, priority: Priority) -> Edit {
+        Edit { buffer_id: buffer_id.into(), changes: vec![(at..at, text.into())], base_version: None, priority, log: None }
+    }
+
+    #[tokio::test]
+    async fn test_interactive_edits_first() {
+        let state = Arc::new(EditorState::new());
+        let buffer_id = state.create_buffer("test".into(), "").await;
+        let background = state.scheduler().enqueue(edit(&buffer_id, 0, "b", Priority::Background));
+        let interactive = state.scheduler().enqueue(edit(&buffer_id, 0, "i", Priority::Interactive));
+        assert_eq!(process_queue(&state).await, 2);
+        assert_eq!(interactive.await.unwrap(), Ok(1));
+        assert_eq!(background.await.unwrap(), Ok(2));
+        assert_eq!(state.snapshot(&buffer_id).await.unwrap().to_string(), "bi");
+
+        let kinds: Vec<_> = state.scheduler().metrics().into_iter().map(|(kind, stats)| (kind, stats.count)).collect();
+        assert_eq!(kinds, [("edit-background".to_string(), 1), ("edit-interactive".to_string(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_background_waits_for_input() {
+        let state = Arc::new(EditorState::new());
+        let buffer_id = state.create_buffer("test".into(), "abc").await;
+        let snapshot = state.snapshot(&buffer_id).await.unwrap();
+
+        let guard = state.scheduler().interactive("key");
+        let mut stale = edit(&buffer_id, 0, "x", Priority::Background);
+        stale.base_version = Some(snapshot.version);
+        let reply = state.scheduler().enqueue(stale.clone());
+        let worker = {
+            let state = state.clone();
+            tokio::spawn(async move { process_queue(&state).await })
+        };
+        // 入力の処理中は適用しない
+        tokio::task::yield_now().await;
+        assert!(!worker.is_finished());
+        state.get_buffer(&buffer_id).await.unwrap().write().await.insert(3, "d").unwrap();
+        drop(guard);
+        worker.await.unwrap();
+        // 入力で版が進んだので、古いスナップショットから作った編集は捨てる
+        assert!(matches!(reply.await.unwrap(), Err(EditError::Stale { version: 1, base_version: 0 })));
+        assert_eq!(state.snapshot(&buffer_id).await.unwrap().to_string(), "abcd");
+        // スナップショットはその後の編集の影響を受けない
+        assert_eq!(snapshot.to_string(), "abc");
+        assert_eq!(state.scheduler().metrics()[1].0, "key");
+    }
+
+    #[tokio::test]
+    async fn test_input_edit_not_delayed_by_deferred_background() {
+        let state = Arc::new(EditorState::new());
+        let buffer_id = state.create_buffer("test".into(), "").await;
+        let worker = tokio::spawn(run(state.clone()));
+        while !state.scheduler().running.load(Ordering::SeqCst) {
+            tokio::task::yield_now().await;
+        }
+
+        // ApplyEdits のように、入力の処理中にその入力の編集を待つ
+        let guard = state.scheduler().interactive("edit-rpc");
+        let background = state.scheduler().enqueue(edit(&buffer_id, 0, "b", Priority::Background));
+        tokio::task::yield_now().await;
+        let started = Instant::now();
+        assert_eq!(submit(&state, edit(&buffer_id, 0, "i", Priority::Interactive)).await, Ok(1));
+        assert!(started.elapsed() < BACKGROUND_MAX_DEFER / 2);
+        drop(guard);
+        assert_eq!(background.await.unwrap(), Ok(2));
+        assert_eq!(state.snapshot(&buffer_id).await.unwrap().to_string(), "bi");
+        worker.abort();
+    }
+}
@@ -0,0 +1,887 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex, RwLock};
+use uuid::Uuid;
+
+use crate::buffer::{Buffer, BufferInfo, BufferSnapshot, Change, LineEnding};
+use crate::builtins;
+use crate::command::CommandRegistry;
+use crate::command_loop::CommandLoop;
+use crate::keymap::{describe_key, Key, KeyDescription, Keymaps, Lookup};
+use crate::kmacro::Kmacros;
+use crate::desktop::{DesktopConfig, RestoreReport};
+use crate::handshake::Clients;
+use crate::hook::{self, HookBus, HookEvent};
+use crate::layout::{Window, WindowLayout, WindowState};
+use crate::quit::RunningCommands;
+use crate::sched::Scheduler;
+use crate::timer::Timers;
+use crate::minibuffer::{MinibufferSession, MinibufferUpdate, HISTORY_LENGTH};
+use crate::mode::{self, MajorMode, MinorMode, Modes, Value};
+use crate::uniquify;
+
+/// レイアウト更新通知のバッファ数。遅れたUIは次のスナップショットで追いつく。
+const LAYOUT_CHANNEL_CAPACITY: usize = 64;
+/// ミニバッファ更新通知のバッファ数。入力のたびに送るのでレイアウトより多めにとる。
+const MINIBUFFER_CHANNEL_CAPACITY: usize = 256;
+/// 最後のバッファを削除したときに代わりに作るバッファ
+pub const SCRATCH_BUFFER: &str = "*scratch*";
+
+/// ファイルの更新時刻。ファイルが無いか、取れなければ None
+pub async fn file_mtime(path: &Path) -> Option<std::time::SystemTime> {
+    tokio::fs::metadata(path).await.and_then(|metadata| metadata.modified()).ok()
+}
+
+#[derive(Debug)]
+pub struct EditorState {
+    buffers: RwLock<HashMap<String, Arc<RwLock<Buffer>>>>,
+    /// 最近表示した順のバッファID (buffer-list)
+    buffer_order: Mutex<Vec<String>>,
+    windows: RwLock<HashMap<String, Window>>,
+    layout_tx: broadcast::Sender<WindowLayout>,
+    commands: RwLock<CommandRegistry>,
+    keymaps: RwLock<Keymaps>,
+    command_loops: Mutex<HashMap<String, CommandLoop>>,
+    kmacros: Mutex<Kmacros>,
+    /// バッファと両方のロックを取る場合は、Modes を先に取る
+    modes: RwLock<Modes>,
+    /// Windowごとのミニバッファ (再帰的に開いたものは後ろに積む)
+    minibuffers: Mutex<HashMap<String, Vec<MinibufferSession>>>,
+    minibuffer_tx: broadcast::Sender<MinibufferUpdate>,
+    /// 名前ごとのミニバッファ履歴 (新しいものが先頭)
+    histories: Mutex<HashMap<String, Vec<String>>>,
+    /// *Buffer List* バッファごとの、各行に表示しているバッファのID
+    buffer_menus: Mutex<HashMap<String, Vec<String>>>,
+    /// ファイルごとの最後の point (saveplace)。表示をやめたときやバッファを削除したときに記録する。
+    places: Mutex<HashMap<PathBuf, usize>>,
+    /// 復元待ちのWindowの分割状態と大きさ。次に作るWindowから順に使う。
+    pending_windows: Mutex<Vec<(WindowState, u16, u16)>>,
+    /// desktop-save-mode の設定。None なら保存しない。
+    desktop: Mutex<Option<DesktopConfig>>,
+    /// 起動時にセッションから復元した結果。復元していなければ None
+    restored: Mutex<Option<RestoreReport>>,
+    hooks: HookBus,
+    timers: Timers,
+    running: RunningCommands,
+    scheduler: Scheduler,
+    clients: Arc<Clients>,
+}
+
+impl Default for EditorState {
+    fn default() -> Self {
+        let (layout_tx, _) = broadcast::channel(LAYOUT_CHANNEL_CAPACITY);
+        let (minibuffer_tx, _) = broadcast::channel(MINIBUFFER_CHANNEL_CAPACITY);
+        let modes = Modes::default();
+        let mut commands = builtins::registry();
+        mode::register_commands(&mut commands, &modes).expect("builtin mode commands must be valid");
+        Self {
+            buffers: RwLock::default(),
+            buffer_order: Mutex::default(),
+            windows: RwLock::default(),
+            layout_tx,
+            commands: RwLock::new(commands),
+            keymaps: RwLock::default(),
+            command_loops: Mutex::default(),
+            kmacros: Mutex::default(),
+            modes: RwLock::new(modes),
+            minibuffers: Mutex::default(),
+            minibuffer_tx,
+            histories: Mutex::default(),
+            buffer_menus: Mutex::default(),
+            places: Mutex::default(),
+            pending_windows: Mutex::default(),
+            desktop: Mutex::default(),
+            restored: Mutex::default(),
+            hooks: HookBus::default(),
+            timers: Timers::default(),
+            running: RunningCommands::default(),
+            scheduler: Scheduler::default(),
+            clients: Arc::default(),
+        }
+    }
+}
+
+impl EditorState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// バッファの現在の内容のスナップショット。ロックはスナップショットを作る間だけ取る。
+    pub async fn snapshot(&self, buffer_id: &str) -> Option<BufferSnapshot> {
+        let buffer = self.get_buffer(buffer_id).await?;
+        let snapshot = buffer.read().await.snapshot();
+        Some(snapshot)
+    }
+
+    /// バッファを作成し、そのIDを返す。名前が使用中なら番号を付ける ("name<2>")。
+    pub async fn create_buffer(&self, name: String, text: &str) -> String {
+        let modes = self.modes.read().await;
+        let id = Uuid::new_v4().to_string();
+
+        // 同時に作ったバッファと名前が重ならないよう、名前を選んでから加えるまで書き込みロックを持つ
+        let mut buffers = self.buffers.write().await;
+        let name = unused_name(&name, &buffer_names(&buffers, None).await);
+        let mut buffer = Buffer::new(name, text);
+        modes.set_auto_mode(&mut buffer);
+        buffers.insert(id.clone(), Arc::new(RwLock::new(buffer)));
+        drop(buffers);
+        drop(modes);
+        self.buffer_order.lock().await.push(id.clone());
+
+        id
+    }
+
+    pub async fn get_buffer(&self, id: &str) -> Option<Arc<RwLock<Buffer>>> {
+        let buffers = self.buffers.read().await;
+        buffers.get(id).cloned()
+    }
+
+    /// 全バッファをIDと組にして返す
+    pub async fn list_buffers(&self) -> Vec<(String, Arc<RwLock<Buffer>>)> {
+        let buffers = self.buffers.read().await;
+        buffers.iter().map(|(id, buffer)| (id.clone(), buffer.clone())).collect()
+    }
+
+    /// バッファの一覧 (最近表示した順)。名前が空白で始まる内部用のバッファは含めない。
+    pub async fn buffer_list(&self) -> Vec<BufferInfo> {
+        let order = self.buffer_order.lock().await.clone();
+        let mut infos = Vec::with_capacity(order.len());
+        for id in order {
+            if let Some(buffer) = self.get_buffer(&id).await {
+                let info = buffer.read().await.info(&id);
+                if !info.name.starts_with(' ') {
+                    infos.push(info);
+                }
+            }
+        }
+        infos
+    }
+
+    /// 名前でバッファを探す (get-buffer)
+    pub async fn find_buffer(&self, name: &str) -> Option<String> {
+        for (id, buffer) in self.list_buffers().await {
+            if buffer.read().await.name == name {
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    /// ファイルを表示しているバッファを探す (find-buffer-visiting)
+    pub async fn find_file_buffer(&self, path: &Path) -> Option<String> {
+        let path = canonical_path(path);
+        for (id, buffer) in self.list_buffers().await {
+            if buffer.read().await.path.as_deref().is_some_and(|p| canonical_path(p) == path) {
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    /// 使用中の名前と重ならないバッファ名 (generate-new-buffer-name)
+    pub async fn generate_new_buffer_name(&self, name: &str) -> String {
+        unused_name(name, &buffer_names(&*self.buffers.read().await, None).await)
+    }
+
+    /// バッファ名を変える。使用中の名前なら、unique のときは番号を付け、そうでなければエラーにする。付けた名前を返す。
+    pub async fn rename_buffer(&self, buffer_id: &str, name: &str, unique: bool) -> Result<String, String> {
+        if name.is_empty() {
+            return Err("Empty string is invalid as a buffer name".into());
+        }
+        // 名前を選んでから付けるまで書き込みロックを持ち、同時に作ったバッファと重ならないようにする
+        let buffers = self.buffers.write().await;
+        let buffer = buffers.get(buffer_id).ok_or_else(|| format!("No such buffer: {}", buffer_id))?;
+        let taken = buffer_names(&buffers, Some(buffer_id)).await;
+        if taken.contains(name) && !unique {
+            return Err(format!("Buffer name `{}' is in use", name));
+        }
+        let name = unused_name(name, &taken);
+        buffer.write().await.name = name.clone();
+        Ok(name)
+    }
+
+    /// ファイルを開く (find-file-noselect)。既に開いていればそのバッファを返す。
+    /// 新しく開いたら find-file-hook を実行する。
+    pub async fn find_file(self: &Arc<Self>, path: &Path) -> Result<String, String> {
+        if let Some(id) = self.find_file_buffer(path).await {
+            return Ok(id);
+        }
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let name = uniquify::file_name(path).unwrap_or("untitled").to_string();
+        let id = self.create_buffer(name, &text).await;
+        self.set_buffer_file(&id, path.to_path_buf()).await?;
+        if let Some(buffer) = self.get_buffer(&id).await {
+            let mut buffer = buffer.write().await;
+            if text.contains("\r\n") {
+                buffer.line_ending = LineEnding::CrLf;
+            }
+            buffer.modified = false;
+            buffer.file_mtime = file_mtime(path).await;
+            buffer.take_changes();
+        }
+        hook::run(self, HookEvent::FindFile { buffer_id: id.clone() }).await;
+        Ok(id)
+    }
+
+    /// バッファをファイルへ保存する (save-buffer)。前後に before-save-hook と after-save-hook を実行する。
+    pub async fn save_buffer(self: &Arc<Self>, buffer_id: &str) -> Result<PathBuf, String> {
+        let buffer = self.buffer_or_err(buffer_id).await?;
+        if buffer.read().await.path.is_none() {
+            return Err("Buffer is not visiting a file".into());
+        }
+        hook::run(self, HookEvent::BeforeSave { buffer_id: buffer_id.to_string() }).await;
+        hook::run_after_change(self).await;
+        // 書き出しの間も編集を止めないよう、スナップショットから書く
+        let snapshot = buffer.read().await.snapshot();
+        let path = snapshot.path.clone().ok_or("Buffer is not visiting a file")?;
+        let text = snapshot.to_string();
+        tokio::fs::write(&path, text)
+            .await
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        {
+            // 書き出している間に編集されていれば未保存のままにする
+            let mut buffer = buffer.write().await;
+            if buffer.version() == snapshot.version {
+                buffer.modified = false;
+            }
+            buffer.file_mtime = file_mtime(&path).await;
+        }
+        hook::run(self, HookEvent::AfterSave { buffer_id: buffer_id.to_string() }).await;
+        Ok(path)
+    }
+
+    /// バッファにファイルを関連付ける。名前とメジャーモードをファイルに合わせ、
+    /// 同じファイル名のバッファとはディレクトリ名で区別できるようにする。
+    pub async fn set_buffer_file(&self, buffer_id: &str, path: PathBuf) -> Result<(), String> {
+        let buffer = self.buffer_or_err(buffer_id).await?;
+        {
+            let modes = self.modes.read().await;
+            let mut buffer = buffer.write().await;
+            buffer.set_path(path);
+            modes.set_auto_mode(&mut buffer);
+        }
+        self.uniquify_buffer_names().await;
+        Ok(())
+    }
+
+    /// ファイルを表示しているバッファの名前を付け直す (uniquify)。
+    /// rename-buffer で名前を変えたバッファはそのままにする。
+    async fn uniquify_buffer_names(&self) {
+        let buffers: HashMap<_, _> = self.list_buffers().await.into_iter().collect();
+        let mut files = Vec::new();
+        let mut taken = HashSet::new();
+        for (id, buffer) in &buffers {
+            let buffer = buffer.read().await;
+            match buffer.path.as_deref() {
+                Some(path) if uniquify::file_name(path).is_some_and(|base| uniquify::is_uniquified_name(&buffer.name, base)) => {
+                    files.push((id.clone(), path.to_path_buf()));
+                }
+                _ => {
+                    taken.insert(buffer.name.clone());
+                }
+            }
+        }
+        let mut names: Vec<_> = uniquify::uniquify_names(&files).into_iter().collect();
+        names.sort_by(|(a_id, a), (b_id, b)| (a, a_id).cmp(&(b, b_id)));
+        for (id, name) in names {
+            let name = unused_name(&name, &taken);
+            taken.insert(name.clone());
+            buffers[&id].write().await.name = name;
+        }
+    }
+
+    /// バッファを最近表示したものとして一覧の先頭へ移す
+    async fn record_buffer(&self, buffer_id: &str) {
+        let mut order = self.buffer_order.lock().await;
+        order.retain(|id| id != buffer_id);
+        order.insert(0, buffer_id.to_string());
+    }
+
+    /// バッファを一覧の末尾へ移し、other_buffer で選ばれにくくする (bury-buffer)
+    pub async fn bury_buffer(&self, buffer_id: &str) {
+        let mut order = self.buffer_order.lock().await;
+        if let Some(i) = order.iter().position(|id| id == buffer_id) {
+            let id = order.remove(i);
+            order.push(id);
+        }
+    }
+
+    /// 選択中のViewにバッファを表示する (switch-to-buffer)
+    pub async fn switch_to_buffer(&self, window_id: &str, buffer_id: &str) -> Result<(), String> {
+        self.buffer_or_err(buffer_id).await?;
+        let point = self.saved_place(buffer_id).await;
+        let previous = self
+            .update_window(window_id, |w| {
+                let view = w.selected_view_mut();
+                if view.buffer_id == buffer_id {
+                    return Ok(None);
+                }
+                let previous = (view.buffer_id.clone(), view.point);
+                view.set_buffer(buffer_id.to_string());
+                view.point = point;
+                Ok(Some(previous))
+            })
+            .await?;
+        if let Some((previous, point)) = previous {
+            self.save_place(&previous, point).await;
+        }
+        self.record_buffer(buffer_id).await;
+        Ok(())
+    }
+
+    /// ファイルのバッファの point を記録する
+    async fn save_place(&self, buffer_id: &str, point: usize) {
+        let Some(buffer) = self.get_buffer(buffer_id).await else {
+            return;
+        };
+        if let Some(path) = buffer.read().await.path.clone() {
+            self.places.lock().await.insert(path, point);
+        }
+    }
+
+    /// バッファを表示するときの最初の point。記録が無ければ先頭。
+    pub async fn saved_place(&self, buffer_id: &str) -> usize {
+        let Some(buffer) = self.get_buffer(buffer_id).await else {
+            return 0;
+        };
+        let buffer = buffer.read().await;
+        let place = match &buffer.path {
+            Some(path) => self.places.lock().await.get(path).copied(),
+            None => None,
+        };
+        place.unwrap_or(0).min(buffer.len_chars())
+    }
+
+    /// 記録済みのファイルごとの point
+    pub async fn places(&self) -> HashMap<PathBuf, usize> {
+        self.places.lock().await.clone()
+    }
+
+    pub async fn set_place(&self, path: PathBuf, point: usize) {
+        self.places.lock().await.insert(path, point);
+    }
+
+    /// except 以外で最近表示したバッファ (other-buffer)
+    pub async fn other_buffer(&self, except: &str) -> Option<String> {
+        self.buffer_list().await.into_iter().map(|info| info.id).find(|id| id != except)
+    }
+
+    /// バッファを削除する。表示していたViewには代わりに other_buffer を表示する。
+    /// 未保存の変更の確認は呼び出し側 (kill-buffer コマンド) で行う。削除の前に kill-buffer-hook を実行する。
+    pub async fn kill_buffer(self: &Arc<Self>, buffer_id: &str) -> Result<(), String> {
+        self.buffer_or_err(buffer_id).await?;
+        hook::run(self, HookEvent::KillBuffer { buffer_id: buffer_id.to_string() }).await;
+        let killed = self
+            .buffers
+            .write()
+            .await
+            .remove(buffer_id)
+            .ok_or_else(|| format!("No such buffer: {}", buffer_id))?;
+        let path = killed.read().await.path.clone();
+        self.buffer_order.lock().await.retain(|id| id != buffer_id);
+        self.keymaps.write().await.local.remove(buffer_id);
+        self.buffer_menus.lock().await.remove(buffer_id);
+
+        let replacement = match self.other_buffer(buffer_id).await {
+            Some(id) => id,
+            None => self.create_buffer(SCRATCH_BUFFER.into(), "").await,
+        };
+        let mut windows = self.windows.write().await;
+        for window in windows.values_mut() {
+            let mut changed = false;
+            for view_id in window.view_ids() {
+                if let Some(view) = window.view_mut(&view_id)
+                    && view.buffer_id == buffer_id
+                {
+                    if let Some(path) = &path {
+                        self.places.lock().await.insert(path.clone(), view.point);
+                    }
+                    view.set_buffer(replacement.clone());
+                    changed = true;
+                }
+            }
+            if changed {
+                let _ = self.layout_tx.send(window.snapshot());
+            }
+        }
+        drop(windows);
+
+        self.uniquify_buffer_names().await;
+        Ok(())
+    }
+
+    /// 指定バッファを表示するWindowを作成し、そのIDを返す
+    pub async fn create_window(&self, buffer_id: &str) -> Result<String, String> {
+        if self.get_buffer(buffer_id).await.is_none() {
+            return Err(format!("No such buffer: {}", buffer_id));
+        }
+        let window = match self.pending_windows.lock().await.pop() {
+            Some((mut saved, width, height)) => {
+                // 復元した分割状態のうち、もう無いバッファは指定のバッファで置き換える
+                for view in saved.views_mut() {
+                    if self.get_buffer(&view.buffer_id).await.is_none() {
+                        view.buffer_id = buffer_id.to_string();
+                    }
+                }
+                Window::from_state(&saved, width, height)
+            }
+            None => {
+                let mut window = Window::new(buffer_id.to_string());
+                window.selected_view_mut().point = self.saved_place(buffer_id).await;
+                window
+            }
+        };
+        let id = window.id.clone();
+        let snapshot = window.snapshot();
+        self.windows.write().await.insert(id.clone(), window);
+        let _ = self.layout_tx.send(snapshot);
+        self.record_buffer(buffer_id).await;
+        Ok(id)
+    }
+
+    /// 全Windowの分割状態と大きさ
+    pub async fn window_states(&self) -> Vec<(WindowState, u16, u16)> {
+        let windows = self.windows.read().await;
+        windows
+            .values()
+            .map(|w| {
+                let (width, height) = w.size();
+                (w.state(), width, height)
+            })
+            .collect()
+    }
+
+    /// 次に作るWindowから順に、保存した分割状態で作るようにする
+    pub async fn restore_windows(&self, mut states: Vec<(WindowState, u16, u16)>) {
+        states.reverse();
+        *self.pending_windows.lock().await = states;
+    }
+
+    pub async fn close_window(&self, window_id: &str) -> Result<(), String> {
+        self.command_loops.lock().await.remove(window_id);
+        self.minibuffers.lock().await.remove(window_id);
+        self.windows
+            .write()
+            .await
+            .remove(window_id)
+            .map(|_| ())
+            .ok_or_else(|| format!("No such window: {}", window_id))
+    }
+
+    pub async fn window_layout(&self, window_id: &str) -> Option<WindowLayout> {
+        self.windows.read().await.get(window_id).map(Window::snapshot)
+    }
+
+    pub async fn window_ids(&self) -> Vec<String> {
+        let mut ids: Vec<_> = self.windows.read().await.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    /// Windowを参照する (変更は配信しない)
+    pub async fn read_window<F, R>(&self, window_id: &str, f: F) -> Result<R, String>
+    where
+        F: FnOnce(&Window) -> R,
+    {
+        let windows = self.windows.read().await;
+        let window = windows
+            .get(window_id)
+            .ok_or_else(|| format!("No such window: {}", window_id))?;
+        Ok(f(window))
+    }
+
+    /// Windowを操作し、変更後のレイアウトを購読中のUIへ配信する
+    pub async fn update_window<F, R>(&self, window_id: &str, f: F) -> Result<R, String>
+    where
+        F: FnOnce(&mut Window) -> Result<R, String>,
+    {
+        let mut windows = self.windows.write().await;
+        let window = windows
+            .get_mut(window_id)
+            .ok_or_else(|| format!("No such window: {}", window_id))?;
+        let result = f(window)?;
+        // 購読者がいない場合の送信エラーは無視する
+        let _ = self.layout_tx.send(window.snapshot());
+        Ok(result)
+    }
+
+    pub fn subscribe_layout(&self) -> broadcast::Receiver<WindowLayout> {
+        self.layout_tx.subscribe()
+    }
+
+    pub fn hooks(&self) -> &HookBus {
+        &self.hooks
+    }
+
+    pub fn timers(&self) -> &Timers {
+        &self.timers
+    }
+
+    /// Windowごとの実行中のコマンド (C-g で中断する)
+    pub fn running(&self) -> &RunningCommands {
+        &self.running
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    /// ハンドシェイクを済ませて接続中のクライアント
+    pub fn clients(&self) -> &Arc<Clients> {
+        &self.clients
+    }
+
+    pub async fn buffer_ids(&self) -> Vec<String> {
+        self.buffers.read().await.keys().cloned().collect()
+    }
+
+    /// 全てのバッファから after-change-functions に渡していない変更を取り出す
+    pub async fn take_changes(&self) -> Vec<(String, Change)> {
+        let buffers: Vec<_> = self.buffers.read().await.iter().map(|(id, b)| (id.clone(), b.clone())).collect();
+        let mut changes = Vec::new();
+        for (id, buffer) in buffers {
+            changes.extend(buffer.write().await.take_changes().into_iter().map(|c| (id.clone(), c)));
+        }
+        changes
+    }
+
+    pub fn commands(&self) -> &RwLock<CommandRegistry> {
+        &self.commands
+    }
+
+    pub fn keymaps(&self) -> &RwLock<Keymaps> {
+        &self.keymaps
+    }
+
+    pub fn command_loops(&self) -> &Mutex<HashMap<String, CommandLoop>> {
+        &self.command_loops
+    }
+
+    pub fn kmacros(&self) -> &Mutex<Kmacros> {
+        &self.kmacros
+    }
+
+    pub fn buffer_menus(&self) -> &Mutex<HashMap<String, Vec<String>>> {
+        &self.buffer_menus
+    }
+
+    pub fn minibuffers(&self) -> &Mutex<HashMap<String, Vec<MinibufferSession>>> {
+        &self.minibuffers
+    }
+
+    pub fn subscribe_minibuffer(&self) -> broadcast::Receiver<MinibufferUpdate> {
+        self.minibuffer_tx.subscribe()
+    }
+
+    pub fn notify_minibuffer(&self, update: MinibufferUpdate) {
+        // 購読者がいない場合の送信エラーは無視する
+        let _ = self.minibuffer_tx.send(update);
+    }
+
+    /// 全ての履歴リスト (savehist)
+    pub async fn histories(&self) -> Vec<(String, Vec<String>)> {
+        let histories = self.histories.lock().await;
+        let mut histories: Vec<_> = histories.iter().map(|(name, items)| (name.clone(), items.clone())).collect();
+        histories.sort();
+        histories
+    }
+
+    pub async fn set_history(&self, name: &str, mut items: Vec<String>) {
+        items.truncate(HISTORY_LENGTH);
+        self.histories.lock().await.insert(name.to_string(), items);
+    }
+
+    pub fn desktop(&self) -> &Mutex<Option<DesktopConfig>> {
+        &self.desktop
+    }
+
+    pub fn restored(&self) -> &Mutex<Option<RestoreReport>> {
+        &self.restored
+    }
+
+    pub async fn history(&self, name: &str) -> Vec<String> {
+        self.histories.lock().await.get(name).cloned().unwrap_or_default()
+    }
+
+    /// 履歴の先頭に追加する。同じ入力が既にあれば先頭へ移す。
+    pub async fn add_history(&self, name: &str, value: &str) {
+        let mut histories = self.histories.lock().await;
+        let history = histories.entry(name.to_string()).or_default();
+        history.retain(|item| item != value);
+        history.insert(0, value.to_string());
+        history.truncate(HISTORY_LENGTH);
+    }
+
+    pub fn modes(&self) -> &RwLock<Modes> {
+        &self.modes
+    }
+
+    async fn buffer_or_err(&self, buffer_id: &str) -> Result<Arc<RwLock<Buffer>>, String> {
+        self.get_buffer(buffer_id)
+            .await
+            .ok_or_else(|| format!("No such buffer: {}", buffer_id))
+    }
+
+    /// メジャーモードを定義し、同名のコマンドで切り替えられるようにする
+    pub async fn define_major_mode(&self, mode: MajorMode) -> Result<(), String> {
+        mode::register_major_mode_command(&mut *self.commands.write().await, &mode)?;
+        self.modes.write().await.define_major_mode(mode);
+        Ok(())
+    }
+
+    pub async fn define_minor_mode(&self, mode: MinorMode) -> Result<(), String> {
+        mode::register_minor_mode_command(&mut *self.commands.write().await, &mode)?;
+        self.modes.write().await.define_minor_mode(mode);
+        Ok(())
+    }
+
+    pub async fn set_major_mode(&self, buffer_id: &str, mode: &str) -> Result<(), String> {
+        let buffer = self.buffer_or_err(buffer_id).await?;
+        let modes = self.modes.read().await;
+        modes.set_major_mode(&mut *buffer.write().await, mode)
+    }
+
+    /// マイナーモードを切り替える。enable が None なら現在の状態を反転する。変更後の状態を返す。
+    pub async fn set_minor_mode(&self, buffer_id: &str, mode: &str, enable: Option<bool>) -> Result<bool, String> {
+        let buffer = self.buffer_or_err(buffer_id).await?;
+        let modes = self.modes.read().await;
+        let mut buffer = buffer.write().await;
+        let enable = enable.unwrap_or(!buffer.minor_mode_enabled(mode));
+        modes.set_minor_mode(&mut buffer, mode, enable)?;
+        Ok(enable)
+    }
+
+    /// 変数の値 (バッファローカルな値、無ければ既定値)
+    pub async fn variable(&self, buffer_id: &str, name: &str) -> Option<Value> {
+        let buffer = self.get_buffer(buffer_id).await?;
+        let modes = self.modes.read().await;
+        modes.variable(&*buffer.read().await, name)
+    }
+
+    /// バッファのメジャーモードと有効なマイナーモード
+    async fn buffer_modes(&self, buffer_id: &str) -> (Option<String>, Vec<String>) {
+        match self.get_buffer(buffer_id).await {
+            Some(buffer) => {
+                let buffer = buffer.read().await;
+                (Some(buffer.major_mode.clone()), buffer.minor_modes.iter().cloned().collect())
+            }
+            None => (None, Vec::new()),
+        }
+    }
+
+    /// ミニバッファ内でのキー列の検索。minibuffer-local-map を最優先にする。
+    pub async fn lookup_minibuffer_key(&self, buffer_id: &str, keys: &[Key]) -> Lookup {
+        let found = self.keymaps.read().await.minibuffer.lookup(keys);
+        match found {
+            Lookup::Undefined => self.lookup_key(buffer_id, keys).await,
+            found => found,
+        }
+    }
+
+    /// バッファで有効なキーマップからキー列を検索する
+    pub async fn lookup_key(&self, buffer_id: &str, keys: &[Key]) -> Lookup {
+        let (major, minor) = self.buffer_modes(buffer_id).await;
+        let keymaps = self.keymaps.read().await;
+        keymaps.active(buffer_id, major.as_deref(), &minor).lookup(keys).0
+    }
+
+    pub async fn describe_key(&self, buffer_id: &str, keys: &[Key]) -> KeyDescription {
+        let (major, minor) = self.buffer_modes(buffer_id).await;
+        let keymaps = self.keymaps.read().await;
+        describe_key(&keymaps.active(buffer_id, major.as_deref(), &minor), keys)
+    }
+}
+
+/// taken と重ならない名前。使用中なら "<2>", "<3>"... を付ける。
+/// except 以外のバッファの名前
+async fn buffer_names(buffers: &HashMap<String, Arc<RwLock<Buffer>>>, except: Option<&str>) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for (id, buffer) in buffers {
+        if except != Some(id.as_str()) {
+            names.insert(buffer.read().await.name.clone());
+        }
+    }
+    names
+}
+
+fn unused_name(name: &str, taken: &HashSet<String>) -> String {
+    if !taken.contains(name) {
+        return name.to_string();
+    }
+    (2..)
+        .map(|n| format!("{}<{}>", name, n))
+        .find(|candidate| !taken.contains(candidate))
+        .expect("some numbered name must be free")
+}
+
+/// 比較用のパス。実在すればシンボリックリンクなどを解決する。
+fn canonical_path(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::SplitDirection;
+
+    #[tokio::test]
+    async fn test_editor_state() {
+        let state = EditorState::new();
+        let id = state.create_buffer("test.txt".into(), "Content").await;
+
+        let buf_arc = state.get_buffer(&id).await.expect("Buffer should exist");
+        let buf = buf_arc.read().await;
+        assert_eq!(buf.name, "test.txt");
+        assert_eq!(buf.to_string(), "Content");
+    }
+
+    #[tokio::test]
+    async fn test_mode_keymaps_follow_buffer_modes() {
+        let state = EditorState::new();
+        let buffer_id = state.create_buffer("main.rs".into(), "").await;
+        assert_eq!(state.variable(&buffer_id, "comment-start").await, Some(Value::String("// ".into())));
+
+        {
+            let mut keymaps = state.keymaps().write().await;
+            let mut rust_map = crate::keymap::Keymap::new();
+            rust_map.bind("C-c C-c", "rust-compile").unwrap();
+            keymaps.major.insert("rust-mode".into(), rust_map);
+            let mut overwrite_map = crate::keymap::Keymap::new();
+            overwrite_map.bind("C-c C-c", "overwrite-compile").unwrap();
+            keymaps.minor.insert("overwrite-mode".into(), overwrite_map);
+        }
+        let keys = crate::keymap::parse_kbd("C-c C-c").unwrap();
+        assert_eq!(state.lookup_key(&buffer_id, &keys).await, Lookup::Command("rust-compile".into()));
+
+        // マイナーモードのキーマップはメジャーモードより優先する
+        assert!(state.set_minor_mode(&buffer_id, "overwrite-mode", None).await.unwrap());
+        assert_eq!(state.lookup_key(&buffer_id, &keys).await, Lookup::Command("overwrite-compile".into()));
+        assert!(!state.set_minor_mode(&buffer_id, "overwrite-mode", None).await.unwrap());
+
+        state.set_major_mode(&buffer_id, "text-mode").await.unwrap();
+        assert_eq!(state.lookup_key(&buffer_id, &keys).await, Lookup::Undefined);
+    }
+
+    #[tokio::test]
+    async fn test_define_major_mode_registers_command() {
+        let state = EditorState::new();
+        let lisp_mode = MajorMode::new("lisp-mode", "Major mode for editing Lisp code.")
+            .extensions(&["lisp"])
+            .variable("comment-start", Value::String(";; ".into()));
+        state.define_major_mode(lisp_mode).await.unwrap();
+        assert!(state.commands().read().await.get("lisp-mode").is_some());
+        let buffer_id = state.create_buffer("init.lisp".into(), "").await;
+        let buffer = state.get_buffer(&buffer_id).await.unwrap();
+        assert_eq!(buffer.read().await.major_mode, "lisp-mode");
+    }
+
+    #[tokio::test]
+    async fn test_window_updates_are_broadcast() {
+        let state = EditorState::new();
+        let buffer_id = state.create_buffer("test.txt".into(), "").await;
+        assert!(state.create_window("missing").await.is_err());
+
+        let window_id = state.create_window(&buffer_id).await.unwrap();
+        let mut rx = state.subscribe_layout();
+
+        let new_view = state
+            .update_window(&window_id, |w| {
+                let selected = w.selected_view().id.clone();
+                w.split_view(&selected, SplitDirection::Right)
+            })
+            .await
+            .unwrap();
+
+        let update = rx.recv().await.unwrap();
+        assert_eq!(update.window_id, window_id);
+        assert_eq!(update.views.len(), 2);
+        assert_eq!(update.views[1].view.id, new_view);
+
+        // 失敗した操作は配信しない
+        assert!(state.update_window(&window_id, |w| w.delete_view("missing")).await.is_err());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_buffer_names_and_lookup() {
+        let state = EditorState::new();
+        let first = state.create_buffer("notes".into(), "").await;
+        let second = state.create_buffer("notes".into(), "").await;
+        assert_eq!(state.get_buffer(&second).await.unwrap().read().await.name, "notes<2>");
+        assert_eq!(state.find_buffer("notes<2>").await, Some(second.clone()));
+
+        assert!(state.rename_buffer(&second, "notes", false).await.is_err());
+        assert_eq!(state.rename_buffer(&second, "notes", true).await.unwrap(), "notes<2>");
+        assert_eq!(state.rename_buffer(&first, "todo", false).await.unwrap(), "todo");
+        assert_eq!(state.find_buffer("notes").await, None);
+
+        state.set_buffer_file(&first, PathBuf::from("/nonexistent/todo.txt")).await.unwrap();
+        assert_eq!(state.find_file_buffer(Path::new("/nonexistent/todo.txt")).await, Some(first));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_buffers_get_unique_names() {
+        let state = Arc::new(EditorState::new());
+        let tasks: Vec<_> = (0..16)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move { state.create_buffer("notes".into(), "").await })
+            })
+            .collect();
+        let mut names = HashSet::new();
+        for task in tasks {
+            let id = task.await.unwrap();
+            names.insert(state.get_buffer(&id).await.unwrap().read().await.name.clone());
+        }
+        assert_eq!(names.len(), 16);
+        assert!(names.contains("notes") && names.contains("notes<16>"));
+    }
+
+    #[tokio::test]
+    async fn test_uniquify_file_buffers() {
+        let state = Arc::new(EditorState::new());
+        let a = state.create_buffer("a".into(), "").await;
+        let b = state.create_buffer("b".into(), "").await;
+        let name = |id: String| {
+            let state = &state;
+            async move { state.get_buffer(&id).await.unwrap().read().await.name.clone() }
+        };
+
+        state.set_buffer_file(&a, PathBuf::from("/p/src/foo.rs")).await.unwrap();
+        assert_eq!(name(a.clone()).await, "foo.rs");
+        state.set_buffer_file(&b, PathBuf::from("/p/tests/foo.rs")).await.unwrap();
+        assert_eq!(name(a.clone()).await, "foo.rs<src>");
+        assert_eq!(name(b.clone()).await, "foo.rs<tests>");
+
+        // 片方を削除すると元の名前に戻る
+        state.kill_buffer(&b).await.unwrap();
+        assert_eq!(name(a).await, "foo.rs");
+    }
+
+    #[tokio::test]
+    async fn test_kill_buffer_replaces_views() {
+        let state = Arc::new(EditorState::new());
+        let first = state.create_buffer("first".into(), "").await;
+        let second = state.create_buffer("second".into(), "").await;
+        let window_id = state.create_window(&first).await.unwrap();
+        state.switch_to_buffer(&window_id, &second).await.unwrap();
+        state.update_window(&window_id, |w| w.split_view(&w.selected_view().id.clone(), SplitDirection::Below)).await.unwrap();
+
+        let names: Vec<_> = state.buffer_list().await.into_iter().map(|info| info.name).collect();
+        assert_eq!(names, ["second", "first"]);
+
+        state.kill_buffer(&second).await.unwrap();
+        let shown = state.read_window(&window_id, |w| w.view_ids().len()).await.unwrap();
+        assert_eq!(shown, 2);
+        let layout = state.window_layout(&window_id).await.unwrap();
+        assert!(layout.views.iter().all(|v| v.view.buffer_id == first));
+
+        // 最後のバッファを削除すると *scratch* を表示する
+        state.kill_buffer(&first).await.unwrap();
+        let buffer_id = state.read_window(&window_id, |w| w.selected_view().buffer_id.clone()).await.unwrap();
+        assert_eq!(state.find_buffer(SCRATCH_BUFFER).await, Some(buffer_id));
+    }
+}
@@ -0,0 +1,264 @@
+use std::sync::{Arc, Weak};
+
+use tokio::sync::{watch, RwLock};
+
+use crate::buffer::{Buffer, BufferSnapshot, Delta};
+use crate::state::EditorState;
+
+/// 購読する行の範囲
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Viewport {
+    pub start_line: usize,
+    pub count: usize,
+}
+
+/// 購読者に送る更新
+#[derive(Debug, Clone)]
+pub enum Update {
+    /// バッファ全体。購読の開始時と、差分を送れないほど遅れた時に送る。
+    Snapshot(BufferSnapshot),
+    /// 表示範囲の行。表示範囲を購読している場合は、変更のたびにこれを送り直す。
+    Lines {
+        version: u64,
+        start_line: usize,
+        lines: Vec<String>,
+        total_lines: usize,
+    },
+    /// 前回の更新からの差分 (古い順)。最後の差分の version が更新後の版。
+    Deltas(Vec<Delta>),
+}
+
+impl Update {
+    /// 更新を適用した後の版
+    pub fn version(&self) -> Option<u64> {
+        match self {
+            Update::Snapshot(snapshot) => Some(snapshot.version),
+            Update::Lines { version, .. } => Some(*version),
+            Update::Deltas(deltas) => deltas.last().map(|d| d.version),
+        }
+    }
+}
+
+/// 購読できるテキスト。版ごとの差分を保持している
+pub trait Versioned {
+    fn version(&self) -> u64;
+    fn snapshot(&self) -> BufferSnapshot;
+    /// version より後の差分 (古い順)。保持している差分で足りなければ None
+    fn deltas_since(&self, version: u64) -> Option<Vec<Delta>>;
+}
+
+impl Versioned for Buffer {
+    fn version(&self) -> u64 {
+        Buffer::version(self)
+    }
+
+    fn snapshot(&self) -> BufferSnapshot {
+        Buffer::snapshot(self)
+    }
+
+    fn deltas_since(&self, version: u64) -> Option<Vec<Delta>> {
+        Buffer::deltas_since(self, version)
+    }
+}
+
+/// 購読者に何を送ったか。最初は全体か表示範囲を送り、以降は送った版からの差分を送る。
+/// 差分が残っていなければスナップショットから送り直す。
+#[derive(Debug, Clone)]
+pub struct Cursor {
+    viewport: Option<Viewport>,
+    /// 購読者が持っている版。None ならまだ何も送っていない。
+    sent: Option<u64>,
+    started: bool,
+}
+
+impl Cursor {
+    /// resume_from を指定すると、その版より後の差分から送る (再接続時)
+    pub fn new(resume_from: Option<u64>, viewport: Option<Viewport>) -> Self {
+        Self { viewport, sent: resume_from, started: false }
+    }
+
+    /// 前回送ってから変わった分。変わっていなければ None
+    pub fn update(&mut self, text: &impl Versioned) -> Option<Update> {
+        let version = text.version();
+        if self.started && self.sent == Some(version) {
+            return None;
+        }
+        let update = if let Some(viewport) = self.viewport {
+            let snapshot = text.snapshot();
+            let end = viewport.start_line.saturating_add(viewport.count);
+            Update::Lines {
+                version,
+                start_line: viewport.start_line,
+                lines: (viewport.start_line..end).map_while(|line| snapshot.line(line)).collect(),
+                total_lines: snapshot.len_lines(),
+            }
+        } else {
+            match self.sent.and_then(|sent| text.deltas_since(sent)) {
+                Some(deltas) => Update::Deltas(coalesce(deltas)),
+                None => Update::Snapshot(text.snapshot()),
+            }
+        };
+        self.started = true;
+        self.sent = Some(version);
+        Some(update)
+    }
+}
+
+/// バッファの購読。next を呼ぶたびに、前回から変わった分を1つの更新にまとめて返すので、
+/// 読むのが遅い購読者には変更がまとめて届く。
+#[derive(Debug)]
+pub struct Subscription {
+    /// バッファを生かし続けないように弱参照で持つ。kill-buffer すると購読は終わる。
+    buffer: Weak<RwLock<Buffer>>,
+    watch: watch::Receiver<u64>,
+    cursor: Cursor,
+}
+
+impl Subscription {
+    /// resume_from を指定すると、その版より後の差分から送る (再接続時)。
+    /// その版の差分が残っていなければスナップショットから送り直す。
+    pub async fn new(
+        state: &EditorState,
+        buffer_id: &str,
+        resume_from: Option<u64>,
+        viewport: Option<Viewport>,
+    ) -> Result<Self, String> {
+        let buffer = state
+            .get_buffer(buffer_id)
+            .await
+            .ok_or_else(|| format!("No such buffer: {}", buffer_id))?;
+        let watch = buffer.read().await.watch_version();
+        Ok(Self {
+            buffer: Arc::downgrade(&buffer),
+            watch,
+            cursor: Cursor::new(resume_from, viewport),
+        })
+    }
+
+    /// 次の更新を待つ。バッファが無くなったら None を返す。
+    pub async fn next(&mut self) -> Option<Update> {
+        loop {
+            let buffer = self.buffer.upgrade()?;
+            {
+                let buffer = buffer.read().await;
+                // 版はバッファの書き込みロック中に進むので、ここで既読にすれば以降の変更を取りこぼさない
+                self.watch.borrow_and_update();
+                if let Some(update) = self.cursor.update(&*buffer) {
+                    return Some(update);
+                }
+            }
+            drop(buffer);
+            if self.watch.changed().await.is_err() {
+                return None;
+            }
+        }
+    }
+}
+
+/// 続けて入力した文字や続けて消した文字の差分を1つにまとめる
+pub fn coalesce(deltas: Vec<Delta>) -> Vec<Delta> {
+    let mut merged: Vec<Delta> = Vec::with_capacity(deltas.len());
+    for delta in deltas {
+        if let Some(last) = merged.last_mut() {
+            let last_end = last.start + last.text.chars().count();
+            // 直前に挿入したテキストの直後への挿入
+            if delta.start == delta.end && delta.start == last_end {
+                last.text.push_str(&delta.text);
+                last.version = delta.version;
+                continue;
+            }
+            // 直前に削除した位置の直前の削除 (後退削除)
+            if last.text.is_empty() && delta.text.is_empty() && delta.end == last.start {
+                last.start = delta.start;
+                last.version = delta.version;
+                continue;
+            }
+        }
+        merged.push(delta);
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 差分を適用する (クライアントの動作)
+    fn apply(text: &mut String, deltas: &[Delta]) {
+        for delta in deltas {
+            let start = text.char_indices().nth(delta.start).map_or(text.len(), |(i, _)| i);
+            let end = text.char_indices().nth(delta.end).map_or(text.len(), |(i, _)| i);
+            text.replace_range(start..end, &delta.text);
+        }
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut buf = Buffer::new("test".into(), "ab");
+        for (i, c) in "xyz".chars().enumerate() {
+            buf.insert(1 + i, &c.to_string()).unwrap();
+        }
+        buf.delete(3..4).unwrap();
+        buf.delete(2..3).unwrap();
+        buf.insert(0, "<").unwrap();
+        let deltas = buf.deltas_since(0).unwrap();
+        let merged = coalesce(deltas.clone());
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged[0], Delta { version: 3, start: 1, end: 1, text: "xyz".into() });
+        assert_eq!(merged[1], Delta { version: 5, start: 2, end: 4, text: String::new() });
+        assert_eq!(merged.last().unwrap().version, buf.version());
+
+        let (mut expected, mut actual) = ("ab".to_string(), "ab".to_string());
+        apply(&mut expected, &deltas);
+        apply(&mut actual, &merged);
+        assert_eq!(actual, expected);
+        assert_eq!(actual, buf.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_subscription() {
+        let state = Arc::new(EditorState::new());
+        let buffer_id = state.create_buffer("test".into(), "Hello").await;
+        let buffer = state.get_buffer(&buffer_id).await.unwrap();
+        let mut subscription = Subscription::new(&state, &buffer_id, None, None).await.unwrap();
+        let Some(Update::Snapshot(snapshot)) = subscription.next().await else { panic!("expected a snapshot") };
+        assert_eq!((snapshot.to_string().as_str(), snapshot.version), ("Hello", 0));
+
+        // 読まないうちに行われた変更は1つの更新にまとまる
+        buffer.write().await.insert(5, " W").unwrap();
+        buffer.write().await.insert(7, "orld").unwrap();
+        let update = subscription.next().await.unwrap();
+        assert_eq!(update.version(), Some(2));
+        let Update::Deltas(deltas) = update else { panic!("expected deltas") };
+        assert_eq!(deltas, [Delta { version: 2, start: 5, end: 5, text: " World".into() }]);
+
+        // 変更されるまで待つ
+        let task = tokio::spawn(async move { subscription.next().await });
+        tokio::task::yield_now().await;
+        assert!(!task.is_finished());
+        buffer.write().await.delete(0..6).unwrap();
+        assert_eq!(task.await.unwrap().unwrap().version(), Some(3));
+
+        // 再接続: 残っている差分から再開する
+        let mut resumed = Subscription::new(&state, &buffer_id, Some(1), None).await.unwrap();
+        let Some(Update::Deltas(deltas)) = resumed.next().await else { panic!("expected deltas") };
+        assert_eq!(deltas.iter().map(|d| d.version).collect::<Vec<_>>(), [2, 3]);
+        let mut current = Subscription::new(&state, &buffer_id, Some(3), None).await.unwrap();
+        assert!(matches!(current.next().await, Some(Update::Deltas(deltas)) if deltas.is_empty()));
+        let mut future = Subscription::new(&state, &buffer_id, Some(10), None).await.unwrap();
+        assert!(matches!(future.next().await, Some(Update::Snapshot(s)) if s.to_string() == "World"));
+
+        let viewport = Viewport { start_line: 1, count: 1 };
+        let mut lines = Subscription::new(&state, &buffer_id, None, Some(viewport)).await.unwrap();
+        buffer.write().await.insert(5, "\nfoo\nbar").unwrap();
+        let Some(Update::Lines { version, start_line, lines: text, total_lines }) = lines.next().await else {
+            panic!("expected lines")
+        };
+        assert_eq!((version, start_line, text, total_lines), (4, 1, vec!["foo".to_string()], 3));
+
+        // kill-buffer すると購読は終わる
+        drop(buffer);
+        state.kill_buffer(&buffer_id).await.unwrap();
+        assert!(resumed.next().await.is_none());
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::FutureExt;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::command::{execute_command, CommandArg, CommandContext, CommandError, CommandInvocation, CommandRegistry, CommandResult, PrefixArg};
+use crate::sched::Priority;
+use crate::state::EditorState;
+
+pub type TimerFuture = Pin<Box<dyn Future<Output = Result<(), String>> + Send>>;
+type TimerFn = Arc<dyn Fn(Arc<EditorState>) -> TimerFuture + Send + Sync>;
+
+/// run_with_timer などの戻り値。cancel に使う。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(pub u64);
+
+impl fmt::Display for TimerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// タイマーが動く条件
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerKind {
+    /// 指定時刻に動く (run-with-timer)。repeat があればその間隔で繰り返す。
+    After { next: Instant, repeat: Option<Duration> },
+    /// 入力が idle の間途絶えたら動く (run-with-idle-timer)。
+    /// repeat なら入力が途絶えるたびに動き、そうでなければ一度だけ動く。
+    Idle { idle: Duration, repeat: bool },
+}
+
+/// list-timers に表示する情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimerInfo {
+    pub id: TimerId,
+    pub name: String,
+    pub kind: TimerKind,
+}
+
+struct Timer {
+    name: String,
+    kind: TimerKind,
+    /// Idle タイマーが最後に動いた入力の区切り (Timers::activity_count)
+    fired_at_activity: Option<u64>,
+    action: TimerFn,
+}
+
+struct TimerTable {
+    timers: BTreeMap<TimerId, Timer>,
+    next_id: u64,
+    /// 最後にユーザーの入力があった時刻
+    last_activity: Instant,
+    /// ユーザーの入力の回数。Idle タイマーは入力が途絶えた期間ごとに一度だけ動く。
+    activity_count: u64,
+}
+
+/// タイマーの一覧とユーザーの入力の状況。実際に動かすのは run (コアのメインループで起動する)。
+pub struct Timers {
+    table: Mutex<TimerTable>,
+    /// タイマーの追加や入力で、次に動く時刻が変わったことを run に知らせる
+    changed: Notify,
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Self {
+            table: Mutex::new(TimerTable {
+                timers: BTreeMap::new(),
+                next_id: 1,
+                last_activity: Instant::now(),
+                activity_count: 0,
+            }),
+            changed: Notify::new(),
+        }
+    }
+}
+
+impl fmt::Debug for Timers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Timers").field("timers", &self.list()).finish_non_exhaustive()
+    }
+}
+
+impl Timers {
+    fn table(&self) -> std::sync::MutexGuard<'_, TimerTable> {
+        self.table.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn add<F, Fut>(&self, name: &str, kind: TimerKind, action: F) -> TimerId
+    where
+        F: Fn(Arc<EditorState>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let mut table = self.table();
+        let id = TimerId(table.next_id);
+        table.next_id += 1;
+        let timer = Timer {
+            name: name.to_string(),
+            kind,
+            fired_at_activity: None,
+            action: Arc::new(move |state| Box::pin(action(state))),
+        };
+        table.timers.insert(id, timer);
+        drop(table);
+        self.changed.notify_one();
+        id
+    }
+
+    /// delay 後に action を実行する。repeat を指定するとその間隔で繰り返す。
+    pub fn run_with_timer<F, Fut>(&self, name: &str, delay: Duration, repeat: Option<Duration>, action: F) -> TimerId
+    where
+        F: Fn(Arc<EditorState>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let next = Instant::now() + delay;
+        self.add(name, TimerKind::After { next, repeat: repeat.filter(|r| !r.is_zero()) }, action)
+    }
+
+    /// ユーザーの入力が idle の間途絶えたら action を実行する
+    pub fn run_with_idle_timer<F, Fut>(&self, name: &str, idle: Duration, repeat: bool, action: F) -> TimerId
+    where
+        F: Fn(Arc<EditorState>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.add(name, TimerKind::Idle { idle, repeat }, action)
+    }
+
+    pub fn cancel(&self, id: TimerId) -> bool {
+        self.table().timers.remove(&id).is_some()
+    }
+
+    pub fn list(&self) -> Vec<TimerInfo> {
+        self.table()
+            .timers
+            .iter()
+            .map(|(id, timer)| TimerInfo { id: *id, name: timer.name.clone(), kind: timer.kind })
+            .collect()
+    }
+
+    /// UIからユーザーの入力があったことを記録する。idle 状態が終わる。
+    pub fn note_activity(&self) {
+        let mut table = self.table();
+        table.last_activity = Instant::now();
+        table.activity_count += 1;
+        drop(table);
+        self.changed.notify_one();
+    }
+
+    /// 最後の入力からの経過時間 (current-idle-time)
+    pub fn idle_time(&self) -> Duration {
+        self.table().last_activity.elapsed()
+    }
+
+    /// now の時点で動くべきタイマーを取り出し、次に動く時刻を更新する。
+    /// 一度きりのタイマーは一覧から外す。
+    fn take_due(&self, now: Instant) -> Vec<(TimerId, String, TimerFn)> {
+        let mut table = self.table();
+        let idle_since = table.last_activity;
+        let activity = table.activity_count;
+        let mut due = Vec::new();
+        let mut finished = Vec::new();
+        for (id, timer) in table.timers.iter_mut() {
+            match &mut timer.kind {
+                TimerKind::After { next, repeat } => {
+                    if *next > now {
+                        continue;
+                    }
+                    match repeat {
+                        // 遅れた分はまとめて1回だけ実行する
+                        Some(repeat) => {
+                            while *next <= now {
+                                *next += *repeat;
+                            }
+                        }
+                        None => finished.push(*id),
+                    }
+                }
+                TimerKind::Idle { idle, repeat } => {
+                    if now < idle_since + *idle || timer.fired_at_activity == Some(activity) {
+                        continue;
+                    }
+                    timer.fired_at_activity = Some(activity);
+                    if !*repeat {
+                        finished.push(*id);
+                    }
+                }
+            }
+            due.push((*id, timer.name.clone(), timer.action.clone()));
+        }
+        for id in finished {
+            table.timers.remove(&id);
+        }
+        due
+    }
+
+    /// 次にタイマーが動く時刻。入力があるまで動かないなら None。
+    fn next_deadline(&self) -> Option<Instant> {
+        let table = self.table();
+        table
+            .timers
+            .values()
+            .filter_map(|timer| match timer.kind {
+                TimerKind::After { next, .. } => Some(next),
+                TimerKind::Idle { idle, .. } => {
+                    (timer.fired_at_activity != Some(table.activity_count)).then_some(table.last_activity + idle)
+                }
+            })
+            .min()
+    }
+}
+
+/// now の時点で動くべきタイマーを実行し、次にタイマーが動く時刻を返す。
+/// タイマーのエラーやパニックは報告するだけで、他のタイマーの実行は続ける。
+pub async fn run_due(state: &Arc<EditorState>, now: Instant) -> Option<Instant> {
+    for (id, name, action) in state.timers().take_due(now) {
+        let result = AssertUnwindSafe(action(state.clone())).catch_unwind().await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("Error running timer {} ({}): {}", id, name, e),
+            Err(_) => eprintln!("Timer {} ({}) panicked", id, name),
+        }
+    }
+    state.timers().next_deadline()
+}
+
+/// タイマーを動かし続ける。コアの起動時に spawn する。
+pub async fn run(state: Arc<EditorState>) {
+    loop {
+        let changed = state.timers().changed.notified();
+        match run_due(&state, Instant::now()).await {
+            Some(deadline) => {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(deadline) => {}
+                    _ = changed => {}
+                }
+            }
+            None => changed.await,
+        }
+    }
+}
+
+/// コマンドを実行するタイマーの処理。タイマーを作ったWindowで引数なしで呼ぶ。
+/// 編集は入力による編集を待たせないよう、バックグラウンドの優先度で適用する。
+fn command_action(
+    window_id: String,
+    command: String,
+) -> impl Fn(Arc<EditorState>) -> TimerFuture + Send + Sync + 'static {
+    move |state| {
+        let (window_id, command) = (window_id.clone(), command.clone());
+        Box::pin(async move {
+            let invocation = CommandInvocation { priority: Priority::Background, ..Default::default() };
+            match execute_command(state, &window_id, &command, invocation).await {
+                Ok(_) => Ok(()),
+                Err(CommandError::MissingArgument(request)) => {
+                    Err(format!("{} reads an argument ({})", command, request.prompt.trim()))
+                }
+                Err(e) => Err(e.to_string()),
+            }
+        })
+    }
+}
+
+/// 秒数の入力。小数も受け付ける。
+fn seconds_arg(ctx: &CommandContext, index: usize) -> Result<Duration, String> {
+    let input = ctx.string_arg(index)?.trim();
+    input
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("Not a number of seconds: {}", input))
+}
+
+async fn ensure_command(ctx: &CommandContext, name: &str) -> Result<(), CommandError> {
+    match ctx.state.commands().read().await.get(name) {
+        Some(_) => Ok(()),
+        None => Err(CommandError::UnknownCommand(name.to_string())),
+    }
+}
+
+pub(crate) fn register_commands(r: &mut CommandRegistry) -> Result<(), String> {
+    r.register(
+        "run-with-timer",
+        "Run COMMAND after SECS seconds, and then every REPEAT seconds if REPEAT is non-zero.",
+        "sRun after (seconds): \nsRepeat every (seconds, 0 for once): \nCCommand: ",
+        run_with_timer,
+    )?;
+    r.register(
+        "run-with-idle-timer",
+        "Run COMMAND when the editor has been idle for SECS seconds. With a prefix argument, repeat on each idle period.",
+        "sRun when idle for (seconds): \nCCommand: \nP",
+        run_with_idle_timer,
+    )?;
+    r.register("cancel-timer", "Cancel the timer with the given ID.", "nCancel timer: ", cancel_timer)?;
+    r.register("list-timers", "Show the active timers.", "", list_timers)?;
+    Ok(())
+}
+
+async fn run_with_timer(ctx: CommandContext) -> CommandResult {
+    let delay = seconds_arg(&ctx, 0)?;
+    let repeat = seconds_arg(&ctx, 1)?;
+    let command = ctx.string_arg(2)?.trim().to_string();
+    ensure_command(&ctx, &command).await?;
+    let action = command_action(ctx.window_id.clone(), command.clone());
+    let id = ctx.state.timers().run_with_timer(&command, delay, Some(repeat), action);
+    Ok(Some(format!("Timer {} will run {}", id, command)))
+}
+
+async fn run_with_idle_timer(ctx: CommandContext) -> CommandResult {
+    let idle = seconds_arg(&ctx, 0)?;
+    let command = ctx.string_arg(1)?.trim().to_string();
+    ensure_command(&ctx, &command).await?;
+    let repeat = !matches!(ctx.args.get(2), Some(CommandArg::Prefix(PrefixArg::None)));
+    let action = command_action(ctx.window_id.clone(), command.clone());
+    let id = ctx.state.timers().run_with_idle_timer(&command, idle, repeat, action);
+    Ok(Some(format!("Idle timer {} will run {}", id, command)))
+}
+
+async fn cancel_timer(ctx: CommandContext) -> CommandResult {
+    let id = TimerId(ctx.number_arg(0).max(0) as u64);
+    if !ctx.state.timers().cancel(id) {
+        return Err(format!("No such timer: {}", id).into());
+    }
+    Ok(Some(format!("Cancelled timer {}", id)))
+}
+
+async fn list_timers(ctx: CommandContext) -> CommandResult {
+    let now = Instant::now();
+    let lines: Vec<_> = ctx
+        .state
+        .timers()
+        .list()
+        .into_iter()
+        .map(|timer| match timer.kind {
+            TimerKind::After { next, repeat } => {
+                let repeat = repeat.map(|r| format!(", every {:.1}s", r.as_secs_f64())).unwrap_or_default();
+                let remaining = next.saturating_duration_since(now).as_secs_f64();
+                format!("{} {} in {:.1}s{}", timer.id, timer.name, remaining, repeat)
+            }
+            TimerKind::Idle { idle, repeat } => {
+                let repeat = if repeat { ", repeat" } else { "" };
+                format!("{} {} idle {:.1}s{}", timer.id, timer.name, idle.as_secs_f64(), repeat)
+            }
+        })
+        .collect();
+    if lines.is_empty() {
+        return Ok(Some("No timers".into()));
+    }
+    Ok(Some(lines.join("\n")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn counter() -> (Arc<AtomicUsize>, impl Fn(Arc<EditorState>) -> TimerFuture + Send + Sync + 'static) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let action_count = count.clone();
+        (count, move |_| {
+            action_count.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Ok(()) })
+        })
+    }
+
+    #[tokio::test]
+    async fn test_timer_repeat_and_cancel() {
+        let state = Arc::new(EditorState::new());
+        let (once, action) = counter();
+        state.timers().run_with_timer("once", Duration::from_secs(1), None, action);
+        let (repeated, action) = counter();
+        let id = state.timers().run_with_timer("repeat", Duration::from_secs(1), Some(Duration::from_secs(2)), action);
+        state.timers().run_with_timer("failing", Duration::ZERO, None, |_| async { Err("boom".to_string()) });
+        let start = Instant::now();
+
+        let next = run_due(&state, start).await.unwrap();
+        assert!(next > start);
+        assert_eq!((once.load(Ordering::SeqCst), repeated.load(Ordering::SeqCst)), (0, 0));
+
+        run_due(&state, start + Duration::from_secs(2)).await;
+        assert_eq!((once.load(Ordering::SeqCst), repeated.load(Ordering::SeqCst)), (1, 1));
+        // 遅れた分はまとめて1回
+        run_due(&state, start + Duration::from_secs(10)).await;
+        assert_eq!((once.load(Ordering::SeqCst), repeated.load(Ordering::SeqCst)), (1, 2));
+        assert_eq!(state.timers().list().len(), 1);
+
+        assert!(state.timers().cancel(id));
+        assert!(!state.timers().cancel(id));
+        assert_eq!(run_due(&state, start + Duration::from_secs(20)).await, None);
+        assert_eq!(repeated.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timer() {
+        let state = Arc::new(EditorState::new());
+        let (once, action) = counter();
+        state.timers().run_with_idle_timer("once", Duration::from_secs(1), false, action);
+        let (repeated, action) = counter();
+        state.timers().run_with_idle_timer("repeat", Duration::from_secs(1), true, action);
+
+        state.timers().note_activity();
+        let start = Instant::now();
+        run_due(&state, start).await;
+        assert_eq!(repeated.load(Ordering::SeqCst), 0);
+
+        // 入力が途絶えた期間ごとに一度だけ動く
+        run_due(&state, start + Duration::from_secs(2)).await;
+        assert_eq!(run_due(&state, start + Duration::from_secs(3)).await, None);
+        assert_eq!((once.load(Ordering::SeqCst), repeated.load(Ordering::SeqCst)), (1, 1));
+
+        state.timers().note_activity();
+        let start = Instant::now();
+        run_due(&state, start + Duration::from_secs(2)).await;
+        assert_eq!((once.load(Ordering::SeqCst), repeated.load(Ordering::SeqCst)), (1, 2));
+    }
+
+    #[tokio::test]
+    async fn test_timer_command() {
+        let state = Arc::new(EditorState::new());
+        let buffer_id = state.create_buffer("test".into(), "hello").await;
+        let window_id = state.create_window(&buffer_id).await.unwrap();
+        let invocation = CommandInvocation {
+            args: vec!["0".into(), "0".into(), "end-of-buffer".into()],
+            ..Default::default()
+        };
+        execute_command(state.clone(), &window_id, "run-with-timer", invocation).await.unwrap();
+        assert_eq!(run_due(&state, Instant::now()).await, None);
+        assert_eq!(state.read_window(&window_id, |w| w.selected_view().point).await.unwrap(), 5);
+
+        let invocation = CommandInvocation { args: vec!["x".into(), "0".into(), "end-of-buffer".into()], ..Default::default() };
+        assert!(execute_command(state.clone(), &window_id, "run-with-timer", invocation).await.is_err());
+    }
+}
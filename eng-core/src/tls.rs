@@ -0,0 +1,607 @@
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::crypto::{self, WebPkiSupportedAlgorithms};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use tokio_rustls::rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, DistinguishedName, Error, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::Connected;
+use tonic::transport::{Channel, Endpoint, Uri};
+
+use crate::editor::pairing_service_client::PairingServiceClient;
+use crate::editor::{PairRequest, PairResponse};
+
+/// ペアリングコードの有効期間
+pub const PAIRING_CODE_TTL: Duration = Duration::from_secs(5 * 60);
+/// TLS のハンドシェイクを待つ時間。これを過ぎた接続は捨てる。
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// 証明書の名前は検証しない (フィンガープリントで照合する) が、形式上必要
+const SERVER_NAME: &str = "eng";
+
+/// 証明書 (DER) の SHA-256 を16進で表したもの
+pub fn fingerprint(cert: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, cert)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// 証明書と鍵を置くユーザー専用のディレクトリ ($XDG_CONFIG_HOME/eng/tls、無ければ ~/.config/eng/tls)。
+/// 無ければ作り、パーミッションを 0700 にする。
+pub fn tls_dir() -> std::io::Result<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home = std::env::var_os("HOME").ok_or_else(|| std::io::Error::other("HOME is not set"))?;
+            PathBuf::from(home).join(".config")
+        }
+    };
+    let dir = base.join("eng").join("tls");
+    std::fs::create_dir_all(&dir)?;
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+    Ok(dir)
+}
+
+/// インストールごとに生成する自己署名証明書と鍵。認証局は使わず、相手はフィンガープリントで照合する。
+pub struct Identity {
+    cert: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+}
+
+impl std::fmt::Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // 鍵は表示しない
+        f.debug_struct("Identity").field("fingerprint", &self.fingerprint()).finish()
+    }
+}
+
+impl Identity {
+    pub fn generate(name: &str) -> Result<Self, String> {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string(), SERVER_NAME.to_string()])
+            .map_err(|e| format!("Failed to generate certificate: {}", e))?;
+        let der = cert.serialize_der().map_err(|e| format!("Failed to generate certificate: {}", e))?;
+        Ok(Self {
+            cert: CertificateDer::from(der),
+            key: PrivatePkcs8KeyDer::from(cert.serialize_private_key_der()),
+        })
+    }
+
+    /// dir の NAME.crt と NAME.key (どちらも DER) を読み込む。無ければ生成して保存する。
+    pub fn load_or_create(dir: &Path, name: &str) -> Result<Self, String> {
+        let cert_path = dir.join(format!("{}.crt", name));
+        let key_path = dir.join(format!("{}.key", name));
+        if cert_path.exists() && key_path.exists() {
+            let cert = std::fs::read(&cert_path).map_err(|e| format!("Failed to read {}: {}", cert_path.display(), e))?;
+            let key = std::fs::read(&key_path).map_err(|e| format!("Failed to read {}: {}", key_path.display(), e))?;
+            return Ok(Self { cert: CertificateDer::from(cert), key: PrivatePkcs8KeyDer::from(key) });
+        }
+        let identity = Self::generate(name)?;
+        write_private(&key_path, identity.key.secret_pkcs8_der())?;
+        std::fs::write(&cert_path, identity.cert.as_ref())
+            .map_err(|e| format!("Failed to write {}: {}", cert_path.display(), e))?;
+        Ok(identity)
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.cert)
+    }
+
+    fn chain(&self) -> Vec<CertificateDer<'static>> {
+        vec![self.cert.clone()]
+    }
+
+    fn key(&self) -> PrivateKeyDer<'static> {
+        PrivateKeyDer::Pkcs8(self.key.clone_key())
+    }
+}
+
+/// 所有者だけが読めるファイルとして書き出す
+fn write_private(path: &Path, content: &[u8]) -> Result<(), String> {
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    file.write_all(content).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// 信頼する証明書のフィンガープリントと名前。サーバーはペアリングしたクライアントを、
+/// クライアントは接続したことのあるサーバーをここに記録する。
+/// ファイルは1行に1つ "FINGERPRINT NAME"。
+#[derive(Debug, Default)]
+pub struct Pins {
+    path: Option<PathBuf>,
+    pins: Mutex<Vec<(String, String)>>,
+}
+
+impl Pins {
+    /// path から読み込む。無ければ空で、追加したときに作る。
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        let pins = content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| match line.split_once(' ') {
+                Some((fingerprint, name)) => (fingerprint.to_string(), name.to_string()),
+                None => (line.to_string(), String::new()),
+            })
+            .collect();
+        Ok(Self { path: Some(path.to_path_buf()), pins: Mutex::new(pins) })
+    }
+
+    fn pins(&self) -> std::sync::MutexGuard<'_, Vec<(String, String)>> {
+        self.pins.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn contains(&self, fingerprint: &str) -> bool {
+        self.pins().iter().any(|(f, _)| f == fingerprint)
+    }
+
+    /// (フィンガープリント, 名前) の一覧
+    pub fn list(&self) -> Vec<(String, String)> {
+        self.pins().clone()
+    }
+
+    /// 追加してファイルに保存する。既にあれば名前を置き換える。
+    pub fn add(&self, fingerprint: &str, name: &str) -> Result<(), String> {
+        let mut pins = self.pins();
+        pins.retain(|(f, _)| f != fingerprint);
+        pins.push((fingerprint.to_string(), name.replace(['\n', '\r'], " ")));
+        self.save(&pins)
+    }
+
+    /// ペアリングを取り消す。あれば true
+    pub fn remove(&self, fingerprint: &str) -> Result<bool, String> {
+        let mut pins = self.pins();
+        let len = pins.len();
+        pins.retain(|(f, _)| f != fingerprint);
+        if pins.len() == len {
+            return Ok(false);
+        }
+        self.save(&pins).map(|_| true)
+    }
+
+    fn save(&self, pins: &[(String, String)]) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let content: String = pins.iter().map(|(f, name)| format!("{} {}\n", f, name)).collect();
+        write_private(path, content.as_bytes())
+    }
+}
+
+/// 新しいクライアントとペアリングするための一度限りのコード
+#[derive(Debug, Default)]
+pub struct Pairing {
+    codes: Mutex<Vec<(String, Instant)>>,
+}
+
+impl Pairing {
+    fn codes(&self) -> std::sync::MutexGuard<'_, Vec<(String, Instant)>> {
+        self.codes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// コードを発行する。PAIRING_CODE_TTL の間、1回だけ使える。
+    pub fn issue(&self) -> String {
+        // 読み間違えやすい文字は使わない
+        const CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+        let mut rng = rand::thread_rng();
+        let code: String = (0..8).map(|_| CHARS[rng.gen_range(0..CHARS.len())] as char).collect();
+        self.codes().push((code.clone(), Instant::now() + PAIRING_CODE_TTL));
+        code
+    }
+
+    /// コードが有効なら使用済みにして true を返す
+    pub fn redeem(&self, code: &str) -> bool {
+        let now = Instant::now();
+        let mut codes = self.codes();
+        codes.retain(|(_, expires)| *expires > now);
+        let code = code.trim().to_ascii_uppercase();
+        match codes.iter().position(|(c, _)| *c == code) {
+            Some(index) => {
+                codes.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// ペアリングするときにユーザーが新しいクライアントへ渡す文字列 "CODE@FINGERPRINT"。
+/// フィンガープリントで、クライアントは初めて接続するサーバーを確かめられる。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairingTicket {
+    pub code: String,
+    pub fingerprint: String,
+}
+
+impl std::fmt::Display for PairingTicket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.code, self.fingerprint)
+    }
+}
+
+impl std::str::FromStr for PairingTicket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once('@') {
+            Some((code, fingerprint)) if !code.is_empty() && fingerprint.len() == 64 => Ok(Self {
+                code: code.to_string(),
+                fingerprint: fingerprint.to_ascii_lowercase(),
+            }),
+            _ => Err(format!("Invalid pairing ticket {:?} (expected CODE@FINGERPRINT)", s)),
+        }
+    }
+}
+
+fn algorithms() -> WebPkiSupportedAlgorithms {
+    crypto::ring::default_provider().signature_verification_algorithms
+}
+
+/// クライアント証明書を必ず要求するが、どの証明書も受け入れる。
+/// 鍵を持っていることはハンドシェイクの署名で確かめ、ペアリング済みかは AuthInterceptor が照合する。
+#[derive(Debug)]
+struct AnyClientCert {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// サーバー証明書をフィンガープリントで照合する (ピン留め)
+#[derive(Debug)]
+struct PinnedServerCert {
+    fingerprint: String,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedServerCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+pub fn server_config(identity: &Identity) -> Result<Arc<ServerConfig>, String> {
+    let mut config = ServerConfig::builder()
+        .with_client_cert_verifier(Arc::new(AnyClientCert { algorithms: algorithms() }))
+        .with_single_cert(identity.chain(), identity.key())
+        .map_err(|e| format!("Invalid server certificate: {}", e))?;
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// server_fingerprint の証明書を持つサーバーにだけ接続し、identity をクライアント証明書として示す
+pub fn client_config(identity: &Identity, server_fingerprint: &str) -> Result<Arc<ClientConfig>, String> {
+    let verifier = PinnedServerCert { fingerprint: server_fingerprint.to_ascii_lowercase(), algorithms: algorithms() };
+    let mut config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_client_auth_cert(identity.chain(), identity.key())
+        .map_err(|e| format!("Invalid client certificate: {}", e))?;
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// 接続してきたクライアントの証明書。リクエストの extensions から取り出せる。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCertificate {
+    pub fingerprint: String,
+    pub remote_addr: Option<SocketAddr>,
+}
+
+/// TLS の上の接続
+#[derive(Debug)]
+pub struct TlsStream<S> {
+    stream: tokio_rustls::server::TlsStream<S>,
+    peer: PeerCertificate,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+impl<S> Connected for TlsStream<S> {
+    type ConnectInfo = PeerCertificate;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.peer.clone()
+    }
+}
+
+/// listener で受け付けた接続を TLS で包む。ハンドシェイクは接続ごとに別のタスクで行うので、
+/// 遅いクライアントが他の接続を待たせることはない。失敗した接続は捨てる。
+pub fn incoming(
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+) -> ReceiverStream<std::io::Result<TlsStream<TcpStream>>> {
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    let acceptor = TlsAcceptor::from(config);
+    tokio::spawn(async move {
+        loop {
+            let (stream, remote_addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        eprintln!("Failed to accept connection: {}", e);
+                        continue;
+                    }
+                },
+                _ = tx.closed() => break,
+            };
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => return eprintln!("TLS handshake with {} failed: {}", remote_addr, e),
+                    Err(_) => return eprintln!("TLS handshake with {} timed out", remote_addr),
+                };
+                let Some(cert) = stream.get_ref().1.peer_certificates().and_then(|certs| certs.first()) else {
+                    return eprintln!("{} sent no client certificate", remote_addr);
+                };
+                let peer = PeerCertificate { fingerprint: fingerprint(cert), remote_addr: Some(remote_addr) };
+                let _ = tx.send(Ok(TlsStream { stream, peer })).await;
+            });
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+/// addr の TLS サーバーへ接続する。サーバー証明書が server_fingerprint と一致しなければ失敗する。
+pub async fn connect(addr: SocketAddr, identity: &Identity, server_fingerprint: &str) -> Result<Channel, String> {
+    let connector = TlsConnector::from(client_config(identity, server_fingerprint)?);
+    // URI は使われないが、形式上必要
+    Endpoint::from_shared(format!("http://{}", addr))
+        .map_err(|e| e.to_string())?
+        .connect_with_connector(tower::service_fn(move |_: Uri| {
+            let connector = connector.clone();
+            async move {
+                let stream = TcpStream::connect(addr).await?;
+                let name = ServerName::try_from(SERVER_NAME).map_err(std::io::Error::other)?;
+                connector.connect(name, stream).await
+            }
+        }))
+        .await
+        .map_err(|e| format!("Failed to connect to {}: {}", addr, e))
+}
+
+/// ticket のコードでサーバーとペアリングする。以降は identity で接続すればよい。
+/// サーバーのフィンガープリントは呼び出し側が Pins に記録しておく。
+pub async fn pair(
+    addr: SocketAddr,
+    identity: &Identity,
+    ticket: &PairingTicket,
+    client_name: &str,
+) -> Result<PairResponse, String> {
+    let channel = connect(addr, identity, &ticket.fingerprint).await?;
+    let request = PairRequest { code: ticket.code.clone(), client_name: client_name.to_string() };
+    PairingServiceClient::new(channel)
+        .pair(request)
+        .await
+        .map(|response| response.into_inner())
+        .map_err(|e| format!("Pairing failed: {}", e.message()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_and_pins() {
+        let dir = std::env::temp_dir().join(format!("eng-tls-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let identity = Identity::load_or_create(&dir, "core").unwrap();
+        assert_eq!(identity.fingerprint().len(), 64);
+        // 2回目は保存したものを使う
+        assert_eq!(Identity::load_or_create(&dir, "core").unwrap().fingerprint(), identity.fingerprint());
+        let mode = std::fs::metadata(dir.join("core.key")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let path = dir.join("clients");
+        let pins = Pins::load(&path).unwrap();
+        pins.add("aa", "laptop").unwrap();
+        pins.add("bb", "tablet").unwrap();
+        pins.add("aa", "work laptop").unwrap();
+        let reloaded = Pins::load(&path).unwrap();
+        assert_eq!(reloaded.list(), [("bb".into(), "tablet".into()), ("aa".into(), "work laptop".into())]);
+        assert!(reloaded.remove("bb").unwrap());
+        assert!(!reloaded.remove("bb").unwrap());
+        assert!(!Pins::load(&path).unwrap().contains("bb"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_pairing() {
+        let pairing = Pairing::default();
+        let code = pairing.issue();
+        assert!(!pairing.redeem("WRONG"));
+        assert!(pairing.redeem(&code.to_ascii_lowercase()));
+        // 一度しか使えない
+        assert!(!pairing.redeem(&code));
+
+        let ticket = PairingTicket { code, fingerprint: "ab".repeat(32) };
+        assert_eq!(ticket.to_string().parse(), Ok(ticket));
+        assert!("CODE@short".parse::<PairingTicket>().is_err());
+        assert!("no-separator".parse::<PairingTicket>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_stream::StreamExt;
+
+        let server = Identity::generate("core").unwrap();
+        let client = Identity::generate("ui").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = incoming(listener, server_config(&server).unwrap());
+
+        // サーバー証明書が一致すれば接続でき、サーバーにはクライアント証明書が届く
+        let connector = TlsConnector::from(client_config(&client, &server.fingerprint()).unwrap());
+        let name = ServerName::try_from(SERVER_NAME).unwrap();
+        let (connected, accepted) = tokio::join!(
+            async { connector.connect(name.clone(), TcpStream::connect(addr).await.unwrap()).await },
+            incoming.next()
+        );
+        let (mut connected, mut accepted) = (connected.unwrap(), accepted.unwrap().unwrap());
+        assert_eq!(accepted.connect_info().fingerprint, client.fingerprint());
+        connected.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // 別のサーバー証明書をピン留めしていれば接続できない
+        let other = Identity::generate("other").unwrap();
+        let connector = TlsConnector::from(client_config(&client, &other.fingerprint()).unwrap());
+        let stream = TcpStream::connect(addr).await.unwrap();
+        assert!(connector.connect(name, stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pairing_service() {
+        use crate::auth::AuthInterceptor;
+        use crate::editor::editor_service_client::EditorServiceClient;
+        use crate::editor::editor_service_server::EditorServiceServer;
+        use crate::editor::pairing_service_server::PairingServiceServer;
+        use crate::editor::NotifyActivityRequest;
+        use crate::state::EditorState;
+
+        let server = Identity::generate("core").unwrap();
+        let pins = Arc::new(Pins::default());
+        let pairing = Arc::new(Pairing::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = crate::MyEditorService::new(Arc::new(EditorState::new()));
+        let pairing_service = crate::MyPairingService::new(pins.clone(), pairing.clone(), "test".into());
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(EditorServiceServer::with_interceptor(service, AuthInterceptor::pinned(pins.clone())))
+                .add_service(PairingServiceServer::new(pairing_service))
+                .serve_with_incoming(incoming(listener, server_config(&server).unwrap())),
+        );
+
+        // ペアリングするまでは他のサービスを使えない
+        let client = Identity::generate("ui").unwrap();
+        let channel = connect(addr, &client, &server.fingerprint()).await.unwrap();
+        let mut editor = EditorServiceClient::new(channel);
+        let status = editor.notify_activity(NotifyActivityRequest::default()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let ticket = PairingTicket { code: "WRONG".into(), fingerprint: server.fingerprint() };
+        assert!(pair(addr, &client, &ticket, "laptop").await.unwrap_err().contains("Invalid or expired"));
+        let ticket = PairingTicket { code: pairing.issue(), fingerprint: server.fingerprint() };
+        let response = pair(addr, &client, &ticket, "laptop").await.unwrap();
+        assert_eq!(response.fingerprint, client.fingerprint());
+        assert_eq!(pins.list(), [(client.fingerprint(), "laptop".to_string())]);
+        editor.notify_activity(NotifyActivityRequest::default()).await.unwrap();
+
+        // ペアリングを取り消すと使えなくなる
+        pins.remove(&client.fingerprint()).unwrap();
+        assert!(editor.notify_activity(NotifyActivityRequest::default()).await.is_err());
+    }
+}
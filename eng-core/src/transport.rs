@@ -0,0 +1,304 @@
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::oneshot;
+use tonic::transport::server::Connected;
+use tonic::transport::{Channel, Endpoint, Uri};
+
+/// gRPC の待ち受け先・接続先
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    /// 既定。ユーザー専用の実行時ディレクトリに置くので、他のユーザーからは接続できない。
+    Unix(PathBuf),
+    /// リモート環境向け。同じマシンの他のユーザーからも接続できるので、トークンだけが頼りになる。
+    Tcp(SocketAddr),
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+            Address::Tcp(addr) => write!(f, "tcp:{}", addr),
+        }
+    }
+}
+
+impl std::str::FromStr for Address {
+    type Err = String;
+
+    /// "unix:PATH"、"tcp:HOST:PORT"、または [::1] のポート番号だけ
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("Socket path is empty".into());
+            }
+            return Ok(Address::Unix(PathBuf::from(path)));
+        }
+        if let Some(addr) = s.strip_prefix("tcp:") {
+            return addr.parse().map(Address::Tcp).map_err(|e| format!("Invalid address {:?}: {}", addr, e));
+        }
+        match s.parse::<u16>() {
+            Ok(port) => Ok(Address::Tcp(SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, port)))),
+            Err(_) => Err(format!("Invalid address {:?} (expected unix:PATH or tcp:HOST:PORT)", s)),
+        }
+    }
+}
+
+/// ユーザー専用の実行時ディレクトリ ($XDG_RUNTIME_DIR/eng、無ければ一時ディレクトリの eng-<USER>)。
+/// 無ければ作り、パーミッションを 0700 にする (private_dir)。
+pub fn runtime_dir() -> std::io::Result<PathBuf> {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("eng"),
+        _ => {
+            let user = std::env::var("USER").unwrap_or_else(|_| "unknown".into());
+            std::env::temp_dir().join(format!("eng-{}", user))
+        }
+    };
+    private_dir(dir)
+}
+
+/// 自分だけが使えるディレクトリ。無ければ作り、パーミッションを 0700 にする。
+/// 一時ディレクトリでは他のユーザーが先に作っておけるので、自分が持つディレクトリでなければ
+/// (シンボリックリンクも) 使わずにエラーにする。
+fn private_dir(dir: PathBuf) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(&dir)?;
+    let refuse = |reason: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("Refusing to use runtime directory {}: {}", dir.display(), reason),
+        )
+    };
+    let metadata = std::fs::symlink_metadata(&dir)?;
+    if metadata.file_type().is_symlink() {
+        return Err(refuse("it is a symbolic link".into()));
+    }
+    if !metadata.is_dir() {
+        return Err(refuse("it is not a directory".into()));
+    }
+    // SAFETY: getuid は引数を取らず、失敗しない
+    let uid = unsafe { libc::getuid() };
+    if metadata.uid() != uid {
+        return Err(refuse(format!("it is owned by uid {}, not {}", metadata.uid(), uid)));
+    }
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("Cannot secure runtime directory {}: {}", dir.display(), e))
+    })?;
+    Ok(dir)
+}
+
+/// 実行時ディレクトリの中のソケットのパス
+pub fn socket_path(name: &str) -> std::io::Result<PathBuf> {
+    Ok(runtime_dir()?.join(format!("{}.sock", name)))
+}
+
+/// ソケットを作る前に、前回異常終了して残ったソケットファイルを消す。ソケット以外のファイルは消さない。
+pub fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Unixソケットで待ち受ける。ソケットは所有者だけが読み書きできるようにする。
+pub fn bind_unix(path: &Path) -> std::io::Result<tokio::net::UnixListener> {
+    remove_stale_socket(path)?;
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// address の gRPC サーバーへ接続する
+pub async fn connect(address: &Address) -> Result<Channel, tonic::transport::Error> {
+    match address {
+        Address::Tcp(addr) => {
+            Endpoint::from_shared(format!("http://{}", addr))?
+                .keep_alive_while_idle(true)
+                .http2_keep_alive_interval(std::time::Duration::from_secs(10))
+                .keep_alive_timeout(std::time::Duration::from_secs(5))
+                .connect()
+                .await
+        }
+        Address::Unix(path) => {
+            let path = path.clone();
+            // URI は使われないが、形式上必要
+            Endpoint::from_static("http://[::1]:50051")
+                .connect_with_connector(tower::service_fn(move |_: Uri| {
+                    tokio::net::UnixStream::connect(path.clone())
+                }))
+                .await
+        }
+    }
+}
+
+/// 読み込み側と書き込み側の組 (子プロセスの標準入出力など) を1本の接続として扱う。
+/// 待ち受けるポートが要らず、ssh 越しにも使える。
+#[derive(Debug)]
+pub struct PipeStream<R, W> {
+    reader: R,
+    writer: W,
+    /// 読み込み側が EOF になったら知らせる
+    closed: Option<oneshot::Sender<()>>,
+}
+
+impl<R, W> PipeStream<R, W> {
+    /// 返した Receiver は、相手が閉じるか接続が捨てられると完了する
+    pub fn new(reader: R, writer: W) -> (Self, oneshot::Receiver<()>) {
+        let (tx, rx) = oneshot::channel();
+        (Self { reader, writer, closed: Some(tx) }, rx)
+    }
+}
+
+impl<R: AsyncRead + Unpin, W: Unpin> AsyncRead for PipeStream<R, W> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.reader).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result
+            && buf.filled().len() == filled
+            && buf.remaining() > 0
+            && let Some(closed) = self.closed.take()
+        {
+            let _ = closed.send(());
+        }
+        result
+    }
+}
+
+impl<R: Unpin, W: AsyncWrite + Unpin> AsyncWrite for PipeStream<R, W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
+
+impl<R, W> Connected for PipeStream<R, W> {
+    type ConnectInfo = ();
+
+    fn connect_info(&self) -> Self::ConnectInfo {}
+}
+
+/// パイプの上で gRPC クライアントを作る。パイプは1本しかないので再接続はできない。
+pub async fn connect_pipe<R, W>(reader: R, writer: W) -> Result<Channel, tonic::transport::Error>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (stream, _) = PipeStream::new(reader, writer);
+    let stream = Arc::new(Mutex::new(Some(stream)));
+    Endpoint::from_static("http://[::1]:50051")
+        .connect_with_connector(tower::service_fn(move |_: Uri| {
+            let stream = stream.lock().unwrap_or_else(|e| e.into_inner()).take();
+            async move {
+                stream.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotConnected, "Pipe is already in use"))
+            }
+        }))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!("unix:/run/eng/core.sock".parse(), Ok(Address::Unix("/run/eng/core.sock".into())));
+        assert_eq!("tcp:127.0.0.1:80".parse(), Ok(Address::Tcp("127.0.0.1:80".parse().unwrap())));
+        assert_eq!("8080".parse(), Ok(Address::Tcp("[::1]:8080".parse().unwrap())));
+        assert!("unix:".parse::<Address>().is_err());
+        assert!("localhost".parse::<Address>().is_err());
+        let address = Address::Unix("/tmp/a.sock".into());
+        assert_eq!(address.to_string().parse(), Ok(address));
+    }
+
+    #[test]
+    fn test_private_dir() {
+        let base = std::env::temp_dir().join(format!("eng-transport-test-{}", uuid::Uuid::new_v4()));
+        let dir = private_dir(base.join("eng")).unwrap();
+        assert_eq!(std::fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+
+        // 他のユーザーが置いたかもしれないシンボリックリンクはたどらない
+        let link = base.join("link");
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+        let error = private_dir(link).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(error.to_string().contains("symbolic link"));
+
+        // 自分のものでないディレクトリは使わない (所有者を変えられる root のときのみ確かめる)
+        // SAFETY: getuid は引数を取らず、失敗しない
+        if unsafe { libc::getuid() } == 0 {
+            let other = base.join("other");
+            std::fs::create_dir(&other).unwrap();
+            std::os::unix::fs::chown(&other, Some(65534), None).unwrap();
+            assert!(private_dir(other).unwrap_err().to_string().contains("owned by uid 65534"));
+        }
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pipe_stream() {
+        use crate::editor::editor_service_client::EditorServiceClient;
+        use crate::editor::editor_service_server::EditorServiceServer;
+        use crate::editor::NotifyActivityRequest;
+        use crate::state::EditorState;
+
+        // クライアント → サーバー と サーバー → クライアント の2本のパイプ
+        let (client_writer, server_reader) = tokio::io::duplex(4096);
+        let (server_writer, client_reader) = tokio::io::duplex(4096);
+        let (stream, closed) = PipeStream::new(server_reader, server_writer);
+        let service = crate::MyEditorService::new(Arc::new(EditorState::new()));
+        let server = tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(EditorServiceServer::new(service))
+                .serve_with_incoming_shutdown(
+                    tokio_stream::once(Ok::<_, std::io::Error>(stream)).chain(tokio_stream::pending()),
+                    async {
+                        let _ = closed.await;
+                    },
+                ),
+        );
+
+        let channel = connect_pipe(client_reader, client_writer).await.unwrap();
+        let mut client = EditorServiceClient::new(channel);
+        client.notify_activity(NotifyActivityRequest::default()).await.unwrap();
+
+        // クライアントが閉じるとサーバーも終わる
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("eng-transport-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.sock");
+        let listener = bind_unix(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // 残っているソケットは作り直せるが、普通のファイルは消さない
+        drop(listener);
+        let _listener = bind_unix(&path).unwrap();
+        let file = dir.join("file");
+        std::fs::write(&file, "keep").unwrap();
+        assert!(bind_unix(&file).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+/// パスのファイル名部分 (バッファ名の元になる)
+pub fn file_name(path: &Path) -> Option<&str> {
+    path.file_name().and_then(|name| name.to_str())
+}
+
+/// name がファイル名 base から自動で付けた名前か ("foo.rs" や "foo.rs<src>")。
+/// rename-buffer で付け直した名前は uniquify の対象にしない。
+pub fn is_uniquified_name(name: &str, base: &str) -> bool {
+    name == base || name.strip_prefix(base).is_some_and(|rest| rest.starts_with('<') && rest.ends_with('>'))
+}
+
+/// 親ディレクトリの名前を近い順に並べる
+fn parent_dirs(path: &Path) -> Vec<String> {
+    let mut dirs: Vec<_> = path
+        .parent()
+        .into_iter()
+        .flat_map(Path::components)
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    dirs.reverse();
+    dirs
+}
+
+/// 同じファイル名のバッファをディレクトリ名で区別した名前にする (uniquify の post-forward-angle-brackets 形式)。
+/// それぞれ他と区別できる最小の数だけ親ディレクトリを付ける ("foo.rs<src>", "foo.rs<a/src>")。
+/// files はバッファIDとファイルのパスの組で、戻り値はバッファIDごとの名前。
+pub fn uniquify_names(files: &[(String, PathBuf)]) -> HashMap<String, String> {
+    let mut groups: HashMap<&str, Vec<(&str, &Path)>> = HashMap::new();
+    for (id, path) in files {
+        if let Some(base) = file_name(path) {
+            groups.entry(base).or_default().push((id, path));
+        }
+    }
+
+    let mut names = HashMap::new();
+    for (base, group) in groups {
+        if let [(id, _)] = group.as_slice() {
+            names.insert(id.to_string(), base.to_string());
+            continue;
+        }
+        let dirs: Vec<_> = group.iter().map(|(_, path)| parent_dirs(path)).collect();
+        for (i, (id, path)) in group.iter().enumerate() {
+            let own = &dirs[i];
+            let depth = (1..=own.len()).find(|&k| {
+                dirs.iter()
+                    .enumerate()
+                    .all(|(j, other)| j == i || other[..k.min(other.len())] != own[..k])
+            });
+            let suffix = match depth {
+                Some(k) => own[..k].iter().rev().cloned().collect::<Vec<_>>().join("/"),
+                // 親ディレクトリで区別できない (同じファイルなど) 場合はディレクトリ全体を付ける
+                None => path.parent().map(|p| p.display().to_string()).unwrap_or_default(),
+            };
+            names.insert(id.to_string(), format!("{}<{}>", base, suffix));
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(paths: &[&str]) -> Vec<(String, PathBuf)> {
+        paths.iter().enumerate().map(|(i, p)| (i.to_string(), PathBuf::from(p))).collect()
+    }
+
+    #[test]
+    fn test_uniquify_names() {
+        let names = uniquify_names(&files(&["/p/a/src/foo.rs", "/p/b/src/foo.rs", "/p/lib/foo.rs", "/p/bar.rs"]));
+        assert_eq!(names["0"], "foo.rs<a/src>");
+        assert_eq!(names["1"], "foo.rs<b/src>");
+        assert_eq!(names["2"], "foo.rs<lib>");
+        assert_eq!(names["3"], "bar.rs");
+
+        // 一方のディレクトリがもう一方の途中までと同じ場合
+        let names = uniquify_names(&files(&["/a/foo.rs", "/b/a/foo.rs"]));
+        assert_eq!(names["1"], "foo.rs<b/a>");
+        assert_eq!(names["0"], "foo.rs</a>");
+    }
+
+    #[test]
+    fn test_is_uniquified_name() {
+        assert!(is_uniquified_name("foo.rs", "foo.rs"));
+        assert!(is_uniquified_name("foo.rs<src>", "foo.rs"));
+        assert!(!is_uniquified_name("notes", "foo.rs"));
+        assert!(!is_uniquified_name("foo.rs.bak", "foo.rs"));
+    }
+}
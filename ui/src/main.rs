@@ -1,7 +1,9 @@
-use iced::{executor, Application, Command, Element, Settings, Theme, Length};
+use iced::{executor, Application, Command, Element, Settings, Subscription, Theme, Length};
+use iced::futures::SinkExt;
 use iced::widget::{column, text, container, scrollable};
 use iced::window;
-use tonic::transport::Channel;
+use std::path::PathBuf;
+use tonic::transport::{Channel, Endpoint, Uri};
 use tonic::Request;
 use tonic::metadata::MetadataValue;
 use tokio_stream::StreamExt;
@@ -10,20 +12,37 @@ use clap::Parser;
 pub mod editor {
     tonic::include_proto!("editor.v1");
 }
+use editor::agent_service_client::AgentServiceClient;
+use editor::core_event::Kind as CoreEventKind;
+use editor::ui_command::Kind as UiCommandKind;
 use editor::editor_service_client::EditorServiceClient;
-use editor::HandshakeRequest;
+use editor::handshake_request::ClientKind;
+use editor::{CoreEvent, HandshakeRequest, OpenedFile, SubscribeUiRequest, UiCommand, WatchCoresRequest};
+
+/// UIが話せるプロトコルの版 (eng-core の handshake::PROTOCOL_VERSION に合わせる)
+const PROTOCOL_VERSION: u32 = 1;
+/// UIが使う機能
+const CAPABILITIES: &[&str] = &["layout", "minibuffer", "buffer-subscription", "quit", "activity"];
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Agent port to connect to
+    /// Agent port to connect to (TCP on [::1])
     #[arg(long)]
     agent_port: Option<u16>,
 
+    /// Agent Unix socket to connect to (takes precedence over --agent-port)
+    #[arg(long)]
+    agent_socket: Option<PathBuf>,
+
     /// Agent token for authentication
     #[arg(long)]
     agent_token: Option<String>,
 
+    /// ID given by the agent that launched this UI (sent back so the agent can track this window)
+    #[arg(long)]
+    ui_id: Option<String>,
+
     /// Run in test mode (auto-close after handshake)
     #[arg(long)]
     test_mode: bool,
@@ -33,11 +52,18 @@ struct Args {
 struct EditorApp {
     logs: Vec<String>,
     test_mode: bool,
+    /// 接続先の Agent とトークン
+    agent: Option<(AgentAddress, String)>,
+    /// Agent が起動したときに渡された ID
+    ui_id: String,
 }
 
 #[derive(Debug, Clone)]
 enum Message {
     HandshakeFinished(Vec<String>),
+    CoreEvent(String),
+    FocusRequested,
+    FilesOpened(Vec<String>),
     Error(String),
     CloseRequested,
 }
@@ -51,14 +77,21 @@ impl Application for EditorApp {
     fn new(args: Args) -> (Self, Command<Message>) {
         let mut logs = vec!["Initializing UI...".into()];
         
-        let command = if let (Some(port), Some(token)) = (args.agent_port, args.agent_token) {
-            logs.push(format!("Connecting to Agent on port {}...", port));
-            Command::perform(connect_to_agent_and_handshake(port, token), |res| match res {
+        let address = match (args.agent_socket, args.agent_port) {
+            (Some(path), _) => Some(AgentAddress::Unix(path)),
+            (None, Some(port)) => Some(AgentAddress::Tcp(port)),
+            (None, None) => None,
+        };
+        let agent = address.zip(args.agent_token);
+        let ui_id = args.ui_id.unwrap_or_default();
+        let command = if let Some((address, token)) = agent.clone() {
+            logs.push(format!("Connecting to Agent on {}...", address));
+            Command::perform(connect_to_agent_and_handshake(address, token, ui_id.clone()), |res| match res {
                 Ok(logs) => Message::HandshakeFinished(logs),
                 Err(e) => Message::Error(e),
             })
         } else {
-            logs.push("Error: Agent port or token not provided.".into());
+            logs.push("Error: Agent address or token not provided.".into());
             Command::none()
         };
 
@@ -66,6 +99,8 @@ impl Application for EditorApp {
             EditorApp {
                 logs,
                 test_mode: args.test_mode,
+                agent,
+                ui_id,
             },
             command
         )
@@ -90,6 +125,15 @@ impl Application for EditorApp {
                 }
                 Command::none()
             }
+            Message::CoreEvent(log) => {
+                self.logs.push(log);
+                Command::none()
+            }
+            Message::FocusRequested => window::gain_focus(window::Id::MAIN),
+            Message::FilesOpened(files) => {
+                self.logs.extend(files);
+                window::gain_focus(window::Id::MAIN)
+            }
             Message::Error(e) => {
                 self.logs.push(format!("Error: {}", e));
                 if self.test_mode {
@@ -104,6 +148,16 @@ impl Application for EditorApp {
         }
     }
 
+    fn subscription(&self) -> Subscription<Message> {
+        match &self.agent {
+            Some((address, token)) => Subscription::batch([
+                watch_cores(address.clone(), token.clone()),
+                subscribe_ui(address.clone(), token.clone(), self.ui_id.clone()),
+            ]),
+            None => Subscription::none(),
+        }
+    }
+
     fn view(&self) -> Element<'_, Message> {
         let content = column(
             self.logs.iter().map(|l| text(l).into()).collect::<Vec<_>>()
@@ -117,42 +171,190 @@ impl Application for EditorApp {
     }
 }
 
-async fn connect_to_agent_and_handshake(port: u16, token: String) -> Result<Vec<String>, String> {
+/// Agentの待ち受け先
+#[derive(Debug, Clone)]
+enum AgentAddress {
+    Unix(PathBuf),
+    Tcp(u16),
+}
+
+impl std::fmt::Display for AgentAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AgentAddress::Unix(path) => write!(f, "{}", path.display()),
+            AgentAddress::Tcp(port) => write!(f, "port {}", port),
+        }
+    }
+}
+
+async fn connect(address: &AgentAddress) -> Result<Channel, tonic::transport::Error> {
+    match address {
+        AgentAddress::Tcp(port) => {
+            Endpoint::from_shared(format!("http://[::1]:{}", port))?
+                .keep_alive_while_idle(true)
+                .http2_keep_alive_interval(std::time::Duration::from_secs(10))
+                .keep_alive_timeout(std::time::Duration::from_secs(5))
+                .connect()
+                .await
+        }
+        AgentAddress::Unix(path) => {
+            let path = path.clone();
+            // URI は使われないが、形式上必要
+            Endpoint::from_static("http://[::1]:50051")
+                .connect_with_connector(tower::service_fn(move |_: Uri| {
+                    tokio::net::UnixStream::connect(path.clone())
+                }))
+                .await
+        }
+    }
+}
+
+#[allow(clippy::result_large_err)]
+async fn connect_to_agent_and_handshake(address: AgentAddress, token: String, ui_id: String) -> Result<Vec<String>, String> {
     // 接続待ち（AgentがgRPCサーバーを起動する猶予）
     tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
     
-    let uri = format!("http://[::1]:{}", port);
-    let channel = Channel::from_shared(uri).map_err(|e| e.to_string())?
-        .keep_alive_while_idle(true)
-        .http2_keep_alive_interval(std::time::Duration::from_secs(10))
-        .keep_alive_timeout(std::time::Duration::from_secs(5))
-        .connect()
-        .await.map_err(|e| format!("Failed to connect to agent: {}", e))?;
+    let channel = connect(&address).await.map_err(|e| format!("Failed to connect to agent: {}", e))?;
 
     let token_metadata: MetadataValue<_> = token.parse().unwrap();
+    // Agent が起動した UI は、渡された ID を名乗る
+    let ui_metadata: Option<MetadataValue<_>> = ui_id.parse().ok().filter(|_| !ui_id.is_empty());
     let mut client = EditorServiceClient::with_interceptor(channel, move |mut req: Request<()>| {
         req.metadata_mut().insert("authorization", token_metadata.clone());
+        if let Some(ui_metadata) = &ui_metadata {
+            req.metadata_mut().insert("eng-ui", ui_metadata.clone());
+        }
         Ok(req)
     });
 
-    let outbound = tokio_stream::iter(vec![
-        HandshakeRequest { client_message: "Hello from UI to Agent!".into() },
-    ]);
+    let outbound = tokio_stream::iter(vec![HandshakeRequest {
+        protocol_version: PROTOCOL_VERSION,
+        min_protocol_version: PROTOCOL_VERSION,
+        client_kind: ClientKind::Gui.into(),
+        client_name: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        encodings: vec!["utf-8".into()],
+        session_id: String::new(),
+    }]);
 
     let response = client.handshake(Request::new(outbound)).await.map_err(|e| format!("RPC failed: {}", e))?;
     let mut inbound = response.into_inner();
 
-    let mut logs = Vec::new();
-    while let Some(resp) = inbound.next().await {
-        match resp {
-            Ok(msg) => logs.push(format!("Agent: {}", msg.server_message)),
-            Err(e) => logs.push(format!("RPC Error: {}", e)),
-        }
+    // 版が合わないなどの取り決めの失敗は、最初の応答がエラーになる
+    let welcome = match inbound.next().await {
+        Some(Ok(welcome)) => welcome,
+        Some(Err(e)) => return Err(format!("Handshake rejected: {}", e.message())),
+        None => return Err("Handshake closed without a response".into()),
+    };
+    // 旧プロトコルの Agent は版を返さない
+    if welcome.protocol_version == 0 {
+        return Err("Handshake rejected: the agent speaks the legacy protocol without version negotiation".into());
     }
+    let logs = vec![
+        format!("Agent: {} (protocol {})", welcome.server_name, welcome.protocol_version),
+        format!("Client ID: {} (session {})", welcome.client_id, welcome.session_id),
+        format!("Capabilities: {}", welcome.capabilities.join(", ")),
+    ];
+    while inbound.next().await.is_some() {}
 
     Ok(logs)
 }
 
+/// Agent からコアの起動・終了・再起動の知らせを受け取り続ける。
+/// コアが落ちると開いていたストリームは切れるので、起動し直したことをログに出す。
+fn watch_cores(address: AgentAddress, token: String) -> Subscription<Message> {
+    struct WatchCores;
+    iced::subscription::channel(std::any::TypeId::of::<WatchCores>(), 16, |mut output| async move {
+        match subscribe_core_events(&address, &token).await {
+            Ok(mut events) => {
+                while let Some(Ok(event)) = events.next().await {
+                    let _ = output.send(Message::CoreEvent(describe_core_event(&event))).await;
+                }
+            }
+            Err(e) => {
+                let _ = output.send(Message::CoreEvent(format!("Failed to watch cores: {}", e))).await;
+            }
+        }
+        std::future::pending().await
+    })
+}
+
+#[allow(clippy::result_large_err)]
+async fn subscribe_core_events(address: &AgentAddress, token: &str) -> Result<tonic::Streaming<CoreEvent>, String> {
+    let channel = connect(address).await.map_err(|e| format!("Failed to connect to agent: {}", e))?;
+    let token_metadata: MetadataValue<_> = token.parse().map_err(|_| "Invalid agent token".to_string())?;
+    let mut client = AgentServiceClient::with_interceptor(channel, move |mut req: Request<()>| {
+        req.metadata_mut().insert("authorization", token_metadata.clone());
+        Ok(req)
+    });
+    let response = client.watch_cores(Request::new(WatchCoresRequest {})).await.map_err(|e| format!("RPC failed: {}", e))?;
+    Ok(response.into_inner())
+}
+
+/// Agent からこの UI への指示 (前面に出す、閉じる) を受け取り続ける
+fn subscribe_ui(address: AgentAddress, token: String, ui_id: String) -> Subscription<Message> {
+    struct SubscribeUi;
+    iced::subscription::channel(std::any::TypeId::of::<SubscribeUi>(), 16, |mut output| async move {
+        match subscribe_ui_commands(&address, &token, ui_id).await {
+            Ok(mut commands) => {
+                while let Some(Ok(command)) = commands.next().await {
+                    let message = match command.kind() {
+                        UiCommandKind::Focus => Message::FocusRequested,
+                        UiCommandKind::Close => Message::CloseRequested,
+                        UiCommandKind::Open => Message::FilesOpened(command.files.iter().map(describe_opened_file).collect()),
+                        UiCommandKind::Unspecified => continue,
+                    };
+                    let _ = output.send(message).await;
+                }
+            }
+            Err(e) => {
+                let _ = output.send(Message::CoreEvent(format!("Failed to subscribe to the agent: {}", e))).await;
+            }
+        }
+        std::future::pending().await
+    })
+}
+
+#[allow(clippy::result_large_err)]
+async fn subscribe_ui_commands(address: &AgentAddress, token: &str, id: String) -> Result<tonic::Streaming<UiCommand>, String> {
+    let channel = connect(address).await.map_err(|e| format!("Failed to connect to agent: {}", e))?;
+    let token_metadata: MetadataValue<_> = token.parse().map_err(|_| "Invalid agent token".to_string())?;
+    let mut client = AgentServiceClient::with_interceptor(channel, move |mut req: Request<()>| {
+        req.metadata_mut().insert("authorization", token_metadata.clone());
+        Ok(req)
+    });
+    let response = client.subscribe_ui(Request::new(SubscribeUiRequest { id })).await.map_err(|e| format!("RPC failed: {}", e))?;
+    Ok(response.into_inner())
+}
+
+fn describe_opened_file(file: &OpenedFile) -> String {
+    let (path, line, column) = match &file.location {
+        Some(location) => (location.path.as_str(), location.line, location.column),
+        None => ("", 0, 0),
+    };
+    let position = match (line, column) {
+        (0, _) => String::new(),
+        (line, 0) => format!(":{}", line),
+        (line, column) => format!(":{}:{}", line, column),
+    };
+    let buffer = file.buffer.as_ref().map(|buffer| buffer.buffer_id.as_str()).unwrap_or_default();
+    format!("Opened {}{} (buffer {} in core {})", path, position, buffer, file.core)
+}
+
+fn describe_core_event(event: &CoreEvent) -> String {
+    match event.kind() {
+        CoreEventKind::Started => format!("Core {} started.", event.name),
+        CoreEventKind::Stopped => format!("Core {} stopped.", event.name),
+        CoreEventKind::Exited => format!("Core {} {}.", event.name, event.message),
+        CoreEventKind::Restarted => format!(
+            "Core {} was restarted ({} buffers recovered from auto-save).",
+            event.name, event.buffers
+        ),
+        CoreEventKind::GaveUp => format!("Core {} was not restarted: {}.", event.name, event.message),
+        CoreEventKind::Unspecified => format!("Core {}: {}", event.name, event.message),
+    }
+}
+
 fn main() -> iced::Result {
     let args = Args::parse();
     let settings = Settings::with_flags(args);